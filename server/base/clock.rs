@@ -8,6 +8,7 @@ use failure::Error;
 use log::warn;
 use parking_lot::Mutex;
 use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::Duration as StdDuration;
@@ -107,8 +108,16 @@ impl Clocks for RealClocks {
     }
 }
 
+/// Number of `TimerGuard`s which have lived "too long" since startup.
+static SLOW_OPERATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of slow operations logged by `TimerGuard` since startup.
+pub fn slow_operations() -> u64 {
+    SLOW_OPERATIONS.load(Ordering::Relaxed)
+}
+
 /// Logs a warning if the TimerGuard lives "too long", using the label created by a supplied
-/// function.
+/// function. Such operations are also counted; see `slow_operations`.
 pub struct TimerGuard<'a, C: Clocks + ?Sized, S: AsRef<str>, F: FnOnce() -> S + 'a> {
     clocks: &'a C,
     label_f: Option<F>,
@@ -135,6 +144,7 @@ where
         let elapsed = self.clocks.monotonic() - self.start;
         if elapsed.num_seconds() >= 1 {
             let label_f = self.label_f.take().unwrap();
+            SLOW_OPERATIONS.fetch_add(1, Ordering::Relaxed);
             warn!("{} took {}!", label_f().as_ref(), elapsed);
         }
     }
@@ -69,6 +69,78 @@ pub fn decode_size(encoded: &str) -> Result<i64, ()> {
     Ok(decoded)
 }
 
+static DURATION_UNITS: [(&str, i64); 4] = [
+    // (singular unit name, seconds)
+    ("day", 86_400),
+    ("hour", 3_600),
+    ("minute", 60),
+    ("second", 1),
+];
+
+/// Encodes a non-negative number of seconds into human-readable form, eg `30 days`.
+pub fn encode_duration(mut raw: i64) -> String {
+    let mut encoded = String::new();
+    for &(unit, secs) in &DURATION_UNITS {
+        if raw >= secs || (secs == 1 && encoded.is_empty()) {
+            let n = raw / secs;
+            raw %= secs;
+            if !encoded.is_empty() {
+                encoded.push(' ');
+            }
+            write!(
+                &mut encoded,
+                "{} {}{}",
+                n,
+                unit,
+                if n == 1 { "" } else { "s" }
+            )
+            .unwrap();
+        }
+    }
+    encoded
+}
+
+fn decode_durationpart(input: &str) -> IResult<&str, i64> {
+    nom::combinator::map_opt(
+        tuple((
+            map_res(take_while1(|c: char| c.is_ascii_digit()), |input: &str| {
+                input.parse::<i64>()
+            }),
+            space0,
+            opt(alt((
+                nom::combinator::value(604_800, alt((tag("weeks"), tag("week"), tag("w")))),
+                nom::combinator::value(86_400, alt((tag("days"), tag("day"), tag("d")))),
+                nom::combinator::value(3_600, alt((tag("hours"), tag("hour"), tag("h")))),
+                nom::combinator::value(
+                    60,
+                    alt((tag("minutes"), tag("minute"), tag("min"), tag("m"))),
+                ),
+                nom::combinator::value(
+                    1,
+                    alt((tag("seconds"), tag("second"), tag("sec"), tag("s"))),
+                ),
+            ))),
+        )),
+        |(n, _, opt_unit)| n.checked_mul(opt_unit.unwrap_or(1)),
+    )(input)
+}
+
+/// Decodes a human-readable duration such as `30 days` or `1d 12h` into seconds. Units may be
+/// weeks, days, hours, minutes, or seconds, singular or plural, or abbreviated to their first
+/// letter (or `min`/`sec`). A number without a unit is in seconds.
+pub fn decode_duration(encoded: &str) -> Result<i64, ()> {
+    let (remaining, decoded) = nom::multi::fold_many1(
+        delimited(space0, decode_durationpart, space0),
+        || Some(0i64),
+        |sum, i| sum.and_then(|s| s.checked_add(i)),
+    )(encoded)
+    .map_err(|_e: nom::Err<nom::error::Error<&str>>| ())?;
+    if !remaining.is_empty() {
+        return Err(());
+    }
+    decoded.ok_or(())
+}
+
 /// Returns a hex-encoded version of the input.
 pub fn hex(raw: &[u8]) -> String {
     #[rustfmt::skip]
@@ -116,6 +188,32 @@ mod tests {
         assert_eq!(super::decode_size("100M 42").unwrap(), (100i64 << 20) + 42);
     }
 
+    #[test]
+    fn duration() {
+        assert_eq!(super::decode_duration("30 days").unwrap(), 30 * 86_400);
+        assert_eq!(super::decode_duration("1d 12h").unwrap(), 36 * 3_600);
+        assert_eq!(
+            super::decode_duration("2 weeks 1 minute 5").unwrap(),
+            1_209_665
+        );
+        assert_eq!(super::decode_duration("90").unwrap(), 90);
+        super::decode_duration("").unwrap_err();
+        super::decode_duration("30 fortnights").unwrap_err();
+        super::decode_duration("99999999999999999999 days").unwrap_err();
+        assert_eq!(super::encode_duration(30 * 86_400), "30 days");
+        assert_eq!(
+            super::encode_duration(86_400 + 3_601),
+            "1 day 1 hour 1 second"
+        );
+        assert_eq!(super::encode_duration(0), "0 seconds");
+        for &secs in &[0, 1, 59, 3_600, 2_592_000, 1_209_665] {
+            assert_eq!(
+                super::decode_duration(&super::encode_duration(secs)),
+                Ok(secs)
+            );
+        }
+    }
+
     #[test]
     fn round_trip() {
         let s = "de382684a471f178e4e3a163762711b0653bfd83";
@@ -7,10 +7,10 @@
 use crate::json::UserConfig;
 use crate::schema::Permissions;
 use base::{bail_t, format_err_t, strutil, ErrorKind, ResultExt as _};
-use failure::{bail, format_err, Error, Fail, ResultExt as _};
+use failure::{bail, format_err, Error};
 use fnv::FnvHashMap;
 use lazy_static::lazy_static;
-use log::info;
+use log::{info, warn};
 use parking_lot::Mutex;
 use protobuf::Message;
 use ring::rand::{SecureRandom, SystemRandom};
@@ -20,11 +20,25 @@ use std::collections::BTreeMap;
 use std::fmt;
 use std::net::IpAddr;
 use std::str::FromStr;
+use uuid::Uuid;
 
 lazy_static! {
     static ref PARAMS: Mutex<scrypt::Params> = Mutex::new(scrypt::Params::recommended());
 }
 
+/// The number of consecutive failed password logins (for a user or from a peer address) allowed
+/// before further attempts are delayed. Each failure beyond this doubles the delay, starting at
+/// one second and up to `MAX_LOGIN_DELAY_SEC`.
+const FREE_LOGIN_FAILURES: i64 = 5;
+
+const MAX_LOGIN_DELAY_SEC: i64 = 3600;
+
+/// Failed logins from a peer address are forgotten after this long without another.
+const ADDR_LOGIN_FAILURE_MEMORY_SEC: i64 = 86400;
+
+/// When tracking more peer addresses than this, forgotten ones are pruned on each failure.
+const MAX_TRACKED_ADDRS: usize = 4096;
+
 /// For testing only: use fast but insecure hashes.
 /// Call via `testutil::init()`.
 pub(crate) fn set_test_config() {
@@ -42,6 +56,10 @@ pub struct User {
     pub password_failure_count: i64,
     pub permissions: Permissions,
 
+    /// The time (in seconds since epoch) of the last failed password login since this process
+    /// started, if any. Unlike `password_failure_count`, this isn't stored in the database.
+    last_password_failure_sec: Option<i64>,
+
     /// True iff this `User` has changed since the last flush.
     /// Only a couple things are flushed lazily: `password_failure_count` and (on upgrade to a new
     /// algorithm) `password_hash`.
@@ -104,6 +122,18 @@ impl UserChange {
     }
 }
 
+impl Permissions {
+    /// Returns true if these permissions allow access to the camera with the given uuid: either
+    /// they aren't restricted to particular cameras, or the camera is among those listed.
+    pub fn may_access_camera(&self, uuid: Uuid) -> bool {
+        self.camera_uuids.is_empty()
+            || self
+                .camera_uuids
+                .iter()
+                .any(|u| Uuid::parse_str(u).map(|u| u == uuid).unwrap_or(false))
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Request {
     pub when_sec: Option<i64>,
@@ -187,6 +217,9 @@ impl FromStr for SessionFlag {
 pub enum RevocationReason {
     LoggedOut = 1,
     AlgorithmChange = 2,
+    PasswordChanged = 3,
+    UserRevoked = 4,
+    Expired = 5,
 }
 
 #[derive(Debug, Default)]
@@ -194,11 +227,14 @@ pub struct Session {
     pub user_id: i32,
     flags: i32, // bitmask of SessionFlag enum values
     domain: Option<Vec<u8>>,
-    description: Option<String>,
+    pub description: Option<String>,
     seed: Seed,
 
     creation_password_id: Option<i32>,
-    creation: Request,
+    pub creation: Request,
+
+    /// The time (in seconds since epoch) after which this session is no longer valid, if any.
+    pub expiration_sec: Option<i64>,
 
     revocation: Request,
     revocation_reason: Option<i32>, // see RevocationReason enum
@@ -206,12 +242,32 @@ pub struct Session {
 
     pub permissions: Permissions,
 
-    last_use: Request,
-    use_count: i32,
+    pub last_use: Request,
+    pub use_count: i32,
     dirty: bool,
 }
 
 impl Session {
+    /// Returns the time (in seconds since epoch) at which this session expires, if any.
+    ///
+    /// An explicit `expiration_sec` applies to every session. The configured maximum age and
+    /// idle timeout apply only to sessions created by logging in with a password.
+    fn expiration(&self, max_age_sec: Option<i64>, idle_timeout_sec: Option<i64>) -> Option<i64> {
+        let mut exp = self.expiration_sec;
+        if self.creation_password_id.is_some() {
+            let created = self.creation.when_sec;
+            let last_use = self.last_use.when_sec.or(created);
+            let limits = [
+                created.and_then(|c| max_age_sec.map(|m| c + m)),
+                last_use.and_then(|l| idle_timeout_sec.map(|i| l + i)),
+            ];
+            for &l in limits.iter().flatten() {
+                exp = Some(exp.map_or(l, |e| e.min(l)));
+            }
+        }
+        exp
+    }
+
     pub fn csrf(&self) -> SessionHash {
         let r = blake3::keyed_hash(&self.seed.0, b"csrf");
         let mut h = SessionHash([0u8; 24]);
@@ -325,15 +381,46 @@ pub(crate) struct State {
     /// (and accept more frequent database accesses).
     sessions: FnvHashMap<SessionHash, Session>,
 
+    /// The maximum age of password-login sessions, from `GlobalConfig::session_max_age_sec`.
+    session_max_age_sec: Option<i64>,
+
+    /// The idle timeout of password-login sessions, from
+    /// `GlobalConfig::session_idle_timeout_sec`.
+    session_idle_timeout_sec: Option<i64>,
+
+    /// Recent failed password logins by peer address. Not stored in the database.
+    login_failures_by_addr: FnvHashMap<IpAddr, AddrLoginFailures>,
+
     rand: SystemRandom,
 }
 
+/// Failed password logins from a single peer address.
+#[derive(Debug)]
+struct AddrLoginFailures {
+    count: i64,
+    last_sec: i64,
+}
+
+/// Returns how long (in seconds) to wait after `last_failure_sec` before attempting another login,
+/// given `failures` consecutive failed attempts. Returns 0 if no wait is necessary.
+fn login_wait_sec(failures: i64, last_failure_sec: Option<i64>, now: i64) -> i64 {
+    let last = match last_failure_sec {
+        Some(l) if failures >= FREE_LOGIN_FAILURES => l,
+        _ => return 0,
+    };
+    let delay = (1i64 << (failures - FREE_LOGIN_FAILURES).min(12)).min(MAX_LOGIN_DELAY_SEC);
+    (last + delay - now).max(0)
+}
+
 impl State {
     pub fn init(conn: &Connection) -> Result<Self, Error> {
         let mut state = State {
             users_by_id: BTreeMap::new(),
             users_by_name: BTreeMap::new(),
             sessions: FnvHashMap::default(),
+            session_max_age_sec: None,
+            session_idle_timeout_sec: None,
+            login_failures_by_addr: FnvHashMap::default(),
             rand: ring::rand::SystemRandom::new(),
         };
         let mut stmt = conn.prepare(
@@ -365,6 +452,7 @@ impl State {
                     password_hash: row.get(3)?,
                     password_id: row.get(4)?,
                     password_failure_count: row.get(5)?,
+                    last_password_failure_sec: None,
                     dirty: false,
                     permissions,
                 },
@@ -374,14 +462,31 @@ impl State {
         Ok(state)
     }
 
-    pub fn apply(&mut self, conn: &Connection, change: UserChange) -> Result<&User, Error> {
+    /// Applies a user change. `req` describes the request making the change; a password change
+    /// revokes all of the user's sessions with it.
+    pub fn apply(
+        &mut self,
+        conn: &Connection,
+        req: Request,
+        change: UserChange,
+    ) -> Result<&User, Error> {
         if let Some(id) = change.id {
-            self.update_user(conn, id, change)
+            self.update_user(conn, req, id, change)
         } else {
             self.add_user(conn, change)
         }
     }
 
+    /// Sets the limits on password-login sessions' lifetimes, as configured in `GlobalConfig`.
+    pub fn set_session_timeouts(
+        &mut self,
+        max_age_sec: Option<i64>,
+        idle_timeout_sec: Option<i64>,
+    ) {
+        self.session_max_age_sec = max_age_sec;
+        self.session_idle_timeout_sec = idle_timeout_sec;
+    }
+
     pub fn users_by_id(&self) -> &BTreeMap<i32, User> {
         &self.users_by_id
     }
@@ -389,6 +494,7 @@ impl State {
     fn update_user(
         &mut self,
         conn: &Connection,
+        req: Request,
         id: i32,
         change: UserChange,
     ) -> Result<&User, Error> {
@@ -439,6 +545,14 @@ impl State {
             u.password_hash = h;
             u.password_id += 1;
             u.password_failure_count = 0;
+            u.last_password_failure_sec = None;
+            State::revoke_user_sessions(
+                conn,
+                &mut self.sessions,
+                id,
+                RevocationReason::PasswordChanged,
+                req,
+            )?;
         }
         u.config = change.config;
         u.permissions = change.permissions;
@@ -477,6 +591,7 @@ impl State {
             password_hash,
             password_id: 0,
             password_failure_count: 0,
+            last_password_failure_sec: None,
             dirty: false,
             permissions: change.permissions,
         }))
@@ -506,6 +621,11 @@ impl State {
         })
     }
 
+    /// Logs in with a password, creating a session.
+    ///
+    /// Repeated failures for a user or from a peer address (`req.addr`) delay further attempts;
+    /// see `FREE_LOGIN_FAILURES`. Attempts made too soon fail with `ResourceExhausted` without
+    /// checking the password.
     pub fn login_by_password(
         &mut self,
         conn: &Connection,
@@ -514,38 +634,119 @@ impl State {
         password: String,
         domain: Option<Vec<u8>>,
         session_flags: i32,
-    ) -> Result<(RawSessionId, &Session), Error> {
-        let id = self
-            .users_by_name
-            .get(username)
-            .ok_or_else(|| format_err!("no such user {:?}", username))?;
+    ) -> Result<(RawSessionId, &Session), base::Error> {
+        let addr_failures = &mut self.login_failures_by_addr;
+        let (addr, now) = (req.addr, req.when_sec);
+        if let (Some(addr), Some(now)) = (addr, now) {
+            if let Some(f) = addr_failures.get(&addr) {
+                let wait = login_wait_sec(f.count, Some(f.last_sec), now);
+                if wait > 0 {
+                    warn!("throttled login for user {:?} from {}", username, addr);
+                    bail_t!(
+                        ResourceExhausted,
+                        "too many failed logins from {}; try again in {} sec",
+                        addr,
+                        wait
+                    );
+                }
+            }
+        }
+        let note_addr_failure = |addr_failures: &mut FnvHashMap<IpAddr, AddrLoginFailures>| {
+            if let (Some(addr), Some(now)) = (addr, now) {
+                if addr_failures.len() >= MAX_TRACKED_ADDRS {
+                    addr_failures.retain(|_, f| now - f.last_sec < ADDR_LOGIN_FAILURE_MEMORY_SEC);
+                }
+                let f = addr_failures.entry(addr).or_insert(AddrLoginFailures {
+                    count: 0,
+                    last_sec: now,
+                });
+                if now - f.last_sec >= ADDR_LOGIN_FAILURE_MEMORY_SEC {
+                    f.count = 0;
+                }
+                f.count += 1;
+                f.last_sec = now;
+            }
+        };
+        let id = match self.users_by_name.get(username) {
+            Some(id) => id,
+            None => {
+                warn!("login for nonexistent user {:?} from {:?}", username, addr);
+                note_addr_failure(addr_failures);
+                bail_t!(Unauthenticated, "no such user {:?}", username);
+            }
+        };
         let u = self
             .users_by_id
             .get_mut(id)
             .expect("users_by_name implies users_by_id");
         if u.config.disabled {
-            bail!("user {:?} is disabled", username);
+            warn!("login for disabled user {:?} from {:?}", username, addr);
+            bail_t!(Unauthenticated, "user {:?} is disabled", username);
+        }
+        if let Some(now) = now {
+            let wait = login_wait_sec(u.password_failure_count, u.last_password_failure_sec, now);
+            if wait > 0 {
+                warn!("throttled login for user {:?} from {:?}", username, addr);
+                bail_t!(
+                    ResourceExhausted,
+                    "too many failed logins for user {:?}; try again in {} sec",
+                    username,
+                    wait
+                );
+            }
         }
-        let hash = u
-            .password_hash
-            .as_ref()
-            .ok_or_else(|| format_err!("no password set for user {:?}", username))?;
-        let hash = PasswordHash::new(hash)
-            .with_context(|_| format!("bad stored password hash for user {:?}", username))?;
+        let hash = match u.password_hash.as_ref() {
+            Some(h) => h,
+            None => {
+                warn!(
+                    "login for user {:?} with no password from {:?}",
+                    username, addr
+                );
+                bail_t!(Unauthenticated, "no password set for user {:?}", username);
+            }
+        };
+        let hash = PasswordHash::new(hash).map_err(|e| {
+            format_err_t!(
+                Internal,
+                "bad stored password hash for user {:?}: {}",
+                username,
+                e
+            )
+        })?;
         match scrypt::Scrypt.verify_password(password.as_bytes(), &hash) {
             Ok(()) => {}
             Err(scrypt::password_hash::errors::Error::Password) => {
                 u.dirty = true;
                 u.password_failure_count += 1;
-                bail!("incorrect password for user {:?}", username);
-            }
-            Err(e) => {
-                return Err(e
-                    .context(format!("unable to verify password for user {:?}", username))
-                    .into());
+                u.last_password_failure_sec = now;
+                warn!(
+                    "incorrect password for user {:?} from {:?} ({} consecutive failures)",
+                    username, addr, u.password_failure_count
+                );
+                note_addr_failure(addr_failures);
+                bail_t!(
+                    Unauthenticated,
+                    "incorrect password for user {:?}",
+                    username
+                );
             }
+            Err(e) => bail_t!(
+                Internal,
+                "unable to verify password for user {:?}: {}",
+                username,
+                e
+            ),
         }
 
+        info!("login for user {:?} from {:?}", username, addr);
+        if u.password_failure_count != 0 {
+            u.password_failure_count = 0;
+            u.dirty = true;
+        }
+        u.last_password_failure_sec = None;
+        if let Some(addr) = addr {
+            addr_failures.remove(&addr);
+        }
         let password_id = u.password_id;
         State::make_session_int(
             &self.rand,
@@ -557,10 +758,32 @@ impl State {
             session_flags,
             &mut self.sessions,
             u.permissions.clone(),
+            None,
+            None,
         )
+        .err_kind(ErrorKind::Internal)
+    }
+
+    /// Clears the given user's failed password logins, so that the next attempt isn't delayed.
+    pub fn unlock_user(&mut self, id: i32) -> Result<(), Error> {
+        let u = self
+            .users_by_id
+            .get_mut(&id)
+            .ok_or_else(|| format_err!("no such uid {:?}", id))?;
+        info!("unlocking user {:?}", &u.username);
+        if u.password_failure_count != 0 {
+            u.password_failure_count = 0;
+            u.dirty = true;
+        }
+        u.last_password_failure_sec = None;
+        Ok(())
     }
 
     /// Makes a session directly (no password required).
+    ///
+    /// This is used for API tokens as well as by `moonfire-nvr login`; unlike sessions created
+    /// by `login_by_password`, these may have a description and an expiration time.
+    #[allow(clippy::too_many_arguments)]
     pub fn make_session<'s>(
         &'s mut self,
         conn: &Connection,
@@ -569,6 +792,8 @@ impl State {
         domain: Option<Vec<u8>>,
         flags: i32,
         permissions: Permissions,
+        description: Option<String>,
+        expiration_sec: Option<i64>,
     ) -> Result<(RawSessionId, &'s Session), Error> {
         let u = self
             .users_by_id
@@ -587,9 +812,12 @@ impl State {
             flags,
             &mut self.sessions,
             permissions,
+            description,
+            expiration_sec,
         )
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn make_session_int<'s>(
         rand: &SystemRandom,
         conn: &Connection,
@@ -600,6 +828,8 @@ impl State {
         flags: i32,
         sessions: &'s mut FnvHashMap<SessionHash, Session>,
         permissions: Permissions,
+        description: Option<String>,
+        expiration_sec: Option<i64>,
     ) -> Result<(RawSessionId, &'s Session), Error> {
         let mut session_id = RawSessionId([0u8; 48]);
         rand.fill(&mut session_id.0).unwrap();
@@ -609,13 +839,13 @@ impl State {
         let mut stmt = conn.prepare_cached(
             r#"
             insert into user_session (session_id_hash,  user_id,  seed,  flags,  domain,
-                                      creation_password_id,  creation_time_sec,
+                                      description,  creation_password_id,  creation_time_sec,
                                       creation_user_agent,  creation_peer_addr,
-                                      permissions)
+                                      expiration_sec,  permissions)
                               values (:session_id_hash, :user_id, :seed, :flags, :domain,
-                                      :creation_password_id, :creation_time_sec,
+                                      :description, :creation_password_id, :creation_time_sec,
                                       :creation_user_agent, :creation_peer_addr,
-                                      :permissions)
+                                      :expiration_sec, :permissions)
             "#,
         )?;
         let addr = creation.addr_buf();
@@ -629,10 +859,12 @@ impl State {
             ":seed": &seed[..],
             ":flags": &flags,
             ":domain": &domain,
+            ":description": &description,
             ":creation_password_id": &creation_password_id,
             ":creation_time_sec": &creation.when_sec,
             ":creation_user_agent": &creation.user_agent,
             ":creation_peer_addr": &addr,
+            ":expiration_sec": &expiration_sec,
             ":permissions": &permissions_blob,
         })?;
         let e = match sessions.entry(hash) {
@@ -643,8 +875,10 @@ impl State {
             user_id: user.id,
             flags,
             domain,
+            description,
             creation_password_id,
             creation,
+            expiration_sec,
             seed: Seed(seed),
             permissions,
             ..Default::default()
@@ -677,6 +911,15 @@ impl State {
         if let Some(r) = s.revocation_reason {
             bail_t!(Unauthenticated, "session is no longer valid (reason={})", r);
         }
+        let exp = s.expiration(self.session_max_age_sec, self.session_idle_timeout_sec);
+        if let (Some(exp), Some(now)) = (exp, req.when_sec) {
+            if now >= exp {
+                if let Err(e) = revoke(conn, s, hash, RevocationReason::Expired, None, req) {
+                    warn!("unable to revoke expired session: {}", e);
+                }
+                bail_t!(Unauthenticated, "session expired at {}", exp);
+            }
+        }
         s.last_use = req;
         s.use_count += 1;
         s.dirty = true;
@@ -698,32 +941,145 @@ impl State {
             ::std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
             ::std::collections::hash_map::Entry::Vacant(e) => e.insert(lookup_session(conn, hash)?),
         };
-        if s.revocation_reason.is_none() {
-            let mut stmt = conn.prepare(
-                r#"
-                update user_session
-                set
-                    revocation_time_sec = ?,
-                    revocation_user_agent = ?,
-                    revocation_peer_addr = ?,
-                    revocation_reason = ?,
-                    revocation_reason_detail = ?
-                where
-                    session_id_hash = ?
-                "#,
-            )?;
-            let addr = req.addr_buf();
-            let addr: Option<&[u8]> = addr.as_ref().map(|a| a.as_ref());
-            stmt.execute(params![
-                req.when_sec,
-                req.user_agent,
-                addr,
-                reason as i32,
-                detail,
-                &hash.0[..],
-            ])?;
-            s.revocation = req;
-            s.revocation_reason = Some(reason as i32);
+        revoke(conn, s, hash, reason, detail, req)
+    }
+
+    /// Returns the given user's unrevoked sessions, oldest first, loading them into the cache as
+    /// necessary.
+    pub fn user_sessions(
+        &mut self,
+        conn: &Connection,
+        user_id: i32,
+    ) -> Result<Vec<(SessionHash, &Session)>, Error> {
+        let mut stmt = conn.prepare_cached(
+            r#"
+            select
+                session_id_hash
+            from
+                user_session
+            where
+                user_id = ? and
+                revocation_reason is null
+            order by
+                creation_time_sec
+            "#,
+        )?;
+        let mut rows = stmt.query(params![user_id])?;
+        let mut hashes = Vec::new();
+        while let Some(row) = rows.next()? {
+            let raw = row.get_ref(0)?.as_blob()?;
+            if raw.len() != 24 {
+                bail!("session hash must be 24 bytes; got {}", raw.len());
+            }
+            let mut h = SessionHash([0u8; 24]);
+            h.0.copy_from_slice(raw);
+            hashes.push(h);
+        }
+        for h in &hashes {
+            if let ::std::collections::hash_map::Entry::Vacant(e) = self.sessions.entry(*h) {
+                e.insert(lookup_session(conn, h)?);
+            }
+        }
+        let sessions = &self.sessions;
+        Ok(hashes.into_iter().map(|h| (h, &sessions[&h])).collect())
+    }
+
+    /// Revokes all unrevoked sessions which have expired as of `req.when_sec`, both in the
+    /// database and in the cache. Returns the number of sessions revoked.
+    pub fn revoke_expired_sessions(
+        &mut self,
+        conn: &Connection,
+        req: Request,
+    ) -> Result<usize, Error> {
+        let now = match req.when_sec {
+            Some(n) => n,
+            None => bail!("revoking expired sessions requires a time"),
+        };
+
+        // The database's last use times may lag the cache's, so this finds a superset of the
+        // expired sessions. Each is checked again below.
+        let mut stmt = conn.prepare_cached(
+            r#"
+            select
+                session_id_hash
+            from
+                user_session
+            where
+                revocation_reason is null and
+                (expiration_sec <= :now or
+                 (creation_password_id is not null and
+                  (creation_time_sec + :max_age_sec <= :now or
+                   coalesce(last_use_time_sec, creation_time_sec) + :idle_timeout_sec <= :now)))
+            "#,
+        )?;
+        let mut rows = stmt.query(named_params! {
+            ":now": &now,
+            ":max_age_sec": &self.session_max_age_sec,
+            ":idle_timeout_sec": &self.session_idle_timeout_sec,
+        })?;
+        let mut hashes = Vec::new();
+        while let Some(row) = rows.next()? {
+            let raw = row.get_ref(0)?.as_blob()?;
+            if raw.len() != 24 {
+                bail!("session hash must be 24 bytes; got {}", raw.len());
+            }
+            let mut h = SessionHash([0u8; 24]);
+            h.0.copy_from_slice(raw);
+            hashes.push(h);
+        }
+        let mut revoked = 0;
+        for h in &hashes {
+            let s = match self.sessions.entry(*h) {
+                ::std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                ::std::collections::hash_map::Entry::Vacant(e) => {
+                    e.insert(lookup_session(conn, h)?)
+                }
+            };
+            match s.expiration(self.session_max_age_sec, self.session_idle_timeout_sec) {
+                Some(exp) if exp <= now => {}
+                _ => continue,
+            }
+            revoke(conn, s, h, RevocationReason::Expired, None, req.clone())?;
+            revoked += 1;
+        }
+        Ok(revoked)
+    }
+
+    /// Revokes all of the given user's unrevoked sessions, both in the database and in the cache.
+    fn revoke_user_sessions(
+        conn: &Connection,
+        sessions: &mut FnvHashMap<SessionHash, Session>,
+        user_id: i32,
+        reason: RevocationReason,
+        req: Request,
+    ) -> Result<(), Error> {
+        let mut stmt = conn.prepare_cached(
+            r#"
+            update user_session
+            set
+                revocation_time_sec = :revocation_time_sec,
+                revocation_user_agent = :revocation_user_agent,
+                revocation_peer_addr = :revocation_peer_addr,
+                revocation_reason = :revocation_reason
+            where
+                user_id = :user_id and
+                revocation_reason is null
+            "#,
+        )?;
+        let addr = req.addr_buf();
+        let addr: Option<&[u8]> = addr.as_ref().map(|a| a.as_ref());
+        stmt.execute(named_params! {
+            ":revocation_time_sec": &req.when_sec,
+            ":revocation_user_agent": &req.user_agent,
+            ":revocation_peer_addr": &addr,
+            ":revocation_reason": reason as i32,
+            ":user_id": &user_id,
+        })?;
+        for s in sessions.values_mut() {
+            if s.user_id == user_id && s.revocation_reason.is_none() {
+                s.revocation = req.clone();
+                s.revocation_reason = Some(reason as i32);
+            }
         }
         Ok(())
     }
@@ -759,17 +1115,14 @@ impl State {
             if !u.dirty {
                 continue;
             }
-            info!(
-                "flushing user with hash: {}",
-                u.password_hash.as_ref().unwrap()
-            );
+            info!("flushing user {:?} (id {})", u.username, id);
             u_stmt.execute(named_params! {
                 ":password_failure_count": &u.password_failure_count,
                 ":password_hash": &u.password_hash,
                 ":id": &id,
             })?;
         }
-        for s in self.sessions.values() {
+        for (hash, s) in &self.sessions {
             if !s.dirty {
                 continue;
             }
@@ -780,6 +1133,7 @@ impl State {
                 ":last_use_user_agent": &s.last_use.user_agent,
                 ":last_use_peer_addr": &addr,
                 ":use_count": &s.use_count,
+                ":hash": &hash.0[..],
             })?;
         }
         Ok(())
@@ -798,6 +1152,45 @@ impl State {
     }
 }
 
+/// Revokes the given session, both in the database and in the cache, if it's not already revoked.
+fn revoke(
+    conn: &Connection,
+    s: &mut Session,
+    hash: &SessionHash,
+    reason: RevocationReason,
+    detail: Option<String>,
+    req: Request,
+) -> Result<(), Error> {
+    if s.revocation_reason.is_none() {
+        let mut stmt = conn.prepare_cached(
+            r#"
+            update user_session
+            set
+                revocation_time_sec = ?,
+                revocation_user_agent = ?,
+                revocation_peer_addr = ?,
+                revocation_reason = ?,
+                revocation_reason_detail = ?
+            where
+                session_id_hash = ?
+            "#,
+        )?;
+        let addr = req.addr_buf();
+        let addr: Option<&[u8]> = addr.as_ref().map(|a| a.as_ref());
+        stmt.execute(params![
+            req.when_sec,
+            req.user_agent,
+            addr,
+            reason as i32,
+            detail,
+            &hash.0[..],
+        ])?;
+        s.revocation = req;
+        s.revocation_reason = Some(reason as i32);
+    }
+    Ok(())
+}
+
 fn lookup_session(conn: &Connection, hash: &SessionHash) -> Result<Session, base::Error> {
     let mut stmt = conn
         .prepare_cached(
@@ -812,6 +1205,7 @@ fn lookup_session(conn: &Connection, hash: &SessionHash) -> Result<Session, base
             creation_time_sec,
             creation_user_agent,
             creation_peer_addr,
+            expiration_sec,
             revocation_time_sec,
             revocation_user_agent,
             revocation_peer_addr,
@@ -837,12 +1231,12 @@ fn lookup_session(conn: &Connection, hash: &SessionHash) -> Result<Session, base
         .err_kind(ErrorKind::Internal)?
         .ok_or_else(|| format_err_t!(NotFound, "no such session"))?;
     let creation_addr: FromSqlIpAddr = row.get(8).err_kind(ErrorKind::Internal)?;
-    let revocation_addr: FromSqlIpAddr = row.get(11).err_kind(ErrorKind::Internal)?;
-    let last_use_addr: FromSqlIpAddr = row.get(16).err_kind(ErrorKind::Internal)?;
+    let revocation_addr: FromSqlIpAddr = row.get(12).err_kind(ErrorKind::Internal)?;
+    let last_use_addr: FromSqlIpAddr = row.get(17).err_kind(ErrorKind::Internal)?;
     let mut permissions = Permissions::new();
     permissions
         .merge_from_bytes(
-            row.get_ref(18)
+            row.get_ref(19)
                 .err_kind(ErrorKind::Internal)?
                 .as_blob()
                 .err_kind(ErrorKind::Internal)?,
@@ -860,19 +1254,20 @@ fn lookup_session(conn: &Connection, hash: &SessionHash) -> Result<Session, base
             user_agent: row.get(7).err_kind(ErrorKind::Internal)?,
             addr: creation_addr.0,
         },
+        expiration_sec: row.get(9).err_kind(ErrorKind::Internal)?,
         revocation: Request {
-            when_sec: row.get(9).err_kind(ErrorKind::Internal)?,
-            user_agent: row.get(10).err_kind(ErrorKind::Internal)?,
+            when_sec: row.get(10).err_kind(ErrorKind::Internal)?,
+            user_agent: row.get(11).err_kind(ErrorKind::Internal)?,
             addr: revocation_addr.0,
         },
-        revocation_reason: row.get(12).err_kind(ErrorKind::Internal)?,
-        revocation_reason_detail: row.get(13).err_kind(ErrorKind::Internal)?,
+        revocation_reason: row.get(13).err_kind(ErrorKind::Internal)?,
+        revocation_reason_detail: row.get(14).err_kind(ErrorKind::Internal)?,
         last_use: Request {
-            when_sec: row.get(14).err_kind(ErrorKind::Internal)?,
-            user_agent: row.get(15).err_kind(ErrorKind::Internal)?,
+            when_sec: row.get(15).err_kind(ErrorKind::Internal)?,
+            user_agent: row.get(16).err_kind(ErrorKind::Internal)?,
             addr: last_use_addr.0,
         },
-        use_count: row.get(17).err_kind(ErrorKind::Internal)?,
+        use_count: row.get(18).err_kind(ErrorKind::Internal)?,
         dirty: false,
         permissions,
     })
@@ -909,7 +1304,7 @@ mod tests {
         };
         let (uid, mut c) = {
             let u = state
-                .apply(&conn, UserChange::add_user("slamb".to_owned()))
+                .apply(&conn, req.clone(), UserChange::add_user("slamb".to_owned()))
                 .unwrap();
             (u.id, u.change())
         };
@@ -923,9 +1318,12 @@ mod tests {
                 0,
             )
             .unwrap_err();
-        assert_eq!(format!("{}", e), "no password set for user \"slamb\"");
+        assert_eq!(
+            format!("{}", e),
+            "Unauthenticated: no password set for user \"slamb\""
+        );
         c.set_password("hunter2".to_owned());
-        state.apply(&conn, c).unwrap();
+        state.apply(&conn, req.clone(), c).unwrap();
         let e = state
             .login_by_password(
                 &conn,
@@ -936,7 +1334,10 @@ mod tests {
                 0,
             )
             .unwrap_err();
-        assert_eq!(format!("{}", e), "incorrect password for user \"slamb\"");
+        assert_eq!(
+            format!("{}", e),
+            "Unauthenticated: incorrect password for user \"slamb\""
+        );
         let sid = {
             let (sid, s) = state
                 .login_by_password(
@@ -1003,7 +1404,7 @@ mod tests {
         {
             let mut c = UserChange::add_user("slamb".to_owned());
             c.set_password("hunter2".to_owned());
-            state.apply(&conn, c).unwrap();
+            state.apply(&conn, req.clone(), c).unwrap();
         };
         let sid = state
             .login_by_password(
@@ -1057,7 +1458,7 @@ mod tests {
         let uid = {
             let mut c = UserChange::add_user("slamb".to_owned());
             c.set_password("hunter2".to_owned());
-            state.apply(&conn, c).unwrap().id
+            state.apply(&conn, req.clone(), c).unwrap().id
         };
 
         // Get a session for later.
@@ -1077,7 +1478,7 @@ mod tests {
         {
             let mut c = state.users_by_id().get(&uid).unwrap().change();
             c.config.disabled = true;
-            state.apply(&conn, c).unwrap();
+            state.apply(&conn, req.clone(), c).unwrap();
         }
 
         // Fresh logins shouldn't work.
@@ -1091,7 +1492,10 @@ mod tests {
                 0,
             )
             .unwrap_err();
-        assert_eq!(format!("{}", e), "user \"slamb\" is disabled");
+        assert_eq!(
+            format!("{}", e),
+            "Unauthenticated: user \"slamb\" is disabled"
+        );
 
         // Authenticating existing sessions shouldn't work either.
         let e = state
@@ -1114,6 +1518,462 @@ mod tests {
         );
     }
 
+    #[test]
+    fn password_change_revokes_sessions() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let req = Request {
+            when_sec: Some(42),
+            addr: Some(::std::net::IpAddr::V4(::std::net::Ipv4Addr::new(
+                127, 0, 0, 1,
+            ))),
+            user_agent: Some(b"some ua".to_vec()),
+        };
+        let uid = {
+            let mut c = UserChange::add_user("slamb".to_owned());
+            c.set_password("hunter2".to_owned());
+            state.apply(&conn, req.clone(), c).unwrap().id
+        };
+        let (sid, _) = state
+            .login_by_password(
+                &conn,
+                req.clone(),
+                "slamb",
+                "hunter2".to_owned(),
+                Some(b"nvr.example.com".to_vec()),
+                0,
+            )
+            .unwrap();
+
+        // Changing something other than the password leaves the session alone.
+        {
+            let mut c = state.users_by_id().get(&uid).unwrap().change();
+            c.config.preferences.insert("foo".to_owned(), 42.into());
+            state.apply(&conn, req.clone(), c).unwrap();
+        }
+        state
+            .authenticate_session(&conn, req.clone(), &sid.hash())
+            .unwrap();
+
+        // Changing the password revokes it.
+        {
+            let mut c = state.users_by_id().get(&uid).unwrap().change();
+            c.set_password("hunter3".to_owned());
+            state.apply(&conn, req.clone(), c).unwrap();
+        }
+        let e = state
+            .authenticate_session(&conn, req.clone(), &sid.hash())
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Unauthenticated: session is no longer valid (reason=3)"
+        );
+
+        // The session should still be revoked after reload.
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        let e = state
+            .authenticate_session(&conn, req, &sid.hash())
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Unauthenticated: session is no longer valid (reason=3)"
+        );
+    }
+
+    #[test]
+    fn list_and_revoke_user_sessions() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let req = Request {
+            when_sec: Some(42),
+            addr: Some(::std::net::IpAddr::V4(::std::net::Ipv4Addr::new(
+                127, 0, 0, 1,
+            ))),
+            user_agent: Some(b"some ua".to_vec()),
+        };
+        let uid = {
+            let mut c = UserChange::add_user("slamb".to_owned());
+            c.set_password("hunter2".to_owned());
+            state.apply(&conn, req.clone(), c).unwrap().id
+        };
+        let mut sids = Vec::new();
+        for _ in 0..2 {
+            let (sid, _) = state
+                .login_by_password(&conn, req.clone(), "slamb", "hunter2".to_owned(), None, 0)
+                .unwrap();
+            sids.push(sid);
+        }
+
+        // Both sessions should be listed, even after a reload empties the cache.
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        let mut listed: Vec<_> = state
+            .user_sessions(&conn, uid)
+            .unwrap()
+            .iter()
+            .map(|(h, s)| {
+                assert_eq!(s.creation.user_agent.as_deref(), Some(&b"some ua"[..]));
+                *h
+            })
+            .collect();
+        let mut expected: Vec<_> = sids.iter().map(|s| s.hash()).collect();
+        listed.sort_by_key(|h| h.0);
+        expected.sort_by_key(|h| h.0);
+        assert_eq!(listed, expected);
+
+        // Revoking one leaves the other.
+        state
+            .revoke_session(
+                &conn,
+                RevocationReason::UserRevoked,
+                None,
+                req.clone(),
+                &sids[0].hash(),
+            )
+            .unwrap();
+        let e = state
+            .authenticate_session(&conn, req.clone(), &sids[0].hash())
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Unauthenticated: session is no longer valid (reason=4)"
+        );
+        let listed = state.user_sessions(&conn, uid).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].0, sids[1].hash());
+    }
+
+    #[test]
+    fn session_timeouts() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let req = |when_sec| Request {
+            when_sec: Some(when_sec),
+            addr: None,
+            user_agent: None,
+        };
+        let uid = {
+            let mut c = UserChange::add_user("slamb".to_owned());
+            c.set_password("hunter2".to_owned());
+            state.apply(&conn, req(42), c).unwrap().id
+        };
+
+        // Idle timeout: each use pushes back the expiration.
+        state.set_session_timeouts(Some(1000), Some(100));
+        let (sid, _) = state
+            .login_by_password(&conn, req(42), "slamb", "hunter2".to_owned(), None, 0)
+            .unwrap();
+        state
+            .authenticate_session(&conn, req(100), &sid.hash())
+            .unwrap();
+        state
+            .authenticate_session(&conn, req(199), &sid.hash())
+            .unwrap();
+        let e = state
+            .authenticate_session(&conn, req(299), &sid.hash())
+            .unwrap_err();
+        assert_eq!(format!("{}", e), "Unauthenticated: session expired at 299");
+        let e = state
+            .authenticate_session(&conn, req(300), &sid.hash())
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Unauthenticated: session is no longer valid (reason=5)"
+        );
+
+        // Maximum age: use doesn't matter.
+        state.set_session_timeouts(Some(1000), None);
+        let (sid, _) = state
+            .login_by_password(&conn, req(42), "slamb", "hunter2".to_owned(), None, 0)
+            .unwrap();
+        state
+            .authenticate_session(&conn, req(1041), &sid.hash())
+            .unwrap();
+        let e = state
+            .authenticate_session(&conn, req(1042), &sid.hash())
+            .unwrap_err();
+        assert_eq!(format!("{}", e), "Unauthenticated: session expired at 1042");
+
+        // Tokens aren't subject to the timeouts, only their own expiration.
+        let (unlimited, _) = state
+            .make_session(
+                &conn,
+                req(42),
+                uid,
+                None,
+                0,
+                Permissions::default(),
+                None,
+                None,
+            )
+            .unwrap();
+        state
+            .authenticate_session(&conn, req(5000), &unlimited.hash())
+            .unwrap();
+        let (limited, _) = state
+            .make_session(
+                &conn,
+                req(42),
+                uid,
+                None,
+                0,
+                Permissions::default(),
+                None,
+                Some(500),
+            )
+            .unwrap();
+        state
+            .authenticate_session(&conn, req(499), &limited.hash())
+            .unwrap();
+        let e = state
+            .authenticate_session(&conn, req(500), &limited.hash())
+            .unwrap_err();
+        assert_eq!(format!("{}", e), "Unauthenticated: session expired at 500");
+    }
+
+    #[test]
+    fn revoke_expired_sessions() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        state.set_session_timeouts(None, Some(100));
+        let req = |when_sec| Request {
+            when_sec: Some(when_sec),
+            addr: None,
+            user_agent: None,
+        };
+        let uid = {
+            let mut c = UserChange::add_user("slamb".to_owned());
+            c.set_password("hunter2".to_owned());
+            state.apply(&conn, req(42), c).unwrap().id
+        };
+        let (idle, _) = state
+            .login_by_password(&conn, req(42), "slamb", "hunter2".to_owned(), None, 0)
+            .unwrap();
+        let (used, _) = state
+            .login_by_password(&conn, req(42), "slamb", "hunter2".to_owned(), None, 0)
+            .unwrap();
+        let (limited, _) = state
+            .make_session(
+                &conn,
+                req(42),
+                uid,
+                None,
+                0,
+                Permissions::default(),
+                None,
+                Some(500),
+            )
+            .unwrap();
+        let (unlimited, _) = state
+            .make_session(
+                &conn,
+                req(42),
+                uid,
+                None,
+                0,
+                Permissions::default(),
+                None,
+                None,
+            )
+            .unwrap();
+
+        // The cached (unflushed) use of the second session keeps it alive.
+        state
+            .authenticate_session(&conn, req(120), &used.hash())
+            .unwrap();
+        assert_eq!(state.revoke_expired_sessions(&conn, req(150)).unwrap(), 1);
+        assert_eq!(state.revoke_expired_sessions(&conn, req(150)).unwrap(), 0);
+
+        // The revocation is in the database, with the right reason.
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        state.set_session_timeouts(None, Some(100));
+        let e = state
+            .authenticate_session(&conn, req(151), &idle.hash())
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Unauthenticated: session is no longer valid (reason=5)"
+        );
+
+        // The unflushed use was lost, so now the second session is expired, as is the token with
+        // an expiration. The other token lives on.
+        assert_eq!(state.revoke_expired_sessions(&conn, req(600)).unwrap(), 2);
+        for sid in &[used, limited] {
+            let e = state
+                .authenticate_session(&conn, req(601), &sid.hash())
+                .unwrap_err();
+            assert_eq!(
+                format!("{}", e),
+                "Unauthenticated: session is no longer valid (reason=5)"
+            );
+        }
+        state
+            .authenticate_session(&conn, req(601), &unlimited.hash())
+            .unwrap();
+    }
+
+    #[test]
+    fn login_throttling() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let addr_a = ::std::net::IpAddr::V4(::std::net::Ipv4Addr::new(192, 0, 2, 1));
+        let addr_b = ::std::net::IpAddr::V4(::std::net::Ipv4Addr::new(192, 0, 2, 2));
+        let req = |when_sec, addr| Request {
+            when_sec: Some(when_sec),
+            addr,
+            user_agent: None,
+        };
+        let uid = {
+            let mut c = UserChange::add_user("slamb".to_owned());
+            c.set_password("hunter2".to_owned());
+            state.apply(&conn, req(0, None), c).unwrap().id
+        };
+
+        // Failures up to the threshold aren't delayed.
+        for _ in 0..FREE_LOGIN_FAILURES {
+            let e = state
+                .login_by_password(
+                    &conn,
+                    req(100, Some(addr_a)),
+                    "slamb",
+                    "x".to_owned(),
+                    None,
+                    0,
+                )
+                .unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::Unauthenticated);
+        }
+        assert_eq!(state.users_by_id()[&uid].password_failure_count, 5);
+
+        // Then the user is throttled, even from another address and with the right password.
+        let e = state
+            .login_by_password(
+                &conn,
+                req(100, Some(addr_b)),
+                "slamb",
+                "hunter2".to_owned(),
+                None,
+                0,
+            )
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Resource exhausted: too many failed logins for user \"slamb\"; try again in 1 sec"
+        );
+        state
+            .login_by_password(
+                &conn,
+                req(101, Some(addr_b)),
+                "slamb",
+                "hunter2".to_owned(),
+                None,
+                0,
+            )
+            .unwrap();
+        assert_eq!(state.users_by_id()[&uid].password_failure_count, 0);
+
+        // The first address is still remembered; trying nonexistent users counts against it.
+        let e = state
+            .login_by_password(
+                &conn,
+                req(101, Some(addr_a)),
+                "bob",
+                "x".to_owned(),
+                None,
+                0,
+            )
+            .unwrap_err();
+        assert_eq!(format!("{}", e), "Unauthenticated: no such user \"bob\"");
+        let e = state
+            .login_by_password(
+                &conn,
+                req(102, Some(addr_a)),
+                "slamb",
+                "hunter2".to_owned(),
+                None,
+                0,
+            )
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Resource exhausted: too many failed logins from 192.0.2.1; try again in 1 sec"
+        );
+        state
+            .login_by_password(
+                &conn,
+                req(103, Some(addr_a)),
+                "slamb",
+                "hunter2".to_owned(),
+                None,
+                0,
+            )
+            .unwrap();
+
+        // Each further failure doubles the delay, until an administrator unlocks the user.
+        for _ in 0..FREE_LOGIN_FAILURES + 3 {
+            state
+                .login_by_password(&conn, req(200, None), "slamb", "x".to_owned(), None, 0)
+                .unwrap_err();
+            state
+                .users_by_id
+                .get_mut(&uid)
+                .unwrap()
+                .last_password_failure_sec = None;
+        }
+        state
+            .login_by_password(&conn, req(200, None), "slamb", "x".to_owned(), None, 0)
+            .unwrap_err();
+        let e = state
+            .login_by_password(
+                &conn,
+                req(215, None),
+                "slamb",
+                "hunter2".to_owned(),
+                None,
+                0,
+            )
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Resource exhausted: too many failed logins for user \"slamb\"; try again in 1 sec"
+        );
+        state.unlock_user(uid).unwrap();
+        state
+            .login_by_password(
+                &conn,
+                req(215, None),
+                "slamb",
+                "hunter2".to_owned(),
+                None,
+                0,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn may_access_camera() {
+        let a = Uuid::parse_str("35144640-ff1e-4619-b0d5-4c74c185741c").unwrap();
+        let b = Uuid::parse_str("7f2e2ce7-6d7a-4b83-8ab8-1a5ff4e4a1c6").unwrap();
+        let mut p = Permissions::new();
+        assert!(p.may_access_camera(a));
+        assert!(p.may_access_camera(b));
+        p.camera_uuids.push(a.to_string());
+        assert!(p.may_access_camera(a));
+        assert!(!p.may_access_camera(b));
+    }
+
     #[test]
     fn delete() {
         testutil::init();
@@ -1130,7 +1990,7 @@ mod tests {
         let uid = {
             let mut c = UserChange::add_user("slamb".to_owned());
             c.set_password("hunter2".to_owned());
-            state.apply(&conn, c).unwrap().id
+            state.apply(&conn, req.clone(), c).unwrap().id
         };
 
         // Get a session for later.
@@ -1170,14 +2030,14 @@ mod tests {
         let mut state = State::init(&conn).unwrap();
         let mut change = UserChange::add_user("slamb".to_owned());
         change.permissions.view_video = true;
-        let u = state.apply(&conn, change).unwrap();
+        let u = state.apply(&conn, Request::default(), change).unwrap();
         assert!(u.permissions.view_video);
         assert!(!u.permissions.update_signals);
         let mut change = u.change();
         assert!(change.permissions.view_video);
         assert!(!change.permissions.update_signals);
         change.permissions.update_signals = true;
-        let u = state.apply(&conn, change).unwrap();
+        let u = state.apply(&conn, Request::default(), change).unwrap();
         assert!(u.permissions.view_video);
         assert!(u.permissions.update_signals);
         let uid = u.id;
@@ -1204,13 +2064,13 @@ mod tests {
             .config
             .preferences
             .insert("foo".to_string(), 42.into());
-        let u = state.apply(&conn, change).unwrap();
+        let u = state.apply(&conn, Request::default(), change).unwrap();
         let mut change = u.change();
         change
             .config
             .preferences
             .insert("bar".to_string(), 26.into());
-        let u = state.apply(&conn, change).unwrap();
+        let u = state.apply(&conn, Request::default(), change).unwrap();
         assert_eq!(u.config.preferences.get("foo"), Some(&42.into()));
         assert_eq!(u.config.preferences.get("bar"), Some(&26.into()));
         let uid = u.id;
@@ -127,7 +127,9 @@ pub fn run(conn: &mut rusqlite::Connection, opts: &Options) -> Result<i32, Error
             select
               id,
               sample_file_dir_id,
-              cum_recordings
+              cum_recordings,
+              cold_sample_file_dir_id,
+              cum_cold_recordings
             from
               stream
             where
@@ -137,14 +139,25 @@ pub fn run(conn: &mut rusqlite::Connection, opts: &Options) -> Result<i32, Error
         let mut rows = stmt.query(params![])?;
         while let Some(row) = rows.next()? {
             let stream_id = row.get(0)?;
-            let dir_id = row.get(1)?;
+            let dirs = StreamDirs {
+                hot: row.get(1)?,
+                cold: row.get(3)?,
+                cum_cold_recordings: row.get(4)?,
+            };
             let cum_recordings = row.get(2)?;
-            let mut stream = match dirs_by_id.get_mut(&dir_id) {
+            let mut stream = match dirs_by_id.get_mut(&dirs.hot) {
                 None => Stream::default(),
                 Some(d) => d.remove(&stream_id).unwrap_or_else(Stream::default),
             };
+            if let Some(cold) = dirs.cold {
+                let cold_stream = match dirs_by_id.get_mut(&cold) {
+                    None => Stream::default(),
+                    Some(d) => d.remove(&stream_id).unwrap_or_else(Stream::default),
+                };
+                printed_error |= merge_cold(&dirs, stream_id, &mut stream, cold_stream);
+            }
             stream.cum_recordings = Some(cum_recordings);
-            printed_error |= compare_stream(conn, dir_id, stream_id, opts, stream, &mut ctx)?;
+            printed_error |= compare_stream(conn, &dirs, stream_id, opts, stream, &mut ctx)?;
         }
     }
 
@@ -203,6 +216,7 @@ struct RecordingSummary {
     video_sync_samples: i32,
     media_duration: i32,
     flags: i32,
+    audio_samples: i32,
 }
 
 #[derive(Debug, Default)]
@@ -232,7 +246,56 @@ struct Stream {
 
 type Dir = FnvHashMap<i32, Stream>;
 
-fn summarize_index(video_index: &[u8]) -> Result<RecordingSummary, Error> {
+/// The sample file directories of a known stream.
+struct StreamDirs {
+    hot: i32,
+    cold: Option<i32>,
+
+    /// Recordings with lower ids should be in `cold`; others in `hot`.
+    cum_cold_recordings: i32,
+}
+
+impl StreamDirs {
+    fn for_recording(&self, id: CompositeId) -> i32 {
+        match self.cold {
+            Some(c) if id.recording() < self.cum_cold_recordings => c,
+            _ => self.hot,
+        }
+    }
+}
+
+/// Merges the recordings in a stream's cold directory into `stream`, from its hot directory,
+/// so that each recording refers to the directory which should hold its sample file.
+///
+/// Files in the hot directory which have been moved should be garbage. Files in the cold
+/// directory which haven't been moved yet are copies which were never committed; they'll be
+/// abandoned on the next run.
+fn merge_cold(dirs: &StreamDirs, stream_id: i32, stream: &mut Stream, cold: Stream) -> bool {
+    let mut printed_error = false;
+    stream.recordings.retain(|&id, r| {
+        if id >= dirs.cum_cold_recordings {
+            return true;
+        }
+        if !r.garbage_row {
+            error!(
+                "Recording {} should have been moved from dir {}: {:#?}",
+                CompositeId::new(stream_id, id),
+                dirs.hot,
+                r
+            );
+            printed_error = true;
+        }
+        false
+    });
+    for (id, r) in cold.recordings {
+        if id < dirs.cum_cold_recordings {
+            stream.recordings.insert(id, r);
+        }
+    }
+    printed_error
+}
+
+fn summarize_index(video_index: &[u8], audio_index: &[u8]) -> Result<RecordingSummary, Error> {
     let mut it = recording::SampleIndexIterator::default();
     let mut media_duration = 0;
     let mut video_samples = 0;
@@ -244,6 +307,12 @@ fn summarize_index(video_index: &[u8]) -> Result<RecordingSummary, Error> {
         video_samples += 1;
         video_sync_samples += it.is_key() as i32;
     }
+    let mut audio_it = recording::AudioIndexIterator::default();
+    let mut audio_samples = 0;
+    while audio_it.next(audio_index)? {
+        bytes += audio_it.bytes as u64;
+        audio_samples += 1;
+    }
     Ok(RecordingSummary {
         bytes,
         video_samples,
@@ -254,6 +323,7 @@ fn summarize_index(video_index: &[u8]) -> Result<RecordingSummary, Error> {
         } else {
             0
         },
+        audio_samples,
     })
 }
 
@@ -299,7 +369,7 @@ fn read_dir(d: &dir::SampleFileDir, opts: &Options) -> Result<Dir, Error> {
 /// Looks through a known stream for errors.
 fn compare_stream(
     conn: &rusqlite::Connection,
-    dir_id: i32,
+    dirs: &StreamDirs,
     stream_id: i32,
     opts: &Options,
     mut stream: Stream,
@@ -322,7 +392,8 @@ fn compare_stream(
               sample_file_bytes,
               wall_duration_90k + media_duration_delta_90k,
               video_samples,
-              video_sync_samples
+              video_sync_samples,
+              audio_samples
             from
               recording
             where
@@ -338,6 +409,7 @@ fn compare_stream(
                 media_duration: row.get(3)?,
                 video_samples: row.get(4)?,
                 video_sync_samples: row.get(5)?,
+                audio_samples: row.get::<_, Option<i32>>(6)?.unwrap_or(0),
             };
             stream
                 .recordings
@@ -353,7 +425,8 @@ fn compare_stream(
             r#"
             select
               composite_id,
-              video_index
+              video_index,
+              audio_index
             from
               recording_playback
             where
@@ -364,14 +437,15 @@ fn compare_stream(
         while let Some(row) = rows.next()? {
             let id = CompositeId(row.get(0)?);
             let video_index: Vec<u8> = row.get(1)?;
-            let s = match summarize_index(&video_index) {
+            let audio_index: Option<Vec<u8>> = row.get(2)?;
+            let s = match summarize_index(&video_index, audio_index.as_deref().unwrap_or(&[])) {
                 Ok(s) => s,
                 Err(e) => {
-                    error!("id {} has bad video_index: {}", id, e);
+                    error!("id {} has bad video_index or audio_index: {}", id, e);
                     printed_error = true;
                     if opts.trash_corrupt_rows {
                         ctx.rows_to_delete.insert(id);
-                        ctx.files_to_trash.insert((dir_id, id));
+                        ctx.files_to_trash.insert((dirs.for_recording(id), id));
                     }
                     continue;
                 }
@@ -428,7 +502,7 @@ fn compare_stream(
                 if db_rows_expected {
                     error!("Missing recording row for {}: {:#?}", id, recording);
                     if opts.trash_orphan_sample_files {
-                        ctx.files_to_trash.insert((dir_id, id));
+                        ctx.files_to_trash.insert((dirs.for_recording(id), id));
                     }
                     if opts.delete_orphan_rows {
                         // also delete playback/integrity rows, if any.
@@ -449,7 +523,7 @@ fn compare_stream(
             Some(ref p) => {
                 if r != p {
                     error!(
-                        "Recording {} summary doesn't match video_index: {:#?}",
+                        "Recording {} summary doesn't match indexes: {:#?}",
                         id, recording
                     );
                     printed_error = true;
@@ -459,7 +533,7 @@ fn compare_stream(
                 error!("Recording {} missing playback row: {:#?}", id, recording);
                 printed_error = true;
                 if opts.trash_orphan_sample_files {
-                    ctx.files_to_trash.insert((dir_id, id));
+                    ctx.files_to_trash.insert((dirs.for_recording(id), id));
                 }
                 if opts.delete_orphan_rows {
                     // also delete recording/integrity rows, if any.
@@ -34,9 +34,9 @@ use crate::raw;
 use crate::recording;
 use crate::schema;
 use crate::signal;
-use base::bail_t;
 use base::clock::{self, Clocks};
 use base::strutil::encode_size;
+use base::{bail_t, ErrorKind, ResultExt as _};
 use failure::{bail, format_err, Error, ResultExt};
 use fnv::{FnvHashMap, FnvHashSet};
 use hashlink::LinkedHashMap;
@@ -56,12 +56,13 @@ use std::ops::Range;
 use std::path::PathBuf;
 use std::str;
 use std::string::String;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::vec::Vec;
 use uuid::Uuid;
 
 /// Expected schema version. See `guide/schema.md` for more information.
-pub const EXPECTED_VERSION: i32 = 7;
+pub const EXPECTED_VERSION: i32 = 8;
 
 /// Length of the video index cache.
 /// The actual data structure is one bigger than this because we insert before we remove.
@@ -70,7 +71,8 @@ const VIDEO_INDEX_CACHE_LEN: usize = 1023;
 
 const GET_RECORDING_PLAYBACK_SQL: &str = r#"
     select
-      video_index
+      video_index,
+      audio_index
     from
       recording_playback
     where
@@ -84,6 +86,11 @@ const INSERT_VIDEO_SAMPLE_ENTRY_SQL: &str = r#"
                                     :rfc6381_codec, :data)
 "#;
 
+const INSERT_AUDIO_SAMPLE_ENTRY_SQL: &str = r#"
+    insert into audio_sample_entry (rfc6381_codec,  clock_rate,  data)
+                            values (:rfc6381_codec, :clock_rate, :data)
+"#;
+
 const UPDATE_STREAM_COUNTERS_SQL: &str = r#"
     update stream
     set cum_recordings = :cum_recordings,
@@ -92,6 +99,12 @@ const UPDATE_STREAM_COUNTERS_SQL: &str = r#"
     where id = :stream_id
 "#;
 
+const UPDATE_STREAM_COLD_SQL: &str = r#"
+    update stream
+    set cum_cold_recordings = :cum_cold_recordings
+    where id = :stream_id
+"#;
+
 /// The size of a filesystem block, to use in disk space accounting.
 /// This should really be obtained by a stat call on the sample file directory in question,
 /// but that requires some refactoring. See
@@ -123,14 +136,22 @@ impl rusqlite::types::ToSql for SqlUuid {
     }
 }
 
-struct VideoIndex(Box<[u8]>);
+struct IndexBlob(Box<[u8]>);
 
-impl rusqlite::types::FromSql for VideoIndex {
+impl rusqlite::types::FromSql for IndexBlob {
     fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
-        Ok(VideoIndex(value.as_blob()?.to_vec().into_boxed_slice()))
+        Ok(IndexBlob(value.as_blob()?.to_vec().into_boxed_slice()))
     }
 }
 
+/// The indexes of a committed recording, as stored in `LockedDatabase::video_index_cache`.
+struct CachedIndexes {
+    video_index: Box<[u8]>,
+
+    /// The audio index, or an empty slice if the recording has no audio.
+    audio_index: Box<[u8]>,
+}
+
 /// A concrete box derived from a ISO/IEC 14496-12 section 8.5.2 VisualSampleEntry box. Describes
 /// the codec, width, height, etc.
 #[derive(Debug)]
@@ -180,6 +201,38 @@ impl std::fmt::Debug for VideoSampleEntryToInsert {
     }
 }
 
+/// A concrete box derived from a ISO/IEC 14496-12 section 8.5.2 AudioSampleEntry box. Describes
+/// the codec, sample rate, etc.
+#[derive(Debug)]
+pub struct AudioSampleEntry {
+    pub id: i32,
+
+    // Fields matching AudioSampleEntryToInsert below.
+    pub data: Vec<u8>,
+    pub rfc6381_codec: String,
+
+    /// The timescale of durations within the audio index, typically the sampling rate.
+    pub clock_rate: u32,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct AudioSampleEntryToInsert {
+    pub data: Vec<u8>,
+    pub rfc6381_codec: String,
+    pub clock_rate: u32,
+}
+
+impl std::fmt::Debug for AudioSampleEntryToInsert {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use pretty_hex::PrettyHex;
+        f.debug_struct("AudioSampleEntryToInsert")
+            .field("data", &self.data.hex_dump())
+            .field("rfc6381_codec", &self.rfc6381_codec)
+            .field("clock_rate", &self.clock_rate)
+            .finish()
+    }
+}
+
 /// A row used in `list_recordings_by_time` and `list_recordings_by_id`.
 #[derive(Copy, Clone, Debug)]
 pub struct ListRecordingsRow {
@@ -198,6 +251,14 @@ pub struct ListRecordingsRow {
     pub open_id: u32,
     pub flags: i32,
 
+    /// The number of audio samples, or 0 if the recording has no audio.
+    pub audio_samples: i32,
+
+    /// The number of bytes at the end of the sample file which hold audio samples. This is 0
+    /// while the recording is still being written, as the audio is held in RAM until then.
+    pub audio_sample_file_bytes: i32,
+    pub audio_sample_entry_id: Option<i32>,
+
     /// This is populated by `list_recordings_by_id` but not `list_recordings_by_time`.
     /// (It's not included in the `recording_cover` index, so adding it to
     /// `list_recordings_by_time` would be inefficient.)
@@ -251,6 +312,13 @@ impl ListAggregatedRecordingsRow {
 #[derive(Debug)]
 pub struct RecordingPlayback<'a> {
     pub video_index: &'a [u8],
+
+    /// The audio index, or an empty slice if the recording has no audio.
+    pub audio_index: &'a [u8],
+
+    /// The audio sample data, iff it is still buffered in RAM rather than written to the end of
+    /// the sample file. This is the case only for a recording which is still being written.
+    pub audio_data: Option<&'a [u8]>,
 }
 
 /// Bitmask in the `flags` field in the `recordings` table; see `schema.sql`.
@@ -286,6 +354,17 @@ pub struct RecordingToInsert {
     pub video_index: Vec<u8>,
     pub sample_file_blake3: Option<[u8; 32]>,
     pub end_reason: Option<String>,
+
+    pub audio_samples: i32,
+
+    /// The byte length of the audio samples at the end of the sample file. This remains 0 until
+    /// the writer appends `audio_data` to the file on close.
+    pub audio_sample_file_bytes: i32,
+    pub audio_sample_entry_id: Option<i32>,
+    pub audio_index: Vec<u8>,
+
+    /// Audio sample data which has not yet been written to the sample file.
+    pub audio_data: Vec<u8>,
 }
 
 impl RecordingToInsert {
@@ -302,6 +381,9 @@ impl RecordingToInsert {
             run_offset: self.run_offset,
             open_id,
             flags: self.flags | RecordingFlags::Uncommitted as i32,
+            audio_samples: self.audio_samples,
+            audio_sample_file_bytes: self.audio_sample_file_bytes,
+            audio_sample_entry_id: self.audio_sample_entry_id,
             prev_media_duration_and_runs: Some((self.prev_media_duration, self.prev_runs)),
         }
     }
@@ -346,6 +428,12 @@ impl SampleFileDir {
             .clone())
     }
 
+    /// Returns the number of recordings in the `garbage` table, whether or not they've been
+    /// unlinked yet.
+    pub fn garbage_len(&self) -> usize {
+        self.garbage_needs_unlink.len() + self.garbage_unlinked.len()
+    }
+
     /// Returns expected existing metadata when opening this directory.
     fn expected_meta(&self, db_uuid: &Uuid) -> schema::DirMeta {
         let mut meta = schema::DirMeta::default();
@@ -434,6 +522,9 @@ pub struct Stream {
     pub id: i32,
     pub camera_id: i32,
     pub sample_file_dir_id: Option<i32>,
+
+    /// The directory to which recordings are moved after `config.cold_after_sec`, if any.
+    pub cold_sample_file_dir_id: Option<i32>,
     pub type_: StreamType,
     pub config: crate::json::StreamConfig,
 
@@ -450,7 +541,8 @@ pub struct Stream {
     pub fs_bytes: i64,
 
     /// On flush, delete the following recordings (move them to the `garbage` table, to be
-    /// collected later). Note they must be the oldest recordings. The later collection involves
+    /// collected later). These are sorted by id. They're usually the oldest recordings, but
+    /// recordings protected by `event_retain_bytes` may be skipped. The later collection involves
     /// the syncer unlinking the files on disk and syncing the directory then enqueueing for
     /// another following flush removal from the `garbage` table.
     to_delete: Vec<ListOldestRecordingsRow>,
@@ -481,6 +573,17 @@ pub struct Stream {
     /// The `cum_runs` currently committed to the database.
     cum_runs: i32,
 
+    /// The `cum_cold_recordings` currently committed to the database. Recordings with lower ids
+    /// are in `cold_sample_file_dir_id`; others are in `sample_file_dir_id`.
+    pub(crate) cum_cold_recordings: i32,
+
+    /// On flush, advance `cum_cold_recordings` to this value, and move the original copies of
+    /// the recordings in `to_cold` to the `garbage` table. The recordings in `to_cold` have
+    /// already been copied to (and synced within) `cold_sample_file_dir_id`. See
+    /// `LockedDatabase::mark_moved`.
+    next_cold_recording: i32,
+    to_cold: Vec<CompositeId>,
+
     /// The recordings which have been added via `LockedDatabase::add_recording` but have yet to
     /// committed to the database.
     ///
@@ -494,6 +597,194 @@ pub struct Stream {
     synced_recordings: usize,
 
     on_live_segment: Vec<Box<dyn FnMut(LiveSegment) -> bool + Send>>,
+
+    /// Counters updated by the streamer and writer without holding the database lock.
+    pub metrics: Arc<StreamMetrics>,
+
+    /// The streamer's most recently reported status; see `LockedDatabase::update_stream_status`.
+    pub status: StreamStatus,
+
+    /// Committed recordings with lower ids are all queued for deletion (or already deleted).
+    /// `delete_oldest_recordings_except` resumes its scan here.
+    delete_scan_start: i32,
+
+    /// The recordings protected by `config.event_retain_bytes`, if computed.
+    protected_events: Option<ProtectedEvents>,
+}
+
+/// The recordings of a stream which are protected by its `event_retain_bytes`: the newest
+/// recordings which overlap a motion event on its camera, up to that many bytes.
+///
+/// `LockedDatabase::list_protected_events` updates this incrementally as recordings are committed
+/// and signals change, so that deleting recordings doesn't require examining all of the stream's
+/// recordings and signal history each time.
+#[derive(Debug)]
+struct ProtectedEvents {
+    /// The `event_retain_bytes` and signal generation as of the last update.
+    event_retain_bytes: i64,
+    signal_generation: u64,
+
+    /// Committed recordings with lower ids have been examined.
+    next_recording: i32,
+
+    /// The protected recordings, oldest first.
+    events: VecDeque<ProtectedEvent>,
+
+    /// The total `fs_bytes` of `events`.
+    fs_bytes: i64,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct ProtectedEvent {
+    id: CompositeId,
+    end: recording::Time,
+    fs_bytes: i64,
+}
+
+impl ProtectedEvents {
+    fn new(event_retain_bytes: i64, signal_generation: u64, next_recording: i32) -> Self {
+        ProtectedEvents {
+            event_retain_bytes,
+            signal_generation,
+            next_recording,
+            events: VecDeque::new(),
+            fs_bytes: 0,
+        }
+    }
+
+    /// Adds a recording which overlaps motion, then drops the oldest recordings as necessary to
+    /// stay within `event_retain_bytes`.
+    fn insert(&mut self, e: ProtectedEvent) {
+        let i = match self.events.iter().rposition(|o| o.id.0 <= e.id.0) {
+            Some(i) if self.events[i].id == e.id => return,
+            Some(i) => i + 1,
+            None => 0,
+        };
+        self.events.insert(i, e);
+        self.fs_bytes += e.fs_bytes;
+        while self.fs_bytes > self.event_retain_bytes {
+            let o = self
+                .events
+                .pop_front()
+                .expect("fs_bytes > 0 implies events");
+            self.fs_bytes -= o.fs_bytes;
+        }
+    }
+
+    /// Examines the given rows, adding those which overlap `motion`.
+    fn examine(
+        &mut self,
+        rows: impl Iterator<Item = (CompositeId, Range<recording::Time>, i32)>,
+        motion: &[Range<recording::Time>],
+    ) {
+        for (id, time, sample_file_bytes) in rows {
+            let i = motion.partition_point(|m| m.end <= time.start);
+            if i < motion.len() && motion[i].start < time.end {
+                self.insert(ProtectedEvent {
+                    id,
+                    end: time.end,
+                    fs_bytes: round_up(i64::from(sample_file_bytes)),
+                });
+            }
+        }
+    }
+}
+
+/// Activity counters for a stream since startup, as exposed by the `/metrics` endpoint.
+#[derive(Debug, Default)]
+pub struct StreamMetrics {
+    /// The number of video frames received from the camera, including ones discarded while
+    /// waiting for the first key frame.
+    pub frames_received: AtomicU64,
+
+    /// The number of bytes of video and audio written to sample files.
+    pub bytes_written: AtomicU64,
+
+    /// The number of times the stream has been reopened after a failure.
+    pub reconnects: AtomicU64,
+
+    /// The `end_reason` of the most recent recording closed on failure or shutdown, if any.
+    pub last_end_reason: Mutex<Option<String>>,
+}
+
+impl StreamMetrics {
+    pub fn add_frame_received(&self) {
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_written(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_end_reason(&self, reason: String) {
+        *self.last_end_reason.lock() = Some(reason);
+    }
+}
+
+/// The connection state of a stream's streamer.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StreamState {
+    /// No streamer is running on this stream, eg because it isn't configured to record.
+    NotStarted,
+
+    /// Waiting for the camera to expire or tear down RTSP sessions from a previous connection.
+    WaitingForStaleSessions,
+
+    /// Opening the RTSP session.
+    Connecting,
+
+    /// Receiving frames.
+    Streaming,
+
+    /// Sleeping after a failure before reconnecting.
+    BackingOff,
+}
+
+impl StreamState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StreamState::NotStarted => "notStarted",
+            StreamState::WaitingForStaleSessions => "waitingForStaleSessions",
+            StreamState::Connecting => "connecting",
+            StreamState::Streaming => "streaming",
+            StreamState::BackingOff => "backingOff",
+        }
+    }
+}
+
+/// A stream's health as reported by its streamer.
+#[derive(Clone, Debug)]
+pub struct StreamStatus {
+    pub state: StreamState,
+
+    /// The most recent error which caused the streamer to back off, if any since startup.
+    pub last_error: Option<String>,
+
+    /// The local time of the most recently received frame, as of the last report.
+    pub last_frame: Option<recording::Time>,
+
+    /// Video frames per second received over the last reporting window.
+    pub fps: f32,
+
+    /// Bits per second of video received over the last reporting window.
+    pub bitrate_bps: u64,
+}
+
+impl Default for StreamStatus {
+    fn default() -> Self {
+        StreamStatus {
+            state: StreamState::NotStarted,
+            last_error: None,
+            last_frame: None,
+            fps: 0.,
+            bitrate_bps: 0,
+        }
+    }
 }
 
 /// Bounds of a live view segment. Currently this is a single frame of video.
@@ -515,6 +806,7 @@ pub struct LiveSegment {
 #[derive(Clone, Debug, Default)]
 pub struct StreamChange {
     pub sample_file_dir_id: Option<i32>,
+    pub cold_sample_file_dir_id: Option<i32>,
     pub config: crate::json::StreamConfig,
 }
 
@@ -543,6 +835,12 @@ impl Stream {
         self.committed_days.adjust(r, 1);
     }
 
+    /// Returns the number of recordings committed since the stream was created, including ones
+    /// which have since been deleted.
+    pub fn cum_recordings(&self) -> i32 {
+        self.cum_recordings
+    }
+
     /// Returns a days map including unflushed recordings.
     pub fn days(&self) -> days::Map<days::StreamValue> {
         let mut days = self.committed_days.clone();
@@ -555,6 +853,22 @@ impl Stream {
         }
         days
     }
+
+    /// Returns true iff the given recording is queued for deletion on the next flush.
+    fn is_to_delete(&self, id: CompositeId) -> bool {
+        self.to_delete
+            .binary_search_by_key(&id.0, |r| r.id.0)
+            .is_ok()
+    }
+
+    /// Returns the directory which holds the given committed recording's sample file.
+    pub fn sample_file_dir_id_for(&self, recording_id: i32) -> Option<i32> {
+        if recording_id < self.cum_cold_recordings {
+            self.cold_sample_file_dir_id
+        } else {
+            self.sample_file_dir_id
+        }
+    }
 }
 
 /// Initializes the recordings associated with the given camera.
@@ -601,6 +915,9 @@ pub struct LockedDatabase {
     uuid: Uuid,
     flush_count: usize,
 
+    /// The total time spent in successful calls to `flush` since startup.
+    flush_duration: recording::Duration,
+
     /// If the database is open in read-write mode, the information about the current Open row.
     pub open: Option<Open>,
 
@@ -616,8 +933,15 @@ pub struct LockedDatabase {
     streams_by_id: BTreeMap<i32, Stream>,
     cameras_by_uuid: BTreeMap<Uuid, i32>, // values are ids.
     video_sample_entries_by_id: BTreeMap<i32, Arc<VideoSampleEntry>>,
-    video_index_cache: RefCell<LinkedHashMap<i64, Box<[u8]>, fnv::FnvBuildHasher>>,
+    audio_sample_entries_by_id: BTreeMap<i32, Arc<AudioSampleEntry>>,
+    video_index_cache: RefCell<LinkedHashMap<i64, CachedIndexes, fnv::FnvBuildHasher>>,
     on_flush: Vec<Box<dyn Fn() + Send>>,
+    on_stream_status: Vec<Box<dyn FnMut(i32, &StreamStatus) -> bool + Send>>,
+    on_signal_update: Vec<Box<dyn FnMut(&signal::Update) -> bool + Send>>,
+    on_camera_change: Vec<Box<dyn FnMut(i32) -> bool + Send>>,
+
+    /// The global configuration, as of when the database was opened.
+    config: crate::json::GlobalConfig,
 }
 
 /// Represents a row of the `open` database table.
@@ -674,6 +998,14 @@ impl StreamStateChanger {
         let existing_streams = existing.map(|e| e.streams).unwrap_or_default();
         for (i, ref mut sc) in change.streams.iter_mut().enumerate() {
             let type_ = StreamType::from_index(i).unwrap();
+            if sc.cold_sample_file_dir_id.is_some()
+                && sc.cold_sample_file_dir_id == sc.sample_file_dir_id
+            {
+                bail!(
+                    "{} stream's cold_sample_file_dir_id must differ from its sample_file_dir_id",
+                    type_
+                );
+            }
             let mut have_data = false;
             if let Some(sid) = existing_streams[i] {
                 let s = streams_by_id.get(&sid).unwrap();
@@ -690,8 +1022,24 @@ impl StreamStateChanger {
                             sid
                         );
                     }
+                    if let (Some(d), false) = (
+                        s.cold_sample_file_dir_id,
+                        s.cold_sample_file_dir_id == sc.cold_sample_file_dir_id,
+                    ) {
+                        bail!(
+                            "can't change cold_sample_file_dir_id {:?}->{:?} for non-empty \
+                             stream {}",
+                            d,
+                            sc.cold_sample_file_dir_id,
+                            sid
+                        );
+                    }
                 }
-                if !have_data && sc.config.is_empty() && sc.sample_file_dir_id.is_none() {
+                if !have_data
+                    && sc.config.is_empty()
+                    && sc.sample_file_dir_id.is_none()
+                    && sc.cold_sample_file_dir_id.is_none()
+                {
                     // Delete stream.
                     let mut stmt = tx.prepare_cached(
                         r#"
@@ -708,7 +1056,8 @@ impl StreamStateChanger {
                         r#"
                         update stream set
                             config = :config,
-                            sample_file_dir_id = :sample_file_dir_id
+                            sample_file_dir_id = :sample_file_dir_id,
+                            cold_sample_file_dir_id = :cold_sample_file_dir_id
                         where
                             id = :id
                         "#,
@@ -716,6 +1065,7 @@ impl StreamStateChanger {
                     let rows = stmt.execute(named_params! {
                         ":config": &sc.config,
                         ":sample_file_dir_id": sc.sample_file_dir_id,
+                        ":cold_sample_file_dir_id": sc.cold_sample_file_dir_id,
                         ":id": sid,
                     })?;
                     if rows != 1 {
@@ -726,22 +1076,28 @@ impl StreamStateChanger {
                     streams.push((sid, Some((camera_id, type_, sc))));
                 }
             } else {
-                if sc.config.is_empty() && sc.sample_file_dir_id.is_none() {
+                if sc.config.is_empty()
+                    && sc.sample_file_dir_id.is_none()
+                    && sc.cold_sample_file_dir_id.is_none()
+                {
                     // Do nothing; there is no record and we want to keep it that way.
                     continue;
                 }
                 // Insert stream.
                 let mut stmt = tx.prepare_cached(
                     r#"
-                    insert into stream (camera_id,  sample_file_dir_id,  type,  config,
-                                        cum_recordings,  cum_media_duration_90k,  cum_runs)
-                                values (:camera_id, :sample_file_dir_id, :type, :config,
-                                        0,               0,                       0)
+                    insert into stream (camera_id,  sample_file_dir_id,  cold_sample_file_dir_id,
+                                        type,  config,  cum_recordings,  cum_media_duration_90k,
+                                        cum_runs)
+                                values (:camera_id, :sample_file_dir_id, :cold_sample_file_dir_id,
+                                        :type, :config, 0,               0,
+                                        0)
                     "#,
                 )?;
                 stmt.execute(named_params! {
                     ":camera_id": camera_id,
                     ":sample_file_dir_id": sc.sample_file_dir_id,
+                    ":cold_sample_file_dir_id": sc.cold_sample_file_dir_id,
                     ":type": type_.as_str(),
                     ":config": &sc.config,
                 })?;
@@ -769,6 +1125,7 @@ impl StreamStateChanger {
                         type_,
                         camera_id,
                         sample_file_dir_id: sc.sample_file_dir_id,
+                        cold_sample_file_dir_id: sc.cold_sample_file_dir_id,
                         config: sc.config,
                         range: None,
                         sample_file_bytes: 0,
@@ -783,15 +1140,23 @@ impl StreamStateChanger {
                         cum_recordings: 0,
                         cum_media_duration: recording::Duration(0),
                         cum_runs: 0,
+                        cum_cold_recordings: 0,
+                        next_cold_recording: 0,
+                        to_cold: Vec::new(),
                         uncommitted: VecDeque::new(),
                         synced_recordings: 0,
                         on_live_segment: Vec::new(),
+                        metrics: Arc::new(StreamMetrics::default()),
+                        status: StreamStatus::default(),
+                        delete_scan_start: 0,
+                        protected_events: None,
                     });
                 }
                 (Entry::Vacant(_), None) => {}
                 (Entry::Occupied(e), Some((_, _, sc))) => {
                     let e = e.into_mut();
                     e.sample_file_dir_id = sc.sample_file_dir_id;
+                    e.cold_sample_file_dir_id = sc.cold_sample_file_dir_id;
                     e.config = sc.config;
                 }
                 (Entry::Occupied(e), None) => {
@@ -808,6 +1173,9 @@ pub struct RetentionChange {
     pub stream_id: i32,
     pub new_record: bool,
     pub new_limit: i64,
+
+    /// The new `retain_duration_sec`, or `None` to leave it unchanged.
+    pub new_retain_duration_sec: Option<u32>,
 }
 
 impl LockedDatabase {
@@ -819,11 +1187,21 @@ impl LockedDatabase {
         &self.sample_file_dirs_by_id
     }
 
+    /// Returns the global configuration, as of when the database was opened.
+    pub fn config(&self) -> &crate::json::GlobalConfig {
+        &self.config
+    }
+
     /// Returns the number of completed database flushes since startup.
     pub fn flushes(&self) -> usize {
         self.flush_count
     }
 
+    /// Returns the total time spent in completed database flushes since startup.
+    pub fn flush_duration(&self) -> recording::Duration {
+        self.flush_duration
+    }
+
     /// Adds a placeholder for an uncommitted recording.
     ///
     /// The caller should write samples and fill the returned `RecordingToInsert` as it goes
@@ -940,6 +1318,33 @@ impl LockedDatabase {
         for s in self.streams_by_id.values_mut() {
             s.on_live_segment.clear();
         }
+        self.on_stream_status.clear();
+        self.on_signal_update.clear();
+        self.on_camera_change.clear();
+    }
+
+    /// Registers a callback to run on every stream status update, with the stream id and its new
+    /// status. As with `watch_live`, the callback is run with the database lock held and should
+    /// return false to unregister.
+    pub fn watch_stream_status(&mut self, cb: Box<dyn FnMut(i32, &StreamStatus) -> bool + Send>) {
+        self.on_stream_status.push(cb);
+    }
+
+    /// Replaces a stream's status and notifies watchers. This is called by the stream's streamer.
+    pub fn update_stream_status(
+        &mut self,
+        stream_id: i32,
+        status: StreamStatus,
+    ) -> Result<(), Error> {
+        let s = match self.streams_by_id.get_mut(&stream_id) {
+            None => bail!("no such stream {}", stream_id),
+            Some(s) => s,
+        };
+        use odds::vec::VecExt;
+        self.on_stream_status
+            .retain_mut(|cb| cb(stream_id, &status));
+        s.status = status;
+        Ok(())
     }
 
     pub(crate) fn send_live_segment(&mut self, stream: i32, l: LiveSegment) -> Result<(), Error> {
@@ -960,11 +1365,13 @@ impl LockedDatabase {
             None => bail!("database is read-only"),
             Some(o) => o,
         };
+        let start = recording::Time::new(clocks.monotonic());
         let tx = self.conn.transaction()?;
         let mut new_ranges =
             FnvHashMap::with_capacity_and_hasher(self.streams_by_id.len(), Default::default());
         {
             let mut stmt = tx.prepare_cached(UPDATE_STREAM_COUNTERS_SQL)?;
+            let mut cold_stmt = tx.prepare_cached(UPDATE_STREAM_COLD_SQL)?;
             for (&stream_id, s) in &self.streams_by_id {
                 // Process additions.
                 let mut new_duration = 0;
@@ -991,35 +1398,75 @@ impl LockedDatabase {
                 }
 
                 // Process deletions.
-                if let Some(l) = s.to_delete.last() {
+                if !s.to_delete.is_empty() {
                     new_ranges.entry(stream_id).or_insert(None);
-                    let dir = match s.sample_file_dir_id {
-                        None => bail!("stream {} has no directory!", stream_id),
-                        Some(d) => d,
-                    };
+                    if s.sample_file_dir_id.is_none() {
+                        bail!("stream {} has no directory!", stream_id);
+                    }
 
                     // raw::delete_recordings does a bulk transfer of a range from recording to
-                    // garbage, rather than operating on each element of to_delete. This is
-                    // guaranteed to give the same result because to_delete is guaranteed to be the
-                    // oldest recordings for the stream.
-                    let start = CompositeId::new(stream_id, 0);
-                    let end = CompositeId(l.id.0 + 1);
-                    let n = raw::delete_recordings(&tx, dir, start..end)? as usize;
+                    // garbage, rather than operating on each element of to_delete. Group
+                    // to_delete (which is sorted) into runs of consecutive ids to use it. There's
+                    // usually a single run, as to_delete is usually the oldest recordings for the
+                    // stream. Runs are split at the boundary between the cold and hot directories.
+                    let cold_end = CompositeId::new(stream_id, s.cum_cold_recordings).0;
+                    let runs = s
+                        .to_delete
+                        .iter()
+                        .map(|r| r.id.0..r.id.0 + 1)
+                        .coalesce(|a, b| {
+                            if a.end == b.start && b.start != cold_end {
+                                Ok(a.start..b.end)
+                            } else {
+                                Err((a, b))
+                            }
+                        });
+                    let mut n = 0;
+                    for run in runs {
+                        let start = CompositeId(run.start);
+                        let dir = match s.sample_file_dir_id_for(start.recording()) {
+                            None => bail!("stream {} has no cold directory!", stream_id),
+                            Some(d) => d,
+                        };
+                        n += raw::delete_recordings(&tx, dir, start..CompositeId(run.end))?;
+                    }
                     if n != s.to_delete.len() {
                         bail!(
-                            "Found {} rows in {} .. {}, expected {}: {:?}",
+                            "Found {} rows, expected {}: {:?}",
                             n,
-                            start,
-                            end,
                             s.to_delete.len(),
                             &s.to_delete
                         );
                     }
                 }
+
+                // Process moves to the cold directory.
+                if s.next_cold_recording != s.cum_cold_recordings {
+                    new_ranges.entry(stream_id).or_insert(None);
+                    let (hot, cold) = match (s.sample_file_dir_id, s.cold_sample_file_dir_id) {
+                        (Some(h), Some(c)) => (h, c),
+                        _ => bail!("stream {} has moved recordings but no cold dir!", stream_id),
+                    };
+
+                    // The original of each moved recording is now garbage. If the recording was
+                    // also deleted, raw::delete_recordings above has already handled the
+                    // original, but the copy is garbage too.
+                    let (deleted, moved): (Vec<CompositeId>, Vec<CompositeId>) = s
+                        .to_cold
+                        .iter()
+                        .copied()
+                        .partition(|&id| s.is_to_delete(id));
+                    raw::insert_garbage(&tx, hot, &moved)?;
+                    raw::insert_garbage(&tx, cold, &deleted)?;
+                    cold_stmt.execute(named_params! {
+                        ":stream_id": stream_id,
+                        ":cum_cold_recordings": s.next_cold_recording,
+                    })?;
+                }
             }
         }
-        for dir in self.sample_file_dirs_by_id.values() {
-            raw::mark_sample_files_deleted(&tx, &dir.garbage_unlinked)?;
+        for (&dir_id, dir) in &self.sample_file_dirs_by_id {
+            raw::mark_sample_files_deleted(&tx, dir_id, &dir.garbage_unlinked)?;
         }
         for (&stream_id, r) in &mut new_ranges {
             *r = raw::get_range(&tx, stream_id)?;
@@ -1045,6 +1492,7 @@ impl LockedDatabase {
         struct DirLog {
             added: SmallVec<[CompositeId; 32]>,
             deleted: SmallVec<[CompositeId; 32]>,
+            moved: SmallVec<[CompositeId; 32]>,
             gced: SmallVec<[CompositeId; 32]>,
             added_bytes: i64,
             deleted_bytes: i64,
@@ -1065,9 +1513,24 @@ impl LockedDatabase {
         for (stream_id, new_range) in new_ranges.drain() {
             let s = self.streams_by_id.get_mut(&stream_id).unwrap();
             let dir_id = s.sample_file_dir_id.unwrap();
-            let dir = self.sample_file_dirs_by_id.get_mut(&dir_id).unwrap();
             let log = dir_logs.entry(dir_id).or_default();
 
+            // Process mark_moved.
+            log.moved.reserve(s.to_cold.len());
+            for id in mem::take(&mut s.to_cold) {
+                log.moved.push(id);
+                let garbage_dir_id = if s.is_to_delete(id) {
+                    s.cold_sample_file_dir_id.unwrap()
+                } else {
+                    dir_id
+                };
+                let garbage_dir = self
+                    .sample_file_dirs_by_id
+                    .get_mut(&garbage_dir_id)
+                    .unwrap();
+                garbage_dir.garbage_needs_unlink.insert(id);
+            }
+
             // Process delete_oldest_recordings.
             s.sample_file_bytes -= s.bytes_to_delete;
             s.fs_bytes -= s.fs_bytes_to_delete;
@@ -1075,9 +1538,20 @@ impl LockedDatabase {
             s.bytes_to_delete = 0;
             s.fs_bytes_to_delete = 0;
             log.deleted.reserve(s.to_delete.len());
+            let (cold_dir_id, cum_cold_recordings) =
+                (s.cold_sample_file_dir_id, s.cum_cold_recordings);
             for row in s.to_delete.drain(..) {
                 log.deleted.push(row.id);
-                dir.garbage_needs_unlink.insert(row.id);
+                let garbage_dir_id = if row.id.recording() < cum_cold_recordings {
+                    cold_dir_id.unwrap()
+                } else {
+                    dir_id
+                };
+                let garbage_dir = self
+                    .sample_file_dirs_by_id
+                    .get_mut(&garbage_dir_id)
+                    .unwrap();
+                garbage_dir.garbage_needs_unlink.insert(row.id);
                 let d = recording::Duration(i64::from(row.wall_duration_90k));
                 s.duration -= d;
                 s.committed_days.adjust(row.start..row.start + d, -1);
@@ -1102,6 +1576,7 @@ impl LockedDatabase {
                 s.add_recording(l.start..end, l.sample_file_bytes);
             }
             s.synced_recordings = 0;
+            s.cum_cold_recordings = s.next_cold_recording;
 
             // Fix the range.
             s.range = new_range;
@@ -1109,6 +1584,7 @@ impl LockedDatabase {
         self.auth.post_flush();
         self.signal.post_flush();
         self.flush_count += 1;
+        self.flush_duration += recording::Time::new(clocks.monotonic()) - start;
         let mut log_msg = String::with_capacity(256);
         for (&dir_id, log) in &dir_logs {
             let dir = self.sample_file_dirs_by_id.get(&dir_id).unwrap();
@@ -1127,6 +1603,15 @@ impl LockedDatabase {
                 log.gced.iter().join(", ")
             )
             .unwrap();
+            if !log.moved.is_empty() {
+                write!(
+                    &mut log_msg,
+                    " Moved {} recordings to cold storage ({}).",
+                    log.moved.len(),
+                    log.moved.iter().join(", ")
+                )
+                .unwrap();
+            }
         }
         if log_msg.is_empty() {
             log_msg.push_str(" no recording changes");
@@ -1234,6 +1719,11 @@ impl LockedDatabase {
         &self.video_sample_entries_by_id
     }
 
+    /// Returns an immutable view of the audio sample entries.
+    pub fn audio_sample_entries_by_id(&self) -> &BTreeMap<i32, Arc<AudioSampleEntry>> {
+        &self.audio_sample_entries_by_id
+    }
+
     /// Gets a given camera by uuid.
     pub fn get_camera(&self, uuid: Uuid) -> Option<&Camera> {
         self.cameras_by_uuid.get(&uuid).map(|id| {
@@ -1318,6 +1808,37 @@ impl LockedDatabase {
         Ok(())
     }
 
+    /// Lists the `sample_file_blake3` of each of the specified recordings which has one, in
+    /// ascending order by id. Uncommitted recordings are included once their sample files have
+    /// been fully written.
+    pub fn list_sample_file_blake3s(
+        &self,
+        stream_id: i32,
+        desired_ids: Range<i32>,
+        f: &mut dyn FnMut(CompositeId, [u8; 32]),
+    ) -> Result<(), base::Error> {
+        let s = match self.streams_by_id.get(&stream_id) {
+            None => bail_t!(NotFound, "no such stream {}", stream_id),
+            Some(s) => s,
+        };
+        if desired_ids.start < s.cum_recordings {
+            raw::list_sample_file_blake3s(&self.conn, stream_id, desired_ids.clone(), f)?;
+        }
+        if desired_ids.end > s.cum_recordings {
+            let start = cmp::max(0, desired_ids.start - s.cum_recordings) as usize;
+            let end = cmp::min(
+                (desired_ids.end - s.cum_recordings) as usize,
+                s.uncommitted.len(),
+            );
+            for i in start..end {
+                if let Some(b) = s.uncommitted[i].lock().sample_file_blake3 {
+                    f(CompositeId::new(stream_id, s.cum_recordings + i as i32), b);
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Calls `list_recordings_by_time` and aggregates consecutive recordings.
     /// Rows are given to the callback in arbitrary order. Callers which care about ordering
     /// should do their own sorting.
@@ -1436,6 +1957,12 @@ impl LockedDatabase {
             let l = s.uncommitted[i as usize].lock();
             return f(&RecordingPlayback {
                 video_index: &l.video_index,
+                audio_index: &l.audio_index,
+                audio_data: if l.audio_sample_file_bytes == 0 {
+                    Some(&l.audio_data)
+                } else {
+                    None
+                },
             });
         }
 
@@ -1446,19 +1973,30 @@ impl LockedDatabase {
             RawEntryMut::Occupied(mut occupied) => {
                 trace!("cache hit for recording {}", id);
                 occupied.to_back();
-                let video_index = occupied.get();
-                f(&RecordingPlayback { video_index })
+                let i = occupied.get();
+                f(&RecordingPlayback {
+                    video_index: &i.video_index,
+                    audio_index: &i.audio_index,
+                    audio_data: None,
+                })
             }
             RawEntryMut::Vacant(vacant) => {
                 trace!("cache miss for recording {}", id);
                 let mut stmt = self.conn.prepare_cached(GET_RECORDING_PLAYBACK_SQL)?;
                 let mut rows = stmt.query(named_params! {":composite_id": id.0})?;
                 if let Some(row) = rows.next()? {
-                    let video_index: VideoIndex = row.get(0)?;
+                    let video_index: IndexBlob = row.get(0)?;
+                    let audio_index: Option<IndexBlob> = row.get(1)?;
+                    let i = CachedIndexes {
+                        video_index: video_index.0,
+                        audio_index: audio_index.map(|i| i.0).unwrap_or_default(),
+                    };
                     let result = f(&RecordingPlayback {
-                        video_index: &video_index.0[..],
+                        video_index: &i.video_index,
+                        audio_index: &i.audio_index,
+                        audio_data: None,
                     });
-                    vacant.insert(id.0, video_index.0);
+                    vacant.insert(id.0, i);
                     if cache.len() > VIDEO_INDEX_CACHE_LEN {
                         cache.pop_front();
                     }
@@ -1475,48 +2013,235 @@ impl LockedDatabase {
         &mut self,
         stream_id: i32,
         f: &mut dyn FnMut(&ListOldestRecordingsRow) -> bool,
+    ) -> Result<(), Error> {
+        self.delete_oldest_recordings_except(stream_id, &FnvHashSet::default(), f)
+    }
+
+    /// Queues for deletion the oldest recordings that aren't already queued, passing over those
+    /// in `keep` without calling `f`.
+    /// `f` should return true for each row that should be deleted.
+    pub(crate) fn delete_oldest_recordings_except(
+        &mut self,
+        stream_id: i32,
+        keep: &FnvHashSet<CompositeId>,
+        f: &mut dyn FnMut(&ListOldestRecordingsRow) -> bool,
     ) -> Result<(), Error> {
         let s = match self.streams_by_id.get_mut(&stream_id) {
             None => bail!("no stream {}", stream_id),
             Some(s) => s,
         };
-        let end = match s.to_delete.last() {
-            None => 0,
-            Some(row) => row.id.recording() + 1,
-        };
-        raw::list_oldest_recordings(&self.conn, CompositeId::new(stream_id, end), &mut |r| {
+
+        // Resume after the rows known to be queued. Rows passed over (whether in `keep` or
+        // because `f` returned false) hold this back, so that a later call can reconsider them.
+        let start = s.delete_scan_start;
+        let mut passed_over = None;
+        let mut next = start;
+        raw::list_oldest_recordings(&self.conn, CompositeId::new(stream_id, start), &mut |r| {
+            next = r.id.recording() + 1;
+            if keep.contains(&r.id) {
+                passed_over.get_or_insert(r.id.recording());
+                return true;
+            }
+            let i = match s.to_delete.binary_search_by_key(&r.id.0, |d| d.id.0) {
+                Ok(_) => return true, // already queued.
+                Err(i) => i,
+            };
             if f(&r) {
-                s.to_delete.push(r);
+                s.to_delete.insert(i, r);
                 let bytes = i64::from(r.sample_file_bytes);
                 s.bytes_to_delete += bytes;
                 s.fs_bytes_to_delete += round_up(bytes);
                 return true;
             }
+            passed_over.get_or_insert(r.id.recording());
             false
-        })
+        })?;
+        s.delete_scan_start = passed_over.unwrap_or(next);
+        Ok(())
     }
 
-    /// Initializes the video_sample_entries. To be called during construction.
-    fn init_video_sample_entries(&mut self) -> Result<(), Error> {
-        info!("Loading video sample entries");
-        let mut stmt = self.conn.prepare(
-            r#"
-            select
-                id,
-                width,
-                height,
-                pasp_h_spacing,
-                pasp_v_spacing,
-                rfc6381_codec,
-                data
-            from
-                video_sample_entry
-            "#,
+    /// Returns the recordings protected by the stream's `event_retain_bytes`: the newest
+    /// recordings which overlap a motion event on its camera, up to that many bytes.
+    ///
+    /// This examines only recordings committed since the last call, plus those affected by
+    /// signal changes since then, except when a change may have made a protected recording
+    /// unprotected.
+    pub(crate) fn list_protected_events(
+        &mut self,
+        stream_id: i32,
+    ) -> Result<FnvHashSet<CompositeId>, Error> {
+        let s = match self.streams_by_id.get_mut(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => s,
+        };
+        let event_retain_bytes = s.config.event_retain_bytes;
+        if event_retain_bytes <= 0 {
+            s.protected_events = None;
+            return Ok(FnvHashSet::default());
+        }
+        let camera_id = s.camera_id;
+        let generation = self.signal.generation();
+        let mut p = match s.protected_events.take() {
+            Some(p) if p.event_retain_bytes == event_retain_bytes => p,
+            _ => ProtectedEvents::new(event_retain_bytes, generation, s.delete_scan_start),
+        };
+
+        // Forget recordings which have been queued for deletion, as when there was nothing else
+        // left to delete.
+        let is_live =
+            |id: CompositeId| id.recording() >= s.delete_scan_start && !s.is_to_delete(id);
+        p.events.retain(|e| is_live(e.id));
+        p.fs_bytes = p.events.iter().map(|e| e.fs_bytes).sum();
+
+        if let Some(t) = self.signal.earliest_change_since(p.signal_generation) {
+            if p.events.back().map(|e| e.end > t).unwrap_or(false) {
+                // Motion may have been removed from a protected recording, which might make an
+                // older one protected instead. Start over.
+                p = ProtectedEvents::new(event_retain_bytes, generation, s.delete_scan_start);
+            } else {
+                // Only recordings which end after `t` are affected, and none are protected.
+                let motion = self.signal.motion_ranges(camera_id, t);
+                let mut rows = Vec::new();
+                raw::list_recordings_by_time(
+                    &self.conn,
+                    stream_id,
+                    t..recording::Time::max_value(),
+                    &mut |r| {
+                        if r.id.recording() < p.next_recording && is_live(r.id) {
+                            let end = r.start + recording::Duration(i64::from(r.wall_duration_90k));
+                            rows.push((r.id, r.start..end, r.sample_file_bytes));
+                        }
+                        Ok(())
+                    },
+                )?;
+                p.examine(rows.into_iter(), &motion);
+            }
+            p.signal_generation = generation;
+        }
+
+        // Examine newly committed recordings.
+        let mut rows = Vec::new();
+        raw::list_oldest_recordings(
+            &self.conn,
+            CompositeId::new(stream_id, p.next_recording),
+            &mut |r| {
+                p.next_recording = r.id.recording() + 1;
+                if is_live(r.id) {
+                    let end = r.start + recording::Duration(i64::from(r.wall_duration_90k));
+                    rows.push((r.id, r.start..end, r.sample_file_bytes));
+                }
+                true
+            },
         )?;
-        let mut rows = stmt.query(params![])?;
-        while let Some(row) = rows.next()? {
-            let id = row.get(0)?;
-            let data: Vec<u8> = row.get(6)?;
+        if let Some((_, first, _)) = rows.first() {
+            let motion = self.signal.motion_ranges(camera_id, first.start);
+            p.examine(rows.into_iter(), &motion);
+        }
+
+        let keep = p.events.iter().map(|e| e.id).collect();
+        self.streams_by_id
+            .get_mut(&stream_id)
+            .expect("stream still exists")
+            .protected_events = Some(p);
+        Ok(keep)
+    }
+
+    /// Lists the oldest recordings for a stream, including ones already queued for deletion.
+    /// `f` should return true as long as further rows are desired.
+    pub(crate) fn list_oldest_recordings(
+        &self,
+        stream_id: i32,
+        f: &mut dyn FnMut(ListOldestRecordingsRow) -> bool,
+    ) -> Result<(), Error> {
+        raw::list_oldest_recordings(&self.conn, CompositeId::new(stream_id, 0), f)
+    }
+
+    /// Lists up to `limit` of the oldest recordings which should be moved to the stream's cold
+    /// sample file directory: ones which haven't been moved yet and which ended no later than
+    /// `cutoff`. Recordings queued for deletion are passed over.
+    ///
+    /// Returns the recordings to copy and the value to pass to `mark_moved` after copying them.
+    pub(crate) fn list_recordings_to_move(
+        &self,
+        stream_id: i32,
+        cutoff: recording::Time,
+        limit: usize,
+    ) -> Result<(Vec<CompositeId>, i32), Error> {
+        let s = match self.streams_by_id.get(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => s,
+        };
+        let mut ids = Vec::new();
+        let mut next = s.next_cold_recording;
+        raw::list_oldest_recordings(
+            &self.conn,
+            CompositeId::new(stream_id, s.next_cold_recording),
+            &mut |r| {
+                if ids.len() >= limit
+                    || r.start + recording::Duration(i64::from(r.wall_duration_90k)) > cutoff
+                {
+                    return false;
+                }
+                if !s.is_to_delete(r.id) {
+                    ids.push(r.id);
+                }
+                next = r.id.recording() + 1;
+                true
+            },
+        )?;
+        Ok((ids, next))
+    }
+
+    /// Notes that the given recordings (as returned by `list_recordings_to_move`) have been
+    /// copied to the stream's cold sample file directory and the copies synced. The next flush
+    /// will commit the move and mark the originals as garbage.
+    pub(crate) fn mark_moved(
+        &mut self,
+        stream_id: i32,
+        ids: &[CompositeId],
+        next: i32,
+    ) -> Result<(), Error> {
+        let s = match self.streams_by_id.get_mut(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => s,
+        };
+        if s.cold_sample_file_dir_id.is_none() {
+            bail!("stream {} has no cold sample file dir", stream_id);
+        }
+        if next < s.next_cold_recording {
+            bail!(
+                "stream {}: can't move back from {} to {}",
+                stream_id,
+                s.next_cold_recording,
+                next
+            );
+        }
+        s.to_cold.extend_from_slice(ids);
+        s.next_cold_recording = next;
+        Ok(())
+    }
+
+    /// Initializes the video_sample_entries. To be called during construction.
+    fn init_video_sample_entries(&mut self) -> Result<(), Error> {
+        info!("Loading video sample entries");
+        let mut stmt = self.conn.prepare(
+            r#"
+            select
+                id,
+                width,
+                height,
+                pasp_h_spacing,
+                pasp_v_spacing,
+                rfc6381_codec,
+                data
+            from
+                video_sample_entry
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let id = row.get(0)?;
+            let data: Vec<u8> = row.get(6)?;
 
             self.video_sample_entries_by_id.insert(
                 id,
@@ -1538,6 +2263,40 @@ impl LockedDatabase {
         Ok(())
     }
 
+    /// Initializes the audio_sample_entries. To be called during construction.
+    fn init_audio_sample_entries(&mut self) -> Result<(), Error> {
+        info!("Loading audio sample entries");
+        let mut stmt = self.conn.prepare(
+            r#"
+            select
+                id,
+                rfc6381_codec,
+                clock_rate,
+                data
+            from
+                audio_sample_entry
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let id = row.get(0)?;
+            self.audio_sample_entries_by_id.insert(
+                id,
+                Arc::new(AudioSampleEntry {
+                    id,
+                    rfc6381_codec: row.get(1)?,
+                    clock_rate: row.get::<_, i64>(2)?.try_into()?,
+                    data: row.get(3)?,
+                }),
+            );
+        }
+        info!(
+            "Loaded {} audio sample entries",
+            self.audio_sample_entries_by_id.len()
+        );
+        Ok(())
+    }
+
     /// Initializes the sample file dirs.
     /// To be called during construction.
     fn init_sample_file_dirs(&mut self) -> Result<(), Error> {
@@ -1635,7 +2394,9 @@ impl LockedDatabase {
               config,
               cum_recordings,
               cum_media_duration_90k,
-              cum_runs
+              cum_runs,
+              cold_sample_file_dir_id,
+              cum_cold_recordings
             from
               stream;
             "#,
@@ -1647,6 +2408,7 @@ impl LockedDatabase {
             let type_ = StreamType::parse(&type_)
                 .ok_or_else(|| format_err!("no such stream type {}", type_))?;
             let camera_id = row.get(2)?;
+            let cum_cold_recordings = row.get(9)?;
             let c = self
                 .cameras_by_id
                 .get_mut(&camera_id)
@@ -1658,6 +2420,7 @@ impl LockedDatabase {
                     type_,
                     camera_id,
                     sample_file_dir_id: row.get(3)?,
+                    cold_sample_file_dir_id: row.get(8)?,
                     config: row.get(4)?,
                     range: None,
                     sample_file_bytes: 0,
@@ -1672,9 +2435,16 @@ impl LockedDatabase {
                     cum_recordings: row.get(5)?,
                     cum_media_duration: recording::Duration(row.get(6)?),
                     cum_runs: row.get(7)?,
+                    cum_cold_recordings,
+                    next_cold_recording: cum_cold_recordings,
+                    to_cold: Vec::new(),
                     uncommitted: VecDeque::new(),
                     synced_recordings: 0,
                     on_live_segment: Vec::new(),
+                    metrics: Arc::new(StreamMetrics::default()),
+                    status: StreamStatus::default(),
+                    delete_scan_start: 0,
+                    protected_events: None,
                 },
             );
             c.streams[type_.index()] = Some(id);
@@ -1738,6 +2508,48 @@ impl LockedDatabase {
         Ok(id)
     }
 
+    /// Inserts the specified audio sample entry if absent.
+    /// On success, returns the id of a new or existing row.
+    pub fn insert_audio_sample_entry(
+        &mut self,
+        entry: AudioSampleEntryToInsert,
+    ) -> Result<i32, Error> {
+        for (&id, a) in &self.audio_sample_entries_by_id {
+            if a.data == entry.data {
+                if a.rfc6381_codec != entry.rfc6381_codec || a.clock_rate != entry.clock_rate {
+                    bail!(
+                        "audio_sample_entry id {}: existing entry {:?}, new {:?}",
+                        id,
+                        a,
+                        &entry
+                    );
+                }
+                return Ok(id);
+            }
+        }
+
+        let mut stmt = self.conn.prepare_cached(INSERT_AUDIO_SAMPLE_ENTRY_SQL)?;
+        stmt.execute(named_params! {
+            ":rfc6381_codec": &entry.rfc6381_codec,
+            ":clock_rate": i64::from(entry.clock_rate),
+            ":data": &entry.data,
+        })
+        .map_err(|e| Error::from(e).context(format!("Unable to insert {:#?}", &entry)))?;
+
+        let id = self.conn.last_insert_rowid() as i32;
+        self.audio_sample_entries_by_id.insert(
+            id,
+            Arc::new(AudioSampleEntry {
+                id,
+                rfc6381_codec: entry.rfc6381_codec,
+                clock_rate: entry.clock_rate,
+                data: entry.data,
+            }),
+        );
+
+        Ok(id)
+    }
+
     pub fn add_sample_file_dir(&mut self, path: PathBuf) -> Result<i32, Error> {
         let mut meta = schema::DirMeta::default();
         let uuid = Uuid::new_v4();
@@ -1790,7 +2602,7 @@ impl LockedDatabase {
 
     pub fn delete_sample_file_dir(&mut self, dir_id: i32) -> Result<(), Error> {
         for (&id, s) in self.streams_by_id.iter() {
-            if s.sample_file_dir_id == Some(dir_id) {
+            if s.sample_file_dir_id == Some(dir_id) || s.cold_sample_file_dir_id == Some(dir_id) {
                 bail!("can't delete dir referenced by stream {}", id);
             }
         }
@@ -1870,6 +2682,7 @@ impl LockedDatabase {
             },
         );
         self.cameras_by_uuid.insert(uuid, camera_id);
+        self.notify_camera_change(camera_id);
         Ok(camera_id)
     }
 
@@ -1896,6 +2709,7 @@ impl LockedDatabase {
                     .expect("cameras reference valid streams");
                 change.streams[i] = StreamChange {
                     sample_file_dir_id: s.sample_file_dir_id,
+                    cold_sample_file_dir_id: s.cold_sample_file_dir_id,
                     config: s.config.clone(),
                 };
             }
@@ -1936,46 +2750,73 @@ impl LockedDatabase {
         c.short_name = camera.short_name;
         c.config = camera.config;
         c.streams = streams.apply(&mut self.streams_by_id);
+        self.notify_camera_change(camera_id);
         Ok(())
     }
 
-    /// Deletes a camera and its streams. The camera must have no recordings.
-    pub fn delete_camera(&mut self, id: i32) -> Result<(), Error> {
-        // TODO: also verify there are no uncommitted recordings.
-        let uuid = self
-            .cameras_by_id
-            .get(&id)
-            .map(|c| c.uuid)
-            .ok_or_else(|| format_err!("No such camera {} to remove", id))?;
+    /// Deletes a camera and its streams. The camera must have no recordings, committed or not,
+    /// and no running streamers; stop recording and delete its recordings first.
+    pub fn delete_camera(&mut self, id: i32) -> Result<(), base::Error> {
+        let uuid = match self.cameras_by_id.get(&id) {
+            None => bail_t!(NotFound, "no such camera {} to remove", id),
+            Some(c) => c.uuid,
+        };
+        for s in self.streams_by_id.values() {
+            if s.camera_id != id {
+                continue;
+            }
+            if s.status.state != StreamState::NotStarted {
+                bail_t!(
+                    FailedPrecondition,
+                    "can't remove camera {}; its {} stream is still running",
+                    id,
+                    s.type_.as_str()
+                );
+            }
+            if s.range.is_some() || !s.uncommitted.is_empty() {
+                bail_t!(
+                    FailedPrecondition,
+                    "can't remove camera {}; its {} stream has recordings",
+                    id,
+                    s.type_.as_str()
+                );
+            }
+        }
         let mut streams_to_delete = Vec::new();
-        let tx = self.conn.transaction()?;
+        let tx = self.conn.transaction().err_kind(ErrorKind::Internal)?;
         {
-            let mut stream_stmt = tx.prepare_cached(r"delete from stream where id = :id")?;
+            let mut stream_stmt = tx
+                .prepare_cached(r"delete from stream where id = :id")
+                .err_kind(ErrorKind::Internal)?;
             for (stream_id, stream) in &self.streams_by_id {
                 if stream.camera_id != id {
                     continue;
                 };
-                if stream.range.is_some() {
-                    bail!("Can't remove camera {}; has recordings.", id);
-                }
-                let rows = stream_stmt.execute(named_params! {":id": stream_id})?;
+                let rows = stream_stmt
+                    .execute(named_params! {":id": stream_id})
+                    .err_kind(ErrorKind::Internal)?;
                 if rows != 1 {
-                    bail!("Stream {} missing from database", id);
+                    bail_t!(Internal, "stream {} missing from database", stream_id);
                 }
                 streams_to_delete.push(*stream_id);
             }
-            let mut cam_stmt = tx.prepare_cached(r"delete from camera where id = :id")?;
-            let rows = cam_stmt.execute(named_params! {":id": id})?;
+            let mut cam_stmt = tx
+                .prepare_cached(r"delete from camera where id = :id")
+                .err_kind(ErrorKind::Internal)?;
+            let rows = cam_stmt
+                .execute(named_params! {":id": id})
+                .err_kind(ErrorKind::Internal)?;
             if rows != 1 {
-                bail!("Camera {} missing from database", id);
+                bail_t!(Internal, "camera {} missing from database", id);
             }
         }
-        tx.commit()?;
+        tx.commit().err_kind(ErrorKind::Internal)?;
         for id in streams_to_delete {
             self.streams_by_id.remove(&id);
         }
         self.cameras_by_id.remove(&id);
         self.cameras_by_uuid.remove(&uuid);
+        self.notify_camera_change(id);
         Ok(())
     }
 
@@ -2002,6 +2843,9 @@ impl LockedDatabase {
                 let mut new_config = stream.config.clone();
                 new_config.mode = (if c.new_record { "record" } else { "" }).into();
                 new_config.retain_bytes = c.new_limit;
+                if let Some(d) = c.new_retain_duration_sec {
+                    new_config.retain_duration_sec = d;
+                }
                 let rows = stmt.execute(named_params! {
                     ":config": &new_config,
                     ":id": c.stream_id,
@@ -2017,18 +2861,115 @@ impl LockedDatabase {
                 .expect("stream in db but not state");
             s.config.mode = (if c.new_record { "record" } else { "" }).into();
             s.config.retain_bytes = c.new_limit;
+            if let Some(d) = c.new_retain_duration_sec {
+                s.config.retain_duration_sec = d;
+            }
+            let camera_id = s.camera_id;
+            self.notify_camera_change(camera_id);
         }
         Ok(())
     }
 
+    /// Registers a callback to run after every successful change to a camera or its streams via
+    /// `add_camera`, `update_camera`, `delete_camera`, or `update_retention`, with the camera's
+    /// id. As with `watch_live`, the callback is run with the database lock held and should
+    /// return false to unregister.
+    pub fn watch_camera_changes(&mut self, cb: Box<dyn FnMut(i32) -> bool + Send>) {
+        self.on_camera_change.push(cb);
+    }
+
+    fn notify_camera_change(&mut self, camera_id: i32) {
+        use odds::vec::VecExt;
+        self.on_camera_change.retain_mut(|cb| cb(camera_id));
+    }
+
+    /// Reloads the cameras' and streams' short names and configuration from the database, to
+    /// pick up changes made outside this process (such as with `sqlite3`). Notifies
+    /// `watch_camera_changes` watchers of each changed camera and returns the number of them.
+    ///
+    /// Cameras and streams added or removed outside this process, and changes to streams'
+    /// sample file directories, are ignored with a warning; these require a restart.
+    pub fn reload_camera_configs(&mut self) -> Result<usize, Error> {
+        let mut changed = FnvHashSet::default();
+        {
+            let mut stmt = self
+                .conn
+                .prepare_cached("select id, short_name, config from camera")?;
+            let mut rows = stmt.query(params![])?;
+            let mut seen = 0;
+            while let Some(row) = rows.next()? {
+                let id: i32 = row.get(0)?;
+                let c = match self.cameras_by_id.get_mut(&id) {
+                    Some(c) => c,
+                    None => {
+                        warn!("Ignoring new camera {}; restart to load it", id);
+                        continue;
+                    }
+                };
+                seen += 1;
+                let short_name: String = row.get(1)?;
+                let config: crate::json::CameraConfig = row.get(2)?;
+                if c.short_name != short_name || c.config != config {
+                    c.short_name = short_name;
+                    c.config = config;
+                    changed.insert(id);
+                }
+            }
+            if seen != self.cameras_by_id.len() {
+                warn!("Ignoring removed cameras; restart to unload them");
+            }
+        }
+        {
+            let mut stmt = self.conn.prepare_cached(
+                "select id, sample_file_dir_id, cold_sample_file_dir_id, config from stream",
+            )?;
+            let mut rows = stmt.query(params![])?;
+            while let Some(row) = rows.next()? {
+                let id: i32 = row.get(0)?;
+                let s = match self.streams_by_id.get_mut(&id) {
+                    Some(s) => s,
+                    None => {
+                        warn!("Ignoring new stream {}; restart to load it", id);
+                        continue;
+                    }
+                };
+                let sample_file_dir_id: Option<i32> = row.get(1)?;
+                let cold_sample_file_dir_id: Option<i32> = row.get(2)?;
+                if sample_file_dir_id != s.sample_file_dir_id
+                    || cold_sample_file_dir_id != s.cold_sample_file_dir_id
+                {
+                    warn!(
+                        "Ignoring changed sample file dirs of stream {}; restart to load them",
+                        id
+                    );
+                }
+                let config: crate::json::StreamConfig = row.get(3)?;
+                if s.config != config {
+                    s.config = config;
+                    changed.insert(s.camera_id);
+                }
+            }
+        }
+        for &id in &changed {
+            self.notify_camera_change(id);
+        }
+        Ok(changed.len())
+    }
+
     // ---- auth ----
 
     pub fn users_by_id(&self) -> &BTreeMap<i32, User> {
         self.auth.users_by_id()
     }
 
-    pub fn apply_user_change(&mut self, change: UserChange) -> Result<&User, Error> {
-        self.auth.apply(&self.conn, change)
+    /// Applies a user change. `req` describes the request making the change; a password change
+    /// revokes all of the user's sessions with it.
+    pub fn apply_user_change(
+        &mut self,
+        req: auth::Request,
+        change: UserChange,
+    ) -> Result<&User, Error> {
+        self.auth.apply(&self.conn, req, change)
     }
 
     pub fn delete_user(&mut self, id: i32) -> Result<(), Error> {
@@ -2046,11 +2987,16 @@ impl LockedDatabase {
         password: String,
         domain: Option<Vec<u8>>,
         session_flags: i32,
-    ) -> Result<(RawSessionId, &Session), Error> {
+    ) -> Result<(RawSessionId, &Session), base::Error> {
         self.auth
             .login_by_password(&self.conn, req, username, password, domain, session_flags)
     }
 
+    pub fn unlock_user(&mut self, id: i32) -> Result<(), Error> {
+        self.auth.unlock_user(id)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn make_session(
         &mut self,
         creation: Request,
@@ -2058,9 +3004,19 @@ impl LockedDatabase {
         domain: Option<Vec<u8>>,
         flags: i32,
         permissions: schema::Permissions,
+        description: Option<String>,
+        expiration_sec: Option<i64>,
     ) -> Result<(RawSessionId, &Session), Error> {
-        self.auth
-            .make_session(&self.conn, creation, uid, domain, flags, permissions)
+        self.auth.make_session(
+            &self.conn,
+            creation,
+            uid,
+            domain,
+            flags,
+            permissions,
+            description,
+            expiration_sec,
+        )
     }
 
     pub fn authenticate_session(
@@ -2071,6 +3027,18 @@ impl LockedDatabase {
         self.auth.authenticate_session(&self.conn, req, sid)
     }
 
+    pub fn user_sessions(
+        &mut self,
+        user_id: i32,
+    ) -> Result<Vec<(auth::SessionHash, &Session)>, Error> {
+        self.auth.user_sessions(&self.conn, user_id)
+    }
+
+    /// Revokes all expired sessions. See `auth::State::revoke_expired_sessions`.
+    pub fn revoke_expired_sessions(&mut self, req: auth::Request) -> Result<usize, Error> {
+        self.auth.revoke_expired_sessions(&self.conn, req)
+    }
+
     pub fn revoke_session(
         &mut self,
         reason: auth::RevocationReason,
@@ -2084,12 +3052,32 @@ impl LockedDatabase {
 
     // ---- signal ----
 
+    /// Returns true if `permissions` allow access to the given signal: it's associated with no
+    /// cameras or with at least one camera they allow.
+    pub fn may_access_signal(&self, permissions: &schema::Permissions, s: &signal::Signal) -> bool {
+        permissions.camera_uuids.is_empty()
+            || s.config.camera_associations.is_empty()
+            || s.config.camera_associations.keys().any(|id| {
+                self.cameras_by_id
+                    .get(id)
+                    .map(|c| permissions.may_access_camera(c.uuid))
+                    .unwrap_or(false)
+            })
+    }
+
     pub fn signals_by_id(&self) -> &BTreeMap<u32, signal::Signal> {
         self.signal.signals_by_id()
     }
     pub fn signal_types_by_uuid(&self) -> &FnvHashMap<Uuid, signal::Type> {
         self.signal.types_by_uuid()
     }
+    pub fn motion_ranges(
+        &self,
+        camera_id: i32,
+        from: recording::Time,
+    ) -> Vec<Range<recording::Time>> {
+        self.signal.motion_ranges(camera_id, from)
+    }
     pub fn list_changes_by_time(
         &self,
         desired_time: Range<recording::Time>,
@@ -2103,7 +3091,24 @@ impl LockedDatabase {
         signals: &[u32],
         states: &[u16],
     ) -> Result<(), base::Error> {
-        self.signal.update_signals(when, signals, states)
+        self.signal.update_signals(when.clone(), signals, states)?;
+        if when.start < when.end && !self.on_signal_update.is_empty() {
+            let u = signal::Update {
+                when,
+                signals: signals.to_vec(),
+                states: states.to_vec(),
+            };
+            use odds::vec::VecExt;
+            self.on_signal_update.retain_mut(|cb| cb(&u));
+        }
+        Ok(())
+    }
+
+    /// Registers a callback to run on every successful `update_signals` call with a non-empty
+    /// time range. As with `watch_live`, the callback is run with the database lock held and
+    /// should return false to unregister.
+    pub fn watch_signals(&mut self, cb: Box<dyn FnMut(&signal::Update) -> bool + Send>) {
+        self.on_signal_update.push(cb);
     }
 }
 
@@ -2290,13 +3295,15 @@ impl<C: Clocks + Clone> Database<C> {
         } else {
             None
         };
-        let auth = auth::State::init(&conn)?;
+        let mut auth = auth::State::init(&conn)?;
+        auth.set_session_timeouts(config.session_max_age_sec, config.session_idle_timeout_sec);
         let signal = signal::State::init(&conn, &config)?;
         let db = Database {
             db: Some(Mutex::new(LockedDatabase {
                 conn,
                 uuid: db_uuid,
                 flush_count: 0,
+                flush_duration: recording::Duration(0),
                 open,
                 open_monotonic,
                 auth,
@@ -2306,17 +3313,23 @@ impl<C: Clocks + Clone> Database<C> {
                 cameras_by_uuid: BTreeMap::new(),
                 streams_by_id: BTreeMap::new(),
                 video_sample_entries_by_id: BTreeMap::new(),
+                audio_sample_entries_by_id: BTreeMap::new(),
                 video_index_cache: RefCell::new(LinkedHashMap::with_capacity_and_hasher(
                     VIDEO_INDEX_CACHE_LEN + 1,
                     Default::default(),
                 )),
                 on_flush: Vec::new(),
+                on_stream_status: Vec::new(),
+                on_signal_update: Vec::new(),
+                on_camera_change: Vec::new(),
+                config,
             })),
             clocks,
         };
         {
             let l = &mut *db.lock();
             l.init_video_sample_entries()?;
+            l.init_audio_sample_entries()?;
             l.init_sample_file_dirs()?;
             l.init_cameras()?;
             l.init_streams()?;
@@ -2376,7 +3389,7 @@ impl<'db, C: Clocks + Clone> DatabaseGuard<'db, C> {
     ///
     /// On success, for each affected sample file directory with a flush watcher set, sends a
     /// `Flush` event.
-    pub(crate) fn flush(&mut self, reason: &str) -> Result<(), Error> {
+    pub fn flush(&mut self, reason: &str) -> Result<(), Error> {
         self.db.flush(self.clocks, reason)
     }
 }
@@ -2523,12 +3536,12 @@ mod tests {
     fn test_version_too_old() {
         testutil::init();
         let c = setup_conn();
-        c.execute_batch("delete from version; insert into version values (6, 0, '');")
+        c.execute_batch("delete from version; insert into version values (7, 0, '');")
             .unwrap();
         let e = Database::new(clock::RealClocks {}, c, false).err().unwrap();
         assert!(
             e.to_string()
-                .starts_with("Database schema version 6 is too old (expected 7)"),
+                .starts_with("Database schema version 7 is too old (expected 8)"),
             "got: {:?}",
             e
         );
@@ -2538,12 +3551,12 @@ mod tests {
     fn test_version_too_new() {
         testutil::init();
         let c = setup_conn();
-        c.execute_batch("delete from version; insert into version values (8, 0, '');")
+        c.execute_batch("delete from version; insert into version values (9, 0, '');")
             .unwrap();
         let e = Database::new(clock::RealClocks {}, c, false).err().unwrap();
         assert!(
             e.to_string()
-                .starts_with("Database schema version 8 is too new (expected 7)"),
+                .starts_with("Database schema version 9 is too new (expected 8)"),
             "got: {:?}",
             e
         );
@@ -2559,6 +3572,69 @@ mod tests {
         assert_eq!(0, db.cameras_by_id().values().count());
     }
 
+    #[test]
+    fn reload_camera_configs() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut l = db.lock();
+        let camera_id = l
+            .add_camera(CameraChange {
+                short_name: "testcam".to_owned(),
+                config: crate::json::CameraConfig::default(),
+                streams: [
+                    StreamChange {
+                        config: crate::json::StreamConfig {
+                            url: Some(Url::parse("rtsp://test-camera/main").unwrap()),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                    StreamChange::default(),
+                    StreamChange::default(),
+                ],
+            })
+            .unwrap();
+        let main_stream_id = l.cameras_by_id()[&camera_id].streams[0].unwrap();
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        l.watch_camera_changes(Box::new({
+            let changes = changes.clone();
+            move |id| {
+                changes.lock().push(id);
+                true
+            }
+        }));
+
+        // Reloading without changes should do nothing.
+        assert_eq!(l.reload_camera_configs().unwrap(), 0);
+        assert!(changes.lock().is_empty());
+
+        // Reloading after an outside change should pick it up.
+        l.conn
+            .execute(
+                "update stream set config = :config where id = :id",
+                named_params! {
+                    ":config": &crate::json::StreamConfig {
+                        url: Some(Url::parse("rtsp://test-camera/new-main").unwrap()),
+                        ..Default::default()
+                    },
+                    ":id": main_stream_id,
+                },
+            )
+            .unwrap();
+        assert_eq!(l.reload_camera_configs().unwrap(), 1);
+        assert_eq!(&changes.lock()[..], &[camera_id]);
+        assert_eq!(
+            l.streams_by_id()[&main_stream_id]
+                .config
+                .url
+                .as_ref()
+                .unwrap()
+                .as_str(),
+            "rtsp://test-camera/new-main"
+        );
+    }
+
     /// Basic test of the full lifecycle of recording. Does not exercise error cases.
     #[test]
     fn test_full_lifecycle() {
@@ -2583,6 +3659,7 @@ mod tests {
             streams: [
                 StreamChange {
                     sample_file_dir_id: Some(sample_file_dir_id),
+                    cold_sample_file_dir_id: None,
                     config: crate::json::StreamConfig {
                         url: Some(Url::parse("rtsp://test-camera/main").unwrap()),
                         mode: crate::json::STREAM_MODE_RECORD.to_owned(),
@@ -2592,6 +3669,7 @@ mod tests {
                 },
                 StreamChange {
                     sample_file_dir_id: Some(sample_file_dir_id),
+                    cold_sample_file_dir_id: None,
                     config: crate::json::StreamConfig {
                         url: Some(Url::parse("rtsp://test-camera/sub").unwrap()),
                         mode: crate::json::STREAM_MODE_RECORD.to_owned(),
@@ -2615,6 +3693,7 @@ mod tests {
                 stream_id: main_stream_id,
                 new_record: true,
                 new_limit: 42,
+                new_retain_duration_sec: None,
             }])
             .unwrap();
             {
@@ -2707,7 +3786,12 @@ mod tests {
             video_sync_samples: 1,
             video_sample_entry_id: vse_id,
             video_index: [0u8; 100].to_vec(),
-            sample_file_blake3: None,
+            audio_samples: 0,
+            audio_sample_file_bytes: 0,
+            audio_sample_entry_id: None,
+            audio_index: Vec::new(),
+            audio_data: Vec::new(),
+            sample_file_blake3: Some([42u8; 32]),
             end_reason: None,
         };
         let id = {
@@ -2734,6 +3818,11 @@ mod tests {
         let conn = db.close();
         let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
         assert_single_recording(&db, main_stream_id, &recording);
+        let mut blake3s = Vec::new();
+        db.lock()
+            .list_sample_file_blake3s(main_stream_id, 0..1, &mut |id, b| blake3s.push((id, b)))
+            .unwrap();
+        assert_eq!(&blake3s, &[(id, [42u8; 32])]);
 
         // Deleting a recording should succeed, update the min/max times, and mark it as garbage.
         {
@@ -2794,6 +3883,243 @@ mod tests {
         assert_eq!(&g, &[]);
     }
 
+    /// Moves recordings to a cold sample file directory, deleting one in the process, and checks
+    /// the resulting garbage in each directory.
+    #[test]
+    fn test_move_to_cold() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test")
+            .tempdir()
+            .unwrap();
+        let hot_dir_id = { db.lock() }
+            .add_sample_file_dir(tmpdir.path().join("hot"))
+            .unwrap();
+        let cold_dir_id = { db.lock() }
+            .add_sample_file_dir(tmpdir.path().join("cold"))
+            .unwrap();
+        let mut c = CameraChange {
+            short_name: "testcam".to_owned(),
+            config: crate::json::CameraConfig::default(),
+            streams: [
+                StreamChange {
+                    sample_file_dir_id: Some(hot_dir_id),
+                    cold_sample_file_dir_id: Some(hot_dir_id),
+                    config: crate::json::StreamConfig {
+                        url: Some(Url::parse("rtsp://test-camera/main").unwrap()),
+                        mode: crate::json::STREAM_MODE_RECORD.to_owned(),
+                        cold_after_sec: 1,
+                        ..Default::default()
+                    },
+                },
+                StreamChange::default(),
+                StreamChange::default(),
+            ],
+        };
+        db.lock().add_camera(c.clone()).unwrap_err(); // cold dir must differ from hot dir.
+        c.streams[0].cold_sample_file_dir_id = Some(cold_dir_id);
+        let camera_id = db.lock().add_camera(c).unwrap();
+        let stream_id = db.lock().cameras_by_id()[&camera_id].streams[0].unwrap();
+        let vse_id = db
+            .lock()
+            .insert_video_sample_entry(VideoSampleEntryToInsert {
+                width: 1920,
+                height: 1080,
+                pasp_h_spacing: 1,
+                pasp_v_spacing: 1,
+                data: include_bytes!("testdata/avc1").to_vec(),
+                rfc6381_codec: "avc1.4d0029".to_owned(),
+            })
+            .unwrap();
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let mut ids = Vec::new();
+        {
+            let mut db = db.lock();
+            for i in 0..3 {
+                let (id, _) = db
+                    .add_recording(
+                        stream_id,
+                        RecordingToInsert {
+                            sample_file_bytes: 42,
+                            start: start + recording::Duration(i * TIME_UNITS_PER_SEC),
+                            wall_duration_90k: TIME_UNITS_PER_SEC.try_into().unwrap(),
+                            media_duration_90k: TIME_UNITS_PER_SEC.try_into().unwrap(),
+                            video_samples: 1,
+                            video_sync_samples: 1,
+                            video_sample_entry_id: vse_id,
+                            video_index: [0u8; 100].to_vec(),
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap();
+                db.mark_synced(id).unwrap();
+                ids.push(id);
+            }
+            db.flush("add test").unwrap();
+        }
+
+        // The first two recordings ended by start + 2 sec. Delete the first after copying it.
+        {
+            let mut db = db.lock();
+            let cutoff = start + recording::Duration(2 * TIME_UNITS_PER_SEC);
+            let (to_move, next) = db.list_recordings_to_move(stream_id, cutoff, 10).unwrap();
+            assert_eq!(&to_move, &ids[..2]);
+            assert_eq!(next, 2);
+            let mut n = 0;
+            db.delete_oldest_recordings(stream_id, &mut |_| {
+                n += 1;
+                n == 1
+            })
+            .unwrap();
+            db.mark_moved(stream_id, &to_move, next).unwrap();
+            db.flush("move test").unwrap();
+            let s = &db.streams_by_id()[&stream_id];
+            assert_eq!(s.sample_file_dir_id_for(1), Some(cold_dir_id));
+            assert_eq!(s.sample_file_dir_id_for(2), Some(hot_dir_id));
+            let dirs = db.sample_file_dirs_by_id();
+            let mut g: Vec<_> = dirs[&hot_dir_id]
+                .garbage_needs_unlink
+                .iter()
+                .copied()
+                .collect();
+            g.sort_by_key(|id| id.0);
+            assert_eq!(&g, &ids[..2]);
+            let g: Vec<_> = dirs[&cold_dir_id]
+                .garbage_needs_unlink
+                .iter()
+                .copied()
+                .collect();
+            assert_eq!(&g, &ids[..1]);
+
+            // Collecting the hot dir's garbage shouldn't affect the cold dir's.
+            db.delete_garbage(hot_dir_id, &mut ids[..2].to_vec())
+                .unwrap();
+            db.flush("gc test").unwrap();
+        }
+
+        // The move should persist, and further deletions should be from the cold dir.
+        let conn = db.close();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut db = db.lock();
+        assert_eq!(db.streams_by_id()[&stream_id].cum_cold_recordings, 2);
+        assert!(db.sample_file_dirs_by_id()[&hot_dir_id]
+            .garbage_needs_unlink
+            .is_empty());
+        db.delete_oldest_recordings(stream_id, &mut |_| true)
+            .unwrap();
+        db.flush("delete test").unwrap();
+        let mut g: Vec<_> = db.sample_file_dirs_by_id()[&cold_dir_id]
+            .garbage_needs_unlink
+            .iter()
+            .copied()
+            .collect();
+        g.sort_by_key(|id| id.0);
+        assert_eq!(&g, &ids[..2]);
+        let g: Vec<_> = db.sample_file_dirs_by_id()[&hot_dir_id]
+            .garbage_needs_unlink
+            .iter()
+            .copied()
+            .collect();
+        assert_eq!(&g, &ids[2..]);
+    }
+
+    /// Deleting a camera is refused while its streamer is running or it has recordings which
+    /// haven't been committed yet.
+    #[test]
+    fn test_delete_camera_in_use() {
+        testutil::init();
+        let db = testutil::TestDb::new(clock::RealClocks {});
+        let mut l = db.db.lock();
+        l.update_stream_status(
+            testutil::TEST_STREAM_ID,
+            StreamStatus {
+                state: StreamState::Streaming,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let e = l.delete_camera(testutil::TEST_CAMERA_ID).unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::FailedPrecondition);
+
+        l.update_stream_status(testutil::TEST_STREAM_ID, StreamStatus::default())
+            .unwrap();
+        l.add_recording(
+            testutil::TEST_STREAM_ID,
+            RecordingToInsert {
+                start: recording::Time(1430006400 * TIME_UNITS_PER_SEC),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let e = l.delete_camera(testutil::TEST_CAMERA_ID).unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::FailedPrecondition);
+        assert!(l.cameras_by_id().contains_key(&testutil::TEST_CAMERA_ID));
+        assert!(l.streams_by_id().contains_key(&testutil::TEST_STREAM_ID));
+
+        let e = l.delete_camera(testutil::TEST_CAMERA_ID + 1).unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::NotFound);
+    }
+
+    /// Logins are delayed after exactly five consecutive failures, whether for a user or from a
+    /// peer address across users, until the database's clocks pass the delay.
+    #[test]
+    fn test_login_throttling() {
+        testutil::init();
+        let db = testutil::TestDb::new(clock::SimulatedClocks::new(::time::Timespec::new(
+            1430006400, 0,
+        )));
+        let clocks = db.db.clocks();
+        let addr = Some(std::net::IpAddr::from([192, 0, 2, 1]));
+        let req = |addr| auth::Request {
+            when_sec: Some(clocks.realtime().sec),
+            addr,
+            user_agent: None,
+        };
+        let mut l = db.db.lock();
+        for &(username, password) in &[("slamb", "hunter2"), ("other", "hunter3")] {
+            let mut c = UserChange::add_user(username.to_owned());
+            c.set_password(password.to_owned());
+            l.apply_user_change(req(None), c).unwrap();
+        }
+        let mut login = |addr, username: &str, password: &str| {
+            l.login_by_password(req(addr), username, password.to_owned(), None, 0)
+                .map(|_| ())
+                .map_err(|e| e.kind())
+        };
+
+        // By user.
+        for _ in 0..5 {
+            assert_eq!(
+                login(None, "slamb", "x"),
+                Err(base::ErrorKind::Unauthenticated)
+            );
+        }
+        assert_eq!(
+            login(None, "slamb", "hunter2"),
+            Err(base::ErrorKind::ResourceExhausted)
+        );
+        clocks.sleep(::time::Duration::seconds(1));
+        assert_eq!(login(None, "slamb", "hunter2"), Ok(()));
+
+        // By address, alternating users so that neither reaches its own limit.
+        for i in 0..5 {
+            let username = if i % 2 == 0 { "slamb" } else { "other" };
+            assert_eq!(
+                login(addr, username, "x"),
+                Err(base::ErrorKind::Unauthenticated)
+            );
+        }
+        assert_eq!(
+            login(addr, "other", "hunter3"),
+            Err(base::ErrorKind::ResourceExhausted)
+        );
+        assert_eq!(login(None, "other", "hunter3"), Ok(()));
+        clocks.sleep(::time::Duration::seconds(1));
+        assert_eq!(login(addr, "other", "hunter3"), Ok(()));
+    }
+
     #[test]
     fn round_up() {
         assert_eq!(super::round_up(0), 0);
@@ -328,7 +328,23 @@ impl SampleFileDir {
 
     /// Opens the given sample file for reading.
     pub fn open_file(&self, composite_id: CompositeId, range: Range<u64>) -> reader::FileStream {
-        self.reader.open_file(composite_id, range)
+        self.reader.open_file(composite_id, range, None)
+    }
+
+    /// Opens the given sample file for reading, from `cold` if it's no longer in this directory.
+    ///
+    /// A recording may be moved to its stream's cold sample file directory while it's being
+    /// served; once the move is committed, the copy in the original directory is unlinked. A
+    /// reader which has already opened the file is unaffected, but one which opens it afterward
+    /// must look in the cold directory.
+    pub fn open_file_or_cold(
+        &self,
+        composite_id: CompositeId,
+        range: Range<u64>,
+        cold: &SampleFileDir,
+    ) -> reader::FileStream {
+        self.reader
+            .open_file(composite_id, range, Some(&cold.reader))
     }
 
     pub fn create_file(&self, composite_id: CompositeId) -> Result<fs::File, nix::Error> {
@@ -341,6 +357,25 @@ impl SampleFileDir {
         )
     }
 
+    /// Copies the given sample file to `to` and syncs the copy. The caller is responsible for
+    /// syncing `to` itself.
+    ///
+    /// An existing file in `to` (as left by an interrupted earlier copy) is replaced.
+    pub(crate) fn copy_file(&self, id: CompositeId, to: &SampleFileDir) -> Result<(), Error> {
+        let p = CompositeIdPath::from(id);
+        let mut src = crate::fs::openat(self.fd.0, &p, OFlag::O_RDONLY, Mode::empty())?;
+        let mut dst = match to.create_file(id) {
+            Err(nix::Error::EEXIST) => {
+                to.unlink_file(id)?;
+                to.create_file(id)?
+            }
+            r => r?,
+        };
+        std::io::copy(&mut src, &mut dst)?;
+        dst.sync_all()?;
+        Ok(())
+    }
+
     pub(crate) fn write_meta(&self, meta: &schema::DirMeta) -> Result<(), Error> {
         write_meta(self.fd.0, meta)
     }
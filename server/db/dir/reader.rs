@@ -61,22 +61,31 @@ impl Reader {
         Self(tx)
     }
 
-    pub(super) fn open_file(&self, composite_id: CompositeId, range: Range<u64>) -> FileStream {
+    /// Opens the given file, or if it's not found and `fallback` is supplied, the file of the
+    /// same name in `fallback`'s directory.
+    pub(super) fn open_file(
+        &self,
+        composite_id: CompositeId,
+        range: Range<u64>,
+        fallback: Option<&Reader>,
+    ) -> FileStream {
         if range.is_empty() {
             return FileStream {
                 state: FileStreamState::Invalid,
                 reader: Reader(self.0.clone()),
+                fallback: None,
             };
         }
         let (tx, rx) = tokio::sync::oneshot::channel();
         self.send(ReaderCommand::OpenFile {
             composite_id,
-            range,
+            range: range.clone(),
             tx,
         });
         FileStream {
             state: FileStreamState::Reading(rx),
             reader: Reader(self.0.clone()),
+            fallback: fallback.map(|r| (Reader(r.0.clone()), composite_id, range)),
         }
     }
 
@@ -91,6 +100,9 @@ impl Reader {
 pub struct FileStream {
     state: FileStreamState,
     reader: Reader,
+
+    /// Where to retry the open if the file isn't found, until the first chunk is read.
+    fallback: Option<(Reader, CompositeId, Range<u64>)>,
 }
 
 type ReadReceiver = tokio::sync::oneshot::Receiver<Result<(Option<OpenFile>, Vec<u8>), Error>>;
@@ -117,15 +129,29 @@ impl FileStream {
                 ))))
             }
             Poll::Ready(Ok(Err(e))) => {
+                if e.kind() == ErrorKind::NotFound {
+                    if let Some((reader, composite_id, range)) = self.fallback.take() {
+                        let (tx, rx) = tokio::sync::oneshot::channel();
+                        reader.send(ReaderCommand::OpenFile {
+                            composite_id,
+                            range,
+                            tx,
+                        });
+                        self.reader = reader;
+                        return self.read(cx, rx);
+                    }
+                }
                 self.state = FileStreamState::Invalid;
                 Poll::Ready(Some(Err(e)))
             }
             Poll::Ready(Ok(Ok((Some(file), chunk)))) => {
                 self.state = FileStreamState::Idle(file);
+                self.fallback = None;
                 Poll::Ready(Some(Ok(chunk)))
             }
             Poll::Ready(Ok(Ok((None, chunk)))) => {
                 self.state = FileStreamState::Invalid;
+                self.fallback = None;
                 Poll::Ready(Some(Ok(chunk)))
             }
             Poll::Pending => {
@@ -299,8 +325,10 @@ impl ReaderInt {
             )
         })?;
 
-        let file = crate::fs::openat(self.dir.0, &p, OFlag::O_RDONLY, Mode::empty())
-            .err_kind(ErrorKind::Unknown)?;
+        let file = match crate::fs::openat(self.dir.0, &p, OFlag::O_RDONLY, Mode::empty()) {
+            Err(nix::Error::ENOENT) => bail_t!(NotFound, "file {} not found", composite_id),
+            r => r.err_kind(ErrorKind::Unknown)?,
+        };
 
         // Check the actual on-disk file length. It's an error (a bug or filesystem corruption)
         // for it to be less than the requested read. Check for this now rather than crashing
@@ -410,7 +438,39 @@ mod tests {
         let fd = std::sync::Arc::new(super::super::Fd::open(tmpdir.path(), false).unwrap());
         let reader = super::Reader::spawn(tmpdir.path(), fd);
         std::fs::write(tmpdir.path().join("0123456789abcdef"), b"blah blah").unwrap();
-        let f = reader.open_file(crate::CompositeId(0x01234567_89abcdef), 1..8);
+        let f = reader.open_file(crate::CompositeId(0x01234567_89abcdef), 1..8, None);
         assert_eq!(f.try_concat().await.unwrap(), b"lah bla");
     }
+
+    #[tokio::test]
+    async fn fallback() {
+        crate::testutil::init();
+        let open = |name| {
+            let tmpdir = tempfile::Builder::new().prefix(name).tempdir().unwrap();
+            let fd = std::sync::Arc::new(super::super::Fd::open(tmpdir.path(), false).unwrap());
+            let reader = super::Reader::spawn(tmpdir.path(), fd);
+            (tmpdir, reader)
+        };
+        let (hot_dir, hot) = open("moonfire-db-test-reader-hot");
+        let (cold_dir, cold) = open("moonfire-db-test-reader-cold");
+        let id = crate::CompositeId(0x01234567_89abcdef);
+        std::fs::write(cold_dir.path().join("0123456789abcdef"), b"blah blah").unwrap();
+
+        // Without a fallback, a missing file is NotFound.
+        let e = hot
+            .open_file(id, 1..8, None)
+            .try_concat()
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::NotFound);
+
+        // With one, it's read from the fallback directory instead.
+        let f = hot.open_file(id, 1..8, Some(&cold));
+        assert_eq!(f.try_concat().await.unwrap(), b"lah bla");
+
+        // A file present in the first directory is read from there.
+        std::fs::write(hot_dir.path().join("0123456789abcdef"), b"blue blue").unwrap();
+        let f = hot.open_file(id, 1..8, Some(&cold));
+        assert_eq!(f.try_concat().await.unwrap(), b"lue blu");
+    }
 }
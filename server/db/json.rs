@@ -66,6 +66,37 @@ macro_rules! sql {
     };
 }
 
+fn is_zero<T: Default + PartialEq>(v: &T) -> bool {
+    *v == T::default()
+}
+
+/// Serializes a number of seconds as a human-readable duration such as `"30 days"`. Also
+/// deserializes a plain number of seconds. See `base::strutil::decode_duration`.
+pub mod human_duration {
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+    use std::convert::TryFrom;
+
+    pub fn serialize<S: Serializer>(secs: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base::strutil::encode_duration(i64::from(*secs)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Secs(u32),
+            Human(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Secs(s) => Ok(s),
+            Raw::Human(h) => base::strutil::decode_duration(&h)
+                .ok()
+                .and_then(|s| u32::try_from(s).ok())
+                .ok_or_else(|| D::Error::custom(format!("invalid duration {:?}", h))),
+        }
+    }
+}
+
 /// Global configuration, used in the `config` column of the `meta` table.
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -85,11 +116,72 @@ pub struct GlobalConfig {
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub signals: BTreeMap<u32, SignalConfig>,
 
+    /// Outbound notifications of signal changes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<WebhookConfig>,
+
+    /// The maximum age of a session created by logging in with a password, in seconds (or `None`
+    /// for unlimited). Older sessions are revoked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_max_age_sec: Option<i64>,
+
+    /// The maximum time a session created by logging in with a password may go unused, in
+    /// seconds (or `None` for unlimited). Idle sessions are revoked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_idle_timeout_sec: Option<i64>,
+
     #[serde(flatten)]
     pub unknown: BTreeMap<String, Value>,
 }
 sql!(GlobalConfig);
 
+/// Webhook configuration, used within `GlobalConfig`.
+///
+/// The server sends a HTTP `POST` with a JSON body to `url` whenever an update to one of the given
+/// signals sets it to one of the given states. See `design/api.md` for the body's format.
+///
+/// The server reads webhooks only at startup.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookConfig {
+    /// The `http://` or `https://` URL to notify.
+    pub url: Option<Url>,
+
+    /// The ids of the signals of interest, or empty for all signals.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signals: Vec<u32>,
+
+    /// The states of interest, or empty for all states other than 0 (unknown).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub states: Vec<u16>,
+
+    /// The minimum time between notifications for a given signal, in seconds. Updates within
+    /// this time of the previous notification are not sent.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub min_interval_sec: u32,
+
+    /// The URL of this server as reachable from the notification's recipient, such as
+    /// `https://nvr.example.com/`. If present, notifications include absolute links to the
+    /// recorded video; otherwise they include links relative to the server root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nvr_url: Option<Url>,
+
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+impl WebhookConfig {
+    /// Returns true iff an update setting `signal` to `state` should be sent.
+    pub fn matches(&self, signal: u32, state: u16) -> bool {
+        (self.signals.is_empty() || self.signals.contains(&signal))
+            && if self.states.is_empty() {
+                state != 0
+            } else {
+                self.states.contains(&state)
+            }
+    }
+}
+
 /// Sample file directory configuration, used in the `config` column of the `sample_file_dir` table.
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -120,11 +212,43 @@ pub struct SignalTypeConfig {
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub values: BTreeMap<u8, SignalTypeValueConfig>,
 
+    /// ONVIF event topics which set signals of this type.
+    ///
+    /// For each camera with an `onvif_base_url`, the server subscribes to the
+    /// camera's events and sets each signal of this type which is `direct`ly
+    /// associated with the camera (see `SignalConfig::camera_associations`)
+    /// accordingly.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub onvif_topics: Vec<OnvifTopicConfig>,
+
     #[serde(flatten)]
     pub unknown: BTreeMap<String, Value>,
 }
 sql!(SignalTypeConfig);
 
+/// A mapping from an ONVIF event to a signal state; used in
+/// `SignalTypeConfig::onvif_topics`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OnvifTopicConfig {
+    /// The event topic, without namespace prefixes, eg
+    /// `RuleEngine/CellMotionDetector/Motion`.
+    pub topic: String,
+
+    /// The name of the boolean `SimpleItem` within the event's data, eg
+    /// `IsMotion`.
+    pub data_item: String,
+
+    /// The state to set when the item is true.
+    pub true_state: u16,
+
+    /// The state to set when the item is false.
+    pub false_state: u16,
+
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
 /// Information about a signal type value; used in `SignalTypeConfig::values`.
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -220,6 +344,47 @@ pub struct StreamConfig {
     #[serde(default)]
     pub retain_bytes: i64,
 
+    /// The number of bytes of *event* video to retain in preference to other
+    /// video, within `retain_bytes`.
+    ///
+    /// An event recording is one that overlaps a time when a signal directly
+    /// associated with the camera (see `SignalConfig::camera_associations`)
+    /// was in a state marked as `motion` (see `SignalTypeValueConfig`). When
+    /// making room, the oldest other recordings are deleted first; the newest
+    /// event recordings up to this many bytes are deleted only if there's
+    /// nothing else left to delete. A value of 0 means events are not treated
+    /// specially.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub event_retain_bytes: i64,
+
+    /// The maximum age of video to retain, in seconds. In JSON, this is
+    /// `retainDuration`, a human-readable duration such as `"30 days"`.
+    ///
+    /// Recordings which ended longer ago than this will be deleted even if
+    /// `retain_bytes` allows more, and regardless of `event_retain_bytes`. A
+    /// value of 0 means no limit.
+    ///
+    /// This is checked as each recording of the stream is saved and otherwise
+    /// every hour, so a recording may outlive this by up to an hour plus the
+    /// time until the following database flush.
+    #[serde(
+        default,
+        rename = "retainDuration",
+        with = "human_duration",
+        skip_serializing_if = "is_zero"
+    )]
+    pub retain_duration_sec: u32,
+
+    /// The age, in seconds, after which recordings are moved from the
+    /// stream's sample file directory to its cold sample file directory.
+    ///
+    /// This allows keeping recent video on fast storage (such as an SSD) and
+    /// older video on larger, slower storage (such as a hard drive). It has no
+    /// effect unless the stream has a cold sample file directory. A value of 0
+    /// means recordings are never moved.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub cold_after_sec: u32,
+
     /// Flush the database when the first instant of completed recording is this
     /// many seconds old. A value of 0 means that every completed recording will
     /// cause an immediate flush. Higher values may allow flushes to be combined,
@@ -246,6 +411,9 @@ impl StreamConfig {
         self.mode.is_empty()
             && self.url.is_none()
             && self.retain_bytes == 0
+            && self.event_retain_bytes == 0
+            && self.retain_duration_sec == 0
+            && self.cold_after_sec == 0
             && self.flush_if_sec == 0
             && self.unknown.is_empty()
     }
@@ -7,10 +7,11 @@
 use crate::db::{self, CompositeId, SqlUuid};
 use crate::json::GlobalConfig;
 use crate::recording;
-use base::{ErrorKind, ResultExt as _};
+use base::{format_err_t, ErrorKind, ResultExt as _};
 use failure::{bail, Error, ResultExt as _};
 use fnv::FnvHashSet;
 use rusqlite::{named_params, params};
+use std::convert::TryFrom;
 use std::ops::Range;
 use uuid::Uuid;
 
@@ -27,7 +28,10 @@ const LIST_RECORDINGS_BY_TIME_SQL: &str = r#"
         recording.video_samples,
         recording.video_sync_samples,
         recording.video_sample_entry_id,
-        recording.open_id
+        recording.open_id,
+        recording.audio_samples,
+        recording.audio_sample_file_bytes,
+        recording.audio_sample_entry_id
     from
         recording
     where
@@ -52,6 +56,9 @@ const LIST_RECORDINGS_BY_ID_SQL: &str = r#"
         recording.video_sync_samples,
         recording.video_sample_entry_id,
         recording.open_id,
+        recording.audio_samples,
+        recording.audio_sample_file_bytes,
+        recording.audio_sample_entry_id,
         recording.prev_media_duration_90k,
         recording.prev_runs
     from
@@ -63,6 +70,20 @@ const LIST_RECORDINGS_BY_ID_SQL: &str = r#"
         recording.composite_id
 "#;
 
+const LIST_SAMPLE_FILE_BLAKE3S_SQL: &str = r#"
+    select
+        composite_id,
+        sample_file_blake3
+    from
+        recording_integrity
+    where
+        :start <= composite_id and
+        composite_id < :end and
+        sample_file_blake3 is not null
+    order by
+        composite_id
+"#;
+
 const STREAM_MIN_START_SQL: &str = r#"
     select
       start_time_90k
@@ -159,11 +180,20 @@ fn list_recordings_inner(
             video_sync_samples: row.get(8).err_kind(ErrorKind::Internal)?,
             video_sample_entry_id: row.get(9).err_kind(ErrorKind::Internal)?,
             open_id: row.get(10).err_kind(ErrorKind::Internal)?,
+            audio_samples: row
+                .get::<_, Option<i32>>(11)
+                .err_kind(ErrorKind::Internal)?
+                .unwrap_or(0),
+            audio_sample_file_bytes: row
+                .get::<_, Option<i32>>(12)
+                .err_kind(ErrorKind::Internal)?
+                .unwrap_or(0),
+            audio_sample_entry_id: row.get(13).err_kind(ErrorKind::Internal)?,
             prev_media_duration_and_runs: match include_prev {
                 false => None,
                 true => Some((
-                    recording::Duration(row.get(11).err_kind(ErrorKind::Internal)?),
-                    row.get(12).err_kind(ErrorKind::Internal)?,
+                    recording::Duration(row.get(14).err_kind(ErrorKind::Internal)?),
+                    row.get(15).err_kind(ErrorKind::Internal)?,
                 )),
             },
         })?;
@@ -171,6 +201,39 @@ fn list_recordings_inner(
     Ok(())
 }
 
+/// Lists the `sample_file_blake3` of each of the specified recordings which has one, in
+/// ascending order by id.
+pub(crate) fn list_sample_file_blake3s(
+    conn: &rusqlite::Connection,
+    stream_id: i32,
+    desired_ids: Range<i32>,
+    f: &mut dyn FnMut(CompositeId, [u8; 32]),
+) -> Result<(), base::Error> {
+    let mut stmt = conn
+        .prepare_cached(LIST_SAMPLE_FILE_BLAKE3S_SQL)
+        .err_kind(ErrorKind::Internal)?;
+    let mut rows = stmt
+        .query(named_params! {
+            ":start": CompositeId::new(stream_id, desired_ids.start).0,
+            ":end": CompositeId::new(stream_id, desired_ids.end).0,
+        })
+        .err_kind(ErrorKind::Internal)?;
+    while let Some(row) = rows.next().err_kind(ErrorKind::Internal)? {
+        let id = CompositeId(row.get(0).err_kind(ErrorKind::Internal)?);
+        let blake3: Vec<u8> = row.get(1).err_kind(ErrorKind::Internal)?;
+        let blake3 = <[u8; 32]>::try_from(&blake3[..]).map_err(|_| {
+            format_err_t!(
+                DataLoss,
+                "recording {} has {}-byte sample_file_blake3",
+                id,
+                blake3.len()
+            )
+        })?;
+        f(id, blake3);
+    }
+    Ok(())
+}
+
 pub(crate) fn read_meta(conn: &rusqlite::Connection) -> Result<(Uuid, GlobalConfig), Error> {
     Ok(conn.query_row(
         "select uuid, config from meta",
@@ -197,15 +260,18 @@ pub(crate) fn insert_recording(
                                sample_file_bytes, start_time_90k, prev_media_duration_90k,
                                prev_runs, wall_duration_90k, media_duration_delta_90k,
                                video_samples, video_sync_samples, video_sample_entry_id,
-                               end_reason)
+                               end_reason, audio_samples, audio_sample_file_bytes,
+                               audio_sample_entry_id)
                        values (:composite_id, :stream_id, :open_id, :run_offset, :flags,
                                :sample_file_bytes, :start_time_90k, :prev_media_duration_90k,
                                :prev_runs, :wall_duration_90k, :media_duration_delta_90k,
                                :video_samples, :video_sync_samples, :video_sample_entry_id,
-                               :end_reason)
+                               :end_reason, :audio_samples, :audio_sample_file_bytes,
+                               :audio_sample_entry_id)
             "#,
         )
         .with_context(|e| format!("can't prepare recording insert: {}", e))?;
+    let has_audio = r.audio_samples > 0;
     stmt.execute(named_params! {
         ":composite_id": id.0,
         ":stream_id": i64::from(id.stream()),
@@ -222,6 +288,9 @@ pub(crate) fn insert_recording(
         ":video_sync_samples": r.video_sync_samples,
         ":video_sample_entry_id": r.video_sample_entry_id,
         ":end_reason": r.end_reason.as_deref(),
+        ":audio_samples": if has_audio { Some(r.audio_samples) } else { None },
+        ":audio_sample_file_bytes": if has_audio { Some(r.audio_sample_file_bytes) } else { None },
+        ":audio_sample_entry_id": if has_audio { r.audio_sample_entry_id } else { None },
     })
     .with_context(|e| {
         format!(
@@ -255,14 +324,15 @@ pub(crate) fn insert_recording(
     let mut stmt = tx
         .prepare_cached(
             r#"
-            insert into recording_playback (composite_id,  video_index)
-                                    values (:composite_id, :video_index)
+            insert into recording_playback (composite_id,  video_index,  audio_index)
+                                    values (:composite_id, :video_index, :audio_index)
             "#,
         )
         .with_context(|e| format!("can't prepare recording_playback insert: {}", e))?;
     stmt.execute(named_params! {
         ":composite_id": id.0,
         ":video_index": &r.video_index,
+        ":audio_index": if has_audio { Some(&r.audio_index) } else { None },
     })
     .with_context(|e| format!("unable to insert recording_playback for {:#?}: {}", r, e))?;
 
@@ -352,18 +422,38 @@ pub(crate) fn delete_recordings(
     Ok(n)
 }
 
+/// Adds the given sample files within the given directory to the `garbage` table, without
+/// affecting their recording rows.
+///
+/// This is used when a recording's sample file has been moved to another directory; the
+/// original is garbage but the recording still exists.
+pub(crate) fn insert_garbage(
+    tx: &rusqlite::Transaction,
+    sample_file_dir_id: i32,
+    ids: &[CompositeId],
+) -> Result<(), Error> {
+    let mut stmt =
+        tx.prepare_cached("insert into garbage (sample_file_dir_id, composite_id) values (?, ?)")?;
+    for &id in ids {
+        stmt.execute(params![sample_file_dir_id, id.0])?;
+    }
+    Ok(())
+}
+
 /// Marks the given sample files as deleted. This shouldn't be called until the files have
 /// been `unlink()`ed and the parent directory `fsync()`ed.
 pub(crate) fn mark_sample_files_deleted(
     tx: &rusqlite::Transaction,
+    sample_file_dir_id: i32,
     ids: &[CompositeId],
 ) -> Result<(), Error> {
     if ids.is_empty() {
         return Ok(());
     }
-    let mut stmt = tx.prepare_cached("delete from garbage where composite_id = ?")?;
+    let mut stmt =
+        tx.prepare_cached("delete from garbage where sample_file_dir_id = ? and composite_id = ?")?;
     for &id in ids {
-        let changes = stmt.execute(params![id.0])?;
+        let changes = stmt.execute(params![sample_file_dir_id, id.0])?;
         if changes != 1 {
             // panic rather than return error. Errors get retried indefinitely, but there's no
             // recovery from this condition.
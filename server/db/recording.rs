@@ -175,6 +175,84 @@ impl SampleIndexEncoder {
     }
 }
 
+/// An iterator through an audio index (as described in `design/schema.md`).
+/// Initially invalid; call `next()` before each read.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AudioIndexIterator {
+    /// The index byte position of the next sample to read.
+    i: usize,
+
+    /// The starting data byte position of this sample within the recording's audio.
+    pub pos: i32,
+
+    /// The starting time of this sample within the recording, in the audio clock rate's units.
+    pub start: i32,
+
+    /// The duration of this sample, in the audio clock rate's units.
+    pub duration: i32,
+
+    /// The byte length of this sample.
+    pub bytes: i32,
+}
+
+impl AudioIndexIterator {
+    pub fn next(&mut self, data: &[u8]) -> Result<bool, Error> {
+        self.pos += self.bytes;
+        self.start += self.duration;
+        if self.i == data.len() {
+            return Ok(false);
+        }
+        let (raw1, i1) = match decode_varint32(data, self.i) {
+            Ok(tuple) => tuple,
+            Err(()) => bail!("bad varint 1 at offset {}", self.i),
+        };
+        let (raw2, i2) = match decode_varint32(data, i1) {
+            Ok(tuple) => tuple,
+            Err(()) => bail!("bad varint 2 at offset {}", i1),
+        };
+        self.i = i2;
+        let duration_delta = unzigzag32(raw1);
+        self.duration += duration_delta;
+        if self.duration <= 0 {
+            bail!(
+                "non-positive duration {} after applying delta {}",
+                self.duration,
+                duration_delta
+            );
+        }
+        let bytes_delta = unzigzag32(raw2);
+        self.bytes += bytes_delta;
+        if self.bytes <= 0 {
+            bail!(
+                "non-positive bytes {} after applying delta {} to audio sample at ts {}",
+                self.bytes,
+                bytes_delta,
+                self.start
+            );
+        }
+        Ok(true)
+    }
+}
+
+/// An encoder for an audio index (as described in `design/schema.md`).
+#[derive(Debug, Default)]
+pub struct AudioIndexEncoder {
+    prev_duration: i32,
+    prev_bytes: i32,
+}
+
+impl AudioIndexEncoder {
+    /// Adds a sample to the index. The caller is responsible for buffering the sample's data
+    /// in `r.audio_data`.
+    pub fn add_sample(&mut self, duration: i32, bytes: i32, r: &mut db::RecordingToInsert) {
+        append_varint32(zigzag32(duration - self.prev_duration), &mut r.audio_index);
+        append_varint32(zigzag32(bytes - self.prev_bytes), &mut r.audio_index);
+        self.prev_duration = duration;
+        self.prev_bytes = bytes;
+        r.audio_samples += 1;
+    }
+}
+
 /// A segment represents a view of some or all of a single recording.
 /// This struct is not specific to a container format; for `.mp4`s, it's wrapped in a
 /// `moonfire_nvr::mp4::Segment`. Other container/transport formats could be
@@ -219,7 +297,7 @@ impl Segment {
             id: recording.id,
             open_id: recording.open_id,
             begin: None,
-            file_end: recording.sample_file_bytes,
+            file_end: recording.sample_file_bytes - recording.audio_sample_file_bytes,
             frames: recording.video_samples as u16,
             key_frames: recording.video_sync_samples as u16,
             video_sample_entry_id_and_trailing_zero: recording.video_sample_entry_id
@@ -469,6 +547,30 @@ mod tests {
         assert!(!it.next(&r.video_index).unwrap());
     }
 
+    /// Tests a round trip from `AudioIndexEncoder` to `AudioIndexIterator`.
+    #[test]
+    fn test_audio_round_trip() {
+        testutil::init();
+        let samples = [(1024, 371), (1024, 380), (1024, 362), (512, 190)];
+        let mut r = db::RecordingToInsert::default();
+        let mut e = AudioIndexEncoder::default();
+        for &(duration, bytes) in &samples {
+            e.add_sample(duration, bytes, &mut r);
+        }
+        assert_eq!(r.audio_samples, 4);
+        let mut it = AudioIndexIterator::default();
+        let mut start = 0;
+        let mut pos = 0;
+        for &(duration, bytes) in &samples {
+            assert!(it.next(&r.audio_index).unwrap());
+            assert_eq!((it.start, it.pos), (start, pos));
+            assert_eq!((it.duration, it.bytes), (duration, bytes));
+            start += duration;
+            pos += bytes;
+        }
+        assert!(!it.next(&r.audio_index).unwrap());
+    }
+
     /// Tests that `SampleIndexIterator` spots several classes of errors.
     /// TODO: test and fix overflow cases.
     #[test]
@@ -96,6 +96,17 @@ create table stream (
   -- on this stream.
   cum_runs integer not null check (cum_runs >= 0),
 
+  -- The directory to which recordings are moved once they're older than the
+  -- config's cold_after_sec, if any. sample_file_dir_id is the "hot"
+  -- directory in which recordings are initially written.
+  cold_sample_file_dir_id integer references sample_file_dir (id),
+
+  -- Recordings with a recording id less than this have been moved to
+  -- cold_sample_file_dir_id. Recordings are moved oldest first, so this is
+  -- the boundary between the two directories.
+  cum_cold_recordings integer not null default 0
+      check (cum_cold_recordings >= 0),
+
   unique (camera_id, type)
 );
 
@@ -168,7 +179,15 @@ create table recording (
 
   -- The reason this run ended. Absent if there are more recordings in this
   -- run or if this recording predates schema version 7.
-  end_reason text
+  end_reason text,
+
+  -- Audio fields, present iff the recording has an audio track. The audio
+  -- samples are stored in the sample file immediately after the video
+  -- samples, so the video samples occupy the first
+  -- (sample_file_bytes - audio_sample_file_bytes) bytes.
+  audio_samples integer check (audio_samples > 0),
+  audio_sample_file_bytes integer check (audio_sample_file_bytes > 0),
+  audio_sample_entry_id integer references audio_sample_entry (id)
 
   check (composite_id >> 32 = stream_id)
 );
@@ -189,7 +208,10 @@ create index recording_cover on recording (
   video_sample_entry_id,
   sample_file_bytes,
   run_offset,
-  flags
+  flags,
+  audio_samples,
+  audio_sample_file_bytes,
+  audio_sample_entry_id
 );
 
 -- Fields which are only needed to check/correct database integrity problems
@@ -240,9 +262,11 @@ create table recording_playback (
   composite_id integer primary key references recording (composite_id),
 
   -- See design/schema.md#video_index for a description of this field.
-  video_index blob not null check (length(video_index) > 0)
+  video_index blob not null check (length(video_index) > 0),
 
-  -- audio_index could be added here in the future.
+  -- See design/schema.md#audio_index for a description of this field.
+  -- Present iff recording.audio_samples is.
+  audio_index blob check (length(audio_index) > 0)
 );
 
 -- Files which are to be deleted (may or may not still exist).
@@ -285,6 +309,23 @@ create table video_sample_entry (
   pasp_v_spacing integer not null default 1 check (pasp_v_spacing > 0)
 );
 
+-- A concrete box derived from a ISO/IEC 14496-12 section 8.5.2
+-- AudioSampleEntry box. Describes the codec, sample rate, etc.
+create table audio_sample_entry (
+  id integer primary key,
+
+  -- The codec in RFC-6381 format, such as "mp4a.40.2".
+  rfc6381_codec text not null,
+
+  -- The media timescale of the track (typically the sampling rate), as in
+  -- the RTP clock rate. The durations within audio_index use this timescale.
+  clock_rate integer not null check (clock_rate > 0),
+
+  -- The serialized box, including the leading length and box type (mp4a in
+  -- the case of AAC).
+  data blob not null check (length(data) > 0)
+);
+
 create table user (
   id integer primary key,
   username unique not null,
@@ -307,8 +348,9 @@ create table user (
   -- A counter which increments with every password reset or clear.
   password_id integer not null default 0,
 
-  -- Updated lazily on database flush; reset when password_id is incremented.
-  -- This could be used to automatically disable the password on hitting a threshold.
+  -- Updated lazily on database flush; reset when password_id is incremented,
+  -- on successful login, or by an administrator. Login attempts are delayed
+  -- when this exceeds a threshold.
   password_failure_count integer not null default 0,
 
   -- Permissions available for newly created tokens or when authenticating via
@@ -361,13 +403,13 @@ create table user_session (
 
   -- A value indicating the reason for revocation, with optional additional
   -- text detail. Enumeration values:
-  -- 0: logout link clicked (i.e. from within the session itself)
-  -- 1: obsoleted by a change in hashing algorithm (eg schema 5->6 upgrade)
+  -- 1: logout link clicked (i.e. from within the session itself)
+  -- 2: obsoleted by a change in hashing algorithm (eg schema 5->6 upgrade)
+  -- 3: password change invalidated all of the user's sessions
+  -- 4: user revoked (while authenticated in another way)
+  -- 5: expired (due to fixed total time or time inactive)
   --
   -- This might be extended for a variety of other reasons:
-  -- x: user revoked (while authenticated in another way)
-  -- x: password change invalidated all sessions created with that password
-  -- x: expired (due to fixed total time or time inactive)
   -- x: evicted (due to too many sessions)
   -- x: suspicious activity
   revocation_reason integer,
@@ -380,7 +422,11 @@ create table user_session (
   use_count not null default 0,
 
   -- Permissions associated with this token; a serialized "Permissions" protobuf.
-  permissions blob not null default X''
+  permissions blob not null default X'',
+
+  -- If non-null, the time (sec since epoch) at which this session stops being
+  -- valid. Typically set on API tokens.
+  expiration_sec integer
 ) without rowid;
 
 create index user_session_uid on user_session (user_id);
@@ -424,4 +470,4 @@ create table signal_change (
 );
 
 insert into version (id, unix_time,                           notes)
-             values (7,  cast(strftime('%s', 'now') as int), 'db creation');
+             values (8,  cast(strftime('%s', 'now') as int), 'db creation');
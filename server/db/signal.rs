@@ -14,7 +14,7 @@ use fnv::FnvHashMap;
 use log::debug;
 use rusqlite::{params, Connection, Transaction};
 use std::collections::btree_map::Entry;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::convert::TryFrom;
 use std::ops::Range;
 use uuid::Uuid;
@@ -40,8 +40,18 @@ pub(crate) struct State {
     dirty_by_time: BTreeSet<recording::Time>,
 
     max_signal_changes: Option<u32>,
+
+    /// Incremented by each `update_signals` call which changes state.
+    generation: u64,
+
+    /// The `generation` and start time of the most recent changes, oldest first, for
+    /// `earliest_change_since`. At most `RECENT_CHANGES_LEN` long.
+    recent_changes: VecDeque<(u64, recording::Time)>,
 }
 
+/// The number of changes remembered for `State::earliest_change_since`.
+const RECENT_CHANGES_LEN: usize = 64;
+
 /// Representation of all signals at a point in time.
 /// Each point matches a `signal_change` table row (when flushed). However, the in-memory
 /// representation keeps not only the changes as of that time but also the complete prior state.
@@ -205,6 +215,8 @@ impl State {
             types_by_uuid: State::init_types(conn)?,
             points_by_time,
             dirty_by_time: BTreeSet::new(),
+            generation: 0,
+            recent_changes: VecDeque::new(),
         };
         s.debug_assert_point_invariants();
         Ok(s)
@@ -261,8 +273,13 @@ impl State {
         // Apply the end before the start so that the `prev` state can be examined.
         self.update_signals_end(when.clone(), signals, states);
         self.update_signals_start(when.start, signals, states);
-        self.update_signals_middle(when, signals, states);
+        self.update_signals_middle(when.clone(), signals, states);
         self.debug_assert_point_invariants();
+        self.generation += 1;
+        if self.recent_changes.len() == RECENT_CHANGES_LEN {
+            self.recent_changes.pop_front();
+        }
+        self.recent_changes.push_back((self.generation, when.start));
 
         self.gc();
         Ok(())
@@ -767,6 +784,111 @@ impl State {
         Ok(())
     }
 
+    /// Returns the current generation, which changes whenever signal state does.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns the earliest time changed since `generation`, or `None` if nothing has changed.
+    /// If that's no longer known, returns the beginning of time.
+    pub fn earliest_change_since(&self, generation: u64) -> Option<recording::Time> {
+        if generation == self.generation {
+            return None;
+        }
+        match self.recent_changes.front() {
+            Some(&(g, _)) if g <= generation + 1 => {}
+            _ => return Some(recording::Time::min_value()),
+        }
+        self.recent_changes
+            .iter()
+            .filter(|&&(g, _)| g > generation)
+            .map(|&(_, t)| t)
+            .min()
+    }
+
+    /// Returns the time ranges in which any signal directly associated with the given camera was
+    /// in a state configured as `motion`, considering only `from` onward. These are in ascending
+    /// order and don't overlap. A range in progress at `from` is clipped to start there.
+    pub fn motion_ranges(
+        &self,
+        camera_id: i32,
+        from: recording::Time,
+    ) -> Vec<Range<recording::Time>> {
+        // signal id -> bitmask of states which are considered motion.
+        let mut motion_states = FnvHashMap::default();
+        for s in self.signals_by_id.values() {
+            if s.config
+                .camera_associations
+                .get(&camera_id)
+                .map(String::as_str)
+                != Some("direct")
+            {
+                continue;
+            }
+            let type_ = match self.types_by_uuid.get(&s.type_) {
+                None => continue,
+                Some(t) => t,
+            };
+            let mask = type_
+                .config
+                .values
+                .iter()
+                .filter(|(_, v)| v.motion)
+                .fold(0u16, |m, (&k, _)| m | (1 << k));
+            if mask != 0 {
+                motion_states.insert(s.id, mask);
+            }
+        }
+        let mut ranges = Vec::new();
+        if motion_states.is_empty() {
+            return ranges;
+        }
+        let is_motion = |signal: u32, state: u16| match motion_states.get(&signal) {
+            Some(&mask) => state < 16 && (mask & (1 << state)) != 0,
+            None => false,
+        };
+        let mut moving = BTreeSet::new();
+        let mut start = None;
+        if let Some((_, p)) = self.points_by_time.range(..from).next_back() {
+            for (signal, state) in p.after() {
+                if is_motion(signal, state) {
+                    moving.insert(signal);
+                }
+            }
+            if !moving.is_empty() {
+                start = Some(from);
+            }
+        }
+        for (&when, p) in self.points_by_time.range(from..) {
+            let mut it = p.changes();
+            while let Some((signal, state)) = it.next().expect("in-mem changes is valid") {
+                if !motion_states.contains_key(&signal) {
+                    continue;
+                }
+                if is_motion(signal, state) {
+                    moving.insert(signal);
+                } else {
+                    moving.remove(&signal);
+                }
+            }
+            match (start, moving.is_empty()) {
+                (None, false) => start = Some(when),
+                (Some(s), true) => {
+                    ranges.push(s..when);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+
+        // The last point's final state should be empty, but if motion is somehow still in
+        // progress, treat it as continuing indefinitely rather than dropping it.
+        if let Some(s) = start {
+            ranges.push(s..recording::Time::max_value());
+        }
+        ranges
+    }
+
     pub fn signals_by_id(&self) -> &BTreeMap<u32, Signal> {
         &self.signals_by_id
     }
@@ -804,6 +926,14 @@ pub struct Signal {
     pub config: SignalConfig,
 }
 
+/// A successful call to `LockedDatabase::update_signals`, as passed to watchers.
+#[derive(Clone, Debug)]
+pub struct Update {
+    pub when: Range<recording::Time>,
+    pub signals: Vec<u32>,
+    pub states: Vec<u16>,
+}
+
 #[derive(Debug, Default)]
 pub struct Type {
     pub valid_states: u16,
@@ -1012,4 +1142,74 @@ mod tests {
         );
         assert_eq!(&rows[..], EXPECTED2);
     }
+
+    #[test]
+    fn motion_ranges() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut type_config = SignalTypeConfig::default();
+        type_config.values.insert(
+            1,
+            SignalTypeValueConfig {
+                name: "still".to_owned(),
+                ..Default::default()
+            },
+        );
+        type_config.values.insert(
+            2,
+            SignalTypeValueConfig {
+                name: "moving".to_owned(),
+                motion: true,
+                ..Default::default()
+            },
+        );
+        conn.execute(
+            "insert into signal_type (uuid, config) values (?, ?)",
+            params![
+                SqlUuid(Uuid::parse_str("ee66270f-d9c6-4819-8b33-9720d4cbca6b").unwrap()),
+                &type_config,
+            ],
+        )
+        .unwrap();
+        conn.execute_batch(
+            r#"
+            insert into signal (id, uuid, type_uuid, config)
+                        values (1, x'1B3889C0A59F400DA24C94EBEB19CC3A',
+                                x'EE66270FD9C648198B339720D4CBCA6B',
+                                '{"cameraAssociations": {"1": "direct"}}'),
+                               (2, x'A4A73D9A53424EBCB9F6366F1E5617FA',
+                                x'EE66270FD9C648198B339720D4CBCA6B',
+                                '{"cameraAssociations": {"1": "indirect"}}'),
+                               (3, x'C7E5B0F35B6B4A4FA4E5B5B0B9E3A4D1',
+                                x'EE66270FD9C648198B339720D4CBCA6B',
+                                '{"cameraAssociations": {"1": "direct"}}');
+            "#,
+        )
+        .unwrap();
+        let mut s = State::init(&conn, &GlobalConfig::default()).unwrap();
+        let all = recording::Time::min_value();
+        assert!(s.motion_ranges(1, all).is_empty());
+        let t = |min| recording::Time(140067462600000 + min * 60 * recording::TIME_UNITS_PER_SEC);
+
+        // Overlapping motion on the two directly-associated signals is merged.
+        s.update_signals(t(0)..t(2), &[1], &[2]).unwrap();
+        s.update_signals(t(1)..t(3), &[3], &[2]).unwrap();
+
+        // Motion on an indirectly-associated signal and non-motion states are ignored.
+        s.update_signals(t(4)..t(5), &[2], &[2]).unwrap();
+        s.update_signals(t(6)..t(7), &[1], &[1]).unwrap();
+
+        let generation = s.generation();
+        s.update_signals(t(8)..t(9), &[3], &[2]).unwrap();
+        assert_eq!(s.earliest_change_since(generation), Some(t(8)));
+        assert_eq!(s.earliest_change_since(s.generation()), None);
+        assert_eq!(s.earliest_change_since(0), Some(t(0)));
+        assert_eq!(s.motion_ranges(1, all), &[t(0)..t(3), t(8)..t(9)]);
+        assert!(s.motion_ranges(2, all).is_empty());
+
+        // A range in progress at `from` is clipped.
+        assert_eq!(s.motion_ranges(1, t(2)), &[t(2)..t(3), t(8)..t(9)]);
+        assert_eq!(s.motion_ranges(1, t(4)), &[t(8)..t(9)]);
+    }
 }
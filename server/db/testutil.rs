@@ -22,6 +22,11 @@ static INIT: parking_lot::Once = parking_lot::Once::new();
 pub const TEST_CAMERA_ID: i32 = 1;
 pub const TEST_STREAM_ID: i32 = 1;
 
+/// id of the signal created by `TestDb::new_with_signal`, directly associated with the camera.
+/// Its type has states 1 (`still`) and 2 (`moving`, which counts as motion), set from the ONVIF
+/// `RuleEngine/CellMotionDetector/Motion` event's `IsMotion` item.
+pub const TEST_SIGNAL_ID: u32 = 1;
+
 pub const TEST_VIDEO_SAMPLE_ENTRY_DATA: &[u8] =
     b"\x00\x00\x00\x7D\x61\x76\x63\x31\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\
     \x00\x00\x00\x00\x00\x00\x00\x00\x00\x07\x80\x04\x38\x00\x48\x00\x00\x00\x48\x00\x00\x00\x00\
@@ -65,7 +70,16 @@ impl<C: Clocks + Clone> TestDb<C> {
         Self::new_with_flush_if_sec(clocks, 0)
     }
 
+    /// Creates a test database with one camera and the signal `TEST_SIGNAL_ID`.
+    pub fn new_with_signal(clocks: C) -> Self {
+        Self::new_inner(clocks, 0, true)
+    }
+
     pub(crate) fn new_with_flush_if_sec(clocks: C, flush_if_sec: u32) -> Self {
+        Self::new_inner(clocks, flush_if_sec, false)
+    }
+
+    fn new_inner(clocks: C, flush_if_sec: u32, with_signal: bool) -> Self {
         let tmpdir = tempfile::Builder::new()
             .prefix("moonfire-nvr-test")
             .tempdir()
@@ -73,6 +87,27 @@ impl<C: Clocks + Clone> TestDb<C> {
 
         let mut conn = rusqlite::Connection::open_in_memory().unwrap();
         db::init(&mut conn).unwrap();
+        if with_signal {
+            conn.execute_batch(
+                r#"
+                insert into signal_type (uuid, config)
+                                 values (x'EE66270FD9C648198B339720D4CBCA6B',
+                                         '{"values": {"1": {"name": "still"},
+                                                      "2": {"name": "moving", "motion": true}},
+                                           "onvifTopics": [{
+                                               "topic": "RuleEngine/CellMotionDetector/Motion",
+                                               "dataItem": "IsMotion",
+                                               "trueState": 2,
+                                               "falseState": 1}]}');
+                insert into signal (id, uuid, type_uuid, config)
+                            values (1, x'1B3889C0A59F400DA24C94EBEB19CC3A',
+                                    x'EE66270FD9C648198B339720D4CBCA6B',
+                                    '{"shortName": "test signal",
+                                      "cameraAssociations": {"1": "direct"}}');
+                "#,
+            )
+            .unwrap();
+        }
         let db = Arc::new(db::Database::new(clocks, conn, true).unwrap());
         let (test_camera_uuid, sample_file_dir_id);
         let path = tmpdir.path().to_owned();
@@ -92,6 +127,7 @@ impl<C: Clocks + Clone> TestDb<C> {
                     streams: [
                         db::StreamChange {
                             sample_file_dir_id: Some(sample_file_dir_id),
+                            cold_sample_file_dir_id: None,
                             config: crate::json::StreamConfig {
                                 url: Some(url::Url::parse("rtsp://test-camera/main").unwrap()),
                                 mode: crate::json::STREAM_MODE_RECORD.to_owned(),
@@ -110,6 +146,7 @@ impl<C: Clocks + Clone> TestDb<C> {
                 stream_id: TEST_STREAM_ID,
                 new_record: true,
                 new_limit: 1048576,
+                new_retain_duration_sec: None,
             }])
             .unwrap();
             dir = l
@@ -22,6 +22,7 @@ mod v3_to_v4;
 mod v4_to_v5;
 mod v5_to_v6;
 mod v6_to_v7;
+mod v7_to_v8;
 
 const UPGRADE_NOTES: &str = concat!("upgraded using moonfire-db ", env!("CARGO_PKG_VERSION"));
 
@@ -55,6 +56,7 @@ fn upgrade(args: &Args, target_ver: i32, conn: &mut rusqlite::Connection) -> Res
         v4_to_v5::run,
         v5_to_v6::run,
         v6_to_v7::run,
+        v7_to_v8::run,
     ];
 
     {
@@ -280,7 +282,8 @@ mod tests {
             (4, None), // transitional; don't compare schemas.
             (5, Some(include_str!("v5.sql"))),
             (6, Some(include_str!("v6.sql"))),
-            (7, Some(include_str!("../schema.sql"))),
+            (7, Some(include_str!("v7.sql"))),
+            (8, Some(include_str!("../schema.sql"))),
         ] {
             upgrade(
                 &Args {
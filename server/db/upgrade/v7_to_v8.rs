@@ -0,0 +1,55 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Upgrades a version 7 schema to a version 8 schema.
+use failure::Error;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    // These create statements match the schema.sql when version 8 was the latest.
+    tx.execute_batch(
+        r#"
+        create table audio_sample_entry (
+          id integer primary key,
+          rfc6381_codec text not null,
+          clock_rate integer not null check (clock_rate > 0),
+          data blob not null check (length(data) > 0)
+        );
+
+        alter table recording add column audio_samples integer check (audio_samples > 0);
+        alter table recording add column audio_sample_file_bytes integer
+            check (audio_sample_file_bytes > 0);
+        alter table recording add column audio_sample_entry_id integer
+            references audio_sample_entry (id);
+
+        alter table recording_playback add column audio_index blob
+            check (length(audio_index) > 0);
+
+        alter table stream add column cold_sample_file_dir_id integer
+            references sample_file_dir (id);
+        alter table stream add column cum_cold_recordings integer not null default 0
+            check (cum_cold_recordings >= 0);
+
+        alter table user_session add column expiration_sec integer;
+
+        drop index recording_cover;
+        create index recording_cover on recording (
+          stream_id,
+          start_time_90k,
+          open_id,
+          wall_duration_90k,
+          media_duration_delta_90k,
+          video_samples,
+          video_sync_samples,
+          video_sample_entry_id,
+          sample_file_bytes,
+          run_offset,
+          flags,
+          audio_samples,
+          audio_sample_file_bytes,
+          audio_sample_entry_id
+        );
+        "#,
+    )?;
+    Ok(())
+}
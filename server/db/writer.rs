@@ -98,8 +98,23 @@ struct Syncer<C: Clocks + Clone, D: DirWriter> {
     db: Arc<db::Database<C>>,
     planned_flushes: std::collections::BinaryHeap<PlannedFlush>,
     shutdown_rx: base::shutdown::Receiver,
+
+    /// Monotonic time at which to next check for recordings past their `retain_duration_sec` or
+    /// `cold_after_sec`. Saving a recording also checks its own stream's `retain_duration_sec`,
+    /// but this ensures recordings are deleted on time even on streams which are no longer
+    /// recording.
+    next_expiry: Timespec,
 }
 
+/// How often the syncer checks all its streams for recordings past their `retain_duration_sec`
+/// or `cold_after_sec`.
+const EXPIRY_INTERVAL_SEC: i64 = 60 * 60;
+
+/// The maximum number of recordings per stream to move to cold storage at once. A large backlog
+/// (as when `cold_after_sec` is first configured) is moved in several batches, so that saving new
+/// recordings isn't delayed for long.
+const MAX_MOVES_PER_BATCH: usize = 60;
+
 /// A plan to flush at a given instant due to a recently-saved recording's `flush_if_sec` parameter.
 struct PlannedFlush {
     /// Monotonic time at which this flush should happen.
@@ -205,6 +220,7 @@ pub fn lower_retention(
     let db2 = db.clone();
     let (_tx, rx) = base::shutdown::channel();
     let (mut syncer, _) = Syncer::new(&db.lock(), rx, db2, dir_id)?;
+    let now = recording::Time::new(db.clocks().realtime());
     syncer.do_rotation(|db| {
         for l in limits {
             let (fs_bytes_before, extra);
@@ -220,7 +236,7 @@ pub fn lower_retention(
             if l.limit >= fs_bytes_before {
                 continue;
             }
-            delete_recordings(db, l.stream_id, extra)?;
+            delete_recordings(db, l.stream_id, extra, now)?;
         }
         Ok(())
     })
@@ -229,16 +245,29 @@ pub fn lower_retention(
 /// Enqueues deletion of recordings to bring a stream's disk usage within bounds.
 /// The next flush will mark the recordings as garbage in the SQLite database, and then they can
 /// be deleted from disk.
+///
+/// Recordings which ended more than the stream's `retain_duration_sec` before `now` are deleted
+/// first. Recordings protected by the stream's `event_retain_bytes` are deleted only if there's
+/// nothing else left to delete.
 fn delete_recordings(
     db: &mut db::LockedDatabase,
     stream_id: i32,
     extra_bytes_needed: i64,
+    now: recording::Time,
 ) -> Result<(), Error> {
+    let retain_duration_sec = match db.streams_by_id().get(&stream_id) {
+        None => bail!("no stream {}", stream_id),
+        Some(s) => s.config.retain_duration_sec,
+    };
+    if retain_duration_sec > 0 {
+        let cutoff = now
+            - recording::Duration(i64::from(retain_duration_sec) * recording::TIME_UNITS_PER_SEC);
+        db.delete_oldest_recordings(stream_id, &mut |row| {
+            row.start + recording::Duration(i64::from(row.wall_duration_90k)) <= cutoff
+        })?;
+    }
     let fs_bytes_needed = {
-        let stream = match db.streams_by_id().get(&stream_id) {
-            None => bail!("no stream {}", stream_id),
-            Some(s) => s,
-        };
+        let stream = db.streams_by_id().get(&stream_id).unwrap();
         stream.fs_bytes + stream.fs_bytes_to_add - stream.fs_bytes_to_delete + extra_bytes_needed
             - stream.config.retain_bytes
     };
@@ -251,15 +280,19 @@ fn delete_recordings(
         );
         return Ok(());
     }
-    let mut n = 0;
-    db.delete_oldest_recordings(stream_id, &mut |row| {
+    let keep = db.list_protected_events(stream_id)?;
+    let mut f = |row: &db::ListOldestRecordingsRow| {
         if fs_bytes_needed >= fs_bytes_to_delete {
             fs_bytes_to_delete += db::round_up(i64::from(row.sample_file_bytes));
-            n += 1;
             return true;
         }
         false
-    })?;
+    };
+    db.delete_oldest_recordings_except(stream_id, &keep, &mut f)?;
+    if !keep.is_empty() {
+        // If that wasn't enough, fall back to deleting protected recordings, oldest first.
+        db.delete_oldest_recordings(stream_id, &mut f)?;
+    }
     Ok(())
 }
 
@@ -321,13 +354,16 @@ impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
         let dir = d.get()?;
 
         // Abandon files.
-        // First, get a list of the streams in question.
+        // First, get a list of the streams in question. Files in a stream's cold directory past
+        // `cum_cold_recordings` are copies which were never committed.
         let streams_to_next: FnvHashMap<_, _> = l
             .streams_by_id()
             .iter()
             .filter_map(|(&k, v)| {
                 if v.sample_file_dir_id == Some(dir_id) {
                     Some((k, v.cum_recordings))
+                } else if v.cold_sample_file_dir_id == Some(dir_id) {
+                    Some((k, v.cum_cold_recordings))
                 } else {
                     None
                 }
@@ -349,6 +385,7 @@ impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
             bail!("Unable to delete {} abandoned recordings.", undeletable);
         }
 
+        let next_expiry = db.clocks().monotonic() + Duration::seconds(EXPIRY_INTERVAL_SEC);
         Ok((
             Syncer {
                 dir_id,
@@ -356,6 +393,7 @@ impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
                 dir,
                 db,
                 planned_flushes: std::collections::BinaryHeap::new(),
+                next_expiry,
             },
             d.path.clone(),
         ))
@@ -364,10 +402,11 @@ impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
     /// Rotates files for all streams and deletes stale files from previous runs.
     /// Called from main thread.
     fn initial_rotation(&mut self) -> Result<(), Error> {
+        let now = recording::Time::new(self.db.clocks().realtime());
         self.do_rotation(|db| {
             let streams: Vec<i32> = db.streams_by_id().keys().copied().collect();
             for &stream_id in &streams {
-                delete_recordings(db, stream_id, 0)?;
+                delete_recordings(db, stream_id, 0, now)?;
             }
             Ok(())
         })
@@ -418,29 +457,28 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
     ///
     /// Returns true iff the loop should continue.
     fn iter(&mut self, cmds: &mpsc::Receiver<SyncerCommand<D::File>>) -> bool {
-        // Wait for a command, the next flush timeout (if specified), or channel disconnect.
-        let next_flush = self.planned_flushes.peek().map(|f| f.when);
-        let cmd = match next_flush {
-            None => match cmds.recv() {
-                Err(_) => return false, // all cmd senders are gone.
-                Ok(cmd) => cmd,
-            },
-            Some(t) => {
-                let now = self.db.clocks().monotonic();
-
-                // Calculate the timeout to use, mapping negative durations to 0.
-                let timeout = (t - now)
-                    .to_std()
-                    .unwrap_or_else(|_| StdDuration::new(0, 0));
-                match self.db.clocks().recv_timeout(&cmds, timeout) {
-                    Err(mpsc::RecvTimeoutError::Disconnected) => return false, // cmd senders gone.
-                    Err(mpsc::RecvTimeoutError::Timeout) => {
-                        self.flush();
-                        return true;
-                    }
-                    Ok(cmd) => cmd,
+        // Wait for a command, the next flush or expiry timeout, or channel disconnect.
+        let t = match self.planned_flushes.peek() {
+            None => self.next_expiry,
+            Some(f) => cmp::min(f.when, self.next_expiry),
+        };
+        let now = self.db.clocks().monotonic();
+
+        // Calculate the timeout to use, mapping negative durations to 0.
+        let timeout = (t - now)
+            .to_std()
+            .unwrap_or_else(|_| StdDuration::new(0, 0));
+        let cmd = match self.db.clocks().recv_timeout(&cmds, timeout) {
+            Err(mpsc::RecvTimeoutError::Disconnected) => return false, // cmd senders gone.
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if self.next_expiry <= self.db.clocks().monotonic() {
+                    self.expire();
+                    self.move_to_cold();
                 }
+                self.flush();
+                return true;
             }
+            Ok(cmd) => cmd,
         };
 
         // Have a command; handle it.
@@ -516,9 +554,10 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
         clock::retry(&self.db.clocks(), &self.shutdown_rx, &mut || {
             self.dir.sync()
         })?;
+        let now = recording::Time::new(self.db.clocks().realtime());
         let mut db = self.db.lock();
         db.mark_synced(id).unwrap();
-        delete_recordings(&mut db, stream_id, 0).unwrap();
+        delete_recordings(&mut db, stream_id, 0, now).unwrap();
         let s = db.streams_by_id().get(&stream_id).unwrap();
         let c = db.cameras_by_id().get(&s.camera_id).unwrap();
 
@@ -545,6 +584,120 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
         Ok(())
     }
 
+    /// Deletes recordings past their stream's `retain_duration_sec`, flushing immediately if there
+    /// are any. Called from worker thread every `EXPIRY_INTERVAL_SEC`.
+    fn expire(&mut self) {
+        let clocks = self.db.clocks();
+        self.next_expiry = clocks.monotonic() + Duration::seconds(EXPIRY_INTERVAL_SEC);
+        let now = recording::Time::new(clocks.realtime());
+        let mut l = self.db.lock();
+        let streams: Vec<i32> = l
+            .streams_by_id()
+            .iter()
+            .filter(|(_, s)| {
+                s.sample_file_dir_id == Some(self.dir_id) && s.config.retain_duration_sec > 0
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        let mut need_flush = false;
+        for stream_id in streams {
+            if let Err(e) = delete_recordings(&mut l, stream_id, 0, now) {
+                warn!(
+                    "unable to expire recordings for stream {}: {}",
+                    stream_id, e
+                );
+                continue;
+            }
+            need_flush |= l.streams_by_id()[&stream_id].bytes_to_delete > 0;
+        }
+        if need_flush {
+            if let Err(e) = l.flush("expiry of recordings past retain_duration_sec") {
+                // The deletion remains queued for the next flush.
+                warn!("flush failed: {}", e);
+            }
+        }
+    }
+
+    /// Moves recordings past their stream's `cold_after_sec` from this directory to the stream's
+    /// cold sample file directory, flushing immediately if there are any. Called from worker
+    /// thread after `expire`.
+    fn move_to_cold(&mut self) {
+        let clocks = self.db.clocks();
+        let now = recording::Time::new(clocks.realtime());
+        let streams: Vec<(i32, i32, u32)> = self
+            .db
+            .lock()
+            .streams_by_id()
+            .iter()
+            .filter_map(|(&id, s)| match s.cold_sample_file_dir_id {
+                Some(c)
+                    if s.sample_file_dir_id == Some(self.dir_id) && s.config.cold_after_sec > 0 =>
+                {
+                    Some((id, c, s.config.cold_after_sec))
+                }
+                _ => None,
+            })
+            .collect();
+        let mut moved = 0;
+        for (stream_id, cold_dir_id, cold_after_sec) in streams {
+            let cutoff = now
+                - recording::Duration(i64::from(cold_after_sec) * recording::TIME_UNITS_PER_SEC);
+            match self.move_stream_to_cold(stream_id, cold_dir_id, cutoff) {
+                Ok(n) => {
+                    moved += n;
+                    if n == MAX_MOVES_PER_BATCH {
+                        // There may be more; check again after handling pending commands.
+                        self.next_expiry = clocks.monotonic();
+                    }
+                }
+                Err(e) => warn!(
+                    "unable to move recordings for stream {} to cold storage: {}",
+                    stream_id, e
+                ),
+            }
+        }
+        if moved > 0 {
+            if let Err(e) = self
+                .db
+                .lock()
+                .flush("move of recordings past cold_after_sec")
+            {
+                // The move remains queued for the next flush.
+                warn!("flush failed: {}", e);
+            }
+        }
+    }
+
+    /// Copies up to `MAX_MOVES_PER_BATCH` recordings which ended before `cutoff` to the given
+    /// cold directory and queues the move for the next database flush. Returns the number of
+    /// recordings copied.
+    fn move_stream_to_cold(
+        &mut self,
+        stream_id: i32,
+        cold_dir_id: i32,
+        cutoff: recording::Time,
+    ) -> Result<usize, Error> {
+        let (hot, cold, ids, next) = {
+            let l = self.db.lock();
+            let dirs = l.sample_file_dirs_by_id();
+            let hot = dirs.get(&self.dir_id).unwrap().get()?;
+            let cold = dirs
+                .get(&cold_dir_id)
+                .ok_or_else(|| format_err!("no dir {}", cold_dir_id))?
+                .get()?;
+            let (ids, next) = l.list_recordings_to_move(stream_id, cutoff, MAX_MOVES_PER_BATCH)?;
+            (hot, cold, ids, next)
+        };
+        for &id in &ids {
+            hot.copy_file(id, &cold)?;
+        }
+        if !ids.is_empty() {
+            cold.sync()?;
+        }
+        self.db.lock().mark_moved(stream_id, &ids, next)?;
+        Ok(ids.len())
+    }
+
     /// Flushes the database if necessary to honor `flush_if_sec` for some recording.
     /// Called from worker thread when one of the `planned_flushes` arrives.
     fn flush(&mut self) {
@@ -559,10 +712,11 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
             let s = match l.streams_by_id().get(&f.recording.stream()) {
                 Some(s) => s,
                 None => {
-                    // Removing streams while running hasn't been implemented yet, so this should
-                    // be impossible.
-                    warn!(
-                        "bug: no stream for {} which was scheduled to be flushed",
+                    // The stream's camera was deleted since this flush was planned.
+                    // `LockedDatabase::delete_camera` requires its recordings be removed
+                    // first, so there's nothing left to flush.
+                    debug!(
+                        "no stream for {} which was scheduled to be flushed; it was deleted",
                         f.recording
                     );
                     PeekMut::pop(f);
@@ -616,6 +770,7 @@ pub struct Writer<'a, C: Clocks + Clone, D: DirWriter> {
     channel: &'a SyncerChannel<D::File>,
     stream_id: i32,
     video_sample_entry_id: i32,
+    audio_sample_entry_id: Option<i32>,
     state: WriterState<D::File>,
 }
 
@@ -633,6 +788,7 @@ struct InnerWriter<F: FileWriter> {
     f: F,
     r: Arc<Mutex<db::RecordingToInsert>>,
     e: recording::SampleIndexEncoder,
+    audio_e: recording::AudioIndexEncoder,
     id: CompositeId,
 
     hasher: blake3::Hasher,
@@ -653,6 +809,13 @@ struct InnerWriter<F: FileWriter> {
     /// `unindexed_sample` should always be `Some`, except when a `write` call has aborted on
     /// shutdown. In that case, the close will be unable to write the full segment.
     unindexed_sample: Option<UnindexedSample>,
+
+    /// An audio frame which has been buffered but not added to `audio_e` yet, as with
+    /// `unindexed_sample`. Its duration is the difference between its pts and the next audio
+    /// frame's, so that lost packets don't shift later audio earlier relative to the video.
+    unindexed_audio: Option<UnindexedAudio>,
+
+    metrics: Arc<db::StreamMetrics>,
 }
 
 /// A sample which has been written to disk but not included in the index yet.
@@ -666,6 +829,17 @@ struct UnindexedSample {
     is_key: bool,
 }
 
+/// An audio frame which has been buffered but not included in the audio index yet.
+#[derive(Copy, Clone)]
+struct UnindexedAudio {
+    /// In units of the audio clock rate, relative to the start of the run.
+    pts: i64,
+    len: i32,
+
+    /// The frame's nominal duration, used if there's no following frame or its pts is unusable.
+    frame_length: i32,
+}
+
 /// State associated with a run's previous recording; used within [Writer].
 #[derive(Copy, Clone)]
 struct PreviousWriter {
@@ -675,12 +849,14 @@ struct PreviousWriter {
 
 impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
     /// `db` must not be locked.
+    /// `audio_sample_entry_id` should be supplied iff the stream has audio.
     pub fn new(
         dir: &'a D,
         db: &'a db::Database<C>,
         channel: &'a SyncerChannel<D::File>,
         stream_id: i32,
         video_sample_entry_id: i32,
+        audio_sample_entry_id: Option<i32>,
     ) -> Self {
         Writer {
             dir,
@@ -688,6 +864,7 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             channel,
             stream_id,
             video_sample_entry_id,
+            audio_sample_entry_id,
             state: WriterState::Unopened,
         }
     }
@@ -702,7 +879,8 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             WriterState::Open(_) => return Ok(()),
             WriterState::Closed(prev) => Some(prev),
         };
-        let (id, r) = self.db.lock().add_recording(
+        let mut l = self.db.lock();
+        let (id, r) = l.add_recording(
             self.stream_id,
             db::RecordingToInsert {
                 run_offset: prev.map(|p| p.run_offset + 1).unwrap_or(0),
@@ -710,10 +888,13 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
                     .map(|p| p.end)
                     .unwrap_or(recording::Time(i64::max_value())),
                 video_sample_entry_id: self.video_sample_entry_id,
+                audio_sample_entry_id: self.audio_sample_entry_id,
                 flags: db::RecordingFlags::Growing as i32,
                 ..Default::default()
             },
         )?;
+        let metrics = l.streams_by_id()[&self.stream_id].metrics.clone();
+        drop(l);
         let f = clock::retry(&self.db.clocks(), shutdown_rx, &mut || {
             self.dir.create_file(id)
         })?;
@@ -722,10 +903,13 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             f,
             r,
             e: recording::SampleIndexEncoder::default(),
+            audio_e: recording::AudioIndexEncoder::default(),
             id,
             hasher: blake3::Hasher::new(),
             local_start: recording::Time(i64::max_value()),
             unindexed_sample: None,
+            unindexed_audio: None,
+            metrics,
         });
         Ok(())
     }
@@ -806,6 +990,7 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
                 };
             remaining = &remaining[written..];
         }
+        w.metrics.add_bytes_written(pkt.len());
         w.unindexed_sample = Some(UnindexedSample {
             local_time,
             pts_90k,
@@ -816,6 +1001,49 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
         Ok(())
     }
 
+    /// Adds an audio frame to the current recording. `pts` is in units of the audio sample
+    /// entry's clock rate, and `frame_length` is the frame's nominal duration in the same units.
+    /// Audio frames are buffered in RAM and written to the end of the sample file on close.
+    /// They're discarded when no recording is open (before the first video frame or between a
+    /// rotation and the next video frame).
+    pub fn write_audio(&mut self, pkt: &[u8], pts: i64, frame_length: i32) -> Result<(), Error> {
+        let w = match self.state {
+            WriterState::Open(ref mut w) => w,
+            _ => return Ok(()),
+        };
+        if self.audio_sample_entry_id.is_none() {
+            bail!("audio frame on stream without audio sample entry");
+        }
+        if pkt.is_empty() || frame_length <= 0 {
+            bail!(
+                "invalid audio frame: {} bytes, frame length {}",
+                pkt.len(),
+                frame_length
+            );
+        }
+        let mut l = w.r.lock();
+        if let Some(prev) = w.unindexed_audio.take() {
+            let duration = match i32::try_from(pts - prev.pts) {
+                Ok(d) if d > 0 => d,
+                _ => {
+                    warn!(
+                        "{}: audio pts jumped from {} to {}; using nominal frame length",
+                        w.id, prev.pts, pts
+                    );
+                    prev.frame_length
+                }
+            };
+            w.audio_e.add_sample(duration, prev.len, &mut l);
+        }
+        l.audio_data.extend_from_slice(pkt);
+        w.unindexed_audio = Some(UnindexedAudio {
+            pts,
+            len: i32::try_from(pkt.len())?,
+            frame_length,
+        });
+        Ok(())
+    }
+
     /// Cleanly closes the writer, using a supplied pts of the next sample for the last sample's
     /// duration (if known). If `close` is not called, the `Drop` trait impl will close the trait,
     /// swallowing errors and using a zero duration for the last sample.
@@ -906,7 +1134,6 @@ impl<F: FileWriter> InnerWriter<F> {
             None => (0, db::RecordingFlags::TrailingZero as i32),
             Some(p) => (i32::try_from(p - unindexed.pts_90k)?, 0),
         };
-        let blake3 = self.hasher.finalize();
         let (run_offset, end);
         self.add_sample(
             last_sample_duration,
@@ -916,6 +1143,8 @@ impl<F: FileWriter> InnerWriter<F> {
             db,
             stream_id,
         )?;
+        self.append_audio();
+        let blake3 = self.hasher.finalize();
 
         // This always ends a live segment.
         let wall_duration;
@@ -924,6 +1153,9 @@ impl<F: FileWriter> InnerWriter<F> {
             l.flags = flags;
             l.local_time_delta = self.local_start - l.start;
             l.sample_file_blake3 = Some(*blake3.as_bytes());
+            if let Some(ref r) = reason {
+                self.metrics.set_end_reason(r.clone());
+            }
             l.end_reason = reason;
             wall_duration = recording::Duration(i64::from(l.wall_duration_90k));
             run_offset = l.run_offset;
@@ -935,6 +1167,48 @@ impl<F: FileWriter> InnerWriter<F> {
     }
 }
 
+impl<F: FileWriter> InnerWriter<F> {
+    /// Appends the buffered audio (if any) to the end of the sample file.
+    /// On failure, discards the audio rather than the entire recording.
+    fn append_audio(&mut self) {
+        let data = {
+            let mut l = self.r.lock();
+            if let Some(a) = self.unindexed_audio.take() {
+                self.audio_e.add_sample(a.frame_length, a.len, &mut l);
+            }
+            if l.audio_data.is_empty() {
+                return;
+            }
+            l.audio_data.clone()
+        };
+        let mut remaining = &data[..];
+        while !remaining.is_empty() {
+            match self.f.write(remaining) {
+                Ok(0) | Err(_) => {
+                    // Note a partial write leaves some unreferenced bytes at the end of the file.
+                    warn!(
+                        "{}: unable to append {} bytes of audio; discarding it",
+                        self.id,
+                        data.len()
+                    );
+                    let mut l = self.r.lock();
+                    l.audio_samples = 0;
+                    l.audio_index = Vec::new();
+                    l.audio_data = Vec::new();
+                    return;
+                }
+                Ok(written) => remaining = &remaining[written..],
+            }
+        }
+        self.hasher.update(&data);
+        self.metrics.add_bytes_written(data.len());
+        let mut l = self.r.lock();
+        l.audio_sample_file_bytes = i32::try_from(data.len()).unwrap();
+        l.sample_file_bytes += l.audio_sample_file_bytes;
+        l.audio_data = Vec::new();
+    }
+}
+
 impl<'a, C: Clocks + Clone, D: DirWriter> Drop for Writer<'a, C, D> {
     fn drop(&mut self) {
         if ::std::thread::panicking() {
@@ -1137,6 +1411,7 @@ mod tests {
             db: tdb.db.clone(),
             planned_flushes: std::collections::BinaryHeap::new(),
             shutdown_rx: shutdown_rx.clone(),
+            next_expiry: ::time::Timespec::new(super::EXPIRY_INTERVAL_SEC, 0),
         };
         let (syncer_tx, syncer_rx) = mpsc::channel();
         tdb.db.lock().on_flush(Box::new({
@@ -1185,6 +1460,7 @@ mod tests {
             &h.channel,
             testutil::TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
         h.dir.expect(MockDirAction::Create(
             CompositeId::new(1, 0),
@@ -1234,6 +1510,7 @@ mod tests {
                 stream_id: testutil::TEST_STREAM_ID,
                 new_record: true,
                 new_limit: 0,
+                new_retain_duration_sec: None,
             }])
             .unwrap();
 
@@ -1255,6 +1532,7 @@ mod tests {
             &h.channel,
             testutil::TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
         let f = MockFile::new();
         h.dir.expect(MockDirAction::Create(
@@ -1375,6 +1653,7 @@ mod tests {
             &h.channel,
             testutil::TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
         h.dir.expect(MockDirAction::Create(
             CompositeId::new(1, 0),
@@ -1446,6 +1725,7 @@ mod tests {
                 stream_id: testutil::TEST_STREAM_ID,
                 new_record: true,
                 new_limit: 0,
+                new_retain_duration_sec: None,
             }])
             .unwrap();
 
@@ -1467,6 +1747,7 @@ mod tests {
             &h.channel,
             testutil::TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
         let f = MockFile::new();
         h.dir.expect(MockDirAction::Create(
@@ -1573,6 +1854,119 @@ mod tests {
         assert!(h.syncer.planned_flushes.is_empty());
     }
 
+    #[test]
+    fn expire() {
+        testutil::init();
+        let mut h = new_harness(0);
+        {
+            let mut l = h.db.lock();
+            let mut c = l.null_camera_change(testutil::TEST_CAMERA_ID).unwrap();
+            c.streams[0].config.retain_duration_sec = 60;
+            l.update_camera(testutil::TEST_CAMERA_ID, c).unwrap();
+        }
+
+        // There's a database constraint forbidding a recording starting at t=0, so advance.
+        h.db.clocks().sleep(time::Duration::seconds(1));
+
+        // Setup: add a 3-byte recording.
+        let video_sample_entry_id =
+            h.db.lock()
+                .insert_video_sample_entry(VideoSampleEntryToInsert {
+                    width: 1920,
+                    height: 1080,
+                    pasp_h_spacing: 1,
+                    pasp_v_spacing: 1,
+                    data: [0u8; 100].to_vec(),
+                    rfc6381_codec: "avc1.000000".to_owned(),
+                })
+                .unwrap();
+        let mut w = Writer::new(
+            &h.dir,
+            &h.db,
+            &h.channel,
+            testutil::TEST_STREAM_ID,
+            video_sample_entry_id,
+            None,
+        );
+        let f = MockFile::new();
+        h.dir.expect(MockDirAction::Create(
+            CompositeId::new(1, 0),
+            Box::new({
+                let f = f.clone();
+                move |_id| Ok(f.clone())
+            }),
+        ));
+        f.expect(MockFileAction::Write(Box::new(|buf| {
+            assert_eq!(buf, b"123");
+            Ok(3)
+        })));
+        f.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+        w.write(
+            &mut h.shutdown_rx,
+            b"123",
+            recording::Time(recording::TIME_UNITS_PER_SEC),
+            0,
+            true,
+        )
+        .unwrap();
+        h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+        drop(w);
+
+        assert!(h.syncer.iter(&h.syncer_rx)); // AsyncSave
+        assert!(h.syncer.iter(&h.syncer_rx)); // planned flush
+        assert!(h.syncer.iter(&h.syncer_rx)); // DatabaseFlushed
+        f.ensure_done();
+        h.dir.ensure_done();
+        assert_eq!(
+            h.db.lock()
+                .streams_by_id()
+                .get(&testutil::TEST_STREAM_ID)
+                .unwrap()
+                .sample_file_bytes,
+            3
+        );
+
+        // Nothing has expired yet.
+        h.syncer.expire();
+        assert_eq!(
+            h.db.lock()
+                .streams_by_id()
+                .get(&testutil::TEST_STREAM_ID)
+                .unwrap()
+                .bytes_to_delete,
+            0
+        );
+
+        // Once the recording ended more than a minute ago, it should be deleted, even though it's
+        // well within the byte limit and nothing new is being recorded.
+        h.db.clocks().sleep(time::Duration::minutes(2));
+        h.dir.expect(MockDirAction::Unlink(
+            CompositeId::new(1, 0),
+            Box::new(|_| Ok(())),
+        ));
+        h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+        h.syncer.expire();
+        assert_eq!(
+            h.db.lock()
+                .streams_by_id()
+                .get(&testutil::TEST_STREAM_ID)
+                .unwrap()
+                .sample_file_bytes,
+            0
+        );
+        assert!(h.syncer.iter(&h.syncer_rx)); // DatabaseFlushed
+        h.dir.ensure_done();
+
+        // The syncer should shut down cleanly.
+        drop(h.channel);
+        h.db.lock().clear_on_flush();
+        assert_eq!(
+            h.syncer_rx.try_recv().err(),
+            Some(std::sync::mpsc::TryRecvError::Disconnected)
+        );
+        assert!(h.syncer.planned_flushes.is_empty());
+    }
+
     #[test]
     fn planned_flush() {
         testutil::init();
@@ -1599,6 +1993,7 @@ mod tests {
             &h.channel,
             testutil::TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
         let f1 = MockFile::new();
         h.dir.expect(MockDirAction::Create(
@@ -1640,6 +2035,7 @@ mod tests {
             &h.channel,
             testutil::TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
         let f2 = MockFile::new();
         h.dir.expect(MockDirAction::Create(
@@ -1695,4 +2091,229 @@ mod tests {
         );
         assert!(h.syncer.planned_flushes.is_empty());
     }
+
+    #[test]
+    fn audio() {
+        testutil::init();
+        let mut h = new_harness(0);
+        let video_sample_entry_id =
+            h.db.lock()
+                .insert_video_sample_entry(VideoSampleEntryToInsert {
+                    width: 1920,
+                    height: 1080,
+                    pasp_h_spacing: 1,
+                    pasp_v_spacing: 1,
+                    data: [0u8; 100].to_vec(),
+                    rfc6381_codec: "avc1.000000".to_owned(),
+                })
+                .unwrap();
+        let audio_sample_entry_id =
+            h.db.lock()
+                .insert_audio_sample_entry(db::AudioSampleEntryToInsert {
+                    data: [0u8; 36].to_vec(),
+                    rfc6381_codec: "mp4a.40.2".to_owned(),
+                    clock_rate: 48_000,
+                })
+                .unwrap();
+        let mut w = Writer::new(
+            &h.dir,
+            &h.db,
+            &h.channel,
+            testutil::TEST_STREAM_ID,
+            video_sample_entry_id,
+            Some(audio_sample_entry_id),
+        );
+
+        // Audio before the first video frame is discarded.
+        w.write_audio(b"xx", 0, 1024).unwrap();
+
+        let f = MockFile::new();
+        h.dir.expect(MockDirAction::Create(
+            CompositeId::new(1, 0),
+            Box::new({
+                let f = f.clone();
+                move |_id| Ok(f.clone())
+            }),
+        ));
+        f.expect(MockFileAction::Write(Box::new(|buf| {
+            assert_eq!(buf, b"1");
+            Ok(1)
+        })));
+        f.expect(MockFileAction::Write(Box::new(|buf| {
+            assert_eq!(buf, b"abcd");
+            Ok(4)
+        })));
+        f.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+        w.write(&mut h.shutdown_rx, b"1", recording::Time(1), 0, true)
+            .unwrap();
+        // A lost packet between these frames lengthens the first one's duration.
+        w.write_audio(b"ab", 1024, 1024).unwrap();
+        w.write_audio(b"cd", 3072, 1024).unwrap();
+        assert!(w.write_audio(b"", 4096, 1024).is_err());
+        h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+        w.close(Some(1), None).unwrap();
+        assert!(h.syncer.iter(&h.syncer_rx)); // AsyncSave
+        assert_eq!(h.syncer.planned_flushes.len(), 1);
+        assert!(h.syncer.iter(&h.syncer_rx)); // planned flush
+        assert_eq!(h.syncer.planned_flushes.len(), 0);
+        assert!(h.syncer.iter(&h.syncer_rx)); // DatabaseFlushed
+        f.ensure_done();
+        h.dir.ensure_done();
+
+        let mut rows = 0;
+        h.db.lock()
+            .list_recordings_by_id(testutil::TEST_STREAM_ID, 0..1, &mut |row| {
+                rows += 1;
+                assert_eq!(row.sample_file_bytes, 5);
+                assert_eq!(row.audio_samples, 2);
+                assert_eq!(row.audio_sample_file_bytes, 4);
+                assert_eq!(row.audio_sample_entry_id, Some(audio_sample_entry_id));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(rows, 1);
+        let durations =
+            h.db.lock()
+                .with_recording_playback(CompositeId::new(1, 0), &mut |p| {
+                    let mut it = recording::AudioIndexIterator::default();
+                    let mut durations = Vec::new();
+                    while it.next(p.audio_index)? {
+                        durations.push(it.duration);
+                    }
+                    Ok(durations)
+                })
+                .unwrap();
+        assert_eq!(durations, &[2048, 1024]);
+
+        // The syncer should shut down cleanly.
+        drop(h.channel);
+        h.db.lock().clear_on_flush();
+        assert_eq!(
+            h.syncer_rx.try_recv().err(),
+            Some(std::sync::mpsc::TryRecvError::Disconnected)
+        );
+        assert!(h.syncer.planned_flushes.is_empty());
+    }
+
+    /// Tests that `delete_recordings` passes over recordings protected by `event_retain_bytes`,
+    /// follows signal changes, and deletes protected recordings only when nothing else is left.
+    #[test]
+    fn delete_recordings_protects_events() {
+        testutil::init();
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        conn.execute_batch(
+            r#"
+            insert into signal_type (uuid, config)
+                             values (x'EE66270FD9C648198B339720D4CBCA6B',
+                                     '{"values": {"1": {"name": "still"},
+                                                  "2": {"name": "moving", "motion": true}}}');
+            insert into signal (id, uuid, type_uuid, config)
+                        values (1, x'1B3889C0A59F400DA24C94EBEB19CC3A',
+                                x'EE66270FD9C648198B339720D4CBCA6B',
+                                '{"cameraAssociations": {"1": "direct"}}');
+            "#,
+        )
+        .unwrap();
+        let db = db::Database::new(
+            SimulatedClocks::new(::time::Timespec::new(0, 0)),
+            conn,
+            true,
+        )
+        .unwrap();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test")
+            .tempdir()
+            .unwrap();
+        let mut l = db.lock();
+        let dir_id = l.add_sample_file_dir(tmpdir.path().to_owned()).unwrap();
+        let camera_id = l
+            .add_camera(db::CameraChange {
+                short_name: "test camera".to_owned(),
+                config: crate::json::CameraConfig::default(),
+                streams: [
+                    db::StreamChange {
+                        sample_file_dir_id: Some(dir_id),
+                        cold_sample_file_dir_id: None,
+                        config: crate::json::StreamConfig {
+                            url: Some(url::Url::parse("rtsp://test-camera/main").unwrap()),
+                            mode: crate::json::STREAM_MODE_RECORD.to_owned(),
+                            event_retain_bytes: 2 * 4096,
+                            ..Default::default()
+                        },
+                    },
+                    Default::default(),
+                    Default::default(),
+                ],
+            })
+            .unwrap();
+        assert_eq!(camera_id, 1);
+        let stream_id = l.cameras_by_id()[&camera_id].streams[0].unwrap();
+        let vse_id = l
+            .insert_video_sample_entry(VideoSampleEntryToInsert {
+                width: 1920,
+                height: 1080,
+                pasp_h_spacing: 1,
+                pasp_v_spacing: 1,
+                data: [0u8; 100].to_vec(),
+                rfc6381_codec: "avc1.000000".to_owned(),
+            })
+            .unwrap();
+
+        // Six one-minute recordings of one 4 KiB block each.
+        let minute = recording::Duration(60 * recording::TIME_UNITS_PER_SEC);
+        let half_minute = recording::Duration(30 * recording::TIME_UNITS_PER_SEC);
+        let t =
+            |min: i64| recording::Time(1430006400 * recording::TIME_UNITS_PER_SEC) + minute * min;
+        for i in 0..6 {
+            let (id, _) = l
+                .add_recording(
+                    stream_id,
+                    db::RecordingToInsert {
+                        start: t(i),
+                        wall_duration_90k: minute.0 as i32,
+                        media_duration_90k: minute.0 as i32,
+                        sample_file_bytes: 4096,
+                        video_samples: 1,
+                        video_sync_samples: 1,
+                        video_sample_entry_id: vse_id,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            l.mark_synced(id).unwrap();
+        }
+        l.flush("add recordings").unwrap();
+
+        // Queues recordings totalling just over `n` blocks and returns those which remain.
+        let delete = |l: &mut db::LockedDatabase, n: i64| {
+            let extra_bytes_needed = {
+                let s = &l.streams_by_id()[&stream_id];
+                n * 4096 - 1 - s.fs_bytes + s.config.retain_bytes
+            };
+            super::delete_recordings(l, stream_id, extra_bytes_needed, t(10)).unwrap();
+            l.flush("delete recordings").unwrap();
+            let mut remaining = Vec::new();
+            l.list_recordings_by_id(stream_id, 0..i32::max_value(), &mut |r| {
+                remaining.push(r.id.recording());
+                Ok(())
+            })
+            .unwrap();
+            remaining
+        };
+
+        // Recordings 1 and 3 overlap motion, so they're passed over.
+        l.update_signals(t(1)..t(1) + half_minute, &[1], &[2])
+            .unwrap();
+        l.update_signals(t(3) + half_minute..t(4), &[1], &[2])
+            .unwrap();
+        assert_eq!(delete(&mut l, 3), &[1, 3, 5]);
+
+        // Motion on recording 5 protects it instead of the older recording 1.
+        l.update_signals(t(5)..t(6), &[1], &[2]).unwrap();
+        assert_eq!(delete(&mut l, 1), &[3, 5]);
+
+        // With only protected recordings left, the oldest is deleted.
+        assert_eq!(delete(&mut l, 1), &[5]);
+    }
 }
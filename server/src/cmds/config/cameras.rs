@@ -2,17 +2,23 @@
 // Copyright (C) 2020 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
+use crate::onvif;
 use crate::stream::{self, Opener};
-use base::strutil::{decode_size, encode_size};
+use base::strutil::{decode_duration, decode_size, encode_duration, encode_size};
 use cursive::traits::{Boxable, Finder, Identifiable, Scrollable};
 use cursive::views::{self, ViewRef};
 use cursive::Cursive;
 use db::writer;
 use failure::{bail, format_err, Error, ResultExt};
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::sync::Arc;
+use std::time::Duration;
 use url::Url;
 
+/// How long to wait for replies to a WS-Discovery probe.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
 #[derive(Debug)]
 struct Camera {
     short_name: String,
@@ -28,8 +34,12 @@ struct Stream {
     url: String,
     record: bool,
     flush_if_sec: String,
+    event_retain: String,
+    retain_duration: String,
+    cold_after_sec: String,
     rtsp_transport: &'static str,
     sample_file_dir_id: Option<i32>,
+    cold_sample_file_dir_id: Option<i32>,
 }
 
 /// Builds a `Camera` from an active `edit_camera_dialog`. No validation.
@@ -95,17 +105,47 @@ fn get_camera(siv: &mut Cursive) -> Camera {
             .get_content()
             .as_str()
             .to_owned();
+        let event_retain = siv
+            .find_name::<views::EditView>(&format!("{}_event_retain", t.as_str()))
+            .unwrap()
+            .get_content()
+            .as_str()
+            .to_owned();
+        let retain_duration = siv
+            .find_name::<views::EditView>(&format!("{}_retain_duration", t.as_str()))
+            .unwrap()
+            .get_content()
+            .as_str()
+            .to_owned();
+        let cold_after_sec = siv
+            .find_name::<views::EditView>(&format!("{}_cold_after_sec", t.as_str()))
+            .unwrap()
+            .get_content()
+            .as_str()
+            .to_owned();
         let sample_file_dir_id = *siv
             .find_name::<views::SelectView<Option<i32>>>(&format!("{}_sample_file_dir", t.as_str()))
             .unwrap()
             .selection()
             .unwrap();
+        let cold_sample_file_dir_id = *siv
+            .find_name::<views::SelectView<Option<i32>>>(&format!(
+                "{}_cold_sample_file_dir",
+                t.as_str()
+            ))
+            .unwrap()
+            .selection()
+            .unwrap();
         camera.streams[t.index()] = Stream {
             url,
             record,
             flush_if_sec,
+            event_retain,
+            retain_duration,
+            cold_after_sec,
             rtsp_transport,
             sample_file_dir_id,
+            cold_sample_file_dir_id,
         };
     }
     log::trace!("camera is: {:#?}", &camera);
@@ -166,6 +206,7 @@ fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>) {
             stream_change.config.url = parse_url(&stream.url, &["rtsp"])?;
             stream_change.config.rtsp_transport = stream.rtsp_transport.to_owned();
             stream_change.sample_file_dir_id = stream.sample_file_dir_id;
+            stream_change.cold_sample_file_dir_id = stream.cold_sample_file_dir_id;
             stream_change.config.flush_if_sec = if stream.flush_if_sec.is_empty() {
                 0
             } else {
@@ -176,6 +217,39 @@ fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>) {
                     )
                 })?
             };
+            stream_change.config.event_retain_bytes = if stream.event_retain.is_empty() {
+                0
+            } else {
+                decode_size(&stream.event_retain).map_err(|_| {
+                    format_err!(
+                        "event_retain for {} must be a size such as 100G",
+                        type_.as_str()
+                    )
+                })?
+            };
+            stream_change.config.retain_duration_sec = if stream.retain_duration.is_empty() {
+                0
+            } else {
+                decode_duration(&stream.retain_duration)
+                    .ok()
+                    .and_then(|d| u32::try_from(d).ok())
+                    .ok_or_else(|| {
+                        format_err!(
+                            "retain_duration for {} must be a duration such as 30 days",
+                            type_.as_str()
+                        )
+                    })?
+            };
+            stream_change.config.cold_after_sec = if stream.cold_after_sec.is_empty() {
+                0
+            } else {
+                stream.cold_after_sec.parse().map_err(|_| {
+                    format_err!(
+                        "cold_after_sec for {} must be a non-negative integer",
+                        type_.as_str()
+                    )
+                })?
+            };
         }
         if let Some(id) = id {
             l.update_camera(id, change)
@@ -214,10 +288,18 @@ fn press_test_inner(url: Url, username: String, password: String) -> Result<Stri
             session_group: Default::default(),
         },
     )?;
-    Ok(format!(
-        "{}x{} video stream",
-        extra_data.entry.width, extra_data.entry.height
-    ))
+    let mut description = format!(
+        "{}x{} video stream ({})",
+        extra_data.entry.width, extra_data.entry.height, extra_data.entry.rfc6381_codec
+    );
+    if extra_data.entry.rfc6381_codec.starts_with("hvc1") {
+        // Retina doesn't support H.265 yet.
+        description.push_str(
+            "\n\nThis is H.265 video, which requires --rtsp-library=ffmpeg. \
+             Audio isn't recorded with this library.",
+        );
+    }
+    Ok(description)
 }
 
 fn press_test(siv: &mut Cursive, t: db::StreamType) {
@@ -279,6 +361,132 @@ fn press_test(siv: &mut Cursive, t: db::StreamType) {
     });
 }
 
+fn show_discovery_error(siv: &mut Cursive, e: Error) {
+    siv.add_layer(
+        views::Dialog::text(e.to_string())
+            .title("Discovery failed")
+            .dismiss_button("Back"),
+    );
+}
+
+/// Fills in the camera's ONVIF base URL and stream URLs, either by querying the camera at the
+/// given `onvif_base_url` or, if that's empty, by looking for cameras on the local network.
+fn press_discover(siv: &mut Cursive) {
+    let c = get_camera(siv);
+    match parse_url(&c.onvif_base_url, &["http", "https"]) {
+        Ok(Some(base)) => match base.join("device_service") {
+            Ok(device_url) => query_device(siv, device_url),
+            Err(e) => show_discovery_error(siv, e.into()),
+        },
+        Ok(None) => discover_devices(siv),
+        Err(e) => show_discovery_error(siv, e),
+    }
+}
+
+fn discover_devices(siv: &mut Cursive) {
+    siv.add_layer(
+        views::Dialog::text("Looking for ONVIF cameras on the local network.").title("Discovering"),
+    );
+
+    // As in press_test, do the work in a background thread and poll for its completion.
+    siv.set_fps(5);
+    let sink = siv.cb_sink().clone();
+    ::std::thread::spawn(move || {
+        let r = onvif::discover(DISCOVERY_TIMEOUT);
+        sink.send(Box::new(move |siv: &mut Cursive| {
+            siv.set_fps(0);
+            siv.pop_layer();
+            let devices = match r {
+                Err(e) => return show_discovery_error(siv, e),
+                Ok(d) if d.is_empty() => {
+                    return show_discovery_error(
+                        siv,
+                        format_err!("No ONVIF cameras responded. Try entering onvif_base_url."),
+                    )
+                }
+                Ok(d) => d,
+            };
+            siv.add_layer(
+                views::Dialog::around(
+                    views::SelectView::<Url>::new()
+                        .with_all(devices.into_iter().map(|u| (u.to_string(), u)))
+                        .on_submit(|siv, device_url: &Url| {
+                            siv.pop_layer();
+                            query_device(siv, device_url.clone());
+                        }),
+                )
+                .title("Choose camera")
+                .dismiss_button("Cancel"),
+            );
+        }))
+        .unwrap();
+    });
+}
+
+/// Queries the camera with the given device service URL for its streams.
+fn query_device(siv: &mut Cursive, device_url: Url) {
+    let c = get_camera(siv);
+    let username = c.username;
+    let password = c.password;
+    siv.add_layer(
+        views::Dialog::text(format!("Querying streams of {}.", &device_url)).title("Discovering"),
+    );
+    siv.set_fps(5);
+    let sink = siv.cb_sink().clone();
+    ::std::thread::spawn(move || {
+        let r = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(Error::from)
+            .and_then(|rt| rt.block_on(onvif::get_streams(&device_url, username, password)));
+        sink.send(Box::new(move |siv: &mut Cursive| {
+            siv.set_fps(0);
+            siv.pop_layer();
+            match r {
+                Err(e) => show_discovery_error(siv, e),
+                Ok(streams) => fill_discovered(siv, &device_url, &streams),
+            }
+        }))
+        .unwrap();
+    });
+}
+
+/// Fills in an active `edit_camera_dialog` from a camera's streams, highest resolution first.
+fn fill_discovered(siv: &mut Cursive, device_url: &Url, streams: &[onvif::ProfileStream]) {
+    // The base URL is the device service URL's "directory".
+    if let Ok(base) = device_url.join(".") {
+        siv.call_on_name("onvif_base_url", |v: &mut views::EditView| {
+            v.set_content(base.as_str().to_owned())
+        });
+    }
+    let mut summary = format!("Found {} stream(s) at {}.\n", streams.len(), device_url);
+    for (t, s) in [db::StreamType::Main, db::StreamType::Sub]
+        .iter()
+        .zip(streams)
+    {
+        siv.call_on_name(&format!("{}_url", t.as_str()), |v: &mut views::EditView| {
+            v.set_content(s.url.as_str().to_owned())
+        });
+        let test_button = siv
+            .find_name::<views::Button>(&format!("{}_test", t.as_str()))
+            .unwrap();
+        edit_url(s.url.as_str(), test_button);
+        summary.push_str(&format!(
+            "\n{} stream: {} ({}x{}) at {}",
+            t.as_str(),
+            s.name,
+            s.width,
+            s.height,
+            &s.url
+        ));
+    }
+    siv.add_layer(
+        views::Dialog::text(summary)
+            .title("Discovery succeeded")
+            .dismiss_button("Back"),
+    );
+}
+
 fn press_delete(siv: &mut Cursive, db: &Arc<db::Database>, id: i32, name: String, to_delete: i64) {
     let dialog = if to_delete > 0 {
         let prompt = format!(
@@ -415,7 +623,14 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
         .child("short name", views::EditView::new().with_name("short_name"))
         .child(
             "onvif_base_url",
-            views::EditView::new().with_name("onvif_base_url"),
+            views::LinearLayout::horizontal()
+                .child(
+                    views::EditView::new()
+                        .with_name("onvif_base_url")
+                        .full_width(),
+                )
+                .child(views::DummyView)
+                .child(views::Button::new("Discover", press_discover)),
         )
         .child("username", views::EditView::new().with_name("username"))
         .child("password", views::EditView::new().with_name("password"))
@@ -467,6 +682,13 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                     .popup()
                     .with_name(format!("{}_sample_file_dir", type_.as_str())),
             )
+            .child(
+                "cold sample file dir",
+                views::SelectView::<Option<i32>>::new()
+                    .with_all(dirs.iter().map(|(p, id)| (p.display().to_string(), *id)))
+                    .popup()
+                    .with_name(format!("{}_cold_sample_file_dir", type_.as_str())),
+            )
             .child(
                 "record",
                 views::Checkbox::new().with_name(format!("{}_record", type_.as_str())),
@@ -482,6 +704,18 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                 "flush_if_sec",
                 views::EditView::new().with_name(format!("{}_flush_if_sec", type_.as_str())),
             )
+            .child(
+                "event_retain",
+                views::EditView::new().with_name(format!("{}_event_retain", type_.as_str())),
+            )
+            .child(
+                "retain_duration",
+                views::EditView::new().with_name(format!("{}_retain_duration", type_.as_str())),
+            )
+            .child(
+                "cold_after_sec",
+                views::EditView::new().with_name(format!("{}_cold_after_sec", type_.as_str())),
+            )
             .child(
                 "usage/capacity",
                 views::TextView::new("").with_name(format!("{}_usage_cap", type_.as_str())),
@@ -506,15 +740,16 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
         for (i, sid) in camera.streams.iter().enumerate() {
             let t = db::StreamType::from_index(i).unwrap();
 
-            // Find the index into dirs of the stored sample file dir.
+            // Find the index into dirs of the stored sample file dirs.
             let mut selected_dir = 0;
+            let mut selected_cold_dir = 0;
             if let Some(s) = sid.map(|sid| l.streams_by_id().get(&sid).unwrap()) {
-                if let Some(id) = s.sample_file_dir_id {
-                    for (i, &(_, d_id)) in dirs.iter().skip(1).enumerate() {
-                        if Some(id) == d_id {
-                            selected_dir = i + 1;
-                            break;
-                        }
+                for (i, &(_, d_id)) in dirs.iter().enumerate().skip(1) {
+                    if s.sample_file_dir_id == d_id {
+                        selected_dir = i;
+                    }
+                    if s.cold_sample_file_dir_id == d_id {
+                        selected_cold_dir = i;
                     }
                 }
                 bytes += s.sample_file_bytes;
@@ -564,12 +799,40 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                     &format!("{}_flush_if_sec", t.as_str()),
                     |v: &mut views::EditView| v.set_content(s.config.flush_if_sec.to_string()),
                 );
+                dialog.call_on_name(
+                    &format!("{}_event_retain", t.as_str()),
+                    |v: &mut views::EditView| {
+                        if s.config.event_retain_bytes != 0 {
+                            v.set_content(encode_size(s.config.event_retain_bytes));
+                        }
+                    },
+                );
+                dialog.call_on_name(
+                    &format!("{}_retain_duration", t.as_str()),
+                    |v: &mut views::EditView| {
+                        if s.config.retain_duration_sec != 0 {
+                            v.set_content(encode_duration(i64::from(s.config.retain_duration_sec)));
+                        }
+                    },
+                );
+                dialog.call_on_name(
+                    &format!("{}_cold_after_sec", t.as_str()),
+                    |v: &mut views::EditView| {
+                        if s.config.cold_after_sec != 0 {
+                            v.set_content(s.config.cold_after_sec.to_string());
+                        }
+                    },
+                );
             }
             log::debug!("setting {} dir to {}", t.as_str(), selected_dir);
             dialog.call_on_name(
                 &format!("{}_sample_file_dir", t.as_str()),
                 |v: &mut views::SelectView<Option<i32>>| v.set_selection(selected_dir),
             );
+            dialog.call_on_name(
+                &format!("{}_cold_sample_file_dir", t.as_str()),
+                |v: &mut views::SelectView<Option<i32>>| v.set_selection(selected_cold_dir),
+            );
         }
         let name = camera.short_name.clone();
         for &(view_id, content) in &[
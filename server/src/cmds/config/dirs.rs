@@ -40,6 +40,7 @@ fn update_limits_inner(model: &Model) -> Result<(), Error> {
             stream_id,
             new_record: stream.record,
             new_limit: stream.retain.unwrap(),
+            new_retain_duration_sec: None,
         });
     }
     model.db.lock().update_retention(&changes)
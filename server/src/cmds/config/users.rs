@@ -2,9 +2,11 @@
 // Copyright (C) 2017 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
+use base::clock::Clocks;
 use cursive::traits::{Boxable, Identifiable};
 use cursive::views;
 use cursive::Cursive;
+use failure::{format_err, Error};
 use log::info;
 use std::sync::Arc;
 
@@ -14,7 +16,7 @@ fn get_change(
     db: &db::LockedDatabase,
     id: Option<i32>,
     pw: PasswordChange,
-) -> db::UserChange {
+) -> Result<db::UserChange, Error> {
     let mut change = match id {
         Some(id) => db.users_by_id().get(&id).unwrap().change(),
         None => db::UserChange::add_user(String::new()),
@@ -25,6 +27,17 @@ fn get_change(
         .unwrap()
         .get_content()
         .as_str();
+    let unix_uid = siv
+        .find_name::<views::EditView>("unix_uid")
+        .unwrap()
+        .get_content();
+    change.config.unix_uid = match unix_uid.trim() {
+        "" => None,
+        u => Some(
+            u.parse()
+                .map_err(|_| format_err!("invalid unix uid {:?}", u))?,
+        ),
+    };
     match pw {
         PasswordChange::Leave => {}
         PasswordChange::Set => {
@@ -46,18 +59,38 @@ fn get_change(
             "perm_update_signals",
             &mut change.permissions.update_signals,
         ),
+        (
+            "perm_update_camera_configs",
+            &mut change.permissions.update_camera_configs,
+        ),
+        ("perm_admin_users", &mut change.permissions.admin_users),
     ] {
         **b = siv.find_name::<views::Checkbox>(id).unwrap().is_checked();
         info!("{}: {}", id, **b);
     }
-    change
+    change.permissions.camera_uuids = db
+        .cameras_by_id()
+        .values()
+        .filter(|c| {
+            siv.find_name::<views::Checkbox>(&format!("camera_{}", c.uuid))
+                .map(|b| b.is_checked())
+                .unwrap_or(false)
+        })
+        .map(|c| c.uuid.to_string())
+        .collect();
+    Ok(change)
 }
 
 fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>, pw: PasswordChange) {
     let result = {
         let mut l = db.lock();
-        let c = get_change(siv, &l, id, pw);
-        l.apply_user_change(c).map(|_| ())
+        get_change(siv, &l, id, pw).and_then(|c| {
+            let req = db::auth::Request {
+                when_sec: Some(db.clocks().realtime().sec),
+                ..Default::default()
+            };
+            l.apply_user_change(req, c).map(|_| ())
+        })
     };
     if let Err(e) = result {
         siv.add_layer(
@@ -121,15 +154,24 @@ fn select_set(siv: &mut Cursive) {
 /// Adds or updates a user.
 /// (The former if `item` is None; the latter otherwise.)
 fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>) {
-    let (username, id_str, has_password, permissions);
+    let (username, id_str, unix_uid, has_password, permissions, cameras);
     let mut pw_group = views::RadioGroup::new();
     {
         let l = db.lock();
+        cameras = l
+            .cameras_by_id()
+            .values()
+            .map(|c| (c.uuid, c.short_name.clone()))
+            .collect::<Vec<_>>();
         let u = item.map(|id| l.users_by_id().get(&id).unwrap());
         username = u.map(|u| u.username.clone()).unwrap_or_default();
         id_str = item
             .map(|id| id.to_string())
             .unwrap_or_else(|| "<new>".to_string());
+        unix_uid = u
+            .and_then(|u| u.config.unix_uid)
+            .map(|uid| uid.to_string())
+            .unwrap_or_default();
         has_password = u.map(|u| u.has_password()).unwrap_or(false);
         permissions = u.map(|u| u.permissions.clone()).unwrap_or_default();
     }
@@ -140,6 +182,12 @@ fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>
             views::EditView::new()
                 .content(username.clone())
                 .with_name("username"),
+        )
+        .child(
+            "unix uid",
+            views::EditView::new()
+                .content(unix_uid)
+                .with_name("unix_uid"),
         );
     let mut layout = views::LinearLayout::vertical()
         .child(top_list)
@@ -190,6 +238,8 @@ fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>
         ("view_video", permissions.view_video),
         ("read_camera_configs", permissions.read_camera_configs),
         ("update_signals", permissions.update_signals),
+        ("update_camera_configs", permissions.update_camera_configs),
+        ("admin_users", permissions.admin_users),
     ] {
         let mut checkbox = views::Checkbox::new();
         checkbox.set_checked(*b);
@@ -197,6 +247,21 @@ fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>
     }
     layout.add_child(perms);
 
+    layout.add_child(views::DummyView);
+    layout.add_child(views::TextView::new("cameras (none checked means all)"));
+    let mut camera_list = views::ListView::new();
+    for (uuid, short_name) in &cameras {
+        let mut checkbox = views::Checkbox::new();
+        checkbox.set_checked(
+            permissions
+                .camera_uuids
+                .iter()
+                .any(|u| *u == uuid.to_string()),
+        );
+        camera_list.add_child(short_name, checkbox.with_name(format!("camera_{}", uuid)));
+    }
+    layout.add_child(camera_list);
+
     let dialog = views::Dialog::around(layout);
     let dialog = if let Some(id) = item {
         dialog
@@ -78,6 +78,8 @@ pub fn run(args: Args) -> Result<i32, Error> {
         args.domain.clone().map(String::into_bytes),
         flags,
         permissions,
+        None,
+        None,
     )?;
     let mut encoded = [0u8; 64];
     base64::encode_config_slice(&sid, base64::STANDARD_NO_PAD, &mut encoded);
@@ -0,0 +1,298 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Runtime configuration file (`moonfire-nvr run --config`).
+
+use crate::stream::RtspLibrary;
+use failure::{bail, format_err, Error, ResultExt};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Top-level configuration file object.
+///
+/// Each setting here may also be given as a command-line flag, which takes precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ConfigFile {
+    /// Directory holding the SQLite3 index database, as with `--db-dir`.
+    pub db_dir: Option<PathBuf>,
+
+    /// Directory holding user interface files, as with `--ui-dir`.
+    pub ui_dir: Option<PathBuf>,
+
+    /// The number of worker threads used by the asynchronous runtime, as with
+    /// `--worker-threads`.
+    pub worker_threads: Option<usize>,
+
+    /// RTSP library to use for fetching the cameras' video stream, as with `--rtsp-library`.
+    #[serde(default, deserialize_with = "deserialize_opt_from_str")]
+    pub rtsp_library: Option<RtspLibrary>,
+
+    /// The RTSP transport to use when none is specified in the stream's configuration, as with
+    /// `--rtsp-transport`.
+    #[serde(default, deserialize_with = "deserialize_opt_from_str")]
+    pub rtsp_transport: Option<retina::client::Transport>,
+
+    /// Trust `X-Real-IP:` and `X-Forwarded-Proto:` headers, as with `--trust-forward-hdrs`.
+    /// Binds may override this.
+    pub trust_forward_headers: Option<bool>,
+
+    /// PEM-encoded certificate chain, as with `--tls-cert`.
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM-encoded private key, as with `--tls-key`.
+    pub tls_key: Option<PathBuf>,
+
+    /// Addresses on which to serve the web interface, each with its own authentication policy.
+    /// If empty, `--http-addr` and related flags apply.
+    #[serde(default)]
+    pub binds: Vec<BindConfig>,
+
+    /// Per-stream overrides, keyed by `<camera short name>/<stream type>`, e.g.
+    /// `driveway/main`.
+    #[serde(default)]
+    pub streams: BTreeMap<String, StreamConfig>,
+}
+
+/// A single address on which to serve the web interface.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct BindConfig {
+    /// A TCP socket address such as `0.0.0.0:8080`, or `unix:<path>`.
+    pub address: BindAddr,
+
+    /// Permissions granted to requests which aren't otherwise authenticated, as with
+    /// `--allow-unauthenticated-permissions`. If absent, such requests are rejected.
+    #[serde(default)]
+    pub allow_unauthenticated_permissions: Option<crate::json::Permissions>,
+
+    /// Trust `X-Real-IP:` and `X-Forwarded-Proto:` headers, overriding the top-level setting.
+    pub trust_forward_headers: Option<bool>,
+
+    /// Serve HTTPS using the TLS certificate and key.
+    #[serde(default)]
+    pub tls: bool,
+}
+
+/// Overrides for a single stream.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct StreamConfig {
+    /// RTSP library to use for this stream.
+    #[serde(default, deserialize_with = "deserialize_opt_from_str")]
+    pub rtsp_library: Option<RtspLibrary>,
+
+    /// The RTSP transport to use when none is specified in the stream's (database)
+    /// configuration.
+    #[serde(default, deserialize_with = "deserialize_opt_from_str")]
+    pub rtsp_transport: Option<retina::client::Transport>,
+}
+
+/// Deserializes a string via `FromStr`, so that eg `rtspLibrary = "bogus"` is reported along
+/// with its position in the file.
+fn deserialize_opt_from_str<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let s = String::deserialize(d)?;
+    s.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+impl ConfigFile {
+    /// Checks constraints which can't be expressed in the types above.
+    ///
+    /// Stream keys are checked for syntax here; whether they name an existing camera can only be
+    /// checked once the database is open.
+    fn validate(&self) -> Result<(), Error> {
+        if self.worker_threads == Some(0) {
+            bail!("workerThreads must be positive");
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            bail!("tlsCert and tlsKey must be specified together");
+        }
+        for key in self.streams.keys() {
+            parse_stream_key(key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a `streams` key into a camera short name and stream type.
+pub fn parse_stream_key(key: &str) -> Result<(&str, db::StreamType), Error> {
+    let (camera, type_) = key.rsplit_once('/').ok_or_else(|| {
+        format_err!(
+            "streams key {:?} should be <camera short name>/<stream type>",
+            key
+        )
+    })?;
+    let type_ = db::StreamType::parse(type_)
+        .ok_or_else(|| format_err!("streams key {:?} has unknown stream type", key))?;
+    Ok((camera, type_))
+}
+
+/// An address on which to serve HTTP: a TCP address or a Unix domain socket path.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BindAddr {
+    Tcp(std::net::SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for BindAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                bail!("unix: requires a path");
+            }
+            return Ok(BindAddr::Unix(path.into()));
+        }
+        Ok(BindAddr::Tcp(s.parse().with_context(|_| {
+            format!("{:?} is neither a socket address nor unix:<path>", s)
+        })?))
+    }
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindAddr::Tcp(a) => write!(f, "{}", a),
+            BindAddr::Unix(p) => write!(f, "unix:{}", p.display()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddr {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Reads and parses the configuration file at `path`.
+pub fn read(path: &Path) -> Result<ConfigFile, Error> {
+    let s = std::fs::read_to_string(path)
+        .with_context(|_| format!("unable to read --config={}", path.display()))?;
+    let c: ConfigFile = toml::from_str(&s)
+        .with_context(|_| format!("unable to parse --config={}", path.display()))?;
+    c.validate()
+        .with_context(|_| format!("invalid --config={}", path.display()))?;
+    Ok(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let c: ConfigFile = toml::from_str(
+            r#"
+            dbDir = "/var/lib/moonfire-nvr/db"
+            workerThreads = 4
+            rtspLibrary = "ffmpeg"
+            rtspTransport = "udp"
+            trustForwardHeaders = true
+
+            [[binds]]
+            address = "127.0.0.1:8080"
+            trustForwardHeaders = false
+            allowUnauthenticatedPermissions = { viewVideo = true }
+
+            [[binds]]
+            address = "0.0.0.0:8443"
+            trustForwardHeaders = true
+            tls = true
+
+            [[binds]]
+            address = "unix:/var/lib/moonfire-nvr/sock"
+
+            [streams."driveway/main"]
+            rtspLibrary = "retina"
+            rtspTransport = "tcp"
+            "#,
+        )
+        .unwrap();
+        c.validate().unwrap();
+        assert_eq!(c.db_dir, Some(PathBuf::from("/var/lib/moonfire-nvr/db")));
+        assert_eq!(c.ui_dir, None);
+        assert_eq!(c.worker_threads, Some(4));
+        assert_eq!(c.rtsp_library, Some(RtspLibrary::Ffmpeg));
+        assert!(matches!(
+            c.rtsp_transport,
+            Some(retina::client::Transport::Udp)
+        ));
+        assert_eq!(c.trust_forward_headers, Some(true));
+        assert_eq!(c.binds.len(), 3);
+        assert_eq!(
+            c.binds[0].address,
+            BindAddr::Tcp("127.0.0.1:8080".parse().unwrap())
+        );
+        assert!(
+            c.binds[0]
+                .allow_unauthenticated_permissions
+                .as_ref()
+                .unwrap()
+                .view_video
+        );
+        assert_eq!(c.binds[0].trust_forward_headers, Some(false));
+        assert!(c.binds[1].allow_unauthenticated_permissions.is_none());
+        assert_eq!(c.binds[1].trust_forward_headers, Some(true));
+        assert!(c.binds[1].tls);
+        assert_eq!(
+            c.binds[2].address,
+            BindAddr::Unix("/var/lib/moonfire-nvr/sock".into())
+        );
+        assert_eq!(c.binds[2].trust_forward_headers, None);
+        let s = &c.streams["driveway/main"];
+        assert_eq!(s.rtsp_library, Some(RtspLibrary::Retina));
+        assert!(matches!(
+            s.rtsp_transport,
+            Some(retina::client::Transport::Tcp)
+        ));
+        assert_eq!(
+            parse_stream_key("driveway/main").unwrap(),
+            ("driveway", db::StreamType::Main)
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let e = toml::from_str::<ConfigFile>("[[binds]]\naddress = \"unix:\"\n").unwrap_err();
+        assert!(e.to_string().contains("unix: requires a path"), "{}", e);
+        let e = toml::from_str::<ConfigFile>("[[binds]]\naddress = \"[::]:80\"\nfoo = 1\n")
+            .unwrap_err();
+        assert!(e.to_string().contains("unknown field `foo`"), "{}", e);
+        let e = toml::from_str::<ConfigFile>("rtspLibrary = \"bogus\"\n").unwrap_err();
+        assert!(e.to_string().contains("unknown RTSP library"), "{}", e);
+        assert!(e.to_string().contains("line 1"), "{}", e);
+
+        let c: ConfigFile = toml::from_str("workerThreads = 0\n").unwrap();
+        let e = c.validate().unwrap_err();
+        assert_eq!(e.to_string(), "workerThreads must be positive");
+        let c: ConfigFile = toml::from_str("tlsCert = \"/etc/cert.pem\"\n").unwrap();
+        let e = c.validate().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "tlsCert and tlsKey must be specified together"
+        );
+        let c: ConfigFile = toml::from_str("[streams.driveway]\n").unwrap();
+        let e = c.validate().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "streams key \"driveway\" should be <camera short name>/<stream type>"
+        );
+        let c: ConfigFile = toml::from_str("[streams.\"driveway/third\"]\n").unwrap();
+        let e = c.validate().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "streams key \"driveway/third\" has unknown stream type"
+        );
+    }
+}
@@ -0,0 +1,1157 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+use crate::onvif;
+use crate::stream;
+use crate::streamer;
+use crate::tls;
+use crate::web;
+use crate::webhook;
+use base::clock::{self, Clocks};
+use config::BindAddr;
+use db::{dir, writer};
+use failure::{bail, format_err, Error, ResultExt};
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::StreamExt;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use log::error;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use structopt::StructOpt;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+mod config;
+
+#[derive(StructOpt)]
+pub struct Args {
+    /// Path to a TOML configuration file, such as /etc/moonfire-nvr.toml.
+    ///
+    /// It may hold any of the settings below; flags take precedence. Its `[[binds]]` entries
+    /// each serve the web interface on an address with its own authentication policy, replacing
+    /// --http-addr and --allow-unauthenticated-permissions. See guide/configuration.md.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Directory holding the SQLite3 index database, defaulting to /var/lib/moonfire-nvr/db.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    db_dir: Option<PathBuf>,
+
+    /// The number of worker threads used by the asynchronous runtime.
+    /// Defaults to the number of cores on the system.
+    #[structopt(long, value_name = "worker_threads")]
+    worker_threads: Option<usize>,
+
+    /// Directory holding user interface files (.html, .js, etc), defaulting to
+    /// /usr/local/lib/moonfire-nvr/ui.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    ui_dir: Option<PathBuf>,
+
+    /// Bind address for HTTP server, defaulting to 0.0.0.0:8080. This is unencrypted unless a
+    /// TLS certificate and key are specified (see --tls-cert).
+    ///
+    /// May be a Unix domain socket path prefixed with "unix:". Connecting processes are then
+    /// authenticated as the user (if any) whose Unix UID matches theirs.
+    #[structopt(long, parse(try_from_str))]
+    http_addr: Option<BindAddr>,
+
+    /// PEM-encoded certificate chain for serving HTTPS. Requires --tls-key.
+    ///
+    /// Without binds in --config, this serves HTTPS on --http-addr. With them, it serves HTTPS
+    /// only on the binds which set `tls = true`. This and --tls-key replace the `tlsCert` and
+    /// `tlsKey` settings in --config as a pair.
+    ///
+    /// This and --tls-key are reloaded on SIGHUP, eg after a certificate renewal.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM-encoded private key for the certificate in --tls-cert. Requires --tls-cert.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    tls_key: Option<PathBuf>,
+
+    /// Open the database in read-only mode and disables recording.
+    ///
+    /// Note this is incompatible with authentication, so you'll likely want to specify
+    /// --allow_unauthenticated_permissions.
+    #[structopt(long)]
+    read_only: bool,
+
+    /// Allow unauthenticated access to the web interface, with the given permissions (may be
+    /// empty). Should be a text Permissions protobuf such as "view_videos: true".
+    ///
+    /// Note that even an empty string allows some basic access that would be rejected if the
+    /// argument were omitted.
+    #[structopt(long, parse(try_from_str = protobuf::text_format::parse_from_str))]
+    allow_unauthenticated_permissions: Option<db::Permissions>,
+
+    /// Trust X-Real-IP: and X-Forwarded-Proto: headers on the incoming request.
+    ///
+    /// Set this only after ensuring your proxy server is configured to set them and that no
+    /// untrusted requests bypass the proxy server. You may want to specify
+    /// --http-addr=127.0.0.1:8080.
+    #[structopt(long)]
+    trust_forward_hdrs: bool,
+
+    /// RTSP library to use for fetching the cameras' video stream, defaulting to `retina`.
+    /// Moonfire NVR is in the process of switching from `ffmpeg` (used since
+    /// the beginning of the project) to `retina` (a pure-Rust RTSP library
+    /// developed by Moonfire NVR's author).
+    #[structopt(long, parse(try_from_str))]
+    rtsp_library: Option<crate::stream::RtspLibrary>,
+
+    /// The RTSP transport (`tcp` or `udp`) to use when none is specified in the
+    /// per-stream configuration, defaulting to `tcp`.
+    #[structopt(long)]
+    rtsp_transport: Option<retina::client::Transport>,
+}
+
+const DEFAULT_DB_DIR: &str = "/var/lib/moonfire-nvr/db";
+const DEFAULT_UI_DIR: &str = "/usr/local/lib/moonfire-nvr/ui";
+
+/// The `--http-addr` used when neither it nor binds in `--config` are specified.
+const DEFAULT_HTTP_ADDR: &str = "0.0.0.0:8080";
+
+// These are used in a hack to get the name of the current time zone (e.g. America/Los_Angeles).
+// They seem to be correct for Linux and macOS at least.
+const LOCALTIME_PATH: &str = "/etc/localtime";
+const TIMEZONE_PATH: &str = "/etc/timezone";
+const ZONEINFO_PATHS: [&str; 2] = [
+    "/usr/share/zoneinfo/",       // Linux, macOS < High Sierra
+    "/var/db/timezone/zoneinfo/", // macOS High Sierra
+];
+
+fn trim_zoneinfo(path: &str) -> &str {
+    for zp in &ZONEINFO_PATHS {
+        if let Some(p) = path.strip_prefix(zp) {
+            return p;
+        }
+    }
+    path
+}
+
+/// Attempt to resolve the timezone of the server.
+/// The Javascript running in the browser needs this to match the server's timezone calculations.
+fn resolve_zone() -> Result<String, Error> {
+    // If the environmental variable `TZ` exists, is valid UTF-8, and doesn't just reference
+    // `/etc/localtime/`, use that.
+    if let Ok(tz) = ::std::env::var("TZ") {
+        let mut p: &str = &tz;
+
+        // Strip of an initial `:` if present. Having `TZ` set in this way is a trick to avoid
+        // repeated `tzset` calls:
+        // https://blog.packagecloud.io/eng/2017/02/21/set-environment-variable-save-thousands-of-system-calls/
+        if p.starts_with(':') {
+            p = &p[1..];
+        }
+
+        p = trim_zoneinfo(p);
+
+        if !p.starts_with('/') {
+            return Ok(p.to_owned());
+        }
+        if p != LOCALTIME_PATH {
+            bail!("Unable to resolve env TZ={} to a timezone.", &tz);
+        }
+    }
+
+    // If `LOCALTIME_PATH` is a symlink, use that. On some systems, it's instead a copy of the
+    // desired timezone, which unfortunately doesn't contain its own name.
+    match ::std::fs::read_link(LOCALTIME_PATH) {
+        Ok(localtime_dest) => {
+            let localtime_dest = match localtime_dest.to_str() {
+                Some(d) => d,
+                None => bail!("{} symlink destination is invalid UTF-8", LOCALTIME_PATH),
+            };
+            let p = trim_zoneinfo(localtime_dest);
+            if p.starts_with('/') {
+                bail!(
+                    "Unable to resolve {} symlink destination {} to a timezone.",
+                    LOCALTIME_PATH,
+                    &localtime_dest
+                );
+            }
+            return Ok(p.to_owned());
+        }
+        Err(e) => {
+            use ::std::io::ErrorKind;
+            if e.kind() != ErrorKind::NotFound && e.kind() != ErrorKind::InvalidInput {
+                bail!("Unable to read {} symlink: {}", LOCALTIME_PATH, e);
+            }
+        }
+    };
+
+    // If `TIMEZONE_PATH` is a file, use its contents as the zone name, trimming whitespace.
+    match ::std::fs::read_to_string(TIMEZONE_PATH) {
+        Ok(z) => Ok(z.trim().to_owned()),
+        Err(e) => {
+            bail!(
+                "Unable to resolve timezone from TZ env, {}, or {}. Last error: {}",
+                LOCALTIME_PATH,
+                TIMEZONE_PATH,
+                e
+            );
+        }
+    }
+}
+
+struct Syncer {
+    dir: Arc<dir::SampleFileDir>,
+    channel: writer::SyncerChannel<::std::fs::File>,
+    join: thread::JoinHandle<()>,
+}
+
+/// The configuration a streamer was started with: everything `Streamer::new` reads except its
+/// rotation offset, which only spreads out the streams' rotations. A change to any of these
+/// requires restarting it; other changes (such as retention limits) take effect without a
+/// restart.
+#[derive(PartialEq)]
+struct StreamerConfig {
+    camera_short_name: String,
+    username: String,
+    password: String,
+    url: Option<url::Url>,
+    rtsp_transport: String,
+    sample_file_dir_id: i32,
+
+    /// The stream's `rtspLibrary` override from `--config`, if any.
+    rtsp_library_override: Option<crate::stream::RtspLibrary>,
+
+    /// The stream's `rtspTransport` override from `--config`, if any.
+    rtsp_transport_override: Option<retina::client::Transport>,
+}
+
+impl StreamerConfig {
+    fn new(
+        camera: &db::Camera,
+        stream: &db::Stream,
+        sample_file_dir_id: i32,
+        overrides: Option<&config::StreamConfig>,
+    ) -> Self {
+        StreamerConfig {
+            camera_short_name: camera.short_name.clone(),
+            username: camera.config.username.clone(),
+            password: camera.config.password.clone(),
+            url: stream.config.url.clone(),
+            rtsp_transport: stream.config.rtsp_transport.clone(),
+            sample_file_dir_id,
+            rtsp_library_override: overrides.and_then(|o| o.rtsp_library),
+            rtsp_transport_override: overrides.and_then(|o| o.rtsp_transport),
+        }
+    }
+}
+
+/// A running streamer's thread and the means to stop it.
+struct RunningStreamer {
+    camera_id: i32,
+    short_name: String,
+    config: StreamerConfig,
+
+    /// Dropped to stop the streamer.
+    shutdown_tx: base::shutdown::Sender,
+    join: thread::JoinHandle<()>,
+}
+
+/// The streamers and syncers of a read-write database.
+///
+/// Streamers are started, stopped, and restarted to match the database's camera and stream
+/// configuration as it changes (see `LockedDatabase::watch_camera_changes`). Syncers are started
+/// as needed and only stopped at shutdown.
+struct Streamers {
+    db: Arc<db::Database>,
+    opener: &'static dyn stream::Opener,
+    default_transport: retina::client::Transport,
+
+    /// Overrides of `opener` and `default_transport` from `--config`, keyed as described at
+    /// `config::ConfigFile::streams`. Replaced on `SIGHUP`.
+    stream_configs: BTreeMap<String, config::StreamConfig>,
+    shutdown_rx: base::shutdown::Receiver,
+    handle: tokio::runtime::Handle,
+    syncers: FnvHashMap<i32, Syncer>,
+
+    /// Running streamers by stream id.
+    running: FnvHashMap<i32, RunningStreamer>,
+    session_groups_by_camera: FnvHashMap<i32, Arc<retina::client::SessionGroup>>,
+}
+
+/// Returns the `--config` overrides for the given stream, if any.
+fn stream_overrides<'a>(
+    stream_configs: &'a BTreeMap<String, config::StreamConfig>,
+    camera: &db::Camera,
+    stream: &db::Stream,
+) -> Option<&'a config::StreamConfig> {
+    stream_configs.get(&format!("{}/{}", camera.short_name, stream.type_.as_str()))
+}
+
+impl Streamers {
+    /// Starts, stops, and restarts streamers for the given cameras (or all cameras if `None`) to
+    /// match the database.
+    ///
+    /// Streamers whose `StreamerConfig` is unchanged keep running. Blocks while streamers shut
+    /// down, so this shouldn't be called from an async context.
+    fn reconcile(&mut self, camera_ids: Option<&FnvHashSet<i32>>) -> Result<(), Error> {
+        let affected = |camera_id: i32| {
+            camera_ids
+                .map(|ids| ids.contains(&camera_id))
+                .unwrap_or(true)
+        };
+
+        // Find the desired streamers and the directories that need syncers.
+        let mut desired = FnvHashMap::default();
+        let mut dirs = FnvHashSet::default();
+        let l = self.db.lock();
+        for (&id, stream) in l.streams_by_id() {
+            if !affected(stream.camera_id) || stream.config.mode != db::json::STREAM_MODE_RECORD {
+                continue;
+            }
+            let camera = l.cameras_by_id().get(&stream.camera_id).unwrap();
+            let sample_file_dir_id = match stream.sample_file_dir_id {
+                Some(s) => s,
+                None => {
+                    warn!(
+                        "Can't record stream {} ({}/{}) because it has no sample file dir",
+                        id,
+                        camera.short_name,
+                        stream.type_.as_str()
+                    );
+                    continue;
+                }
+            };
+
+            // The cold directory (if any) also needs a syncer to collect garbage.
+            for id in std::iter::once(sample_file_dir_id).chain(stream.cold_sample_file_dir_id) {
+                if !self.syncers.contains_key(&id) {
+                    dirs.insert(id);
+                }
+            }
+            let overrides = stream_overrides(&self.stream_configs, camera, stream);
+            desired.insert(
+                id,
+                StreamerConfig::new(camera, stream, sample_file_dir_id, overrides),
+            );
+        }
+        drop(l);
+
+        // Stop the affected streamers which are unwanted or have changed. Signal them all before
+        // waiting for any.
+        let to_stop: Vec<i32> = self
+            .running
+            .iter()
+            .filter(|(id, s)| affected(s.camera_id) && desired.get(id) != Some(&s.config))
+            .map(|(&id, _)| id)
+            .collect();
+        let mut joins = Vec::with_capacity(to_stop.len());
+        for id in to_stop {
+            let s = self.running.remove(&id).unwrap();
+            info!("Stopping streamer for {}", s.short_name);
+            drop(s.shutdown_tx);
+            joins.push((s.short_name, s.join));
+        }
+        for (short_name, join) in joins {
+            if join.join().is_err() {
+                error!("Streamer for {} panicked", short_name);
+            }
+        }
+
+        // Then create syncers for new directories.
+        if !dirs.is_empty() {
+            let dir_ids: Vec<i32> = dirs.into_iter().collect();
+            self.db.lock().open_sample_file_dirs(&dir_ids)?;
+            for id in dir_ids {
+                let dir = {
+                    let l = self.db.lock();
+                    let d = l.sample_file_dirs_by_id().get(&id).unwrap();
+                    info!("Starting syncer for path {}", d.path.display());
+                    d.get()?
+                };
+                let (channel, join) =
+                    writer::start_syncer(self.db.clone(), self.shutdown_rx.clone(), id)?;
+                self.syncers.insert(id, Syncer { dir, channel, join });
+            }
+        }
+
+        // Then start up streams.
+        let l = self.db.lock();
+        let streams = l.streams_by_id().len();
+        for (i, (id, stream)) in l.streams_by_id().iter().enumerate() {
+            if !desired.contains_key(id)
+                || self.running.contains_key(id)
+                || stream.config.mode != db::json::STREAM_MODE_RECORD
+            {
+                continue;
+            }
+
+            // Use the current configuration, which may have changed since `desired` was
+            // computed. If so, another reconcile will follow.
+            let camera = l.cameras_by_id().get(&stream.camera_id).unwrap();
+            let sample_file_dir_id = match stream.sample_file_dir_id {
+                Some(s) if self.syncers.contains_key(&s) => s,
+                _ => continue,
+            };
+            let rotate_offset_sec = streamer::ROTATE_INTERVAL_SEC * i as i64 / streams as i64;
+            let syncer = self.syncers.get(&sample_file_dir_id).unwrap();
+            let session_group = self
+                .session_groups_by_camera
+                .entry(camera.id)
+                .or_default()
+                .clone();
+
+            // Each streamer has its own shutdown channel so it can be stopped individually.
+            let (shutdown_tx, shutdown_rx) = base::shutdown::channel();
+            let overrides = stream_overrides(&self.stream_configs, camera, stream);
+            let env = streamer::Environment {
+                db: &self.db,
+                opener: overrides
+                    .and_then(|c| c.rtsp_library)
+                    .map(|l| l.opener())
+                    .unwrap_or(self.opener),
+                default_transport: overrides
+                    .and_then(|c| c.rtsp_transport)
+                    .unwrap_or(self.default_transport),
+                shutdown_rx: &shutdown_rx,
+            };
+            let mut streamer = streamer::Streamer::new(
+                &env,
+                syncer.dir.clone(),
+                syncer.channel.clone(),
+                *id,
+                camera,
+                stream,
+                session_group,
+                rotate_offset_sec,
+                streamer::ROTATE_INTERVAL_SEC,
+            )?;
+            info!("Starting streamer for {}", streamer.short_name());
+            let short_name = streamer.short_name().to_owned();
+            let name = format!("s-{}", streamer.short_name());
+            let handle = self.handle.clone();
+            let join = thread::Builder::new()
+                .name(name)
+                .spawn(move || {
+                    let _enter = handle.enter();
+                    streamer.run();
+                })
+                .expect("can't create thread");
+            self.running.insert(
+                *id,
+                RunningStreamer {
+                    camera_id: camera.id,
+                    short_name,
+                    config: StreamerConfig::new(camera, stream, sample_file_dir_id, overrides),
+                    shutdown_tx,
+                    join,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Applies camera changes and replacement `--config` stream overrides until shutdown, then
+    /// stops all streamers and syncers.
+    ///
+    /// Returns the session groups, so the caller can wait for their TEARDOWN requests.
+    async fn run(
+        mut self,
+        mut changes: futures::channel::mpsc::UnboundedReceiver<i32>,
+        mut stream_configs: futures::channel::mpsc::UnboundedReceiver<
+            BTreeMap<String, config::StreamConfig>,
+        >,
+    ) -> Vec<Arc<retina::client::SessionGroup>> {
+        loop {
+            let camera_ids = tokio::select! {
+                id = changes.next() => match id {
+                    Some(id) => {
+                        // Coalesce changes which have already arrived.
+                        let mut camera_ids = FnvHashSet::default();
+                        camera_ids.insert(id);
+                        while let Ok(Some(id)) = changes.try_next() {
+                            camera_ids.insert(id);
+                        }
+                        Some(camera_ids)
+                    },
+                    None => break,
+                },
+
+                // New overrides may affect any camera.
+                Some(c) = stream_configs.next() => {
+                    self.stream_configs = c;
+                    None
+                },
+                _ = self.shutdown_rx.as_future() => break,
+            };
+            self = tokio::task::spawn_blocking(move || {
+                if let Err(e) = self.reconcile(camera_ids.as_ref()) {
+                    error!(
+                        "Unable to apply camera changes: {}",
+                        base::prettify_failure(&e)
+                    );
+                }
+                self
+            })
+            .await
+            .expect("reconcile shouldn't panic");
+        }
+
+        info!("Shutting down streamers and syncers.");
+        tokio::task::spawn_blocking(move || self.stop())
+            .await
+            .expect("stop shouldn't panic")
+    }
+
+    fn stop(mut self) -> Vec<Arc<retina::client::SessionGroup>> {
+        let streamers: Vec<_> = self.running.drain().map(|(_, s)| s).collect();
+        for s in &streamers {
+            info!("Stopping streamer for {}", s.short_name);
+        }
+        let joins: Vec<_> = streamers.into_iter().map(|s| s.join).collect();
+        for join in joins {
+            join.join().unwrap();
+        }
+
+        // The syncers shut down when all channels to them have been dropped.
+        // The database maintains one; and `self.syncers` holds one. Drop both.
+        self.db.lock().clear_on_flush();
+        for (_, s) in self.syncers.drain() {
+            drop(s.channel);
+            s.join.join().unwrap();
+        }
+        self.session_groups_by_camera
+            .drain()
+            .map(|(_, g)| g)
+            .collect()
+    }
+}
+
+pub fn run(args: Args) -> Result<i32, Error> {
+    let config = match args.config {
+        Some(ref p) => config::read(p)?,
+        None => config::ConfigFile::default(),
+    };
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(worker_threads) = args.worker_threads.or(config.worker_threads) {
+        builder.worker_threads(worker_threads);
+    }
+    let rt = builder.build()?;
+    let r = rt.block_on(async_run(args, config));
+
+    // tokio normally waits for all spawned tasks to complete, but:
+    // * in the graceful shutdown path, we wait for specific tasks with logging.
+    // * in the immediate shutdown path, we don't want to wait.
+    rt.shutdown_background();
+
+    r
+}
+
+async fn async_run(args: Args, config: config::ConfigFile) -> Result<i32, Error> {
+    let (shutdown_tx, shutdown_rx) = base::shutdown::channel();
+    let mut shutdown_tx = Some(shutdown_tx);
+
+    tokio::pin! {
+        let int = signal(SignalKind::interrupt())?;
+        let term = signal(SignalKind::terminate())?;
+        let inner = inner(args, config, shutdown_rx);
+    }
+
+    tokio::select! {
+        _ = int.recv() => {
+            info!("Received SIGINT; shutting down gracefully. \
+                   Send another SIGINT or SIGTERM to shut down immediately.");
+            shutdown_tx.take();
+        },
+        _ = term.recv() => {
+            info!("Received SIGTERM; shutting down gracefully. \
+                   Send another SIGINT or SIGTERM to shut down immediately.");
+            shutdown_tx.take();
+        },
+        result = &mut inner => return result,
+    }
+
+    tokio::select! {
+        _ = int.recv() => bail!("immediate shutdown due to second signal (SIGINT)"),
+        _ = term.recv() => bail!("immediate shutdown due to second singal (SIGTERM)"),
+        result = &mut inner => result,
+    }
+}
+
+/// Reloads camera configuration from the database, the stream overrides from `--config` (if
+/// any), and the TLS certificate and key (if any) on each `SIGHUP` until shutdown.
+///
+/// `Streamers` and the ONVIF event subscriptions pick up the camera changes via
+/// `LockedDatabase::watch_camera_changes`; `Streamers` receives the stream overrides via
+/// `stream_configs`.
+async fn reload_on_sighup(
+    db: Arc<db::Database>,
+    config_path: Option<PathBuf>,
+    stream_configs: Option<
+        futures::channel::mpsc::UnboundedSender<BTreeMap<String, config::StreamConfig>>,
+    >,
+    tls: Option<Arc<tls::CertResolver>>,
+    mut hup: Signal,
+    shutdown_rx: base::shutdown::Receiver,
+) {
+    loop {
+        tokio::select! {
+            _ = hup.recv() => {},
+            _ = shutdown_rx.as_future() => return,
+        }
+        info!("Received SIGHUP; reloading camera configuration.");
+        match db.lock().reload_camera_configs() {
+            Ok(n) => info!("Reloaded camera configuration; {} cameras changed.", n),
+            Err(e) => error!(
+                "Unable to reload camera configuration: {}",
+                base::prettify_failure(&e)
+            ),
+        }
+        if let (Some(p), Some(tx)) = (&config_path, &stream_configs) {
+            let c = config::read(p).and_then(|c| {
+                check_stream_configs(&db.lock(), &c.streams)?;
+                Ok(c.streams)
+            });
+            match c {
+                Ok(c) => {
+                    info!("Reloaded stream overrides from --config={}.", p.display());
+                    let _ = tx.unbounded_send(c);
+                }
+                Err(e) => error!(
+                    "Unable to reload stream overrides; keeping the old ones: {}",
+                    base::prettify_failure(&e)
+                ),
+            }
+        }
+        if let Some(ref tls) = tls {
+            match tls.reload() {
+                Ok(()) => info!("Reloaded TLS certificate and key."),
+                Err(e) => error!(
+                    "Unable to reload TLS certificate and key; keeping the old ones: {}",
+                    base::prettify_failure(&e)
+                ),
+            }
+        }
+    }
+}
+
+/// How often `sweep_sessions` looks for expired sessions.
+const SESSION_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Periodically revokes expired sessions until shutdown.
+///
+/// Expired sessions are also revoked when next used; this catches ones which are never used again.
+async fn sweep_sessions(db: Arc<db::Database>, shutdown_rx: base::shutdown::Receiver) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(SESSION_SWEEP_INTERVAL) => {},
+            _ = shutdown_rx.as_future() => return,
+        }
+        let req = db::auth::Request {
+            when_sec: Some(db.clocks().realtime().sec),
+            addr: None,
+            user_agent: None,
+        };
+        match db.lock().revoke_expired_sessions(req) {
+            Ok(0) => {}
+            Ok(n) => info!("Revoked {} expired sessions.", n),
+            Err(e) => error!(
+                "Unable to revoke expired sessions: {}",
+                base::prettify_failure(&e)
+            ),
+        }
+    }
+}
+
+/// Starts serving the web interface on `addr` until shutdown.
+async fn serve(
+    svc: Arc<web::Service>,
+    addr: &BindAddr,
+    tls: Option<Arc<tls::CertResolver>>,
+    shutdown_rx: &base::shutdown::Receiver,
+) -> Result<tokio::task::JoinHandle<Result<(), hyper::Error>>, Error> {
+    // Each arm has its own make_service_fn because the connection type differs.
+    Ok(match (addr, tls) {
+        (BindAddr::Tcp(a), None) => {
+            let make_svc = make_service_fn(move |conn: &AddrStream| {
+                let conn_data = web::ConnData {
+                    client_addr: Some(conn.remote_addr()),
+                    ..Default::default()
+                };
+                futures::future::ok::<_, std::convert::Infallible>(service_fn({
+                    let svc = Arc::clone(&svc);
+                    move |req| Arc::clone(&svc).serve(req, conn_data)
+                }))
+            });
+            let server = ::hyper::Server::try_bind(a)
+                .with_context(|_| format!("unable to bind {}", addr))?
+                .tcp_nodelay(true)
+                .serve(make_svc);
+            tokio::spawn(server.with_graceful_shutdown(shutdown_rx.future()))
+        }
+        (BindAddr::Tcp(a), Some(resolver)) => {
+            let make_svc = make_service_fn(
+                move |conn: &tokio_rustls::server::TlsStream<tokio::net::TcpStream>| {
+                    let conn_data = web::ConnData {
+                        client_addr: conn.get_ref().0.peer_addr().ok(),
+                        ..Default::default()
+                    };
+                    futures::future::ok::<_, std::convert::Infallible>(service_fn({
+                        let svc = Arc::clone(&svc);
+                        move |req| Arc::clone(&svc).serve(req, conn_data)
+                    }))
+                },
+            );
+            let listener = tokio::net::TcpListener::bind(a)
+                .await
+                .with_context(|_| format!("unable to bind {}", addr))?;
+            let incoming = tls::incoming(listener, tls::server_config(resolver));
+            let server = ::hyper::Server::builder(::hyper::server::accept::from_stream(incoming))
+                .serve(make_svc);
+            tokio::spawn(server.with_graceful_shutdown(shutdown_rx.future()))
+        }
+        (BindAddr::Unix(p), None) => {
+            let make_svc = make_service_fn(move |conn: &tokio::net::UnixStream| {
+                let client_unix_uid = match conn.peer_cred() {
+                    Ok(c) => Some(c.uid()),
+                    Err(e) => {
+                        warn!("Unable to get Unix socket peer credentials: {}", e);
+                        None
+                    }
+                };
+                let conn_data = web::ConnData {
+                    client_unix_uid,
+                    ..Default::default()
+                };
+                futures::future::ok::<_, std::convert::Infallible>(service_fn({
+                    let svc = Arc::clone(&svc);
+                    move |req| Arc::clone(&svc).serve(req, conn_data)
+                }))
+            });
+
+            // Remove any socket left behind by a previous run.
+            if let Ok(m) = std::fs::symlink_metadata(p) {
+                if m.file_type().is_socket() {
+                    std::fs::remove_file(p)
+                        .with_context(|_| format!("unable to remove stale {}", addr))?;
+                }
+            }
+            let listener = tokio::net::UnixListener::bind(p)
+                .with_context(|_| format!("unable to bind {}", addr))?;
+            let incoming = futures::stream::unfold(listener, |l| async move {
+                let c = l.accept().await;
+                Some((c, l))
+            })
+            .filter_map(|c| async move {
+                match c {
+                    Ok((s, _)) => Some(Ok::<_, std::io::Error>(s)),
+                    Err(e) => {
+                        // Likely out of file descriptors; back off rather than spin.
+                        warn!("Unable to accept connection: {}", e);
+                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                        None
+                    }
+                }
+            });
+            let server = ::hyper::Server::builder(::hyper::server::accept::from_stream(incoming))
+                .serve(make_svc);
+            tokio::spawn(server.with_graceful_shutdown(shutdown_rx.future()))
+        }
+        (BindAddr::Unix(_), Some(_)) => {
+            bail!(
+                "--tls-cert and --tls-key aren't supported with unix: address {}",
+                addr
+            )
+        }
+    })
+}
+
+/// An address on which to serve the web interface, with its authentication policy.
+struct Bind {
+    addr: BindAddr,
+    allow_unauthenticated_permissions: Option<db::Permissions>,
+    trust_forward_hdrs: bool,
+    tls: bool,
+}
+
+/// Returns the binds from `--config` if it has any, or else the one described by flags.
+///
+/// `trust_forward_hdrs` is the default for binds which don't specify otherwise.
+fn binds(
+    args: &Args,
+    config_binds: Vec<config::BindConfig>,
+    trust_forward_hdrs: bool,
+    have_tls: bool,
+) -> Result<Vec<Bind>, Error> {
+    if config_binds.is_empty() {
+        let addr = match args.http_addr {
+            Some(ref a) => a.clone(),
+            None => DEFAULT_HTTP_ADDR.parse().unwrap(),
+        };
+        return Ok(vec![Bind {
+            addr,
+            allow_unauthenticated_permissions: args.allow_unauthenticated_permissions.clone(),
+            trust_forward_hdrs,
+            tls: have_tls,
+        }]);
+    }
+    if args.http_addr.is_some() || args.allow_unauthenticated_permissions.is_some() {
+        bail!(
+            "--http-addr and --allow-unauthenticated-permissions can't be used with binds in \
+             --config"
+        );
+    }
+    let mut binds = Vec::with_capacity(config_binds.len());
+    for b in config_binds {
+        if b.tls && !have_tls {
+            bail!(
+                "bind {} in --config sets tls but no TLS certificate and key were specified",
+                &b.address
+            );
+        }
+        if binds.iter().any(|o: &Bind| o.addr == b.address) {
+            bail!("bind {} appears more than once in --config", &b.address);
+        }
+        binds.push(Bind {
+            addr: b.address,
+            allow_unauthenticated_permissions: b
+                .allow_unauthenticated_permissions
+                .map(crate::json::Permissions::into_db),
+            trust_forward_hdrs: b.trust_forward_headers.unwrap_or(trust_forward_hdrs),
+            tls: b.tls,
+        });
+    }
+    if have_tls && !binds.iter().any(|b| b.tls) {
+        warn!("TLS certificate and key are unused; no bind in --config sets tls");
+    }
+    Ok(binds)
+}
+
+/// Checks that each stream override in `--config` names an existing stream.
+///
+/// Cameras may be renamed or removed later; overrides which no longer match are then ignored.
+fn check_stream_configs(
+    l: &db::LockedDatabase,
+    stream_configs: &BTreeMap<String, config::StreamConfig>,
+) -> Result<(), Error> {
+    for key in stream_configs.keys() {
+        let (camera, type_) = config::parse_stream_key(key)?;
+        let c = l
+            .cameras_by_id()
+            .values()
+            .find(|c| c.short_name == camera)
+            .ok_or_else(|| format_err!("--config streams key {:?}: no such camera", key))?;
+        if c.streams[type_.index()].is_none() {
+            bail!(
+                "--config streams key {:?}: camera has no {} stream",
+                key,
+                type_.as_str()
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn inner(
+    args: Args,
+    config: config::ConfigFile,
+    shutdown_rx: base::shutdown::Receiver,
+) -> Result<i32, Error> {
+    let db_dir = args
+        .db_dir
+        .clone()
+        .or(config.db_dir)
+        .unwrap_or_else(|| DEFAULT_DB_DIR.into());
+    let ui_dir = args
+        .ui_dir
+        .clone()
+        .or(config.ui_dir)
+        .unwrap_or_else(|| DEFAULT_UI_DIR.into());
+    let clocks = clock::RealClocks {};
+    let (_db_dir, conn) = super::open_conn(
+        &db_dir,
+        if args.read_only {
+            super::OpenMode::ReadOnly
+        } else {
+            super::OpenMode::ReadWrite
+        },
+    )?;
+    let db = Arc::new(db::Database::new(clocks, conn, !args.read_only)?);
+    info!("Database is loaded.");
+
+    {
+        let mut l = db.lock();
+        let dirs_to_open: Vec<_> = l
+            .streams_by_id()
+            .values()
+            .flat_map(|s| {
+                s.sample_file_dir_id
+                    .into_iter()
+                    .chain(s.cold_sample_file_dir_id)
+            })
+            .collect();
+        l.open_sample_file_dirs(&dirs_to_open)?;
+        check_stream_configs(&l, &config.streams)?;
+    }
+    info!("Directories are opened.");
+
+    // The flags replace the file's certificate and key as a pair, so a key from one is never
+    // matched with a certificate from the other.
+    let tls_files = if args.tls_cert.is_some() || args.tls_key.is_some() {
+        (args.tls_cert.clone(), args.tls_key.clone())
+    } else {
+        (config.tls_cert, config.tls_key)
+    };
+    let tls = match tls_files {
+        (Some(cert), Some(key)) => Some(Arc::new(tls::CertResolver::new(cert, key)?)),
+        (None, None) => None,
+        _ => bail!(
+            "--tls-cert and --tls-key (or tlsCert and tlsKey in --config) must be specified \
+             together"
+        ),
+    };
+    let trust_forward_hdrs =
+        args.trust_forward_hdrs || config.trust_forward_headers.unwrap_or(false);
+    let binds = binds(&args, config.binds, trust_forward_hdrs, tls.is_some())?;
+
+    let time_zone_name = resolve_zone()?;
+    info!("Resolved timezone: {}", &time_zone_name);
+    let svc = web::Service::new(web::Config {
+        db: db.clone(),
+        ui_dir: Some(&ui_dir),
+        allow_unauthenticated_permissions: None,
+        trust_forward_hdrs: false,
+        tls: false,
+        time_zone_name,
+    })?;
+
+    // Start a streamer for each stream, and restart them as cameras or stream overrides change.
+    let mut stream_configs_tx = None;
+    let streamers = if !args.read_only {
+        let mut streamers = Streamers {
+            db: db.clone(),
+            opener: args
+                .rtsp_library
+                .or(config.rtsp_library)
+                .unwrap_or(crate::stream::RtspLibrary::Retina)
+                .opener(),
+            default_transport: args
+                .rtsp_transport
+                .or(config.rtsp_transport)
+                .unwrap_or_default(),
+            stream_configs: config.streams,
+            shutdown_rx: shutdown_rx.clone(),
+            handle: tokio::runtime::Handle::current(),
+            syncers: FnvHashMap::default(),
+            running: FnvHashMap::default(),
+            session_groups_by_camera: FnvHashMap::default(),
+        };
+        streamers.reconcile(None)?;
+        let (changes_tx, changes_rx) = futures::channel::mpsc::unbounded();
+        db.lock()
+            .watch_camera_changes(Box::new(move |id| changes_tx.unbounded_send(id).is_ok()));
+        let (tx, stream_configs_rx) = futures::channel::mpsc::unbounded();
+        stream_configs_tx = Some(tx);
+        Some(tokio::spawn(streamers.run(changes_rx, stream_configs_rx)))
+    } else {
+        None
+    };
+
+    // Reload camera configuration from the database on SIGHUP.
+    let reloader = tokio::spawn(reload_on_sighup(
+        db.clone(),
+        args.config.clone(),
+        stream_configs_tx,
+        tls.clone(),
+        signal(SignalKind::hangup())?,
+        shutdown_rx.clone(),
+    ));
+
+    // Revoke expired sessions.
+    let sweeper = if !args.read_only {
+        Some(tokio::spawn(sweep_sessions(
+            db.clone(),
+            shutdown_rx.clone(),
+        )))
+    } else {
+        None
+    };
+
+    // Start sending webhook notifications.
+    let webhooks = webhook::start(db.clone(), shutdown_rx.clone());
+
+    // Start subscribing to cameras' ONVIF events, and resubscribe as cameras change.
+    let onvif_subscriptions = onvif::start(db.clone(), shutdown_rx.clone());
+
+    // Start the web interface.
+    let mut server_handles = Vec::with_capacity(binds.len());
+    for b in binds {
+        let bind_svc = Arc::new(svc.with_policy(
+            b.allow_unauthenticated_permissions,
+            b.trust_forward_hdrs,
+            b.tls,
+        ));
+        let bind_tls = if b.tls { tls.clone() } else { None };
+        server_handles.push(serve(bind_svc, &b.addr, bind_tls, &shutdown_rx).await?);
+        info!("Listening on {}", &b.addr);
+    }
+
+    info!("Ready to serve HTTP requests");
+    let _ = shutdown_rx.as_future().await;
+
+    let session_groups = match streamers {
+        Some(s) => s.await?,
+        None => Vec::new(),
+    };
+
+    reloader.await?;
+    if let Some(s) = sweeper {
+        s.await?;
+    }
+    db.lock().clear_watches();
+
+    if let Some(w) = webhooks {
+        info!("Waiting for webhook notifier to stop.");
+        w.await?;
+    }
+
+    info!("Waiting for ONVIF event subscriptions to stop.");
+    onvif_subscriptions.await?;
+
+    info!("Waiting for HTTP requests to finish.");
+    for h in server_handles {
+        h.await??;
+    }
+
+    info!("Waiting for TEARDOWN requests to complete.");
+    for g in &session_groups {
+        if let Err(e) = g.await_teardown().await {
+            error!("{}", e);
+        }
+    }
+
+    info!("Exiting.");
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h264;
+
+    /// An opener for cameras which never answer; streamers retry until stopped.
+    struct UnreachableOpener;
+
+    impl stream::Opener for UnreachableOpener {
+        fn open(
+            &self,
+            _label: String,
+            _src: stream::Source,
+        ) -> Result<(h264::ExtraData, Box<dyn stream::Stream>), Error> {
+            bail!("camera unreachable")
+        }
+    }
+
+    fn add_camera(db: &db::Database, short_name: &str, dir_id: i32) -> i32 {
+        db.lock()
+            .add_camera(db::CameraChange {
+                short_name: short_name.to_owned(),
+                config: db::json::CameraConfig::default(),
+                streams: [
+                    db::StreamChange {
+                        sample_file_dir_id: Some(dir_id),
+                        cold_sample_file_dir_id: None,
+                        config: db::json::StreamConfig {
+                            url: Some(
+                                url::Url::parse(&format!("rtsp://{}/main", short_name)).unwrap(),
+                            ),
+                            mode: db::json::STREAM_MODE_RECORD.to_owned(),
+                            ..Default::default()
+                        },
+                    },
+                    Default::default(),
+                    Default::default(),
+                ],
+            })
+            .unwrap()
+    }
+
+    /// Changing one camera's stream URL or `--config` override restarts only that camera's
+    /// streamer.
+    #[test]
+    fn reconcile_restarts_only_changed() {
+        db::testutil::init();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test")
+            .tempdir()
+            .unwrap();
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let db = Arc::new(db::Database::new(clock::RealClocks {}, conn, true).unwrap());
+        let dir_id = db
+            .lock()
+            .add_sample_file_dir(tmpdir.path().to_owned())
+            .unwrap();
+        let a = add_camera(&db, "a", dir_id);
+        let b = add_camera(&db, "b", dir_id);
+        let stream_a = db.lock().cameras_by_id()[&a].streams[0].unwrap();
+        let stream_b = db.lock().cameras_by_id()[&b].streams[0].unwrap();
+
+        let (_shutdown_tx, shutdown_rx) = base::shutdown::channel();
+        let mut streamers = Streamers {
+            db: db.clone(),
+            opener: &UnreachableOpener,
+            default_transport: retina::client::Transport::default(),
+            stream_configs: BTreeMap::new(),
+            shutdown_rx,
+            handle: rt.handle().clone(),
+            syncers: FnvHashMap::default(),
+            running: FnvHashMap::default(),
+            session_groups_by_camera: FnvHashMap::default(),
+        };
+        streamers.reconcile(None).unwrap();
+        let thread_id = |s: &Streamers, id| s.running[&id].join.thread().id();
+        let (thread_a, thread_b) = (
+            thread_id(&streamers, stream_a),
+            thread_id(&streamers, stream_b),
+        );
+
+        let new_url = url::Url::parse("rtsp://b/new-main").unwrap();
+        {
+            let mut l = db.lock();
+            let mut c = l.null_camera_change(b).unwrap();
+            c.streams[0].config.url = Some(new_url.clone());
+            l.update_camera(b, c).unwrap();
+        }
+        let changed: FnvHashSet<i32> = std::iter::once(b).collect();
+        streamers.reconcile(Some(&changed)).unwrap();
+        assert_eq!(thread_id(&streamers, stream_a), thread_a);
+        assert_ne!(thread_id(&streamers, stream_b), thread_b);
+        assert_eq!(streamers.running[&stream_b].config.url, Some(new_url));
+
+        // Reconciling everything with no further changes leaves both alone.
+        let thread_b = thread_id(&streamers, stream_b);
+        streamers.reconcile(None).unwrap();
+        assert_eq!(thread_id(&streamers, stream_a), thread_a);
+        assert_eq!(thread_id(&streamers, stream_b), thread_b);
+
+        // So does a replaced set of overrides, as on SIGHUP, but only for the stream it changes.
+        streamers.stream_configs.insert(
+            "b/main".to_owned(),
+            config::StreamConfig {
+                rtsp_transport: Some(retina::client::Transport::Udp),
+                ..Default::default()
+            },
+        );
+        streamers.reconcile(None).unwrap();
+        assert_eq!(thread_id(&streamers, stream_a), thread_a);
+        assert_ne!(thread_id(&streamers, stream_b), thread_b);
+        assert_eq!(
+            streamers.running[&stream_b].config.rtsp_transport_override,
+            Some(retina::client::Transport::Udp)
+        );
+
+        streamers.stop();
+    }
+}
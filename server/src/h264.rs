@@ -50,7 +50,7 @@ const PIXEL_ASPECT_RATIOS: [((u16, u16), (u16, u16)); 6] = [
 ///
 /// Note that at least in the case of .mp4 muxing, we don't need to fix up the underlying SPS.
 /// SPS; PixelAspectRatioBox's definition says that it overrides the H.264-level declaration.
-fn default_pixel_aspect_ratio(width: u16, height: u16) -> (u16, u16) {
+pub fn default_pixel_aspect_ratio(width: u16, height: u16) -> (u16, u16) {
     if width >= height {
         PIXEL_ASPECT_RATIOS
             .iter()
@@ -67,7 +67,8 @@ fn default_pixel_aspect_ratio(width: u16, height: u16) -> (u16, u16) {
 }
 
 /// Decodes a H.264 Annex B byte stream into NAL units. Calls `f` for each NAL unit in the byte
-/// stream. Aborts if `f` returns error.
+/// stream. Aborts if `f` returns error. H.265's Annex B byte stream format is identical, so this
+/// is also used by the `h265` module.
 ///
 /// Note `f` is called with the encoded NAL form, not the RBSP. The NAL header byte and any
 /// emulation prevention bytes will be present.
@@ -77,7 +78,7 @@ fn default_pixel_aspect_ratio(width: u16, height: u16) -> (u16, u16) {
 ///
 /// TODO: detect invalid byte streams. For example, several 0x00s not followed by a 0x01, a stream
 /// stream not starting with 0x00 0x00 0x00 0x01, or an empty NAL unit.
-fn decode_h264_annex_b<'a, F>(mut data: &'a [u8], mut f: F) -> Result<(), Error>
+pub fn decode_h264_annex_b<'a, F>(mut data: &'a [u8], mut f: F) -> Result<(), Error>
 where
     F: FnMut(&'a [u8]) -> Result<(), Error>,
 {
@@ -172,33 +173,7 @@ impl ExtraData {
         };
 
         let mut sample_entry = Vec::with_capacity(256);
-
-        // This is a concatenation of the following boxes/classes.
-
-        // SampleEntry, ISO/IEC 14496-12 section 8.5.2.
-        let avc1_len_pos = sample_entry.len();
-        // length placeholder + type + reserved + data_reference_index = 1
-        sample_entry.extend_from_slice(b"\x00\x00\x00\x00avc1\x00\x00\x00\x00\x00\x00\x00\x01");
-
-        // VisualSampleEntry, ISO/IEC 14496-12 section 12.1.3.
-        sample_entry.extend_from_slice(&[0; 16]); // pre-defined + reserved
-        sample_entry.write_u16::<BigEndian>(width)?;
-        sample_entry.write_u16::<BigEndian>(height)?;
-        sample_entry.extend_from_slice(&[
-            0x00, 0x48, 0x00, 0x00, // horizresolution
-            0x00, 0x48, 0x00, 0x00, // vertresolution
-            0x00, 0x00, 0x00, 0x00, // reserved
-            0x00, 0x01, // frame count
-            0x00, 0x00, 0x00, 0x00, // compressorname
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x18, 0xff, 0xff, // depth + pre_defined
-        ]);
+        let avc1_len_pos = start_visual_sample_entry(&mut sample_entry, b"avc1", width, height)?;
 
         // AVCSampleEntry, ISO/IEC 14496-15 section 5.3.4.1.
         // AVCConfigurationBox, ISO/IEC 14496-15 section 5.3.4.1.
@@ -243,25 +218,13 @@ impl ExtraData {
             u32::try_from(cur_pos - avcc_len_pos)?,
         );
 
-        // PixelAspectRatioBox, ISO/IEC 14496-12 section 12.1.4.2.
-        // Write a PixelAspectRatioBox if necessary, as the sub streams can be be anamorphic.
         let pasp = sps
             .vui_parameters
             .as_ref()
             .and_then(|v| v.aspect_ratio_info.as_ref())
             .and_then(|a| a.clone().get())
             .unwrap_or_else(|| default_pixel_aspect_ratio(width, height));
-        if pasp != (1, 1) {
-            sample_entry.extend_from_slice(b"\x00\x00\x00\x10pasp"); // length + box name
-            sample_entry.write_u32::<BigEndian>(pasp.0.into())?;
-            sample_entry.write_u32::<BigEndian>(pasp.1.into())?;
-        }
-
-        let cur_pos = sample_entry.len();
-        BigEndian::write_u32(
-            &mut sample_entry[avc1_len_pos..avc1_len_pos + 4],
-            u32::try_from(cur_pos - avc1_len_pos)?,
-        );
+        finish_visual_sample_entry(&mut sample_entry, avc1_len_pos, pasp)?;
 
         let profile_idc = sample_entry[103];
         let constraint_flags = sample_entry[104];
@@ -285,9 +248,75 @@ impl ExtraData {
     }
 }
 
+/// Appends the start of a video sample entry of the given type (eg `avc1`) to `sample_entry`.
+/// Returns the position of the length placeholder, which should be passed to
+/// `finish_visual_sample_entry` after appending the codec-specific configuration box.
+pub fn start_visual_sample_entry(
+    sample_entry: &mut Vec<u8>,
+    box_type: &[u8; 4],
+    width: u16,
+    height: u16,
+) -> Result<usize, Error> {
+    // This is a concatenation of the following boxes/classes.
+
+    // SampleEntry, ISO/IEC 14496-12 section 8.5.2.
+    let len_pos = sample_entry.len();
+    sample_entry.extend_from_slice(b"\x00\x00\x00\x00"); // length placeholder
+    sample_entry.extend_from_slice(&box_type[..]);
+    // reserved + data_reference_index = 1
+    sample_entry.extend_from_slice(b"\x00\x00\x00\x00\x00\x00\x00\x01");
+
+    // VisualSampleEntry, ISO/IEC 14496-12 section 12.1.3.
+    sample_entry.extend_from_slice(&[0; 16]); // pre-defined + reserved
+    sample_entry.write_u16::<BigEndian>(width)?;
+    sample_entry.write_u16::<BigEndian>(height)?;
+    sample_entry.extend_from_slice(&[
+        0x00, 0x48, 0x00, 0x00, // horizresolution
+        0x00, 0x48, 0x00, 0x00, // vertresolution
+        0x00, 0x00, 0x00, 0x00, // reserved
+        0x00, 0x01, // frame count
+        0x00, 0x00, 0x00, 0x00, // compressorname
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x18, 0xff, 0xff, // depth + pre_defined
+    ]);
+    Ok(len_pos)
+}
+
+/// Finishes a video sample entry started by `start_visual_sample_entry`, appending a
+/// `PixelAspectRatioBox` if necessary and fixing up the length.
+pub fn finish_visual_sample_entry(
+    sample_entry: &mut Vec<u8>,
+    len_pos: usize,
+    pasp: (u16, u16),
+) -> Result<(), Error> {
+    // PixelAspectRatioBox, ISO/IEC 14496-12 section 12.1.4.2.
+    // Write a PixelAspectRatioBox if necessary, as the sub streams can be be anamorphic.
+    if pasp != (1, 1) {
+        sample_entry.extend_from_slice(b"\x00\x00\x00\x10pasp"); // length + box name
+        sample_entry.write_u32::<BigEndian>(pasp.0.into())?;
+        sample_entry.write_u32::<BigEndian>(pasp.1.into())?;
+    }
+
+    let cur_pos = sample_entry.len();
+    BigEndian::write_u32(
+        &mut sample_entry[len_pos..len_pos + 4],
+        u32::try_from(cur_pos - len_pos)?,
+    );
+    Ok(())
+}
+
 /// Transforms sample data from Annex B format to AVC format. Should be called on samples iff
 /// `ExtraData::need_transform` is true. Uses an out parameter `avc_sample` rather than a return
 /// so that memory allocations can be reused from sample to sample.
+///
+/// This is also correct for H.265, which uses the same length-prefixed sample format (ISO/IEC
+/// 14496-15 section 8.3.2).
 pub fn transform_sample_data(annexb_sample: &[u8], avc_sample: &mut Vec<u8>) -> Result<(), Error> {
     // See AVCParameterSamples, ISO/IEC 14496-15 section 5.3.2.
     avc_sample.clear();
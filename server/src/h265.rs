@@ -0,0 +1,461 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! H.265 (HEVC) decoding
+//!
+//! Like the `h264` module, this converts ffmpeg's "extra data" into an `.mp4` sample entry: an
+//! `hvc1` box holding the ISO/IEC 14496-15 section 8.3.3.1 `HEVCDecoderConfigurationRecord`.
+//! H.265's Annex B byte stream format and `.mp4` sample format are the same as H.264's, so the
+//! samples themselves are transformed with `h264::transform_sample_data`.
+
+use crate::h264;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use failure::{bail, format_err, Error};
+use std::convert::TryFrom;
+
+// See ITU-T H.265 table 7-1 - NAL unit type codes and NAL unit type classes.
+const NAL_UNIT_VPS: u8 = 32;
+const NAL_UNIT_SPS: u8 = 33;
+const NAL_UNIT_PPS: u8 = 34;
+const NAL_UNIT_PREFIX_SEI: u8 = 39;
+const NAL_UNIT_SUFFIX_SEI: u8 = 40;
+
+/// Returns the type of the given NAL unit, which must be non-empty.
+/// Unlike H.264, H.265 has a two-byte NAL header with the type in bits 1 through 6.
+fn nal_unit_type(unit: &[u8]) -> u8 {
+    (unit[0] >> 1) & 0x3F
+}
+
+/// Parses Annex B extra data, returning a tuple holding the `vps`, `sps`, and `pps` substrings.
+fn parse_annex_b_extra_data(data: &[u8]) -> Result<(&[u8], &[u8], &[u8]), Error> {
+    let mut vps = None;
+    let mut sps = None;
+    let mut pps = None;
+    h264::decode_h264_annex_b(data, |unit| {
+        if unit.len() < 2 {
+            bail!("NAL unit too short");
+        }
+        match nal_unit_type(unit) {
+            NAL_UNIT_VPS => vps = Some(unit),
+            NAL_UNIT_SPS => sps = Some(unit),
+            NAL_UNIT_PPS => pps = Some(unit),
+            NAL_UNIT_PREFIX_SEI | NAL_UNIT_SUFFIX_SEI => {} // x265 includes its settings here.
+            t => bail!("Expected VPS, SPS, and PPS; got type {}", t),
+        };
+        Ok(())
+    })?;
+    match (vps, sps, pps) {
+        (Some(v), Some(s), Some(p)) => Ok((v, s, p)),
+        _ => bail!("VPS, SPS, and PPS must be specified"),
+    }
+}
+
+/// Checks that `data` looks like a `HEVCDecoderConfigurationRecord` with a SPS.
+fn check_hvcc(data: &[u8]) -> Result<(), Error> {
+    if data.len() < 23 || data[0] != 1 {
+        bail!("Bad HEVCDecoderConfigurationRecord header");
+    }
+    let num_arrays = data[22];
+    let mut rest = &data[23..];
+    let mut have_sps = false;
+    for _ in 0..num_arrays {
+        if rest.len() < 3 {
+            bail!("HEVCDecoderConfigurationRecord truncated in array header");
+        }
+        have_sps |= rest[0] & 0x3F == NAL_UNIT_SPS;
+        let num_nalus = BigEndian::read_u16(&rest[1..3]);
+        rest = &rest[3..];
+        for _ in 0..num_nalus {
+            if rest.len() < 2 {
+                bail!("HEVCDecoderConfigurationRecord truncated in NAL unit length");
+            }
+            let len = usize::from(BigEndian::read_u16(&rest[0..2]));
+            if rest.len() < 2 + len {
+                bail!("HEVCDecoderConfigurationRecord truncated in NAL unit");
+            }
+            rest = &rest[2 + len..];
+        }
+    }
+    if !rest.is_empty() {
+        bail!(
+            "HEVCDecoderConfigurationRecord has {} trailing bytes",
+            rest.len()
+        );
+    }
+    if !have_sps {
+        bail!("HEVCDecoderConfigurationRecord has no SPS");
+    }
+    Ok(())
+}
+
+/// Reads bits from a RBSP (raw byte sequence payload, without emulation prevention bytes).
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize, // in bits.
+}
+
+impl<'a> BitReader<'a> {
+    fn read_bits(&mut self, n: u32) -> Result<u32, Error> {
+        debug_assert!(n <= 32);
+        let mut v = 0;
+        for _ in 0..n {
+            let byte = self
+                .data
+                .get(self.pos >> 3)
+                .ok_or_else(|| format_err!("SPS truncated"))?;
+            v = (v << 1) | u32::from((byte >> (7 - (self.pos & 7))) & 1);
+            self.pos += 1;
+        }
+        Ok(v)
+    }
+
+    fn skip_bits(&mut self, n: usize) -> Result<(), Error> {
+        if self.pos + n > self.data.len() << 3 {
+            bail!("SPS truncated");
+        }
+        self.pos += n;
+        Ok(())
+    }
+
+    /// Reads an unsigned Exp-Golomb-coded value, as in ITU-T H.265 section 9.2.
+    fn read_ue(&mut self) -> Result<u32, Error> {
+        let mut leading_zeros = 0;
+        while self.read_bits(1)? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                bail!("Exp-Golomb value too large");
+            }
+        }
+        Ok((1 << leading_zeros) - 1 + self.read_bits(leading_zeros)?)
+    }
+}
+
+/// The parts of a sequence parameter set (ITU-T H.265 section 7.3.2.2.1) needed to build a
+/// `HEVCDecoderConfigurationRecord`.
+#[derive(Debug)]
+struct Sps {
+    /// The general profile, tier, and level: `general_profile_space` through
+    /// `general_level_idc`, laid out as in both the SPS and the `HEVCDecoderConfigurationRecord`.
+    general_profile_tier_level: [u8; 12],
+    max_sub_layers_minus1: u8,
+    temporal_id_nesting: bool,
+    chroma_format_idc: u8,
+    bit_depth_luma_minus8: u8,
+    bit_depth_chroma_minus8: u8,
+}
+
+impl Sps {
+    /// Parses the given SPS NAL unit, including its two-byte header.
+    fn parse(nal: &[u8]) -> Result<Self, Error> {
+        let rbsp = h264_reader::rbsp::decode_nal(&nal[2..]);
+        let mut r = BitReader {
+            data: &rbsp,
+            pos: 0,
+        };
+        r.skip_bits(4)?; // sps_video_parameter_set_id
+        let max_sub_layers_minus1 = r.read_bits(3)? as u8;
+        let temporal_id_nesting = r.read_bits(1)? == 1;
+
+        // profile_tier_level(1, sps_max_sub_layers_minus1), section 7.3.3.
+        // The general portion is byte-aligned here.
+        let mut general_profile_tier_level = [0u8; 12];
+        general_profile_tier_level.copy_from_slice(
+            rbsp.get(1..13)
+                .ok_or_else(|| format_err!("SPS truncated"))?,
+        );
+        r.skip_bits(96)?;
+        let mut sub_layer_profile_present = [false; 8];
+        let mut sub_layer_level_present = [false; 8];
+        for i in 0..usize::from(max_sub_layers_minus1) {
+            sub_layer_profile_present[i] = r.read_bits(1)? == 1;
+            sub_layer_level_present[i] = r.read_bits(1)? == 1;
+        }
+        if max_sub_layers_minus1 > 0 {
+            r.skip_bits(2 * (8 - usize::from(max_sub_layers_minus1)))?; // reserved_zero_2bits
+        }
+        for i in 0..usize::from(max_sub_layers_minus1) {
+            if sub_layer_profile_present[i] {
+                r.skip_bits(88)?;
+            }
+            if sub_layer_level_present[i] {
+                r.skip_bits(8)?;
+            }
+        }
+
+        r.read_ue()?; // sps_seq_parameter_set_id
+        let chroma_format_idc = r.read_ue()?;
+        if chroma_format_idc > 3 {
+            bail!("Bad chroma_format_idc {}", chroma_format_idc);
+        }
+        if chroma_format_idc == 3 {
+            r.skip_bits(1)?; // separate_colour_plane_flag
+        }
+        r.read_ue()?; // pic_width_in_luma_samples
+        r.read_ue()?; // pic_height_in_luma_samples
+        if r.read_bits(1)? == 1 {
+            // conformance_window_flag
+            for _ in 0..4 {
+                r.read_ue()?; // conf_win_{left,right,top,bottom}_offset
+            }
+        }
+        let bit_depth_luma_minus8 = r.read_ue()?;
+        let bit_depth_chroma_minus8 = r.read_ue()?;
+        if bit_depth_luma_minus8 > 7 || bit_depth_chroma_minus8 > 7 {
+            bail!(
+                "Bad bit depths {}/{}",
+                bit_depth_luma_minus8 + 8,
+                bit_depth_chroma_minus8 + 8
+            );
+        }
+        Ok(Sps {
+            general_profile_tier_level,
+            max_sub_layers_minus1,
+            temporal_id_nesting,
+            chroma_format_idc: chroma_format_idc as u8,
+            bit_depth_luma_minus8: bit_depth_luma_minus8 as u8,
+            bit_depth_chroma_minus8: bit_depth_chroma_minus8 as u8,
+        })
+    }
+}
+
+/// Returns the RFC 6381 codec string for the given general profile, tier, and level bytes, as
+/// specified in ISO/IEC 14496-15 section E.3.
+fn rfc6381_codec(ptl: &[u8]) -> String {
+    let profile_space = ptl[0] >> 6;
+    let tier_flag = (ptl[0] >> 5) & 1;
+    let profile_idc = ptl[0] & 0x1F;
+    let compatibility_flags = BigEndian::read_u32(&ptl[1..5]).reverse_bits();
+    let level_idc = ptl[11];
+    let mut codec = format!(
+        "hvc1.{}{}.{:X}.{}{}",
+        ["", "A", "B", "C"][usize::from(profile_space)],
+        profile_idc,
+        compatibility_flags,
+        if tier_flag == 1 { 'H' } else { 'L' },
+        level_idc
+    );
+
+    // Constraint bytes, omitting trailing zero bytes.
+    let constraints = &ptl[5..11];
+    let len = constraints
+        .iter()
+        .rposition(|&b| b != 0)
+        .map(|p| p + 1)
+        .unwrap_or(0);
+    for b in &constraints[..len] {
+        codec.push_str(&format!(".{:X}", b));
+    }
+    codec
+}
+
+/// Parses H.265 "extradata" from ffmpeg. This data may be in either Annex B format or
+/// `HEVCDecoderConfigurationRecord` format.
+///
+/// The pixel aspect ratio isn't read from the SPS's VUI parameters, which are preceded by a
+/// lot of syntax irrelevant to Moonfire NVR. Instead, `h264::default_pixel_aspect_ratio` is
+/// always used.
+pub fn parse_extra_data(
+    extradata: &[u8],
+    width: u16,
+    height: u16,
+) -> Result<h264::ExtraData, Error> {
+    let mut sample_entry = Vec::with_capacity(256);
+    let hvc1_len_pos = h264::start_visual_sample_entry(&mut sample_entry, b"hvc1", width, height)?;
+
+    // HEVCSampleEntry, ISO/IEC 14496-15 section 8.4.1.1.
+    // HEVCConfigurationBox, ISO/IEC 14496-15 section 8.4.1.1.
+    let hvcc_len_pos = sample_entry.len();
+    sample_entry.extend_from_slice(b"\x00\x00\x00\x00hvcC");
+
+    let need_transform;
+    let ptl_pos = sample_entry.len() + 1;
+    if extradata.starts_with(b"\x00\x00\x00\x01") || extradata.starts_with(b"\x00\x00\x01") {
+        // ffmpeg supplied "extradata" in Annex B format.
+        need_transform = true;
+        let (vps, sps, pps) = parse_annex_b_extra_data(extradata)?;
+        let parsed = Sps::parse(sps)?;
+
+        // Create the HEVCDecoderConfigurationRecord, ISO/IEC 14496-15 section 8.3.3.1.2.
+        sample_entry.push(1); // configurationVersion
+        sample_entry.extend_from_slice(&parsed.general_profile_tier_level);
+        sample_entry.extend_from_slice(&[
+            0xf0,
+            0x00, // reserved + min_spatial_segmentation_idc = 0 (unknown)
+            0xfc, // reserved + parallelismType = 0 (unknown)
+            0xfc | parsed.chroma_format_idc,
+            0xf8 | parsed.bit_depth_luma_minus8,
+            0xf8 | parsed.bit_depth_chroma_minus8,
+            0x00,
+            0x00, // avgFrameRate = 0 (unspecified)
+        ]);
+
+        // constantFrameRate = 0 (unknown) + numTemporalLayers + temporalIdNested +
+        // lengthSizeMinusOne = 3, matching transform_sample_data's 4-byte lengths.
+        sample_entry.push(
+            ((parsed.max_sub_layers_minus1 + 1) << 3)
+                | (u8::from(parsed.temporal_id_nesting) << 2)
+                | 3,
+        );
+
+        // Only support one of each parameter set, as in the h264 module.
+        sample_entry.push(3); // numOfArrays
+        for (t, nal) in &[
+            (NAL_UNIT_VPS, vps),
+            (NAL_UNIT_SPS, sps),
+            (NAL_UNIT_PPS, pps),
+        ] {
+            sample_entry.push(0x80 | t); // array_completeness = 1 + reserved + NAL_unit_type
+            sample_entry.write_u16::<BigEndian>(1)?; // numNalus
+            sample_entry.write_u16::<BigEndian>(u16::try_from(nal.len())?)?;
+            sample_entry.extend_from_slice(nal);
+        }
+    } else {
+        // Assume "extradata" holds a HEVCDecoderConfigurationRecord.
+        need_transform = false;
+        check_hvcc(extradata)?;
+        sample_entry.extend_from_slice(extradata);
+    }
+
+    // Fix up hvcC box length.
+    let cur_pos = sample_entry.len();
+    BigEndian::write_u32(
+        &mut sample_entry[hvcc_len_pos..hvcc_len_pos + 4],
+        u32::try_from(cur_pos - hvcc_len_pos)?,
+    );
+
+    let pasp = h264::default_pixel_aspect_ratio(width, height);
+    h264::finish_visual_sample_entry(&mut sample_entry, hvc1_len_pos, pasp)?;
+
+    let rfc6381_codec = rfc6381_codec(&sample_entry[ptl_pos..ptl_pos + 12]);
+    Ok(h264::ExtraData {
+        entry: db::VideoSampleEntryToInsert {
+            data: sample_entry,
+            rfc6381_codec,
+            width,
+            height,
+            pasp_h_spacing: pasp.0,
+            pasp_v_spacing: pasp.1,
+        },
+        need_transform,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil;
+
+    #[rustfmt::skip]
+    const ANNEX_B_TEST_INPUT: [u8; 79] = [
+        0x00, 0x00, 0x00, 0x01, 0x40, 0x01, 0x0c, 0x01,
+        0xff, 0xff, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00,
+        0x90, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00,
+        0x5d, 0x95, 0x98, 0x09, 0x00, 0x00, 0x00, 0x01,
+        0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03,
+        0x00, 0x90, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03,
+        0x00, 0x5d, 0xa0, 0x02, 0x80, 0x80, 0x2d, 0x16,
+        0x59, 0x59, 0xa4, 0x93, 0x2b, 0xc0, 0x5a, 0x70,
+        0x80, 0x80, 0x80, 0x82, 0x00, 0x00, 0x00, 0x01,
+        0x44, 0x01, 0xc1, 0x72, 0xb4, 0x62, 0x40,
+    ];
+
+    #[rustfmt::skip]
+    const HVCC_TEST_INPUT: [u8; 105] = [
+        0x01, 0x01, 0x60, 0x00, 0x00, 0x00, 0x90, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x5d, 0xf0, 0x00, 0xfc,
+        0xfd, 0xf8, 0xf8, 0x00, 0x00, 0x0f, 0x03, 0xa0,
+        0x00, 0x01, 0x00, 0x18, 0x40, 0x01, 0x0c, 0x01,
+        0xff, 0xff, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00,
+        0x90, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00,
+        0x5d, 0x95, 0x98, 0x09, 0xa1, 0x00, 0x01, 0x00,
+        0x24, 0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00,
+        0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00, 0x00,
+        0x03, 0x00, 0x5d, 0xa0, 0x02, 0x80, 0x80, 0x2d,
+        0x16, 0x59, 0x59, 0xa4, 0x93, 0x2b, 0xc0, 0x5a,
+        0x70, 0x80, 0x80, 0x80, 0x82, 0xa2, 0x00, 0x01,
+        0x00, 0x07, 0x44, 0x01, 0xc1, 0x72, 0xb4, 0x62,
+        0x40,
+    ];
+
+    #[rustfmt::skip]
+    const TEST_OUTPUT: [u8; 199] = [
+        0x00, 0x00, 0x00, 0xc7, 0x68, 0x76, 0x63, 0x31,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x05, 0x00, 0x02, 0xd0, 0x00, 0x48, 0x00, 0x00,
+        0x00, 0x48, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x18, 0xff, 0xff, 0x00, 0x00,
+        0x00, 0x71, 0x68, 0x76, 0x63, 0x43, 0x01, 0x01,
+        0x60, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x5d, 0xf0, 0x00, 0xfc, 0xfd, 0xf8,
+        0xf8, 0x00, 0x00, 0x0f, 0x03, 0xa0, 0x00, 0x01,
+        0x00, 0x18, 0x40, 0x01, 0x0c, 0x01, 0xff, 0xff,
+        0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00,
+        0x00, 0x03, 0x00, 0x00, 0x03, 0x00, 0x5d, 0x95,
+        0x98, 0x09, 0xa1, 0x00, 0x01, 0x00, 0x24, 0x42,
+        0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00,
+        0x90, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00,
+        0x5d, 0xa0, 0x02, 0x80, 0x80, 0x2d, 0x16, 0x59,
+        0x59, 0xa4, 0x93, 0x2b, 0xc0, 0x5a, 0x70, 0x80,
+        0x80, 0x80, 0x82, 0xa2, 0x00, 0x01, 0x00, 0x07,
+        0x44, 0x01, 0xc1, 0x72, 0xb4, 0x62, 0x40,
+    ];
+
+    #[test]
+    fn test_sample_entry_from_hvcc() {
+        testutil::init();
+        let e = super::parse_extra_data(&HVCC_TEST_INPUT, 1280, 720).unwrap();
+        assert_eq!(&e.entry.data[..], &TEST_OUTPUT[..]);
+        assert_eq!(e.entry.width, 1280);
+        assert_eq!(e.entry.height, 720);
+        assert_eq!(e.entry.rfc6381_codec, "hvc1.1.6.L93.90");
+        assert_eq!(e.need_transform, false);
+    }
+
+    #[test]
+    fn test_sample_entry_from_annex_b() {
+        testutil::init();
+        let e = super::parse_extra_data(&ANNEX_B_TEST_INPUT, 1280, 720).unwrap();
+        assert_eq!(&e.entry.data[..], &TEST_OUTPUT[..]);
+        assert_eq!(e.entry.rfc6381_codec, "hvc1.1.6.L93.90");
+        assert_eq!(e.need_transform, true);
+    }
+
+    #[test]
+    fn test_rejects_avcc() {
+        testutil::init();
+        #[rustfmt::skip]
+        const AVC_DECODER_CONFIG: [u8; 38] = [
+            0x01, 0x4d, 0x00, 0x1f, 0xff, 0xe1, 0x00, 0x17,
+            0x67, 0x4d, 0x00, 0x1f, 0x9a, 0x66, 0x02, 0x80,
+            0x2d, 0xff, 0x35, 0x01, 0x01, 0x01, 0x40, 0x00,
+            0x00, 0xfa, 0x00, 0x00, 0x1d, 0x4c, 0x01, 0x01,
+            0x00, 0x04, 0x68, 0xee, 0x3c, 0x80,
+        ];
+        super::parse_extra_data(&AVC_DECODER_CONFIG, 1280, 720).unwrap_err();
+    }
+
+    #[test]
+    fn rfc6381_codec() {
+        // An example from ISO/IEC 14496-15 section E.5.
+        #[rustfmt::skip]
+        let ptl = [
+            0x01, 0x60, 0x00, 0x00, 0x00, 0xb0, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x5d,
+        ];
+        assert_eq!(super::rfc6381_codec(&ptl), "hvc1.1.6.L93.B0");
+
+        // Non-zero profile space, high tier, and no constraint flags.
+        #[rustfmt::skip]
+        let ptl = [
+            0x62, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x78,
+        ];
+        assert_eq!(super::rfc6381_codec(&ptl), "hvc1.A2.20000000.H120");
+    }
+}
@@ -7,6 +7,7 @@ use db::auth::SessionHash;
 use failure::{format_err, Error};
 use serde::ser::{Error as _, SerializeMap, SerializeSeq, Serializer};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::ops::Not;
 use uuid::Uuid;
 
@@ -18,15 +19,16 @@ pub struct TopLevel<'a> {
     pub server_version: &'static str,
 
     // Use a custom serializer which presents the map's values as a sequence and includes the
-    // "days" and "camera_configs" attributes or not, according to the respective bools.
+    // "days" and "camera_configs" attributes or not, according to the respective bools. Cameras
+    // the permissions don't allow are omitted.
     #[serde(serialize_with = "TopLevel::serialize_cameras")]
-    pub cameras: (&'a db::LockedDatabase, bool, bool),
+    pub cameras: (&'a db::LockedDatabase, bool, bool, &'a db::Permissions),
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<ToplevelUser>,
 
     #[serde(serialize_with = "TopLevel::serialize_signals")]
-    pub signals: (&'a db::LockedDatabase, bool),
+    pub signals: (&'a db::LockedDatabase, bool, &'a db::Permissions),
 
     #[serde(serialize_with = "TopLevel::serialize_signal_types")]
     pub signal_types: &'a db::LockedDatabase,
@@ -77,6 +79,7 @@ pub struct Stream<'a> {
     pub total_sample_file_bytes: i64,
     pub fs_bytes: i64,
     pub record: bool,
+    pub status: StreamStatus<'a>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(serialize_with = "Stream::serialize_days")]
@@ -84,6 +87,48 @@ pub struct Stream<'a> {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub config: Option<&'a db::json::StreamConfig>,
+
+    /// The sample file directories; included along with `config`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_file_dir_id: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cold_sample_file_dir_id: Option<i32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamStatus<'a> {
+    pub state: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<&'a str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_frame_time_90k: Option<Time>,
+
+    pub fps: f32,
+    pub bitrate_bps: u64,
+}
+
+impl<'a> StreamStatus<'a> {
+    pub fn wrap(s: &'a db::StreamStatus) -> Self {
+        StreamStatus {
+            state: s.state.as_str(),
+            last_error: s.last_error.as_deref(),
+            last_frame_time_90k: s.last_frame,
+            fps: s.fps,
+            bitrate_bps: s.bitrate_bps,
+        }
+    }
+}
+
+/// A message on the `/api/status/live` WebSocket.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamStatusUpdate<'a> {
+    pub stream_id: i32,
+    pub status: StreamStatus<'a>,
 }
 
 #[derive(Serialize)]
@@ -91,7 +136,7 @@ pub struct Stream<'a> {
 pub struct Signal<'a> {
     pub id: u32,
     #[serde(serialize_with = "Signal::serialize_cameras")]
-    pub cameras: (&'a db::Signal, &'a db::LockedDatabase),
+    pub cameras: (&'a db::Signal, &'a db::LockedDatabase, &'a db::Permissions),
     pub uuid: Uuid,
     pub type_: Uuid,
     pub short_name: &'a str,
@@ -101,6 +146,33 @@ pub struct Signal<'a> {
     pub days: Option<&'a db::days::Map<db::days::SignalValue>>,
 }
 
+/// The body of a webhook notification; see `db::json::WebhookConfig`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookNotification<'a> {
+    pub signal_id: u32,
+    pub signal_short_name: &'a str,
+    pub state: u16,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_name: Option<&'a str>,
+
+    pub start_time_90k: Time,
+    pub end_time_90k: Time,
+    pub cameras: Vec<WebhookCamera<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookCamera<'a> {
+    pub uuid: Uuid,
+    pub short_name: &'a str,
+    pub association: &'a str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub view_url: Option<String>,
+}
+
 #[derive(Deserialize)]
 #[serde(tag = "base", content = "rel90k", rename_all = "camelCase")]
 pub enum PostSignalsTimeBase {
@@ -121,6 +193,12 @@ pub struct LogoutRequest<'a> {
     pub csrf: &'a str,
 }
 
+/// The `csrf` member of a mutating request's JSON body, alongside its other members.
+#[derive(Deserialize)]
+pub struct CsrfToken {
+    pub csrf: Option<String>,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PostSignalsRequest {
@@ -144,6 +222,16 @@ pub struct Signals {
     pub states: Vec<u16>,
 }
 
+/// A message on the `/api/signals/live` WebSocket.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignalsUpdate<'a> {
+    pub start_time_90k: Time,
+    pub end_time_90k: Time,
+    pub signal_ids: &'a [u32],
+    pub states: &'a [u16],
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SignalType<'a> {
@@ -233,11 +321,14 @@ impl<'a> Stream<'a> {
             total_sample_file_bytes: s.sample_file_bytes,
             fs_bytes: s.fs_bytes,
             record: s.config.mode == db::json::STREAM_MODE_RECORD,
+            status: StreamStatus::wrap(&s.status),
             days: if include_days { Some(s.days()) } else { None },
             config: match include_config {
                 false => None,
                 true => Some(&s.config),
             },
+            sample_file_dir_id: s.sample_file_dir_id.filter(|_| include_config),
+            cold_sample_file_dir_id: s.cold_sample_file_dir_id.filter(|_| include_config),
         }))
     }
 
@@ -267,10 +358,15 @@ impl<'a> Stream<'a> {
 }
 
 impl<'a> Signal<'a> {
-    pub fn wrap(s: &'a db::Signal, db: &'a db::LockedDatabase, include_days: bool) -> Self {
+    pub fn wrap(
+        s: &'a db::Signal,
+        db: &'a db::LockedDatabase,
+        include_days: bool,
+        permissions: &'a db::Permissions,
+    ) -> Self {
         Signal {
             id: s.id,
-            cameras: (s, db),
+            cameras: (s, db, permissions),
             uuid: s.uuid,
             type_: s.type_,
             short_name: &s.config.short_name,
@@ -278,19 +374,23 @@ impl<'a> Signal<'a> {
         }
     }
 
+    /// Serializes the signal's camera associations, omitting cameras the permissions don't allow.
     fn serialize_cameras<S>(
-        cameras: &(&db::Signal, &db::LockedDatabase),
+        cameras: &(&db::Signal, &db::LockedDatabase, &db::Permissions),
         serializer: S,
     ) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let (s, db) = cameras;
-        let mut map = serializer.serialize_map(Some(s.config.camera_associations.len()))?;
+        let (s, db, permissions) = cameras;
+        let mut map = serializer.serialize_map(None)?;
         for (camera_id, association) in &s.config.camera_associations {
             let c = db.cameras_by_id().get(camera_id).ok_or_else(|| {
                 S::Error::custom(format!("signal has missing camera id {}", camera_id))
             })?;
+            if !permissions.may_access_camera(c.uuid) {
+                continue;
+            }
             map.serialize_key(&c.uuid)?;
             map.serialize_value(association.as_str())?;
         }
@@ -373,16 +473,19 @@ impl<'a> TopLevel<'a> {
     /// Serializes cameras as a list (rather than a map), optionally including the `days` and
     /// `cameras` fields.
     fn serialize_cameras<S>(
-        cameras: &(&db::LockedDatabase, bool, bool),
+        cameras: &(&db::LockedDatabase, bool, bool, &db::Permissions),
         serializer: S,
     ) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let (db, include_days, include_config) = *cameras;
+        let (db, include_days, include_config, permissions) = *cameras;
         let cs = db.cameras_by_id();
-        let mut seq = serializer.serialize_seq(Some(cs.len()))?;
-        for c in cs.values() {
+        let mut seq = serializer.serialize_seq(None)?;
+        for c in cs
+            .values()
+            .filter(|c| permissions.may_access_camera(c.uuid))
+        {
             seq.serialize_element(
                 &Camera::wrap(c, db, include_days, include_config).map_err(S::Error::custom)?,
             )?;
@@ -392,17 +495,17 @@ impl<'a> TopLevel<'a> {
 
     /// Serializes signals as a list (rather than a map), optionally including the `days` field.
     fn serialize_signals<S>(
-        signals: &(&db::LockedDatabase, bool),
+        signals: &(&db::LockedDatabase, bool, &db::Permissions),
         serializer: S,
     ) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let (db, include_days) = *signals;
+        let (db, include_days, permissions) = *signals;
         let ss = db.signals_by_id();
-        let mut seq = serializer.serialize_seq(Some(ss.len()))?;
-        for s in ss.values() {
-            seq.serialize_element(&Signal::wrap(s, db, include_days))?;
+        let mut seq = serializer.serialize_seq(None)?;
+        for s in ss.values().filter(|s| db.may_access_signal(permissions, s)) {
+            seq.serialize_element(&Signal::wrap(s, db, include_days, permissions))?;
         }
         seq.end()
     }
@@ -502,6 +605,47 @@ impl VideoSampleEntry {
     }
 }
 
+/// The `manifest.json` within an `/api/export.zip` archive. See `design/api.md` for details.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportManifest {
+    pub server_version: &'static str,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub files: Vec<ExportFile>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportFile {
+    pub filename: String,
+    pub camera_uuid: Uuid,
+    pub camera_short_name: String,
+    pub stream_type: &'static str,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+
+    /// The hex-encoded BLAKE3 hash of the `.mp4` file, filled in as it's written to the archive.
+    pub blake3: Option<String>,
+    pub recordings: Vec<ExportRecording>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportRecording {
+    pub id: i32,
+    pub open_id: u32,
+
+    /// The start and end of the portion of this recording included in the `.mp4` file.
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+
+    /// The hex-encoded BLAKE3 hash of the entire sample file, as recorded in the database's
+    /// `recording_integrity` table, if available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_file_blake3: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ToplevelUser {
@@ -521,5 +665,456 @@ pub struct PostUser {
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserSubset {
+    pub username: Option<String>,
     pub preferences: Option<db::json::UserPreferences>,
+
+    /// The new password, or `null` to clear it. Only valid in updates.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub password: Option<Option<String>>,
+
+    pub permissions: Option<Permissions>,
+    pub disabled: Option<bool>,
+}
+
+/// Deserializes a present field as `Some`, so that `Option<Option<T>>` can distinguish an absent
+/// field (`None`) from a `null` one (`Some(None)`).
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Deserialize::deserialize(deserializer).map(Some)
+}
+
+/// Deserializes a present duration as in `db::json::StreamConfig::retain_duration_sec`: either
+/// seconds or a human-readable string such as `"30 days"`.
+fn deserialize_some_duration<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    db::json::human_duration::deserialize(deserializer).map(Some)
+}
+
+/// The JSON form of `db::Permissions`.
+#[derive(Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Permissions {
+    #[serde(default)]
+    pub view_video: bool,
+
+    #[serde(default)]
+    pub read_camera_configs: bool,
+
+    #[serde(default)]
+    pub update_signals: bool,
+
+    #[serde(default)]
+    pub update_camera_configs: bool,
+
+    #[serde(default)]
+    pub admin_users: bool,
+
+    /// If non-empty, the cameras to which access is restricted.
+    #[serde(default)]
+    pub camera_uuids: Vec<Uuid>,
+}
+
+impl From<&db::Permissions> for Permissions {
+    fn from(p: &db::Permissions) -> Self {
+        Permissions {
+            view_video: p.view_video,
+            read_camera_configs: p.read_camera_configs,
+            update_signals: p.update_signals,
+            update_camera_configs: p.update_camera_configs,
+            admin_users: p.admin_users,
+            camera_uuids: p
+                .camera_uuids
+                .iter()
+                .filter_map(|u| Uuid::parse_str(u).ok())
+                .collect(),
+        }
+    }
+}
+
+impl Permissions {
+    pub fn into_db(self) -> db::Permissions {
+        let mut p = db::Permissions::new();
+        p.view_video = self.view_video;
+        p.read_camera_configs = self.read_camera_configs;
+        p.update_signals = self.update_signals;
+        p.update_camera_configs = self.update_camera_configs;
+        p.admin_users = self.admin_users;
+        p.camera_uuids = self.camera_uuids.iter().map(Uuid::to_string).collect();
+        p
+    }
+}
+
+/// A user, as returned by `GET /api/users` and `GET /api/users/<id>`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct User<'a> {
+    pub id: i32,
+    pub username: &'a str,
+    pub preferences: &'a db::json::UserPreferences,
+    pub permissions: Permissions,
+    pub disabled: bool,
+    pub has_password: bool,
+
+    /// The number of consecutive failed password logins; see `POST /api/users/<id>/unlock`.
+    pub password_failure_count: i64,
+}
+
+impl<'a> User<'a> {
+    pub fn wrap(u: &'a db::User) -> Self {
+        User {
+            id: u.id,
+            username: &u.username,
+            preferences: &u.config.preferences,
+            permissions: (&u.permissions).into(),
+            disabled: u.config.disabled,
+            has_password: u.has_password(),
+            password_failure_count: u.password_failure_count,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetUsersResponse<'a> {
+    pub users: Vec<User<'a>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostUsers {
+    pub user: UserSubset,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostUsersResponse {
+    pub id: i32,
+}
+
+/// A session, as returned by `GET /api/users/<id>/sessions`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSession<'a> {
+    /// The base64-encoded session hash; not usable as a credential.
+    pub id: String,
+    pub description: Option<&'a str>,
+    pub creation: SessionRequest,
+    pub expiration_sec: Option<i64>,
+    pub last_use: SessionRequest,
+    pub use_count: i32,
+
+    /// True iff this is the session making the request.
+    pub current: bool,
+}
+
+impl<'a> UserSession<'a> {
+    pub fn wrap(hash: &SessionHash, s: &'a db::Session, current: bool) -> Self {
+        UserSession {
+            id: session_hash_id(hash),
+            description: s.description.as_deref(),
+            creation: SessionRequest::wrap(&s.creation),
+            expiration_sec: s.expiration_sec,
+            last_use: SessionRequest::wrap(&s.last_use),
+            use_count: s.use_count,
+            current,
+        }
+    }
+}
+
+/// Encodes a session hash as the `id` used in `/api/users/<id>/sessions` and friends.
+fn session_hash_id(hash: &SessionHash) -> String {
+    let mut id = [0u8; 32];
+    hash.encode_base64(&mut id);
+    String::from_utf8(id.to_vec()).expect("base64 is UTF-8")
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionRequest {
+    pub when_sec: Option<i64>,
+    pub user_agent: Option<String>,
+    pub addr: Option<std::net::IpAddr>,
+}
+
+impl SessionRequest {
+    fn wrap(r: &db::Request) -> Self {
+        SessionRequest {
+            when_sec: r.when_sec,
+            user_agent: r
+                .user_agent
+                .as_ref()
+                .map(|ua| String::from_utf8_lossy(ua).into_owned()),
+            addr: r.addr,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetUserSessionsResponse<'a> {
+    pub sessions: Vec<UserSession<'a>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostUserTokens {
+    pub description: Option<String>,
+    pub expiration_sec: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostUserTokensResponse {
+    /// The bearer token itself. Sensitive.
+    pub token: String,
+
+    /// The session id, as in `GET /api/users/<id>/sessions`.
+    pub id: String,
+}
+
+impl PostUserTokensResponse {
+    pub fn wrap(sid: &db::RawSessionId) -> Self {
+        PostUserTokensResponse {
+            token: base64::encode_config(sid, base64::STANDARD_NO_PAD),
+            id: session_hash_id(&sid.hash()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostUserSessionsRevoke<'a> {
+    /// The `id` of a session from `GET /api/users/<id>/sessions`.
+    pub id: &'a str,
+}
+
+/// A camera's desired configuration, as in `POST /api/cameras` or the `update` of
+/// `PUT /api/cameras/<uuid>/`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CameraChange {
+    pub short_name: String,
+
+    #[serde(default)]
+    pub config: db::json::CameraConfig,
+
+    /// Streams by type (`main`, `sub`, or `ext`). An omitted type means no stream.
+    #[serde(default)]
+    pub streams: BTreeMap<String, StreamChange>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamChange {
+    pub sample_file_dir_id: Option<i32>,
+    pub cold_sample_file_dir_id: Option<i32>,
+
+    #[serde(default)]
+    pub config: db::json::StreamConfig,
+}
+
+impl CameraChange {
+    pub fn into_db(self) -> Result<db::CameraChange, Error> {
+        let mut change = db::CameraChange {
+            short_name: self.short_name,
+            config: self.config,
+            streams: Default::default(),
+        };
+        for (type_, s) in self.streams {
+            let type_ = db::StreamType::parse(&type_)
+                .ok_or_else(|| format_err!("unknown stream type {:?}", type_))?;
+            change.streams[type_.index()] = db::StreamChange {
+                sample_file_dir_id: s.sample_file_dir_id,
+                cold_sample_file_dir_id: s.cold_sample_file_dir_id,
+                config: s.config,
+            };
+        }
+        Ok(change)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostCamerasResponse {
+    pub id: i32,
+    pub uuid: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PutCamera {
+    pub update: CameraUpdate,
+    pub precondition: Option<CameraSubset>,
+}
+
+/// Changes to a camera, as in the `update` of `PUT /api/cameras/<uuid>/`. Omitted fields are
+/// left unchanged.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct CameraUpdate {
+    pub short_name: Option<String>,
+    pub config: Option<CameraConfigUpdate>,
+
+    /// Streams by type. An omitted type is left unchanged; `null` removes the stream.
+    #[serde(default)]
+    pub streams: BTreeMap<String, Option<StreamUpdate>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct CameraConfigUpdate {
+    pub description: Option<String>,
+
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub onvif_base_url: Option<Option<url::Url>>,
+
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Changes to a stream. Applied to a stream which doesn't exist yet, omitted fields take their
+/// defaults.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct StreamUpdate {
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub sample_file_dir_id: Option<Option<i32>>,
+
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub cold_sample_file_dir_id: Option<Option<i32>>,
+
+    pub config: Option<StreamConfigUpdate>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct StreamConfigUpdate {
+    pub mode: Option<String>,
+
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub url: Option<Option<url::Url>>,
+
+    pub rtsp_transport: Option<String>,
+    pub retain_bytes: Option<i64>,
+    pub event_retain_bytes: Option<i64>,
+
+    #[serde(default, deserialize_with = "deserialize_some_duration")]
+    pub retain_duration: Option<u32>,
+
+    pub cold_after_sec: Option<u32>,
+    pub flush_if_sec: Option<u32>,
+}
+
+impl CameraUpdate {
+    /// Applies this update to `change`, which should describe the camera's current configuration.
+    pub fn apply(self, change: &mut db::CameraChange) -> Result<(), Error> {
+        if let Some(n) = self.short_name {
+            change.short_name = n;
+        }
+        if let Some(u) = self.config {
+            let c = &mut change.config;
+            if let Some(d) = u.description {
+                c.description = d;
+            }
+            if let Some(o) = u.onvif_base_url {
+                c.onvif_base_url = o;
+            }
+            if let Some(n) = u.username {
+                c.username = n;
+            }
+            if let Some(p) = u.password {
+                c.password = p;
+            }
+        }
+        for (type_, s) in self.streams {
+            let type_ = db::StreamType::parse(&type_)
+                .ok_or_else(|| format_err!("unknown stream type {:?}", type_))?;
+            let stream = &mut change.streams[type_.index()];
+            match s {
+                None => *stream = db::StreamChange::default(),
+                Some(s) => s.apply(stream),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StreamUpdate {
+    fn apply(self, s: &mut db::StreamChange) {
+        if let Some(d) = self.sample_file_dir_id {
+            s.sample_file_dir_id = d;
+        }
+        if let Some(d) = self.cold_sample_file_dir_id {
+            s.cold_sample_file_dir_id = d;
+        }
+        let u = match self.config {
+            Some(u) => u,
+            None => return,
+        };
+        let c = &mut s.config;
+        if let Some(m) = u.mode {
+            c.mode = m;
+        }
+        if let Some(url) = u.url {
+            c.url = url;
+        }
+        if let Some(t) = u.rtsp_transport {
+            c.rtsp_transport = t;
+        }
+        if let Some(b) = u.retain_bytes {
+            c.retain_bytes = b;
+        }
+        if let Some(b) = u.event_retain_bytes {
+            c.event_retain_bytes = b;
+        }
+        if let Some(d) = u.retain_duration {
+            c.retain_duration_sec = d;
+        }
+        if let Some(a) = u.cold_after_sec {
+            c.cold_after_sec = a;
+        }
+        if let Some(f) = u.flush_if_sec {
+            c.flush_if_sec = f;
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CameraSubset {
+    pub short_name: Option<String>,
+    pub config: Option<db::json::CameraConfig>,
+
+    /// Streams by type. A type listed here must exist.
+    #[serde(default)]
+    pub streams: BTreeMap<String, StreamSubset>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamSubset {
+    pub config: Option<db::json::StreamConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PutStreamRetention {
+    pub update: RetentionSubset,
+    pub precondition: Option<RetentionSubset>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionSubset {
+    pub record: Option<bool>,
+    pub retain_bytes: Option<i64>,
+
+    /// As in `db::json::StreamConfig::retain_duration_sec`.
+    #[serde(default, deserialize_with = "deserialize_some_duration")]
+    pub retain_duration: Option<u32>,
 }
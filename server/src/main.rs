@@ -12,12 +12,18 @@ use structopt::StructOpt;
 mod body;
 mod cmds;
 mod h264;
+mod h265;
 mod json;
 mod mp4;
+mod onvif;
 mod slices;
+mod snapshot;
 mod stream;
 mod streamer;
+mod tls;
 mod web;
+mod webhook;
+mod zip;
 
 #[derive(StructOpt)]
 #[structopt(
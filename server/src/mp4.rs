@@ -36,6 +36,23 @@
 //! ***** co64 (64-bit chunk offset)
 //! ***** stss (sync sample table)
 //!
+//! ** (optional) trak (audio: container for an individual track or stream)
+//! *** tkhd (track header, overall information about the track)
+//! *** (optional) edts (edit list container)
+//! **** elst (an edit list)
+//! *** mdia (container for the media information in a track)
+//! **** mdhd (media header, overall information about the media)
+//! *** minf (media information container)
+//! **** smhd (sound media header, overall information (sound track only))
+//! **** dinf (data information box, container)
+//! ***** dref (data reference box, declares source(s) of media data in track)
+//! **** stbl (sample table box, container for the time/space map)
+//! ***** stsd (sample descriptions (codec types, initilization etc.)
+//! ***** stts ((decoding) time-to-sample)
+//! ***** stsc (sample-to-chunk, partial data-offset information)
+//! ***** stsz (samples sizes (framing))
+//! ***** co64 (64-bit chunk offset)
+//!
 //! ** (optional) trak (subtitle: container for an individual track or stream)
 //! *** tkhd (track header, overall information about the track)
 //! *** mdia (container for the media information in a track)
@@ -123,6 +140,19 @@ const VIDEO_HDLR_BOX: &[u8] = &[
     0x00, // name, zero-terminated (empty)
 ];
 
+/// An `hdlr` (ISO/IEC 14496-12 section 8.4.3 `HandlerBox`) box suitable for audio.
+const AUDIO_HDLR_BOX: &[u8] = &[
+    0x00, 0x00, 0x00, 0x21, // length == sizeof(kHdlrBox)
+    b'h', b'd', b'l', b'r', // type == hdlr, ISO/IEC 14496-12 section 8.4.3.
+    0x00, 0x00, 0x00, 0x00, // version + flags
+    0x00, 0x00, 0x00, 0x00, // pre_defined
+    b's', b'o', b'u', b'n', // handler = soun
+    0x00, 0x00, 0x00, 0x00, // reserved[0]
+    0x00, 0x00, 0x00, 0x00, // reserved[1]
+    0x00, 0x00, 0x00, 0x00, // reserved[2]
+    0x00, // name, zero-terminated (empty)
+];
+
 /// An `hdlr` (ISO/IEC 14496-12 section 8.4.3 `HandlerBox`) box suitable for subtitles.
 const SUBTITLE_HDLR_BOX: &[u8] = &[
     0x00, 0x00, 0x00, 0x21, // length == sizeof(kHdlrBox)
@@ -179,6 +209,24 @@ const TKHD_JUNK: &[u8] = &[
     0x40, 0x00, 0x00, 0x00, // matrix[8]
 ];
 
+/// Part of a `tkhd` (`TrackHeaderBox` version 0, ISO/IEC 14496-12 section 8.3.2), used from
+/// `append_audio_tkhd`. This differs from `TKHD_JUNK` only in the volume.
+const AUDIO_TKHD_JUNK: &[u8] = &[
+    0x00, 0x00, 0x00, 0x00, // reserved
+    0x00, 0x00, 0x00, 0x00, // reserved
+    0x00, 0x00, 0x00, 0x00, // layer + alternate_group
+    0x01, 0x00, 0x00, 0x00, // volume (1.0) + reserved
+    0x00, 0x01, 0x00, 0x00, // matrix[0]
+    0x00, 0x00, 0x00, 0x00, // matrix[1]
+    0x00, 0x00, 0x00, 0x00, // matrix[2]
+    0x00, 0x00, 0x00, 0x00, // matrix[3]
+    0x00, 0x01, 0x00, 0x00, // matrix[4]
+    0x00, 0x00, 0x00, 0x00, // matrix[5]
+    0x00, 0x00, 0x00, 0x00, // matrix[6]
+    0x00, 0x00, 0x00, 0x00, // matrix[7]
+    0x40, 0x00, 0x00, 0x00, // matrix[8]
+];
+
 /// Part of a `minf` (`MediaInformationBox`, ISO/IEC 14496-12 section 8.4.4), used from
 /// `append_video_minf`.
 const VIDEO_MINF_JUNK: &[u8] = &[
@@ -203,6 +251,28 @@ const VIDEO_MINF_JUNK: &[u8] = &[
     0x00, 0x00, 0x00, 0x01, // version=0, flags=self-contained
 ];
 
+/// Part of a `minf` (`MediaInformationBox`, ISO/IEC 14496-12 section 8.4.4), used from
+/// `append_audio_minf`.
+const AUDIO_MINF_JUNK: &[u8] = &[
+    b'm', b'i', b'n', b'f', // type = minf, ISO/IEC 14496-12 section 8.4.4.
+    // A smhd box.
+    0x00, 0x00, 0x00, 0x10, // length == sizeof(kSmhdBox)
+    b's', b'm', b'h', b'd', // type = smhd, ISO/IEC 14496-12 section 12.2.2.
+    0x00, 0x00, 0x00, 0x00, // version + flags
+    0x00, 0x00, 0x00, 0x00, // balance + reserved
+    // A dinf box suitable for a "self-contained" .mp4 file (no URL/URN
+    // references to external data).
+    0x00, 0x00, 0x00, 0x24, // length == sizeof(kDinfBox)
+    b'd', b'i', b'n', b'f', // type = dinf, ISO/IEC 14496-12 section 8.7.1.
+    0x00, 0x00, 0x00, 0x1c, // length
+    b'd', b'r', b'e', b'f', // type = dref, ISO/IEC 14496-12 section 8.7.2.
+    0x00, 0x00, 0x00, 0x00, // version and flags
+    0x00, 0x00, 0x00, 0x01, // entry_count
+    0x00, 0x00, 0x00, 0x0c, // length
+    b'u', b'r', b'l', b' ', // type = url, ISO/IEC 14496-12 section 8.7.2.
+    0x00, 0x00, 0x00, 0x01, // version=0, flags=self-contained
+];
+
 /// Part of a `minf` (`MediaInformationBox`, ISO/IEC 14496-12 section 8.4.4), used from
 /// `append_subtitle_minf`.
 const SUBTITLE_MINF_JUNK: &[u8] = &[
@@ -267,7 +337,7 @@ const SUBTITLE_STBL_JUNK: &[u8] = &[
 
 /// Pointers to each static bytestrings.
 /// The order here must match the `StaticBytestring` enum.
-const STATIC_BYTESTRINGS: [&[u8]; 9] = [
+const STATIC_BYTESTRINGS: [&[u8]; 12] = [
     NORMAL_FTYP_BOX,
     INIT_SEGMENT_FTYP_BOX,
     VIDEO_HDLR_BOX,
@@ -277,6 +347,9 @@ const STATIC_BYTESTRINGS: [&[u8]; 9] = [
     VIDEO_MINF_JUNK,
     SUBTITLE_MINF_JUNK,
     SUBTITLE_STBL_JUNK,
+    AUDIO_HDLR_BOX,
+    AUDIO_TKHD_JUNK,
+    AUDIO_MINF_JUNK,
 ];
 
 /// Enumeration of the static bytestrings. The order here must match the `STATIC_BYTESTRINGS`
@@ -293,6 +366,9 @@ enum StaticBytestring {
     VideoMinfJunk,
     SubtitleMinfJunk,
     SubtitleStblJunk,
+    AudioHdlrBox,
+    AudioTkhdJunk,
+    AudioMinfJunk,
 }
 
 /// The template fed into strtime for a timestamp subtitle. This must produce fixed-length output
@@ -338,6 +414,87 @@ struct Segment {
     /// The 1-indexed frame number in the `File` of the first frame in this segment.
     first_frame_num: u32,
     num_subtitle_samples: u16,
+
+    /// The audio samples to include with this segment, if any.
+    audio: Option<SegmentAudio>,
+
+    /// The directory holding the sample file, iff it has been moved to the stream's cold sample
+    /// file directory. Otherwise, it's read from the directory supplied to `FileBuilder::build`.
+    cold_dir: Option<Arc<dir::SampleFileDir>>,
+
+    /// The stream's cold sample file directory, iff the sample file hadn't yet been moved there
+    /// when this segment was created. It may be moved while the `File` is being served, so reads
+    /// fall back to this directory.
+    cold_fallback: Option<Arc<dir::SampleFileDir>>,
+}
+
+/// The audio portion of a `Segment`. Unlike the video sample tables, the audio sample tables are
+/// generated when the segment is appended, as selecting the samples requires reading the audio
+/// index anyway.
+struct SegmentAudio {
+    /// Index into the `File`'s `audio_sample_entries`.
+    sample_entry_i: usize,
+
+    samples: u32,
+
+    /// The total duration of the included samples, in units of the audio clock rate.
+    duration: u64,
+
+    /// The duration from the start of the first included sample to the desired start of the
+    /// segment, in units of the audio clock rate. This is skipped via edit list.
+    skip: u64,
+
+    /// The byte range of the included samples within the sample file.
+    file_range: Range<u64>,
+
+    /// The `.mp4`-format sample indexes:
+    ///    1. stts: `index[.. 8 * samples]`
+    ///    2. stsz: `index[8 * samples ..]`
+    index: Box<[u8]>,
+
+    /// The included samples' data, iff it was buffered in RAM (not yet written to the sample
+    /// file) when the segment was appended.
+    data: Option<Box<[u8]>>,
+}
+
+impl SegmentAudio {
+    fn stts(&self) -> &[u8] {
+        &self.index[..8 * self.samples as usize]
+    }
+
+    fn stsz(&self) -> &[u8] {
+        &self.index[8 * self.samples as usize..]
+    }
+
+    fn data_len(&self) -> u64 {
+        self.file_range.end - self.file_range.start
+    }
+
+    /// Returns the `TrackRunBox` (8.8.8) sample entries (duration and size) for these samples.
+    fn trun_entries(&self) -> Vec<u8> {
+        let stts = self.stts();
+        let stsz = self.stsz();
+        let mut v = Vec::with_capacity(8 * self.samples as usize);
+        for i in 0..self.samples as usize {
+            v.extend_from_slice(&stts[8 * i + 4..8 * i + 8]);
+            v.extend_from_slice(&stsz[4 * i..4 * i + 4]);
+        }
+        v
+    }
+}
+
+// Manually implement Debug to avoid dumping the potentially large index and data.
+impl fmt::Debug for SegmentAudio {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("mp4::SegmentAudio")
+            .field("sample_entry_i", &self.sample_entry_i)
+            .field("samples", &self.samples)
+            .field("duration", &self.duration)
+            .field("skip", &self.skip)
+            .field("file_range", &self.file_range)
+            .field("in_ram", &self.data.is_some())
+            .finish()
+    }
 }
 
 // Manually implement Debug because `index` and `index_once` are not Debug.
@@ -357,6 +514,9 @@ impl fmt::Debug for Segment {
             .field("rel_media_range_90k", &self.rel_media_range_90k)
             .field("first_frame_num", &self.first_frame_num)
             .field("num_subtitle_samples", &self.num_subtitle_samples)
+            .field("audio", &self.audio)
+            .field("cold", &self.cold_dir.is_some())
+            .field("cold_fallback", &self.cold_fallback.is_some())
             .finish()
     }
 }
@@ -371,6 +531,17 @@ impl Segment {
         first_frame_num: u32,
         start_at_key: bool,
     ) -> Result<Self, Error> {
+        let (mut cold_dir, mut cold_fallback) = (None, None);
+        if let Some(s) = db.streams_by_id().get(&row.id.stream()) {
+            if let Some(c) = s.cold_sample_file_dir_id {
+                let c = db.sample_file_dirs_by_id().get(&c).unwrap().get();
+                if s.sample_file_dir_id_for(row.id.recording()) == s.cold_sample_file_dir_id {
+                    cold_dir = Some(c.err_kind(ErrorKind::Unavailable)?);
+                } else {
+                    cold_fallback = c.ok();
+                }
+            }
+        }
         Ok(Segment {
             s: recording::Segment::new(db, row, rel_media_range_90k.clone(), start_at_key)
                 .err_kind(ErrorKind::Unknown)?,
@@ -382,9 +553,25 @@ impl Segment {
             index_once: Once::new(),
             first_frame_num,
             num_subtitle_samples: 0,
+            audio: None,
+            cold_dir,
+            cold_fallback,
         })
     }
 
+    /// Opens the given range of the sample file, which is in `dir` unless it has since been
+    /// moved to `cold_fallback`.
+    fn open_file(
+        &self,
+        dir: &dir::SampleFileDir,
+        range: Range<u64>,
+    ) -> impl Stream<Item = Result<Vec<u8>, Error>> + Send + Sync {
+        match self.cold_fallback {
+            Some(ref c) => dir.open_file_or_cold(self.s.id, range, c),
+            None => dir.open_file(self.s.id, range),
+        }
+    }
+
     fn wall(&self, rel_media_90k: i32) -> i32 {
         rescale(
             rel_media_90k,
@@ -631,16 +818,25 @@ pub struct FileBuilder {
     /// appear in the video.
     segments: Vec<Segment>,
     video_sample_entries: SmallVec<[Arc<db::VideoSampleEntry>; 1]>,
+
+    /// The audio sample entries. All must have the same clock rate, which is used as the audio
+    /// track's timescale. The audio track is present iff this is non-empty.
+    audio_sample_entries: SmallVec<[Arc<db::AudioSampleEntry>; 1]>,
     next_frame_num: u32,
 
     /// The total media time, after applying edit lists (if applicable) to skip unwanted portions.
     media_duration_90k: u64,
     num_subtitle_samples: u32,
     subtitle_co64_pos: Option<usize>,
+
+    /// The position within `body.buf` of the audio track's `co64` entries (for normal files) or
+    /// its `trun` data offset (for media segments), to be filled in once the `mdat` is laid out.
+    audio_offset_pos: Option<usize>,
     body: BodyState,
     type_: Type,
     prev_media_duration_and_cur_runs: Option<(recording::Duration, i32)>,
     include_timestamp_subtitle_track: bool,
+    include_audio: bool,
     content_disposition: Option<HeaderValue>,
 }
 
@@ -682,6 +878,11 @@ enum SliceType {
     VideoSampleData = 7,    // param is index into m.segments
     SubtitleSampleData = 8, // param is index into m.segments
     Truns = 9,              // param is index into m.segments
+    AudioSampleEntry = 10,  // param is index into m.audio_sample_entries
+    AudioSampleData = 11,   // param is index into m.segments
+    AudioStts = 12,         // param is index into m.segments
+    AudioStsz = 13,         // param is index into m.segments
+    AudioTruns = 14,        // param is index into m.segments
 
                             // There must be no value > 15, as this is packed into 4 bits in Slice.
 }
@@ -745,6 +946,59 @@ impl Slice {
         Ok(truns.map(|t| &t[r.start as usize..r.end as usize]).into())
     }
 
+    fn wrap_audio_index<F>(
+        &self,
+        mp4: &File,
+        r: Range<u64>,
+        len: u64,
+        f: &F,
+    ) -> Result<Chunk, Error>
+    where
+        F: Fn(&SegmentAudio) -> &[u8],
+    {
+        let mp4 = ARefss::new(mp4.0.clone());
+        let p = self.p();
+        Ok(mp4
+            .try_map(|mp4| {
+                let a = mp4.segments[p]
+                    .audio
+                    .as_ref()
+                    .ok_or_else(|| format_err_t!(Internal, "segment {} has no audio", p))?;
+                let i = f(a);
+                if u64::try_from(i.len()).unwrap() != len {
+                    bail_t!(Internal, "expected len {} got {}", len, i.len());
+                }
+                Ok::<_, Error>(&i[r.start as usize..r.end as usize])
+            })?
+            .into())
+    }
+
+    fn wrap_audio_truns(&self, mp4: &File, r: Range<u64>, len: u64) -> Result<Chunk, Error> {
+        let a = mp4.0.segments[self.p()]
+            .audio
+            .as_ref()
+            .ok_or_else(|| format_err_t!(Internal, "segment {} has no audio", self.p()))?;
+        let truns = a.trun_entries();
+        if u64::try_from(truns.len()).unwrap() != len {
+            bail_t!(Internal, "expected len {} got {}", len, truns.len());
+        }
+        let truns = ARefss::new(truns);
+        Ok(truns.map(|t| &t[r.start as usize..r.end as usize]).into())
+    }
+
+    fn wrap_audio_sample_entry(&self, f: &File, r: Range<u64>, len: u64) -> Result<Chunk, Error> {
+        let mp4 = ARefss::new(f.0.clone());
+        Ok(mp4
+            .try_map(|mp4| {
+                let data = &mp4.audio_sample_entries[self.p()].data;
+                if u64::try_from(data.len()).unwrap() != len {
+                    bail_t!(Internal, "expected len {} got len {}", len, data.len());
+                }
+                Ok::<_, Error>(&data[r.start as usize..r.end as usize])
+            })?
+            .into())
+    }
+
     fn wrap_video_sample_entry(&self, f: &File, r: Range<u64>, len: u64) -> Result<Chunk, Error> {
         let mp4 = ARefss::new(f.0.clone());
         Ok(mp4
@@ -804,6 +1058,17 @@ impl slices::Slice for Slice {
             SliceType::VideoSampleData => return f.0.get_video_sample_data(p, range.clone()),
             SliceType::SubtitleSampleData => f.0.get_subtitle_sample_data(p, range.clone(), len),
             SliceType::Truns => self.wrap_truns(f, range.clone(), len as usize),
+            SliceType::AudioSampleEntry => self.wrap_audio_sample_entry(f, range.clone(), len),
+            SliceType::AudioSampleData => {
+                return FileInner::get_audio_sample_data(&f.0, p, range.clone())
+            }
+            SliceType::AudioStts => {
+                self.wrap_audio_index(f, range.clone(), len, &SegmentAudio::stts)
+            }
+            SliceType::AudioStsz => {
+                self.wrap_audio_index(f, range.clone(), len, &SegmentAudio::stsz)
+            }
+            SliceType::AudioTruns => self.wrap_audio_truns(f, range.clone(), len),
         };
         Box::new(stream::once(futures::future::ready(
             res.map_err(wrap_error).and_then(move |c| {
@@ -872,10 +1137,12 @@ impl FileBuilder {
         FileBuilder {
             segments: Vec::new(),
             video_sample_entries: SmallVec::new(),
+            audio_sample_entries: SmallVec::new(),
             next_frame_num: 1,
             media_duration_90k: 0,
             num_subtitle_samples: 0,
             subtitle_co64_pos: None,
+            audio_offset_pos: None,
             body: BodyState {
                 slices: Slices::new(),
                 buf: Vec::new(),
@@ -883,6 +1150,7 @@ impl FileBuilder {
             },
             type_,
             include_timestamp_subtitle_track: false,
+            include_audio: false,
             content_disposition: None,
             prev_media_duration_and_cur_runs: None,
         }
@@ -904,6 +1172,13 @@ impl FileBuilder {
         Ok(())
     }
 
+    /// Sets if segments appended after this call should include their recordings' audio, if any.
+    /// Default is false, so that callers which can't handle an audio track (such as a Media Source
+    /// Extensions source buffer initialized without one) don't receive it unexpectedly.
+    pub fn include_audio(&mut self, b: bool) {
+        self.include_audio = b;
+    }
+
     /// Reserves space for the given number of additional segments.
     pub fn reserve(&mut self, additional: usize) {
         self.segments.reserve(additional);
@@ -913,6 +1188,12 @@ impl FileBuilder {
         self.video_sample_entries.push(ent);
     }
 
+    /// Appends an audio sample entry. This is only necessary for initialization segments; when
+    /// appending segments, the audio sample entries are added automatically.
+    pub fn append_audio_sample_entry(&mut self, ent: Arc<db::AudioSampleEntry>) {
+        self.audio_sample_entries.push(ent);
+    }
+
     /// Appends a segment for (a subset of) the given recording.
     /// `rel_media_range_90k` is the media time range within the recording.
     /// Eg `0 .. row.media_duration_90k` means the full recording.
@@ -939,13 +1220,14 @@ impl FileBuilder {
                 .prev_media_duration_and_runs
                 .map(|(d, r)| (d, r + if row.open_id == 0 { 1 } else { 0 }));
         }
-        let s = Segment::new(
+        let mut s = Segment::new(
             db,
             &row,
             rel_media_range_90k,
             self.next_frame_num,
             start_at_key,
         )?;
+        s.audio = self.select_audio(db, &row, &s)?;
 
         self.next_frame_num += s.s.frames as u32;
         self.segments.push(s);
@@ -963,6 +1245,136 @@ impl FileBuilder {
         Ok(())
     }
 
+    /// Selects the audio samples to include with the given segment, if the recording has audio.
+    ///
+    /// When edit lists are supported (not media segments), this includes the sample which
+    /// overlaps the desired start; the edit list skips the remainder. Otherwise, it starts with
+    /// the first sample at or after the actual start, so that consecutive media segments of the
+    /// same recording don't duplicate samples.
+    fn select_audio(
+        &mut self,
+        db: &db::LockedDatabase,
+        row: &db::ListRecordingsRow,
+        s: &Segment,
+    ) -> Result<Option<SegmentAudio>, Error> {
+        let entry_id = match row.audio_sample_entry_id {
+            Some(id) if self.include_audio => id,
+            _ => return Ok(None),
+        };
+        let entry = db
+            .audio_sample_entries_by_id()
+            .get(&entry_id)
+            .ok_or_else(|| {
+                format_err_t!(
+                    Internal,
+                    "recording {} has unknown audio sample entry {}",
+                    row.id,
+                    entry_id
+                )
+            })?
+            .clone();
+        if let Some(first) = self.audio_sample_entries.first() {
+            if first.clock_rate != entry.clock_rate {
+                warn!(
+                    "omitting audio from recording {}: clock rate {} doesn't match {}",
+                    row.id, entry.clock_rate, first.clock_rate
+                );
+                return Ok(None);
+            }
+        }
+
+        // Add the sample entry even if no samples are selected, so that the audio track (and
+        // thus the MIME type) is consistent across the media segments of a growing recording.
+        let sample_entry_i = match self
+            .audio_sample_entries
+            .iter()
+            .position(|e| e.id == entry_id)
+        {
+            Some(i) => i,
+            None => {
+                self.audio_sample_entries.push(entry.clone());
+                self.audio_sample_entries.len() - 1
+            }
+        };
+        if row.audio_samples == 0 {
+            return Ok(None);
+        }
+        let clock_rate = i64::from(entry.clock_rate);
+        let to_audio = |t_90k: i32| i64::from(t_90k) * clock_rate / TIME_UNITS_PER_SEC;
+        let md = &s.rel_media_range_90k;
+        let is_media_segment = self.type_ == Type::MediaSegment;
+        let start = to_audio(if is_media_segment {
+            s.s.actual_start_90k()
+        } else {
+            md.start
+        });
+        let end = to_audio(md.end);
+        let is_growing = (row.flags & db::RecordingFlags::Growing as i32) != 0;
+        let video_end = u64::try_from(row.sample_file_bytes - row.audio_sample_file_bytes)
+            .err_kind(ErrorKind::Internal)?;
+        db.with_recording_playback(row.id, &mut |playback| {
+            if is_growing && playback.audio_data.is_none() {
+                // The recording was closed (and its audio written to the sample file) since
+                // `row` was read, so `video_end` may be stale. Just omit the audio.
+                return Ok(None);
+            }
+            let mut it = recording::AudioIndexIterator::default();
+            let mut stts = Vec::new();
+            let mut stsz = Vec::new();
+            let mut first = None;
+            let mut data_end = 0;
+            let mut duration = 0;
+            while it.next(playback.audio_index)? {
+                let sample_start = i64::from(it.start);
+                if sample_start >= end {
+                    break;
+                }
+                let included = if is_media_segment {
+                    sample_start >= start
+                } else {
+                    sample_start + i64::from(it.duration) > start
+                };
+                if !included {
+                    continue;
+                }
+                if first.is_none() {
+                    first = Some((sample_start, it.pos));
+                }
+                stts.write_u32::<BigEndian>(1)?;
+                stts.write_u32::<BigEndian>(it.duration as u32)?;
+                stsz.write_u32::<BigEndian>(it.bytes as u32)?;
+                duration += it.duration as u64;
+                data_end = it.pos + it.bytes;
+            }
+            let (first_start, data_start) = match first {
+                None => return Ok(None),
+                Some(f) => f,
+            };
+            let samples = (stsz.len() / 4) as u32;
+            stts.extend_from_slice(&stsz);
+            let data_range = data_start as usize..data_end as usize;
+            let data = match playback.audio_data {
+                None => None,
+                Some(d) if d.len() >= data_range.end => Some(d[data_range].into()),
+                Some(d) => failure::bail!(
+                    "audio index references {:?} but only have {} bytes",
+                    data_range,
+                    d.len()
+                ),
+            };
+            Ok(Some(SegmentAudio {
+                sample_entry_i,
+                samples,
+                duration,
+                skip: u64::try_from(start - first_start).unwrap_or(0),
+                file_range: video_end + data_start as u64..video_end + data_end as u64,
+                index: stts.into_boxed_slice(),
+                data,
+            }))
+        })
+        .err_kind(ErrorKind::Unknown)
+    }
+
     pub fn set_filename(&mut self, filename: &str) -> Result<(), Error> {
         self.content_disposition = Some(
             HeaderValue::try_from(format!("attachment; filename=\"{}\"", filename))
@@ -1041,6 +1453,14 @@ impl FileBuilder {
                 .write_i32::<BigEndian>(md.end)
                 .err_kind(ErrorKind::Internal)?;
             etag.update(cursor.into_inner());
+
+            // The audio of a growing recording can change as more samples arrive.
+            if let Some(a) = s.audio.as_ref() {
+                etag.update(b":audio:");
+                etag.update(&a.samples.to_be_bytes()[..]);
+                etag.update(&a.file_range.start.to_be_bytes()[..]);
+                etag.update(&a.file_range.end.to_be_bytes()[..]);
+            }
         }
         let max_end = match max_end {
             None => 0,
@@ -1051,6 +1471,9 @@ impl FileBuilder {
         if self.include_timestamp_subtitle_track {
             est_slices += 16 + self.segments.len();
         }
+        if !self.audio_sample_entries.is_empty() {
+            est_slices += 16 + self.audio_sample_entries.len() + 4 * self.segments.len();
+        }
         self.body.slices.reserve(est_slices);
         const EST_BUF_LEN: usize = 2048;
         self.body.buf.reserve(EST_BUF_LEN);
@@ -1123,6 +1546,7 @@ impl FileBuilder {
             slices: self.body.slices,
             buf: self.body.buf,
             video_sample_entries: self.video_sample_entries,
+            audio_sample_entries: self.audio_sample_entries,
             initial_sample_byte_pos,
             last_modified,
             etag: HeaderValue::try_from(format!("\"{}\"", etag.to_hex().as_str()))
@@ -1139,6 +1563,26 @@ impl FileBuilder {
             self.body
                 .append_slice(r.end - r.start, SliceType::VideoSampleData, i)?;
         }
+        if let Some(mut p) = self.audio_offset_pos {
+            // All audio follows all video, so a media segment's single audio trun just needs the
+            // starting position. A normal file needs the position of each chunk (segment).
+            if self.type_ == Type::MediaSegment {
+                let pos = u32::try_from(self.body.slices.len()).map_err(|_| {
+                    format_err_t!(InvalidArgument, "media segment too large for audio trun")
+                })?;
+                BigEndian::write_u32(&mut self.body.buf[p..p + 4], pos);
+            }
+            for (i, s) in self.segments.iter().enumerate() {
+                if let Some(a) = s.audio.as_ref() {
+                    if self.type_ != Type::MediaSegment {
+                        BigEndian::write_u64(&mut self.body.buf[p..p + 8], self.body.slices.len());
+                        p += 8;
+                    }
+                    self.body
+                        .append_slice(a.data_len(), SliceType::AudioSampleData, i)?;
+                }
+            }
+        }
         if let Some(p) = self.subtitle_co64_pos {
             BigEndian::write_u64(&mut self.body.buf[p..p + 8], self.body.slices.len());
             for (i, s) in self.segments.iter().enumerate() {
@@ -1203,6 +1647,9 @@ impl FileBuilder {
             self.body.buf.extend_from_slice(b"moov");
             self.append_mvhd(creation_ts)?;
             self.append_video_trak(creation_ts)?;
+            if !self.audio_sample_entries.is_empty() {
+                self.append_audio_trak(creation_ts)?;
+            }
             if self.include_timestamp_subtitle_track {
                 self.append_subtitle_trak(creation_ts)?;
             }
@@ -1236,6 +1683,27 @@ impl FileBuilder {
                                             // sample_degradation_priority: 0
                 ]);
             })?;
+
+            // ...and for the audio track, if any. Every audio sample is a sync sample.
+            if !self.audio_sample_entries.is_empty() {
+                write_length!(self, {
+                    #[rustfmt::skip]
+                    self.body.buf.extend_from_slice(&[
+                        b't', b'r', b'e', b'x', 0x00, 0x00, 0x00, 0x00, // version + flags
+                        0x00, 0x00, 0x00, 0x02, // track_id
+                        0x00, 0x00, 0x00, 0x01, // default_sample_description_index
+                        0x00, 0x00, 0x00, 0x00, // default_sample_duration
+                        0x00, 0x00, 0x00, 0x00, // default_sample_size
+                        0x02, 0x00, 0x00, 0x00, // default_sample_flags (sync):
+                                                // is_leading: unknown
+                                                // sample_depends_on: does not depend on others
+                                                // sample_is_depend_on: unknown
+                                                // sample_has_redundancy: unknown
+                                                // sample_is_non_sync_sample: 0
+                                                // sample_degradation_priority: 0
+                    ]);
+                })?;
+            }
         })
     }
 
@@ -1277,6 +1745,68 @@ impl FileBuilder {
                 })?;
                 self.append_truns()?;
             })?;
+
+            if self.segments.iter().any(|s| s.audio.is_some()) {
+                self.append_audio_traf()?;
+            }
+        })
+    }
+
+    /// Appends a `TrackFragmentBox` (ISO/IEC 14496-12 section 8.8.6) for the audio track.
+    fn append_audio_traf(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"traf");
+
+            // TrackFragmentHeaderBox, tfhd (ISO/IEC 14496-12 section 8.8.7).
+            write_length!(self, {
+                #[rustfmt::skip]
+                self.body.buf.extend_from_slice(&[
+                    b't', b'f', b'h', b'd',
+                    0x00, 0x02, 0x00, 0x00, // version + flags (default-base-is-moof)
+                    0x00, 0x00, 0x00, 0x02, // track_id = 2
+                ]);
+            })?;
+
+            // `TrackFragmentBaseMediaDecodeTimeBox`, tfdt (ISO/IEC 14496-12 section 8.8.12).
+            // See the note in `append_moof`.
+            write_length!(self, {
+                self.body.buf.extend_from_slice(&[
+                    b't', b'f', b'd', b't', 0x00, 0x00, 0x00, 0x00, // version + flags
+                    0x00, 0x00, 0x00, 0x00, // TODO: baseMediaDecodeTime
+                ]);
+            })?;
+
+            // A single TrackRunBox, trun (ISO/IEC 14496-12 section 8.8.8), as all the audio
+            // samples are contiguous.
+            write_length!(self, {
+                #[rustfmt::skip]
+                self.body.buf.extend_from_slice(&[
+                    b't', b'r', b'u', b'n',
+                    // version 0, tr_flags:
+                    // 0x000001 data-offset-present
+                    // 0x000100 sample-duration-present
+                    // 0x000200 sample-size-present
+                    0x00, 0x00, 0x03, 0x01,
+                ]);
+                let samples = self
+                    .segments
+                    .iter()
+                    .filter_map(|s| s.audio.as_ref().map(|a| a.samples))
+                    .sum();
+                self.body.append_u32(samples);
+                self.audio_offset_pos = Some(self.body.buf.len());
+                self.body.append_u32(0); // placeholder for data_offset
+                self.body.flush_buf()?;
+                for (i, s) in self.segments.iter().enumerate() {
+                    if let Some(a) = s.audio.as_ref() {
+                        self.body.append_slice(
+                            8 * u64::from(a.samples),
+                            SliceType::AudioTruns,
+                            i,
+                        )?;
+                    }
+                }
+            })?;
         })
     }
 
@@ -1300,14 +1830,24 @@ impl FileBuilder {
             self.body.append_u64(d);
             self.body.append_static(StaticBytestring::MvhdJunk)?;
             let next_track_id = if self.include_timestamp_subtitle_track {
-                3
+                self.subtitle_track_id() + 1
             } else {
-                2
+                self.subtitle_track_id()
             };
             self.body.append_u32(next_track_id);
         })
     }
 
+    /// Returns the track id to use for the subtitle track (if any).
+    /// The video track is always 1, and the audio track (if any) is 2.
+    fn subtitle_track_id(&self) -> u32 {
+        if self.audio_sample_entries.is_empty() {
+            2
+        } else {
+            3
+        }
+    }
+
     /// Appends a `TrackBox` (ISO/IEC 14496-12 section 8.3.1) suitable for video.
     fn append_video_trak(&mut self, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
@@ -1327,6 +1867,18 @@ impl FileBuilder {
         })
     }
 
+    /// Appends a `TrackBox` (ISO/IEC 14496-12 section 8.3.1) suitable for audio.
+    fn append_audio_trak(&mut self, creation_ts: u32) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"trak");
+            self.append_audio_tkhd(creation_ts)?;
+            if self.type_ == Type::Normal {
+                self.append_audio_edts()?;
+            }
+            self.append_audio_mdia(creation_ts)?;
+        })
+    }
+
     /// Appends a `TrackHeaderBox` (ISO/IEC 14496-12 section 8.3.2) suitable for video.
     fn append_video_tkhd(&mut self, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
@@ -1359,7 +1911,7 @@ impl FileBuilder {
             self.body.buf.extend_from_slice(b"tkhd\x01\x00\x00\x07");
             self.body.append_u64(creation_ts as u64);
             self.body.append_u64(creation_ts as u64);
-            self.body.append_u32(2); // track_id
+            self.body.append_u32(self.subtitle_track_id());
             self.body.append_u32(0); // reserved
             self.body.append_u64(self.media_duration_90k);
             self.body.append_static(StaticBytestring::TkhdJunk)?;
@@ -1368,6 +1920,64 @@ impl FileBuilder {
         })
     }
 
+    /// Appends a `TrackHeaderBox` (ISO/IEC 14496-12 section 8.3.2) suitable for audio.
+    fn append_audio_tkhd(&mut self, creation_ts: u32) -> Result<(), Error> {
+        write_length!(self, {
+            // flags 7: track_enabled | track_in_movie | track_in_preview
+            self.body.buf.extend_from_slice(b"tkhd\x01\x00\x00\x07");
+            self.body.append_u64(creation_ts as u64);
+            self.body.append_u64(creation_ts as u64);
+            self.body.append_u32(2); // track_id
+            self.body.append_u32(0); // reserved
+            self.body.append_u64(self.media_duration_90k);
+            self.body.append_static(StaticBytestring::AudioTkhdJunk)?;
+            self.body.append_u32(0); // width, unused.
+            self.body.append_u32(0); // height, unused.
+        })
+    }
+
+    /// Appends an `EditBox` (ISO/IEC 14496-12 section 8.6.5) suitable for audio.
+    /// Unlike the video track's, this is always present. It aligns each segment's audio with its
+    /// video by skipping the part of the first sample before the desired start, and it uses
+    /// empty edits to leave gaps for segments without audio.
+    fn append_audio_edts(&mut self) -> Result<(), Error> {
+        let mut entries: Vec<(u64, i64)> = Vec::with_capacity(self.segments.len());
+        let mut cur_media_time: u64 = 0;
+        for s in &self.segments {
+            let md = &s.rel_media_range_90k;
+            let segment_duration = u64::try_from(md.end - md.start).unwrap();
+            let media_time = match s.audio.as_ref() {
+                None => -1, // empty edit.
+                Some(a) => {
+                    let t = cur_media_time + a.skip;
+                    cur_media_time += a.duration;
+                    i64::try_from(t).unwrap()
+                }
+            };
+            match entries.last_mut() {
+                Some(last) if last.1 == -1 && media_time == -1 => last.0 += segment_duration,
+                _ => entries.push((segment_duration, media_time)),
+            }
+        }
+
+        trace!("Using audio edit list: {:?}", entries);
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"edts");
+            write_length!(self, {
+                // Use version 1 for 64-bit times.
+                self.body.buf.extend_from_slice(b"elst\x01\x00\x00\x00");
+                self.body.append_u32(entries.len() as u32);
+                for &(segment_duration, media_time) in &entries {
+                    self.body.append_u64(segment_duration);
+                    self.body.append_u64(media_time as u64);
+
+                    // media_rate_integer + media_rate_fraction: fixed at 1.0
+                    self.body.buf.extend_from_slice(b"\x00\x01\x00\x00");
+                }
+            })?;
+        })
+    }
+
     /// Appends an `EditBox` (ISO/IEC 14496-12 section 8.6.5) suitable for video, if necessary.
     fn maybe_append_video_edts(&mut self) -> Result<(), Error> {
         #[derive(Debug, Default)]
@@ -1432,31 +2042,60 @@ impl FileBuilder {
     fn append_video_mdia(&mut self, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"mdia");
-            self.append_mdhd(creation_ts)?;
+            self.append_mdhd(
+                creation_ts,
+                TIME_UNITS_PER_SEC as u32,
+                self.media_duration_90k,
+            )?;
             self.body.append_static(StaticBytestring::VideoHdlrBox)?;
             self.append_video_minf()?;
         })
     }
 
+    /// Appends a `MediaBox` (ISO/IEC 14496-12 section 8.4.1) suitable for audio.
+    fn append_audio_mdia(&mut self, creation_ts: u32) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"mdia");
+            let timescale = self.audio_sample_entries[0].clock_rate;
+            let duration = self
+                .segments
+                .iter()
+                .filter_map(|s| s.audio.as_ref().map(|a| a.duration))
+                .sum();
+            self.append_mdhd(creation_ts, timescale, duration)?;
+            self.body.append_static(StaticBytestring::AudioHdlrBox)?;
+            self.append_audio_minf()?;
+        })
+    }
+
     /// Appends a `MediaBox` (ISO/IEC 14496-12 section 8.4.1) suitable for subtitles.
     fn append_subtitle_mdia(&mut self, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"mdia");
-            self.append_mdhd(creation_ts)?;
+            self.append_mdhd(
+                creation_ts,
+                TIME_UNITS_PER_SEC as u32,
+                self.media_duration_90k,
+            )?;
             self.body.append_static(StaticBytestring::SubtitleHdlrBox)?;
             self.append_subtitle_minf()?;
         })
     }
 
-    /// Appends a `MediaHeaderBox` (ISO/IEC 14496-12 section 8.4.2) suitable for either the video
-    /// or subtitle track.
-    fn append_mdhd(&mut self, creation_ts: u32) -> Result<(), Error> {
+    /// Appends a `MediaHeaderBox` (ISO/IEC 14496-12 section 8.4.2) suitable for any track.
+    /// `duration` is in units of `timescale`.
+    fn append_mdhd(
+        &mut self,
+        creation_ts: u32,
+        timescale: u32,
+        duration: u64,
+    ) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"mdhd\x01\x00\x00\x00");
             self.body.append_u64(u64::from(creation_ts));
             self.body.append_u64(u64::from(creation_ts));
-            self.body.append_u32(TIME_UNITS_PER_SEC as u32);
-            self.body.append_u64(self.media_duration_90k);
+            self.body.append_u32(timescale);
+            self.body.append_u64(duration);
             self.body.append_u32(0x55c40000); // language=und + pre_defined
         })
     }
@@ -1469,6 +2108,14 @@ impl FileBuilder {
         })
     }
 
+    /// Appends a `MediaInformationBox` (ISO/IEC 14496-12 section 8.4.4) suitable for audio.
+    fn append_audio_minf(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.append_static(StaticBytestring::AudioMinfJunk)?;
+            self.append_audio_stbl()?;
+        })
+    }
+
     /// Appends a `MediaInformationBox` (ISO/IEC 14496-12 section 8.4.4) suitable for subtitles.
     fn append_subtitle_minf(&mut self) -> Result<(), Error> {
         write_length!(self, {
@@ -1493,6 +2140,19 @@ impl FileBuilder {
         })
     }
 
+    /// Appends a `SampleTableBox` (ISO/IEC 14496-12 section 8.5.1) suitable for audio.
+    /// There's no `stss`; every audio sample is a sync sample.
+    fn append_audio_stbl(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"stbl");
+            self.append_audio_stsd()?;
+            self.append_audio_stts()?;
+            self.append_audio_stsc()?;
+            self.append_audio_stsz()?;
+            self.append_audio_co64()?;
+        })
+    }
+
     /// Appends a `SampleTableBox` (ISO/IEC 14496-12 section 8.5.1) suitable for subtitles.
     fn append_subtitle_stbl(&mut self) -> Result<(), Error> {
         write_length!(self, {
@@ -1541,6 +2201,39 @@ impl FileBuilder {
         })
     }
 
+    /// Appends a `SampleDescriptionBox` (ISO/IEC 14496-12 section 8.5.2) suitable for audio.
+    fn append_audio_stsd(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"stsd\x00\x00\x00\x00");
+            let n_entries = self.audio_sample_entries.len() as u32;
+            self.body.append_u32(n_entries);
+            self.body.flush_buf()?;
+            for (i, e) in self.audio_sample_entries.iter().enumerate() {
+                self.body
+                    .append_slice(e.data.len() as u64, SliceType::AudioSampleEntry, i)?;
+            }
+        })
+    }
+
+    /// Appends an `stts` / `TimeToSampleBox` (ISO/IEC 14496-12 section 8.6.1) for audio.
+    fn append_audio_stts(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"stts\x00\x00\x00\x00");
+            let mut entry_count = 0;
+            for s in &self.segments {
+                entry_count += s.audio.as_ref().map(|a| a.samples).unwrap_or(0);
+            }
+            self.body.append_u32(entry_count);
+            self.body.flush_buf()?;
+            for (i, s) in self.segments.iter().enumerate() {
+                if let Some(a) = s.audio.as_ref() {
+                    self.body
+                        .append_slice(8 * u64::from(a.samples), SliceType::AudioStts, i)?;
+                }
+            }
+        })
+    }
+
     /// Appends an `stts` / `TimeToSampleBox` (ISO/IEC 14496-12 section 8.6.1) for subtitles.
     fn append_subtitle_stts(&mut self) -> Result<(), Error> {
         write_length!(self, {
@@ -1624,6 +2317,29 @@ impl FileBuilder {
         })
     }
 
+    /// Appends a `SampleToChunkBox` (ISO/IEC 14496-12 section 8.7.4) suitable for audio.
+    /// There's one chunk for each segment with audio.
+    fn append_audio_stsc(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"stsc\x00\x00\x00\x00");
+            let entry_count_pos = self.body.buf.len();
+            self.body.append_u32(0); // placeholder for entry_count
+            let mut chunk = 0;
+            for s in &self.segments {
+                if let Some(a) = s.audio.as_ref() {
+                    chunk += 1;
+                    self.body.append_u32(chunk);
+                    self.body.append_u32(a.samples);
+                    self.body.append_u32((a.sample_entry_i + 1) as u32);
+                }
+            }
+            BigEndian::write_u32(
+                &mut self.body.buf[entry_count_pos..entry_count_pos + 4],
+                chunk,
+            );
+        })
+    }
+
     /// Appends a `SampleToChunkBox` (ISO/IEC 14496-12 section 8.7.4) suitable for subtitles.
     fn append_subtitle_stsc(&mut self) -> Result<(), Error> {
         write_length!(self, {
@@ -1659,6 +2375,27 @@ impl FileBuilder {
         })
     }
 
+    /// Appends a `SampleSizeBox` (ISO/IEC 14496-12 section 8.7.3) suitable for audio.
+    fn append_audio_stsz(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body
+                .buf
+                .extend_from_slice(b"stsz\x00\x00\x00\x00\x00\x00\x00\x00");
+            let mut entry_count = 0;
+            for s in &self.segments {
+                entry_count += s.audio.as_ref().map(|a| a.samples).unwrap_or(0);
+            }
+            self.body.append_u32(entry_count);
+            self.body.flush_buf()?;
+            for (i, s) in self.segments.iter().enumerate() {
+                if let Some(a) = s.audio.as_ref() {
+                    self.body
+                        .append_slice(4 * u64::from(a.samples), SliceType::AudioStsz, i)?;
+                }
+            }
+        })
+    }
+
     /// Appends a `SampleSizeBox` (ISO/IEC 14496-12 section 8.7.3) suitable for subtitles.
     fn append_subtitle_stsz(&mut self) -> Result<(), Error> {
         write_length!(self, {
@@ -1685,6 +2422,20 @@ impl FileBuilder {
         })
     }
 
+    /// Appends a `ChunkLargeOffsetBox` (ISO/IEC 14496-12 section 8.7.5) suitable for audio.
+    fn append_audio_co64(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"co64\x00\x00\x00\x00");
+            let chunks = self.segments.iter().filter(|s| s.audio.is_some()).count();
+            self.body.append_u32(chunks as u32);
+            if chunks > 0 {
+                // Write placeholders; the actual values will be filled in later.
+                self.audio_offset_pos = Some(self.body.buf.len());
+                self.body.buf.resize(self.body.buf.len() + 8 * chunks, 0);
+            }
+        })
+    }
+
     /// Appends a `ChunkLargeOffsetBox` (ISO/IEC 14496-12 section 8.7.5) suitable for subtitles.
     fn append_subtitle_co64(&mut self) -> Result<(), Error> {
         write_length!(self, {
@@ -1767,6 +2518,7 @@ struct FileInner {
     slices: Slices<Slice>,
     buf: Vec<u8>,
     video_sample_entries: SmallVec<[Arc<db::VideoSampleEntry>; 1]>,
+    audio_sample_entries: SmallVec<[Arc<db::AudioSampleEntry>; 1]>,
     initial_sample_byte_pos: u64,
     last_modified: SystemTime,
     etag: HeaderValue,
@@ -1806,13 +2558,57 @@ impl FileInner {
     ) -> Box<dyn Stream<Item = Result<Chunk, BoxedError>> + Send + Sync> {
         let s = &self.segments[i];
         let sr = s.s.sample_file_range();
-        let f = match self.dirs_by_stream_id.get(&s.s.id.stream()) {
+        let dir = s
+            .cold_dir
+            .as_ref()
+            .or_else(|| self.dirs_by_stream_id.get(&s.s.id.stream()));
+        let f = match dir {
+            None => {
+                return Box::new(stream::iter(std::iter::once(Err(wrap_error(
+                    format_err_t!(NotFound, "{}: stream not found", s.s.id),
+                )))))
+            }
+            Some(d) => s.open_file(d, (r.start + sr.start)..(r.end + sr.start)),
+        };
+        Box::new(f.map_ok(Chunk::from).map_err(wrap_error))
+    }
+
+    /// Gets a `Chunk` of audio sample data, either from RAM or (as in `get_video_sample_data`)
+    /// from disk.
+    fn get_audio_sample_data(
+        this: &Arc<FileInner>,
+        i: usize,
+        r: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Chunk, BoxedError>> + Send + Sync> {
+        let s = &this.segments[i];
+        let a = s
+            .audio
+            .as_ref()
+            .expect("AudioSampleData slice on segment without audio");
+        if a.data.is_some() {
+            let f = ARefss::new(this.clone());
+            let c = f.map(|f| {
+                let d = f.segments[i].audio.as_ref().unwrap().data.as_ref().unwrap();
+                &d[r.start as usize..r.end as usize]
+            });
+            return Box::new(stream::once(futures::future::ok::<Chunk, BoxedError>(
+                c.into(),
+            )));
+        }
+        let dir = s
+            .cold_dir
+            .as_ref()
+            .or_else(|| this.dirs_by_stream_id.get(&s.s.id.stream()));
+        let f = match dir {
             None => {
                 return Box::new(stream::iter(std::iter::once(Err(wrap_error(
                     format_err_t!(NotFound, "{}: stream not found", s.s.id),
                 )))))
             }
-            Some(d) => d.open_file(s.s.id, (r.start + sr.start)..(r.end + sr.start)),
+            Some(d) => s.open_file(
+                d,
+                (r.start + a.file_range.start)..(r.end + a.file_range.start),
+            ),
         };
         Box::new(f.map_ok(Chunk::from).map_err(wrap_error))
     }
@@ -1899,6 +2695,10 @@ impl http_serve::Entity for File {
             }
             mime.extend_from_slice(e.rfc6381_codec.as_bytes());
         }
+        for e in &self.0.audio_sample_entries {
+            mime.extend_from_slice(b", ");
+            mime.extend_from_slice(e.rfc6381_codec.as_bytes());
+        }
         mime.extend_from_slice(b"\"");
         hdrs.insert(
             http::header::CONTENT_TYPE,
@@ -2299,6 +3099,7 @@ mod tests {
             &db.syncer_channel,
             TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
 
         // end_pts is the pts of the end of the most recent frame (start + duration).
@@ -2312,7 +3113,8 @@ mod tests {
 
         loop {
             let pkt = match input.next() {
-                Ok(p) => p,
+                Ok(stream::Frame::Video(p)) => p,
+                Ok(stream::Frame::Audio(_)) => unreachable!("ffmpeg streams are video-only"),
                 Err(e) if e.to_string().contains("End of file") => {
                     break;
                 }
@@ -2411,14 +3213,16 @@ mod tests {
         let mut final_durations = None;
         loop {
             let orig_pkt = match orig.next() {
-                Ok(p) => Some(p),
+                Ok(stream::Frame::Video(p)) => Some(p),
+                Ok(stream::Frame::Audio(_)) => unreachable!("ffmpeg streams are video-only"),
                 Err(e) if e.to_string() == "End of file" => None,
                 Err(e) => {
                     panic!("unexpected input error: {}", e);
                 }
             };
             let new_pkt = match new.next() {
-                Ok(p) => Some(p),
+                Ok(stream::Frame::Video(p)) => Some(p),
+                Ok(stream::Frame::Audio(_)) => unreachable!("ffmpeg streams are video-only"),
                 Err(e) if e.to_string() == "End of file" => {
                     break;
                 }
@@ -0,0 +1,217 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Decodes a single key frame into a JPEG still image, for `/snapshot.jpg`.
+//!
+//! Recorded samples are in ISO/IEC 14496-15 format (length-prefixed NAL units), with the
+//! parameter sets in the video sample entry's `avcC` or `hvcC` box. ffmpeg is given them as an
+//! Annex B elementary stream, which its `h264` and `hevc` demuxers detect without further help.
+
+use byteorder::{BigEndian, ByteOrder};
+use cstr::cstr;
+use failure::{bail, format_err, Error};
+use std::convert::TryFrom;
+use std::ffi::CString;
+
+const ANNEX_B_START_CODE: &[u8] = b"\x00\x00\x00\x01";
+
+/// The length of the box header and `VisualSampleEntry` fields written by
+/// `h264::start_visual_sample_entry`, after which come the child boxes.
+const VISUAL_SAMPLE_ENTRY_LEN: usize = 86;
+
+/// JPEG quality, from 1 to 100.
+const JPEG_QUALITY: u8 = 85;
+
+/// Decodes `sample`, a key frame described by `entry`, into a JPEG of the same dimensions.
+///
+/// This is CPU-intensive; async callers should use `tokio::task::spawn_blocking`.
+pub fn jpeg(entry: &db::VideoSampleEntry, sample: &[u8]) -> Result<Vec<u8>, Error> {
+    lazy_static::initialize(&crate::stream::FFMPEG);
+    let annex_b = to_annex_b(&entry.data, sample)?;
+
+    // ffmpeg's data: protocol avoids writing a temporary file.
+    let url = format!(
+        "data:application/octet-stream;base64,{}",
+        base64::encode(&annex_b)
+    );
+    let mut open_options = ffmpeg::avutil::Dictionary::new();
+    let mut input =
+        ffmpeg::avformat::InputFormatContext::open(&CString::new(url)?, &mut open_options)?;
+    input.find_stream_info()?;
+
+    // With frame threading, the decoder would wait for more frames before returning this one.
+    let mut decoder_options = ffmpeg::avutil::Dictionary::new();
+    decoder_options.set(cstr!("threads"), cstr!("1")).unwrap();
+    let mut decoder = input
+        .streams()
+        .get(0)
+        .codecpar()
+        .new_decoder(&mut decoder_options)?;
+    let mut decoded = ffmpeg::avutil::VideoFrame::empty()?;
+    loop {
+        let pkt = input
+            .read_frame()
+            .map_err(|e| format_err!("key frame didn't decode: {}", e))?;
+        if decoder.decode_video(&pkt, &mut decoded)? {
+            break;
+        }
+    }
+
+    let dims = decoded.dims();
+    let mut rgb = ffmpeg::avutil::VideoFrame::owned(ffmpeg::avutil::ImageDimensions {
+        width: dims.width,
+        height: dims.height,
+        pix_fmt: ffmpeg::avutil::PixelFormat::rgb24(),
+    })?;
+    let mut scaler = ffmpeg::swscale::Scaler::new(dims, rgb.dims())?;
+    scaler.scale(&decoded, &mut rgb);
+
+    // Copy out the rows, omitting any padding ffmpeg added at the end of each.
+    let (width, height) = (u16::try_from(dims.width)?, u16::try_from(dims.height)?);
+    let plane = rgb.plane(0);
+    let row_len = 3 * usize::from(width);
+    let mut pixels = Vec::with_capacity(row_len * usize::from(height));
+    for row in plane.data.chunks(plane.linesize).take(usize::from(height)) {
+        pixels.extend_from_slice(&row[..row_len]);
+    }
+    let mut out = Vec::new();
+    jpeg_encoder::Encoder::new(&mut out, JPEG_QUALITY).encode(
+        &pixels,
+        width,
+        height,
+        jpeg_encoder::ColorType::Rgb,
+    )?;
+    Ok(out)
+}
+
+/// Converts `sample` to an Annex B elementary stream, preceded by the parameter sets from
+/// `sample_entry` so that it can be decoded on its own.
+fn to_annex_b(sample_entry: &[u8], sample: &[u8]) -> Result<Vec<u8>, Error> {
+    let (length_size, parameter_sets) = parameter_sets(sample_entry)?;
+    let mut out = Vec::with_capacity(sample.len() + 256);
+    for s in parameter_sets {
+        out.extend_from_slice(ANNEX_B_START_CODE);
+        out.extend_from_slice(s);
+    }
+    let mut rest = sample;
+    while !rest.is_empty() {
+        if rest.len() < length_size {
+            bail!("sample truncated in NAL unit length");
+        }
+        let len = BigEndian::read_uint(&rest[..length_size], length_size) as usize;
+        rest = &rest[length_size..];
+        if rest.len() < len {
+            bail!("sample truncated in NAL unit");
+        }
+        out.extend_from_slice(ANNEX_B_START_CODE);
+        out.extend_from_slice(&rest[..len]);
+        rest = &rest[len..];
+    }
+    Ok(out)
+}
+
+/// Returns the NAL unit length size and parameter sets from a video sample entry.
+fn parameter_sets(sample_entry: &[u8]) -> Result<(usize, Vec<&[u8]>), Error> {
+    let mut rest = sample_entry
+        .get(VISUAL_SAMPLE_ENTRY_LEN..)
+        .ok_or_else(|| format_err!("sample entry too short"))?;
+    while rest.len() >= 8 {
+        let len = BigEndian::read_u32(&rest[0..4]) as usize;
+        if len < 8 || len > rest.len() {
+            bail!("bad box length {} in sample entry", len);
+        }
+        let body = &rest[8..len];
+        match &rest[4..8] {
+            b"avcC" => return parse_avcc(body),
+            b"hvcC" => return parse_hvcc(body),
+            _ => {}
+        }
+        rest = &rest[len..];
+    }
+    bail!("sample entry has no avcC or hvcC box")
+}
+
+/// Parses an `AVCDecoderConfigurationRecord`, ISO/IEC 14496-15 section 5.2.4.1.
+fn parse_avcc(data: &[u8]) -> Result<(usize, Vec<&[u8]>), Error> {
+    if data.len() < 6 || data[0] != 1 {
+        bail!("Bad AVCDecoderConfigurationRecord header");
+    }
+    let length_size = usize::from(data[4] & 0x3) + 1;
+    let mut sets = Vec::new();
+    let mut rest = &data[5..];
+
+    // SPSs, then PPSs; the former's count has three reserved bits.
+    for mask in &[0x1f, 0xff] {
+        let (&n, r) = rest
+            .split_first()
+            .ok_or_else(|| format_err!("AVCDecoderConfigurationRecord truncated"))?;
+        rest = r;
+        for _ in 0..(n & mask) {
+            rest = take_parameter_set(rest, &mut sets)?;
+        }
+    }
+    Ok((length_size, sets))
+}
+
+/// Parses a `HEVCDecoderConfigurationRecord`, ISO/IEC 14496-15 section 8.3.3.1.2.
+fn parse_hvcc(data: &[u8]) -> Result<(usize, Vec<&[u8]>), Error> {
+    if data.len() < 23 || data[0] != 1 {
+        bail!("Bad HEVCDecoderConfigurationRecord header");
+    }
+    let length_size = usize::from(data[21] & 0x3) + 1;
+    let mut sets = Vec::new();
+    let mut rest = &data[23..];
+    for _ in 0..data[22] {
+        if rest.len() < 3 {
+            bail!("HEVCDecoderConfigurationRecord truncated in array header");
+        }
+        let num_nalus = BigEndian::read_u16(&rest[1..3]);
+        rest = &rest[3..];
+        for _ in 0..num_nalus {
+            rest = take_parameter_set(rest, &mut sets)?;
+        }
+    }
+    Ok((length_size, sets))
+}
+
+/// Takes a parameter set with a 16-bit length prefix from `data` into `sets`, returning the rest.
+fn take_parameter_set<'a>(data: &'a [u8], sets: &mut Vec<&'a [u8]>) -> Result<&'a [u8], Error> {
+    if data.len() < 2 {
+        bail!("decoder configuration truncated in parameter set length");
+    }
+    let len = usize::from(BigEndian::read_u16(&data[0..2]));
+    if data.len() < 2 + len {
+        bail!("decoder configuration truncated in parameter set");
+    }
+    sets.push(&data[2..2 + len]);
+    Ok(&data[2 + len..])
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil;
+
+    #[test]
+    fn to_annex_b() {
+        testutil::init();
+        let sample = b"\x00\x00\x00\x03\x65\x88\x80\x00\x00\x00\x02\x06\x05";
+        let annex_b = super::to_annex_b(testutil::TEST_VIDEO_SAMPLE_ENTRY_DATA, sample).unwrap();
+        let (_, sets) = super::parameter_sets(testutil::TEST_VIDEO_SAMPLE_ENTRY_DATA).unwrap();
+        assert_eq!(sets.len(), 2); // SPS and PPS.
+        let mut expected = Vec::new();
+        for s in &sets {
+            expected.extend_from_slice(b"\x00\x00\x00\x01");
+            expected.extend_from_slice(s);
+        }
+        expected.extend_from_slice(b"\x00\x00\x00\x01\x65\x88\x80\x00\x00\x00\x01\x06\x05");
+        assert_eq!(annex_b, expected);
+
+        let e = super::to_annex_b(
+            testutil::TEST_VIDEO_SAMPLE_ENTRY_DATA,
+            b"\x00\x00\x00\x04\x65",
+        )
+        .unwrap_err();
+        assert_eq!(e.to_string(), "sample truncated in NAL unit");
+    }
+}
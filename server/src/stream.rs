@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
 use crate::h264;
+use crate::h265;
 use cstr::cstr;
 use failure::format_err;
 use failure::{bail, Error};
@@ -26,6 +27,7 @@ lazy_static! {
     pub static ref FFMPEG: Ffmpeg = Ffmpeg::new();
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum RtspLibrary {
     Ffmpeg,
     Retina,
@@ -95,8 +97,31 @@ pub struct VideoFrame<'a> {
     pub data: &'a [u8],
 }
 
+pub struct AudioFrame<'a> {
+    /// The presentation timestamp, in units of the audio sample entry's clock rate, relative to
+    /// the start of the stream.
+    pub pts: i64,
+
+    /// The nominal duration of the frame, in units of the audio sample entry's clock rate.
+    /// The recorded duration comes from the following frame's pts when possible.
+    pub frame_length: i32,
+
+    pub data: &'a [u8],
+}
+
+pub enum Frame<'a> {
+    Video(VideoFrame<'a>),
+    Audio(AudioFrame<'a>),
+}
+
 pub trait Stream: Send {
-    fn next(&mut self) -> Result<VideoFrame, Error>;
+    fn next(&mut self) -> Result<Frame, Error>;
+
+    /// Returns the audio sample entry, if the stream has a supported audio track.
+    /// Audio frames are only returned from `next` when this is `Some`.
+    fn audio_sample_entry(&self) -> Option<&db::AudioSampleEntryToInsert> {
+        None
+    }
 }
 
 pub struct Ffmpeg {}
@@ -171,8 +196,9 @@ impl Opener for Ffmpeg {
                     .set(cstr!("fflags"), cstr!("nobuffer"))
                     .unwrap();
 
-                // Moonfire NVR currently only supports video, so receiving audio is wasteful.
-                // It also triggers <https://github.com/scottlamb/moonfire-nvr/issues/36>.
+                // Moonfire NVR only records audio via the Retina library, so receiving audio
+                // here is wasteful. It also triggers
+                // <https://github.com/scottlamb/moonfire-nvr/issues/36>.
                 open_options
                     .set(cstr!("allowed_media_types"), cstr!("video"))
                     .unwrap();
@@ -221,9 +247,6 @@ impl Opener for Ffmpeg {
         let video = input.streams().get(video_i);
         let codec = video.codecpar();
         let codec_id = codec.codec_id();
-        if !codec_id.is_h264() {
-            bail!("stream's video codec {:?} is not h264", codec_id);
-        }
         let tb = video.time_base();
         if tb.num != 1 || tb.den != 90000 {
             bail!(
@@ -233,11 +256,17 @@ impl Opener for Ffmpeg {
             );
         }
         let dims = codec.dims();
-        let extra_data = h264::ExtraData::parse(
-            codec.extradata(),
-            u16::try_from(dims.width)?,
-            u16::try_from(dims.height)?,
-        )?;
+        let (width, height) = (u16::try_from(dims.width)?, u16::try_from(dims.height)?);
+        let extra_data = if codec_id.is_h264() {
+            h264::ExtraData::parse(codec.extradata(), width, height)?
+        } else if codec_id.is_hevc() {
+            h265::parse_extra_data(codec.extradata(), width, height)?
+        } else {
+            bail!(
+                "unsupported video codec {:?}; only H.264 and H.265 are supported",
+                codec_id
+            );
+        };
         let need_transform = extra_data.need_transform;
         let stream = Box::new(FfmpegStream {
             input,
@@ -257,7 +286,7 @@ struct FfmpegStream {
 }
 
 impl Stream for FfmpegStream {
-    fn next(&mut self) -> Result<VideoFrame, Error> {
+    fn next(&mut self) -> Result<Frame, Error> {
         let pkt = loop {
             let pkt = self.input.read_frame()?;
             if pkt.stream_index() == self.video_i {
@@ -275,12 +304,12 @@ impl Stream for FfmpegStream {
             self.data.extend_from_slice(data);
         }
         let pts = pkt.pts().ok_or_else(|| format_err!("packet with no pts"))?;
-        Ok(VideoFrame {
+        Ok(Frame::Video(VideoFrame {
             pts,
             is_key: pkt.is_key(),
             duration: pkt.duration(),
             data: &self.data,
-        })
+        }))
     }
 }
 
@@ -325,18 +354,25 @@ impl Opener for RetinaOpener {
 
         handle.spawn(async move {
             let r = tokio::time::timeout(RETINA_TIMEOUT, RetinaOpener::play(url, options)).await;
-            let (mut session, video_params, first_frame) =
+            let (mut session, video_params, audio_sample_entry, first_frame) =
                 match r.unwrap_or_else(|_| Err(format_err!("timeout opening stream"))) {
                     Err(e) => {
                         let _ = startup_tx.send(Err(e));
                         return;
                     }
-                    Ok((s, p, f)) => (s, p, f),
+                    Ok((s, p, a, f)) => (s, p, a, f),
                 };
-            if startup_tx.send(Ok(video_params)).is_err() {
+            if startup_tx
+                .send(Ok((video_params, audio_sample_entry)))
+                .is_err()
+            {
                 return;
             }
-            if frame_tx.send(Ok(first_frame)).await.is_err() {
+            if frame_tx
+                .send(Ok(CodecItem::VideoFrame(first_frame)))
+                .await
+                .is_err()
+            {
                 return;
             }
 
@@ -370,7 +406,20 @@ impl Opener for RetinaOpener {
                                 v.start_ctx()
                             );
                         }
-                        if frame_tx.send(Ok(v)).await.is_err() {
+                        if frame_tx.send(Ok(CodecItem::VideoFrame(v))).await.is_err() {
+                            return; // other end died.
+                        }
+                    }
+                    Ok(Some(Ok(CodecItem::AudioFrame(a)))) => {
+                        if a.loss > 0 {
+                            log::warn!(
+                                "{}: lost {} audio RTP packets @ {:?}",
+                                &label,
+                                a.loss,
+                                a.ctx
+                            );
+                        }
+                        if frame_tx.send(Ok(CodecItem::AudioFrame(a))).await.is_err() {
                             return; // other end died.
                         }
                     }
@@ -378,7 +427,7 @@ impl Opener for RetinaOpener {
                 }
             }
         });
-        let video_params = handle.block_on(startup_rx)??;
+        let (video_params, audio_sample_entry) = handle.block_on(startup_rx)??;
         let dims = video_params.pixel_dimensions();
         let extra_data = h264::ExtraData::parse(
             video_params.extra_data(),
@@ -387,7 +436,8 @@ impl Opener for RetinaOpener {
         )?;
         let stream = Box::new(RetinaStream {
             frame_rx,
-            frame: None,
+            item: None,
+            audio_sample_entry,
         });
         Ok((extra_data, stream))
     }
@@ -402,6 +452,7 @@ impl RetinaOpener {
         (
             Pin<Box<retina::client::Demuxed>>,
             Box<VideoParameters>,
+            Option<db::AudioSampleEntryToInsert>,
             retina::codec::VideoFrame,
         ),
         Error,
@@ -424,8 +475,31 @@ impl RetinaOpener {
                     None
                 }
             })
-            .ok_or_else(|| format_err!("couldn't find H.264 video stream"))?;
+            .ok_or_else(|| {
+                // Retina doesn't support H.265 yet, but ffmpeg does.
+                if session
+                    .streams()
+                    .iter()
+                    .any(|s| s.media == "video" && s.encoding_name == "h265")
+                {
+                    format_err!("H.265 video streams require --rtsp-library=ffmpeg")
+                } else {
+                    format_err!("couldn't find H.264 video stream")
+                }
+            })?;
+        let audio = session
+            .streams()
+            .iter()
+            .enumerate()
+            .find_map(|(i, s)| audio_sample_entry(s).map(|e| (i, e)));
         session.setup(video_i).await?;
+        let audio_sample_entry = match audio {
+            None => None,
+            Some((audio_i, entry)) => {
+                session.setup(audio_i).await?;
+                Some(entry)
+            }
+        };
         let session = session.play(retina::client::PlayOptions::default()).await?;
         let mut session = Box::pin(session.demuxed()?);
 
@@ -448,30 +522,95 @@ impl RetinaOpener {
         Ok((
             session,
             video_params.ok_or_else(|| format_err!("couldn't find H.264 parameters"))?,
+            audio_sample_entry,
             first_frame,
         ))
     }
 }
 
+/// Returns an audio sample entry for the given RTSP stream, if it's a supported audio stream:
+/// AAC (`mpeg4-generic`) or G.711 (`PCMU` or `PCMA`).
+fn audio_sample_entry(s: &retina::client::Stream) -> Option<db::AudioSampleEntryToInsert> {
+    if s.media != "audio" {
+        return None;
+    }
+    match s.encoding_name.as_str() {
+        "mpeg4-generic" => {
+            let p = match s.parameters() {
+                Some(retina::codec::Parameters::Audio(p)) => p,
+                _ => return None,
+            };
+            Some(db::AudioSampleEntryToInsert {
+                data: p.sample_entry()?.to_vec(),
+                rfc6381_codec: p.rfc6381_codec()?.to_owned(),
+                clock_rate: s.clock_rate,
+            })
+        }
+        "pcmu" | "pcma" => {
+            let (fourcc, codec) = if s.encoding_name == "pcmu" {
+                (b"ulaw", "ulaw")
+            } else {
+                (b"alaw", "alaw")
+            };
+            let channels = s.channels.map(|c| c.get()).unwrap_or(1);
+            Some(db::AudioSampleEntryToInsert {
+                data: g711_sample_entry(fourcc, channels, s.clock_rate)?,
+                rfc6381_codec: codec.to_owned(),
+                clock_rate: s.clock_rate,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Returns a QuickTime-style sound sample entry for G.711 audio, or `None` if the clock rate
+/// can't be represented in the entry's 16.16 fixed-point sample rate field.
+fn g711_sample_entry(fourcc: &[u8; 4], channels: u16, clock_rate: u32) -> Option<Vec<u8>> {
+    let rate = u16::try_from(clock_rate).ok()?;
+    let mut buf = Vec::with_capacity(36);
+    buf.extend_from_slice(&36u32.to_be_bytes()); // length
+    buf.extend_from_slice(&fourcc[..]);
+    buf.extend_from_slice(&[0u8; 6]); // reserved
+    buf.extend_from_slice(&1u16.to_be_bytes()); // data_reference_index
+    buf.extend_from_slice(&[0u8; 8]); // reserved
+    buf.extend_from_slice(&channels.to_be_bytes()); // channelcount
+    buf.extend_from_slice(&16u16.to_be_bytes()); // samplesize
+    buf.extend_from_slice(&[0u8; 4]); // pre_defined, reserved
+    buf.extend_from_slice(&(u32::from(rate) << 16).to_be_bytes()); // samplerate
+    Some(buf)
+}
+
 struct RetinaStream {
-    frame_rx: tokio::sync::mpsc::Receiver<Result<retina::codec::VideoFrame, Error>>,
-    frame: Option<retina::codec::VideoFrame>,
+    frame_rx: tokio::sync::mpsc::Receiver<Result<CodecItem, Error>>,
+    item: Option<CodecItem>,
+    audio_sample_entry: Option<db::AudioSampleEntryToInsert>,
 }
 
 impl Stream for RetinaStream {
-    fn next(&mut self) -> Result<VideoFrame, Error> {
+    fn next(&mut self) -> Result<Frame, Error> {
         // TODO: use Option::insert after bumping MSRV to 1.53.
-        self.frame = Some(
+        self.item = Some(
             self.frame_rx
                 .blocking_recv()
                 .ok_or_else(|| format_err!("stream ended"))??,
         );
-        let frame = self.frame.as_ref().unwrap();
-        Ok(VideoFrame {
-            pts: frame.timestamp.elapsed(),
-            duration: 0,
-            is_key: frame.is_random_access_point,
-            data: &frame.data()[..],
-        })
+        match self.item.as_ref().unwrap() {
+            CodecItem::VideoFrame(frame) => Ok(Frame::Video(VideoFrame {
+                pts: frame.timestamp.elapsed(),
+                duration: 0,
+                is_key: frame.is_random_access_point,
+                data: &frame.data()[..],
+            })),
+            CodecItem::AudioFrame(frame) => Ok(Frame::Audio(AudioFrame {
+                pts: frame.timestamp.elapsed(),
+                frame_length: i32::try_from(frame.frame_length.get())?,
+                data: &frame.data[..],
+            })),
+            _ => unreachable!(),
+        }
+    }
+
+    fn audio_sample_entry(&self) -> Option<&db::AudioSampleEntryToInsert> {
+        self.audio_sample_entry.as_ref()
     }
 }
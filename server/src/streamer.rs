@@ -14,6 +14,10 @@ use url::Url;
 
 pub static ROTATE_INTERVAL_SEC: i64 = 60;
 
+/// The minimum interval over which to calculate `db::StreamStatus::fps` and `bitrate_bps`. This
+/// is also roughly how often the status is reported while streaming.
+const STATUS_INTERVAL: recording::Duration = recording::Duration(5 * recording::TIME_UNITS_PER_SEC);
+
 /// Common state that can be used by multiple `Streamer` instances.
 pub struct Environment<'a, 'tmp, C>
 where
@@ -42,6 +46,8 @@ where
     opener: &'a dyn stream::Opener,
     transport: retina::client::Transport,
     stream_id: i32,
+    metrics: Arc<db::StreamMetrics>,
+    status: db::StreamStatus,
     session_group: Arc<retina::client::SessionGroup>,
     short_name: String,
     url: Url,
@@ -98,6 +104,8 @@ where
             opener: env.opener,
             transport: stream_transport.unwrap_or(env.default_transport),
             stream_id,
+            metrics: s.metrics.clone(),
+            status: db::StreamStatus::default(),
             session_group,
             short_name: format!("{}-{}", c.short_name, s.type_.as_str()),
             url: url.clone(),
@@ -116,6 +124,7 @@ where
     pub fn run(&mut self) {
         while self.shutdown_rx.check().is_ok() {
             if let Err(e) = self.run_once() {
+                self.metrics.add_reconnect();
                 let sleep_time = time::Duration::seconds(1);
                 warn!(
                     "{}: sleeping for {} after error: {}",
@@ -123,10 +132,33 @@ where
                     sleep_time,
                     base::prettify_failure(&e)
                 );
+                self.status.last_error = Some(e.to_string());
+                self.status.fps = 0.;
+                self.status.bitrate_bps = 0;
+                self.set_state(db::StreamState::BackingOff);
                 self.db.clocks().sleep(sleep_time);
             }
         }
         info!("{}: shutting down", self.short_name);
+        self.status = db::StreamStatus::default();
+        self.report_status();
+    }
+
+    /// Reports `self.status` to the database, which passes it along to any watchers.
+    fn report_status(&self) {
+        // This can fail if the stream was just deleted; the streamer will be stopped shortly.
+        if let Err(e) = self
+            .db
+            .lock()
+            .update_stream_status(self.stream_id, self.status.clone())
+        {
+            warn!("{}: unable to report status: {}", self.short_name, e);
+        }
+    }
+
+    fn set_state(&mut self, state: db::StreamState) {
+        self.status.state = state;
+        self.report_status();
     }
 
     fn run_once(&mut self) -> Result<(), Error> {
@@ -137,6 +169,9 @@ where
         loop {
             let status = self.session_group.stale_sessions();
             if let Some(max_expires) = status.max_expires {
+                if !waited {
+                    self.set_state(db::StreamState::WaitingForStaleSessions);
+                }
                 log::info!(
                     "{}: waiting up to {:?} for TEARDOWN or expiration of {} stale sessions",
                     &self.short_name,
@@ -158,6 +193,7 @@ where
             }
         }
 
+        self.set_state(db::StreamState::Connecting);
         let (extra_data, mut stream) = {
             let _t = TimerGuard::new(&clocks, || format!("opening {}", self.url.as_str()));
             self.opener.open(
@@ -184,7 +220,22 @@ where
             let _t = TimerGuard::new(&clocks, || "inserting video sample entry");
             self.db.lock().insert_video_sample_entry(extra_data.entry)?
         };
+        let audio_sample_entry_id = match stream.audio_sample_entry() {
+            None => None,
+            Some(e) => {
+                let _t = TimerGuard::new(&clocks, || "inserting audio sample entry");
+                Some(self.db.lock().insert_audio_sample_entry(e.clone())?)
+            }
+        };
         let mut seen_key_frame = false;
+        self.status.fps = 0.;
+        self.status.bitrate_bps = 0;
+        self.set_state(db::StreamState::Streaming);
+
+        // The start of the current status interval, and the frames and bytes received since.
+        let mut interval_start: Option<recording::Time> = None;
+        let mut interval_frames = 0u32;
+        let mut interval_bytes = 0u64;
 
         // Seconds since epoch at which to next rotate.
         let mut rotate: Option<i64> = None;
@@ -194,6 +245,7 @@ where
             &self.syncer_channel,
             self.stream_id,
             video_sample_entry_id,
+            audio_sample_entry_id,
         );
         while self.shutdown_rx.check().is_ok() {
             let pkt = {
@@ -201,7 +253,15 @@ where
                 stream.next()
             };
             let pkt = match pkt {
-                Ok(p) => p,
+                Ok(stream::Frame::Video(p)) => {
+                    self.metrics.add_frame_received();
+                    p
+                }
+                Ok(stream::Frame::Audio(a)) => {
+                    // Audio is only recorded within a recording; the writer drops it otherwise.
+                    w.write_audio(a.data, a.pts, a.frame_length)?;
+                    continue;
+                }
                 Err(e) => {
                     let _ = w.close(None, Some(e.to_string()));
                     return Err(e);
@@ -215,6 +275,24 @@ where
             }
             let frame_realtime = clocks.monotonic() + realtime_offset;
             let local_time = recording::Time::new(frame_realtime);
+            match interval_start {
+                None => interval_start = Some(local_time),
+                Some(start) => {
+                    interval_frames += 1;
+                    interval_bytes += pkt.data.len() as u64;
+                    let elapsed = local_time - start;
+                    if elapsed >= STATUS_INTERVAL {
+                        let secs = elapsed.0 as f64 / recording::TIME_UNITS_PER_SEC as f64;
+                        self.status.fps = (f64::from(interval_frames) / secs) as f32;
+                        self.status.bitrate_bps = (interval_bytes as f64 * 8. / secs) as u64;
+                        self.status.last_frame = Some(local_time);
+                        self.report_status();
+                        interval_start = Some(local_time);
+                        interval_frames = 0;
+                        interval_bytes = 0;
+                    }
+                }
+            }
             rotate = if let Some(r) = rotate {
                 if frame_realtime.sec > r && pkt.is_key {
                     trace!("{}: write on normal rotation", self.short_name);
@@ -313,13 +391,16 @@ mod tests {
     }
 
     impl Stream for ProxyingStream {
-        fn next(&mut self) -> Result<stream::VideoFrame, Error> {
+        fn next(&mut self) -> Result<stream::Frame, Error> {
             if self.pkts_left == 0 {
                 bail!("end of stream");
             }
             self.pkts_left -= 1;
 
-            let mut frame = self.inner.next()?;
+            let mut frame = match self.inner.next()? {
+                stream::Frame::Video(v) => v,
+                stream::Frame::Audio(_) => bail!("unexpected audio frame"),
+            };
 
             // XXX: comment wrong.
             // Emulate the behavior of real cameras that send some pre-buffered frames immediately
@@ -348,7 +429,7 @@ mod tests {
                 frame.duration = i32::try_from(3600 * recording::TIME_UNITS_PER_SEC).unwrap();
             }
 
-            Ok(frame)
+            Ok(stream::Frame::Video(frame))
         }
     }
 
@@ -464,6 +545,19 @@ mod tests {
         db.syncer_channel.flush();
         let db = db.db.lock();
 
+        // The second open fails, so the streamer ends up backing off.
+        let s = db.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+        assert_eq!(s.status.state, db::StreamState::BackingOff);
+        assert_eq!(s.status.last_error.as_deref(), Some("done"));
+        assert!(s.status.last_frame.is_some());
+        assert_eq!(s.status.fps, 0.);
+        assert_eq!(
+            s.metrics
+                .frames_received
+                .load(std::sync::atomic::Ordering::Relaxed),
+            10
+        );
+
         // Compare frame-by-frame. Note below that while the rotation is scheduled to happen near
         // 3-second boundaries (such as 2016-04-26 00:00:03), rotation happens somewhat later:
         // * the first rotation is always skipped
@@ -0,0 +1,236 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! TLS termination for the web interface, as configured by `--tls-cert` and `--tls-key`.
+
+use failure::{bail, format_err, Error, ResultExt};
+use futures::{Stream, StreamExt};
+use log::{debug, warn};
+use parking_lot::Mutex;
+use rustls_pemfile::Item;
+use std::convert::TryFrom;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::{
+    self,
+    server::{ClientHello, ResolvesServerCert},
+    sign::{CertifiedKey, SigningKey},
+    Certificate, PrivateKey, SignatureScheme,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+/// The maximum number of TLS handshakes in progress at once. Further connections wait to be
+/// accepted.
+const MAX_CONCURRENT_HANDSHAKES: usize = 64;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Supplies the certificate and key to each TLS handshake, allowing them to be replaced (as on
+/// `SIGHUP`) without interrupting established connections.
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: Mutex<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Result<Self, Error> {
+        let current = Mutex::new(Arc::new(load(&cert_path, &key_path)?));
+        Ok(CertResolver {
+            cert_path,
+            key_path,
+            current,
+        })
+    }
+
+    /// Reloads the certificate and key from their files. On error, including a key which doesn't
+    /// match the certificate, the previous ones remain in use.
+    pub fn reload(&self) -> Result<(), Error> {
+        let k = load(&self.cert_path, &self.key_path)?;
+        *self.current.lock() = Arc::new(k);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.lock().clone())
+    }
+}
+
+/// Returns a server configuration using the given resolver.
+pub fn server_config(resolver: Arc<CertResolver>) -> Arc<rustls::ServerConfig> {
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Arc::new(config)
+}
+
+/// Returns a stream of TLS connections accepted from `listener`, suitable for
+/// `hyper::server::accept::from_stream`. Failed handshakes are logged and skipped.
+pub fn incoming(
+    listener: TcpListener,
+    config: Arc<rustls::ServerConfig>,
+) -> impl Stream<Item = Result<TlsStream<TcpStream>, std::io::Error>> {
+    let acceptor = TlsAcceptor::from(config);
+    futures::stream::unfold(listener, |l| async move {
+        let c = l.accept().await;
+        Some((c, l))
+    })
+    .filter_map(|c| async move {
+        match c {
+            Ok((s, _)) => {
+                if let Err(e) = s.set_nodelay(true) {
+                    warn!("unable to set TCP_NODELAY: {}", e);
+                }
+                Some(s)
+            }
+            Err(e) => {
+                // Likely out of file descriptors; back off rather than spin.
+                warn!("unable to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                None
+            }
+        }
+    })
+    .map(move |s| tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(s)))
+    .buffer_unordered(MAX_CONCURRENT_HANDSHAKES)
+    .filter_map(|r| async move {
+        match r {
+            Ok(Ok(s)) => Some(Ok::<_, std::io::Error>(s)),
+            Ok(Err(e)) => {
+                debug!("TLS handshake failed: {}", e);
+                None
+            }
+            Err(_) => {
+                debug!("TLS handshake timed out");
+                None
+            }
+        }
+    })
+}
+
+/// Loads a PEM-encoded certificate chain and private key.
+fn load(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, Error> {
+    let f = std::fs::File::open(cert_path)
+        .with_context(|_| format!("unable to open --tls-cert={}", cert_path.display()))?;
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(f))
+        .with_context(|_| format!("unable to read --tls-cert={}", cert_path.display()))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        bail!("no certificates in --tls-cert={}", cert_path.display());
+    }
+    let f = std::fs::File::open(key_path)
+        .with_context(|_| format!("unable to open --tls-key={}", key_path.display()))?;
+    let key = rustls_pemfile::read_all(&mut BufReader::new(f))
+        .with_context(|_| format!("unable to read --tls-key={}", key_path.display()))?
+        .into_iter()
+        .find_map(|i| match i {
+            Item::RSAKey(k) | Item::PKCS8Key(k) | Item::ECKey(k) => Some(PrivateKey(k)),
+            _ => None,
+        })
+        .ok_or_else(|| format_err!("no private key in --tls-key={}", key_path.display()))?;
+    let key = rustls::sign::any_supported_type(&key).map_err(|_| {
+        format_err!(
+            "unsupported private key type in --tls-key={}",
+            key_path.display()
+        )
+    })?;
+    check_key_matches(&certs[0], &*key).with_context(|_| {
+        format!(
+            "--tls-key={} doesn't match --tls-cert={}",
+            key_path.display(),
+            cert_path.display()
+        )
+    })?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Checks that `key` belongs to `cert` by signing a message and verifying it with the
+/// certificate's public key.
+fn check_key_matches(cert: &Certificate, key: &dyn SigningKey) -> Result<(), Error> {
+    const MSG: &[u8] = b"moonfire-nvr key check";
+    let cert = webpki::EndEntityCert::try_from(&cert.0[..])
+        .map_err(|e| format_err!("unable to parse certificate: {:?}", e))?;
+    let signer = key
+        .choose_scheme(&[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ED25519,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ])
+        .ok_or_else(|| format_err!("no supported signature scheme for key"))?;
+    let alg = match signer.scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::RSA_PKCS1_SHA256 => &webpki::RSA_PKCS1_2048_8192_SHA256,
+        s => bail!("unexpected signature scheme {:?}", s),
+    };
+    let sig = signer
+        .sign(MSG)
+        .map_err(|e| format_err!("unable to sign: {}", e))?;
+    cert.verify_signature(alg, MSG, &sig)
+        .map_err(|_| format_err!("certificate's public key doesn't match"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::load;
+    use std::path::{Path, PathBuf};
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new("src/testdata").join(format!("tls-{}.pem", name))
+    }
+
+    #[test]
+    fn load_key_types() {
+        for (cert, key) in &[
+            ("rsa-cert", "rsa-key"),
+            ("rsa-cert", "pkcs8-key"),
+            ("ec-cert", "ec-key"),
+        ] {
+            let k = load(&testdata(cert), &testdata(key)).unwrap();
+            assert_eq!(k.cert.len(), 1, "{} {}", cert, key);
+        }
+    }
+
+    #[test]
+    fn load_mismatched_key() {
+        let e = load(&testdata("rsa-cert"), &testdata("ec-key")).unwrap_err();
+        assert!(e.to_string().contains("doesn't match"), "{}", e);
+        let e = load(&testdata("ec-cert"), &testdata("pkcs8-key")).unwrap_err();
+        assert!(e.to_string().contains("doesn't match"), "{}", e);
+    }
+
+    #[test]
+    fn load_empty_cert() {
+        let empty = tempfile::NamedTempFile::new().unwrap();
+        let e = load(empty.path(), &testdata("rsa-key")).unwrap_err();
+        assert!(e.to_string().starts_with("no certificates"), "{}", e);
+    }
+
+    #[test]
+    fn load_missing_key() {
+        // The certificate file has no private key.
+        let e = load(&testdata("rsa-cert"), &testdata("rsa-cert")).unwrap_err();
+        assert!(e.to_string().starts_with("no private key"), "{}", e);
+
+        // The key file doesn't exist.
+        let e = load(&testdata("rsa-cert"), &testdata("nonexistent")).unwrap_err();
+        assert!(
+            e.to_string().starts_with("unable to open --tls-key"),
+            "{}",
+            e
+        );
+    }
+}
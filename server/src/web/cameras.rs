@@ -0,0 +1,471 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! `/api/cameras` handling: camera and stream configuration changes.
+//!
+//! Changes are applied to the database, which notifies `moonfire-nvr run` to restart the
+//! affected streamers.
+
+use base::bail_t;
+use http::{Method, Request, StatusCode};
+use uuid::Uuid;
+
+use crate::json;
+
+use super::{
+    bad_req, check_csrf, extract_json_body, from_base_error, internal_server_err, not_found,
+    plain_response, serve_json, Caller, HttpError, ResponseResult, Service,
+};
+
+fn require_update_camera_configs(caller: &Caller) -> Result<(), HttpError> {
+    if !caller.permissions.update_camera_configs {
+        bail_t!(PermissionDenied, "update_camera_configs required");
+    }
+    Ok(())
+}
+
+/// Converts a requested change to the database's form, checking that the sample file directories
+/// exist.
+fn camera_change(
+    l: &db::LockedDatabase,
+    change: json::CameraChange,
+) -> Result<db::CameraChange, HttpError> {
+    let change = change.into_db().map_err(|e| bad_req(e.to_string()))?;
+    check_sample_file_dirs(l, &change)?;
+    Ok(change)
+}
+
+/// Returns the camera's current configuration, as a starting point for a `json::CameraUpdate`.
+fn current_change(l: &db::LockedDatabase, camera: &db::Camera) -> db::CameraChange {
+    let mut change = db::CameraChange {
+        short_name: camera.short_name.clone(),
+        config: camera.config.clone(),
+        streams: Default::default(),
+    };
+    for (i, id) in camera.streams.iter().enumerate() {
+        if let Some(id) = id {
+            let s = l
+                .streams_by_id()
+                .get(id)
+                .expect("cameras reference valid streams");
+            change.streams[i] = db::StreamChange {
+                sample_file_dir_id: s.sample_file_dir_id,
+                cold_sample_file_dir_id: s.cold_sample_file_dir_id,
+                config: s.config.clone(),
+            };
+        }
+    }
+    change
+}
+
+fn check_sample_file_dirs(
+    l: &db::LockedDatabase,
+    change: &db::CameraChange,
+) -> Result<(), HttpError> {
+    for s in &change.streams {
+        for id in s
+            .sample_file_dir_id
+            .iter()
+            .chain(&s.cold_sample_file_dir_id)
+        {
+            if !l.sample_file_dirs_by_id().contains_key(id) {
+                return Err(bad_req(format!("no such sample file dir {}", id)));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_camera_precondition(
+    l: &db::LockedDatabase,
+    camera: &db::Camera,
+    precondition: &json::CameraSubset,
+) -> Result<(), HttpError> {
+    if matches!(&precondition.short_name, Some(n) if n != &camera.short_name) {
+        bail_t!(FailedPrecondition, "shortName mismatch");
+    }
+    if matches!(&precondition.config, Some(c) if c != &camera.config) {
+        bail_t!(FailedPrecondition, "config mismatch");
+    }
+    for (type_, p) in &precondition.streams {
+        let type_ = db::StreamType::parse(type_)
+            .ok_or_else(|| bad_req(format!("unknown stream type {:?}", type_)))?;
+        let stream = match camera.streams[type_.index()] {
+            Some(id) => l
+                .streams_by_id()
+                .get(&id)
+                .expect("cameras reference valid streams"),
+            None => bail_t!(FailedPrecondition, "no {} stream", type_.as_str()),
+        };
+        if matches!(&p.config, Some(c) if c != &stream.config) {
+            bail_t!(
+                FailedPrecondition,
+                "{} stream config mismatch",
+                type_.as_str()
+            );
+        }
+    }
+    Ok(())
+}
+
+impl Service {
+    pub(super) async fn cameras(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        match *req.method() {
+            Method::POST => self.post_cameras(req, caller).await,
+            _ => Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected").into()),
+        }
+    }
+
+    pub(super) async fn camera(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        match *req.method() {
+            Method::GET | Method::HEAD => self.get_camera(&req, caller, uuid),
+            Method::PUT => self.put_camera(req, caller, uuid).await,
+            Method::DELETE => self.delete_camera(&req, caller, uuid),
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, PUT, or DELETE expected",
+            )
+            .into()),
+        }
+    }
+
+    async fn post_cameras(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        require_update_camera_configs(&caller)?;
+        if !caller.permissions.camera_uuids.is_empty() {
+            bail_t!(
+                PermissionDenied,
+                "adding cameras requires permissions not restricted to particular cameras"
+            );
+        }
+        let r = extract_json_body(&mut req).await?;
+        check_csrf(&req, &caller, Some(&r[..]))?;
+        let r: json::CameraChange =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let mut l = self.db.lock();
+        let change = camera_change(&l, r)?;
+        let id = l.add_camera(change).map_err(|e| bad_req(e.to_string()))?;
+        self.reload_dirs_by_stream_id(&mut l)
+            .map_err(internal_server_err)?;
+        let uuid = l.cameras_by_id().get(&id).expect("camera just added").uuid;
+        serve_json(&req, &json::PostCamerasResponse { id, uuid })
+    }
+
+    async fn put_camera(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        require_update_camera_configs(&caller)?;
+        let r = extract_json_body(&mut req).await?;
+        check_csrf(&req, &caller, Some(&r[..]))?;
+        let r: json::PutCamera = serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let mut l = self.db.lock();
+        let camera = l
+            .get_camera(uuid)
+            .filter(|_| caller.permissions.may_access_camera(uuid))
+            .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+        let id = camera.id;
+        if let Some(precondition) = r.precondition {
+            check_camera_precondition(&l, camera, &precondition)?;
+        }
+        let mut change = current_change(&l, camera);
+        r.update
+            .apply(&mut change)
+            .map_err(|e| bad_req(e.to_string()))?;
+        check_sample_file_dirs(&l, &change)?;
+        l.update_camera(id, change)
+            .map_err(|e| bad_req(e.to_string()))?;
+        self.reload_dirs_by_stream_id(&mut l)
+            .map_err(internal_server_err)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    fn delete_camera(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        require_update_camera_configs(&caller)?;
+        check_csrf(req, &caller, None)?;
+        let mut l = self.db.lock();
+        let id = l
+            .get_camera(uuid)
+            .filter(|_| caller.permissions.may_access_camera(uuid))
+            .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?
+            .id;
+        l.delete_camera(id).map_err(from_base_error)?;
+        self.reload_dirs_by_stream_id(&mut l)
+            .map_err(internal_server_err)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    pub(super) async fn stream_retention(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        type_: db::StreamType,
+    ) -> ResponseResult {
+        if *req.method() != Method::PUT {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "PUT expected").into());
+        }
+        require_update_camera_configs(&caller)?;
+        let r = extract_json_body(&mut req).await?;
+        check_csrf(&req, &caller, Some(&r[..]))?;
+        let r: json::PutStreamRetention =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let mut l = self.db.lock();
+        let camera = l
+            .get_camera(uuid)
+            .filter(|_| caller.permissions.may_access_camera(uuid))
+            .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+        let stream_id = camera.streams[type_.index()]
+            .ok_or_else(|| not_found(format!("no such stream {}/{}", uuid, type_.as_str())))?;
+        let stream = l
+            .streams_by_id()
+            .get(&stream_id)
+            .expect("cameras reference valid streams");
+        let record = stream.config.mode == db::json::STREAM_MODE_RECORD;
+        if let Some(precondition) = r.precondition {
+            if matches!(precondition.record, Some(r) if r != record) {
+                bail_t!(FailedPrecondition, "record mismatch");
+            }
+            if matches!(precondition.retain_bytes, Some(b) if b != stream.config.retain_bytes) {
+                bail_t!(FailedPrecondition, "retainBytes mismatch");
+            }
+            let retain_duration = stream.config.retain_duration_sec;
+            if matches!(precondition.retain_duration, Some(d) if d != retain_duration) {
+                bail_t!(FailedPrecondition, "retainDuration mismatch");
+            }
+        }
+        let change = db::RetentionChange {
+            stream_id,
+            new_record: r.update.record.unwrap_or(record),
+            new_limit: r.update.retain_bytes.unwrap_or(stream.config.retain_bytes),
+            new_retain_duration_sec: r.update.retain_duration,
+        };
+        if change.new_limit < 0 {
+            return Err(bad_req("retainBytes must be non-negative"));
+        }
+        if change.new_record && stream.sample_file_dir_id.is_none() {
+            return Err(bad_req("can't record a stream with no sample file dir"));
+        }
+        l.update_retention(&[change])
+            .map_err(|e| bad_req(e.to_string()))?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web::tests::Server;
+    use db::testutil::{self, TEST_CAMERA_ID, TEST_STREAM_ID};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn requires_permission() {
+        testutil::init();
+        let s = Server::new(Some(db::Permissions::new()));
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .post(&format!("{}/api/cameras", &s.base_url))
+            .json(&json!({"shortName": "new camera"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(s.db.db.lock().cameras_by_id().len(), 1);
+
+        // Permissions restricted to particular cameras can't add more.
+        let mut permissions = db::Permissions::new();
+        permissions.update_camera_configs = true;
+        permissions
+            .camera_uuids
+            .push(s.db.test_camera_uuid.to_string());
+        let s = Server::new(Some(permissions));
+        let resp = cli
+            .post(&format!("{}/api/cameras", &s.base_url))
+            .json(&json!({"shortName": "new camera"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(s.db.db.lock().cameras_by_id().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn add_update_delete() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.update_camera_configs = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let dir_id = s.db.db.lock().streams_by_id()[&TEST_STREAM_ID]
+            .sample_file_dir_id
+            .unwrap();
+
+        // Add a camera.
+        let resp = cli
+            .post(&format!("{}/api/cameras", &s.base_url))
+            .json(&json!({
+                "shortName": "new camera",
+                "config": {"username": "admin", "password": "12345"},
+                "streams": {
+                    "main": {
+                        "sampleFileDirId": dir_id,
+                        "config": {"url": "rtsp://new-camera/main"},
+                    },
+                },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let id = resp["id"].as_i64().unwrap() as i32;
+        let uuid = resp["uuid"].as_str().unwrap().to_owned();
+        let camera_url = format!("{}/api/cameras/{}/", &s.base_url, &uuid);
+
+        // An update with a stale precondition should fail.
+        let resp = cli
+            .put(&camera_url)
+            .json(&json!({
+                "precondition": {"shortName": "old camera"},
+                "update": {"shortName": "renamed camera"},
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        assert_eq!(s.db.db.lock().cameras_by_id()[&id].short_name, "new camera");
+
+        // One with a matching precondition should succeed.
+        let resp = cli
+            .put(&camera_url)
+            .json(&json!({
+                "precondition": {
+                    "shortName": "new camera",
+                    "streams": {"main": {"config": {"url": "rtsp://new-camera/main"}}},
+                },
+                "update": {
+                    "shortName": "renamed camera",
+                    "streams": {
+                        "main": {
+                            "sampleFileDirId": dir_id,
+                            "config": {"url": "rtsp://new-camera/main"},
+                        },
+                    },
+                },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        {
+            let l = s.db.db.lock();
+            let c = &l.cameras_by_id()[&id];
+            assert_eq!(c.short_name, "renamed camera");
+            assert!(c.streams[db::StreamType::Main.index()].is_some());
+        }
+
+        // Start recording the new main stream.
+        let resp = cli
+            .put(&format!("{}main/retention", &camera_url))
+            .json(&json!({
+                "precondition": {"record": false},
+                "update": {"record": true, "retainBytes": 1048576},
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        {
+            let l = s.db.db.lock();
+            let stream_id = l.cameras_by_id()[&id].streams[db::StreamType::Main.index()].unwrap();
+            let stream = &l.streams_by_id()[&stream_id];
+            assert_eq!(stream.config.mode, db::json::STREAM_MODE_RECORD);
+            assert_eq!(stream.config.retain_bytes, 1048576);
+        }
+
+        // Set a duration limit too.
+        let resp = cli
+            .put(&format!("{}main/retention", &camera_url))
+            .json(&json!({"update": {"retainDuration": "30 days"}}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+
+        // A partial update leaves omitted fields alone.
+        let resp = cli
+            .put(&camera_url)
+            .json(&json!({
+                "update": {
+                    "shortName": "driveway",
+                    "config": {"description": "by the garage"},
+                    "streams": {"main": {"config": {"url": "rtsp://new-camera/main2"}}},
+                },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        {
+            let l = s.db.db.lock();
+            let c = &l.cameras_by_id()[&id];
+            assert_eq!(c.short_name, "driveway");
+            assert_eq!(c.config.description, "by the garage");
+            assert_eq!(c.config.password, "12345");
+            let stream_id = c.streams[db::StreamType::Main.index()].unwrap();
+            let stream = &l.streams_by_id()[&stream_id];
+            assert_eq!(stream.sample_file_dir_id, Some(dir_id));
+            assert_eq!(
+                stream.config.url.as_ref().unwrap().as_str(),
+                "rtsp://new-camera/main2"
+            );
+            assert_eq!(stream.config.mode, db::json::STREAM_MODE_RECORD);
+            assert_eq!(stream.config.retain_bytes, 1048576);
+            assert_eq!(stream.config.retain_duration_sec, 30 * 86400);
+        }
+
+        // Unknown fields are rejected rather than ignored.
+        let resp = cli
+            .put(&camera_url)
+            .json(&json!({"update": {"streams": {"main": {"config": {"retainByte": 0}}}}}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        // A null stream is removed.
+        let resp = cli
+            .put(&camera_url)
+            .json(&json!({"update": {"streams": {"main": null}}}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        assert!(
+            s.db.db.lock().cameras_by_id()[&id].streams[db::StreamType::Main.index()].is_none()
+        );
+
+        // Delete it.
+        let resp = cli.delete(&camera_url).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let l = s.db.db.lock();
+        assert!(!l.cameras_by_id().contains_key(&id));
+        assert!(l.cameras_by_id().contains_key(&TEST_CAMERA_ID));
+    }
+}
@@ -0,0 +1,496 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! `/api/export.zip` handling.
+//!
+//! An export is a `.zip` archive holding one `.mp4` file per run of recordings in each requested
+//! stream, trimmed to the requested time range, followed by a `manifest.json` describing them.
+//! The archive is streamed as the `.mp4` files are read, so the response has no length or
+//! etag and doesn't support range requests.
+
+use base::bail_t;
+use db::recording::{self, rescale};
+use futures::StreamExt;
+use http::header::{self, HeaderValue};
+use http::{Request, Response};
+use http_serve::Entity;
+use hyper::body::Buf;
+use log::trace;
+use std::borrow::Borrow;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use url::form_urlencoded;
+use uuid::Uuid;
+
+use crate::body::{BodyStream, BoxedError, Chunk};
+use crate::json;
+use crate::mp4;
+use crate::web::{bad_req, from_base_error, internal_server_err, not_found};
+use crate::zip;
+
+use super::{Caller, ResponseResult, Service};
+
+/// A `.mp4` file to include in the archive, before it's been built.
+struct PendingFile {
+    stream_id: i32,
+    builder: mp4::FileBuilder,
+    manifest: json::ExportFile,
+
+    /// The id and open id of the most recently appended recording, and if it has a trailing zero.
+    last: (i32, u32, bool),
+}
+
+impl Service {
+    pub(super) fn export(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.view_video {
+            bail_t!(PermissionDenied, "view_video required");
+        }
+        let mut start_time = None;
+        let mut end_time = None;
+        let mut streams = Vec::new();
+        let mut ts = false;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value): (_, &str) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => {
+                        start_time = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable startTime90k"))?,
+                        )
+                    }
+                    "endTime90k" => {
+                        end_time = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable endTime90k"))?,
+                        )
+                    }
+                    "stream" => {
+                        let mut parts = value.splitn(2, '/');
+                        let uuid = parts.next().and_then(|u| Uuid::from_str(u).ok());
+                        let type_ = parts.next().and_then(db::StreamType::parse);
+                        match (uuid, type_) {
+                            (Some(u), Some(t)) => streams.push((u, t)),
+                            _ => return Err(bad_req(format!("invalid stream {:?}", value))),
+                        }
+                    }
+                    "ts" => ts = value == "true",
+                    _ => return Err(bad_req(format!("parameter {} not understood", key))),
+                }
+            }
+        }
+        let desired_time = match (start_time, end_time) {
+            (Some(s), Some(e)) if s < e => s..e,
+            (Some(_), Some(_)) => bail_t!(InvalidArgument, "endTime90k must be after startTime90k"),
+            _ => bail_t!(InvalidArgument, "startTime90k and endTime90k are required"),
+        };
+        if streams.is_empty() {
+            bail_t!(InvalidArgument, "at least one stream is required");
+        }
+
+        let mut pending = Vec::new();
+        {
+            let db = self.db.lock();
+            for &(uuid, type_) in &streams {
+                let camera = db
+                    .get_camera(uuid)
+                    .filter(|_| caller.permissions.may_access_camera(uuid))
+                    .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+                let stream_id = camera.streams[type_.index()]
+                    .ok_or_else(|| not_found(format!("no such stream {}/{}", uuid, type_)))?;
+                let first_file = pending.len();
+                db.list_recordings_by_time(stream_id, desired_time.clone(), &mut |r| {
+                    let recording_id = r.id.recording();
+                    let has_trailing_zero =
+                        (r.flags & db::RecordingFlags::TrailingZero as i32) != 0;
+
+                    // Start a new file unless this recording directly follows the previous one.
+                    let continues = match pending[first_file..].last() {
+                        Some(f) => {
+                            let (id, open_id, trailing_zero) = f.last;
+                            id + 1 == recording_id && open_id == r.open_id && !trailing_zero
+                        }
+                        None => false,
+                    };
+
+                    // Trim to the requested time range. This is in wall times / wall durations.
+                    let wd = i64::from(r.wall_duration_90k);
+                    let start = cmp::max(0, desired_time.start.0 - r.start.0);
+                    let end = cmp::min(wd, desired_time.end.0 - r.start.0);
+                    if start >= end {
+                        return Ok(());
+                    }
+                    let wr = i32::try_from(start).unwrap()..i32::try_from(end).unwrap();
+                    trace!(
+                        "export: appending recording {} with wall range {:?}",
+                        r.id,
+                        wr
+                    );
+                    let mr = rescale(wr.start, r.wall_duration_90k, r.media_duration_90k)
+                        ..rescale(wr.end, r.wall_duration_90k, r.media_duration_90k);
+                    let rec = json::ExportRecording {
+                        id: recording_id,
+                        open_id: r.open_id,
+                        start_time_90k: r.start.0 + start,
+                        end_time_90k: r.start.0 + end,
+                        sample_file_blake3: None,
+                    };
+                    if !continues {
+                        let mut builder = mp4::FileBuilder::new(mp4::Type::Normal);
+                        builder.include_timestamp_subtitle_track(ts)?;
+                        builder.include_audio(true);
+                        let tm = time::at(time::Timespec {
+                            sec: recording::Time(rec.start_time_90k).unix_seconds(),
+                            nsec: 0,
+                        });
+                        let filename = format!(
+                            "{}-{}-{}-{}.mp4",
+                            tm.strftime("%Y%m%d%H%M%S").unwrap(),
+                            camera.short_name.replace(&['/', '\\'][..], "_"),
+                            type_.as_str(),
+                            recording_id,
+                        );
+                        pending.push(PendingFile {
+                            stream_id,
+                            builder,
+                            manifest: json::ExportFile {
+                                filename,
+                                camera_uuid: uuid,
+                                camera_short_name: camera.short_name.clone(),
+                                stream_type: type_.as_str(),
+                                start_time_90k: rec.start_time_90k,
+                                end_time_90k: rec.end_time_90k,
+                                blake3: None,
+                                recordings: Vec::new(),
+                            },
+                            last: (recording_id, r.open_id, has_trailing_zero),
+                        });
+                    }
+                    let f = pending.last_mut().unwrap();
+                    f.builder.append(&db, r, mr, true)?;
+                    f.manifest.end_time_90k = rec.end_time_90k;
+                    f.manifest.recordings.push(rec);
+                    f.last = (recording_id, r.open_id, has_trailing_zero);
+                    Ok(())
+                })
+                .map_err(from_base_error)?;
+
+                // Fill in the sample files' hashes.
+                let ids = pending[first_file..]
+                    .iter()
+                    .flat_map(|f| f.manifest.recordings.iter().map(|rec| rec.id));
+                let (min_id, max_id) = match (ids.clone().min(), ids.max()) {
+                    (Some(min), Some(max)) => (min, max),
+                    _ => continue,
+                };
+                let mut blake3s = BTreeMap::new();
+                db.list_sample_file_blake3s(stream_id, min_id..max_id + 1, &mut |id, b| {
+                    blake3s.insert(id.recording(), b);
+                })
+                .map_err(from_base_error)?;
+                for f in &mut pending[first_file..] {
+                    for rec in &mut f.manifest.recordings {
+                        rec.sample_file_blake3 =
+                            blake3s.get(&rec.id).map(|b| base::strutil::hex(&b[..]));
+                    }
+                }
+            }
+        }
+        if pending.is_empty() {
+            bail_t!(NotFound, "no recordings in the requested time range");
+        }
+
+        let mut files = Vec::with_capacity(pending.len());
+        let mut manifest = json::ExportManifest {
+            server_version: env!("CARGO_PKG_VERSION"),
+            start_time_90k: desired_time.start.0,
+            end_time_90k: desired_time.end.0,
+            files: Vec::with_capacity(pending.len()),
+        };
+        for p in pending {
+            trace!(
+                "export: building {} from stream {}",
+                &p.manifest.filename,
+                p.stream_id
+            );
+            files.push(
+                p.builder
+                    .build(self.db.clone(), self.dirs_by_stream_id())
+                    .map_err(from_base_error)?,
+            );
+            manifest.files.push(p.manifest);
+        }
+
+        let tm = time::at(time::Timespec {
+            sec: desired_time.start.unix_seconds(),
+            nsec: 0,
+        });
+        let writer = zip::Writer::new(&tm);
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(async move {
+            if let Err(e) = write_zip(writer, files, manifest, &tx).await {
+                let _ = tx.send(Err(e)).await;
+            }
+        });
+        let body: BodyStream = Box::new(tokio_stream::wrappers::ReceiverStream::new(rx));
+        Ok(Response::builder()
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/zip"),
+            )
+            .header(
+                header::CONTENT_DISPOSITION,
+                HeaderValue::try_from(format!(
+                    "attachment; filename=\"{}-export.zip\"",
+                    tm.strftime("%Y%m%d%H%M%S").unwrap()
+                ))
+                .map_err(internal_server_err)?,
+            )
+            .body(body.into())
+            .expect("hardcoded head should be valid"))
+    }
+}
+
+/// Writes the archive to `tx`: each file in turn, then the manifest, then the central directory.
+async fn write_zip(
+    mut writer: zip::Writer,
+    files: Vec<mp4::File>,
+    mut manifest: json::ExportManifest,
+    tx: &tokio::sync::mpsc::Sender<Result<Chunk, BoxedError>>,
+) -> Result<(), BoxedError> {
+    // An error here means the client went away; there's no one to tell about it.
+    async fn send(
+        tx: &tokio::sync::mpsc::Sender<Result<Chunk, BoxedError>>,
+        c: Chunk,
+    ) -> Result<(), BoxedError> {
+        tx.send(Ok(c)).await.map_err(|_| "receiver dropped".into())
+    }
+
+    for (f, m) in files.iter().zip(manifest.files.iter_mut()) {
+        let len = f.len();
+        send(tx, writer.start_entry(&m.filename, len).into()).await?;
+        let mut crc32 = crc32fast::Hasher::new();
+        let mut blake3 = blake3::Hasher::new();
+        let mut s = std::pin::Pin::from(f.get_range(0..len));
+        while let Some(c) = s.next().await {
+            let c = c?;
+            crc32.update(c.chunk());
+            blake3.update(c.chunk());
+            send(tx, c).await?;
+        }
+        send(tx, writer.finish_entry(crc32.finalize()).into()).await?;
+        m.blake3 = Some(blake3.finalize().to_hex().to_string());
+    }
+
+    let m = serde_json::to_vec_pretty(&manifest)?;
+    send(
+        tx,
+        writer.start_entry("manifest.json", m.len() as u64).into(),
+    )
+    .await?;
+    let crc32 = crc32fast::hash(&m);
+    send(tx, m.into()).await?;
+    send(tx, writer.finish_entry(crc32).into()).await?;
+    send(tx, writer.finish().into()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web::tests::Server;
+    use base::clock::RealClocks;
+    use byteorder::{ByteOrder, LittleEndian};
+    use db::recording::{self, TIME_UNITS_PER_SEC};
+    use db::testutil::{self, TestDb};
+    use db::writer;
+
+    /// Writes a single-recording run of one-second key frames starting at `start`.
+    fn write_run(
+        tdb: &TestDb<RealClocks>,
+        video_sample_entry_id: i32,
+        start: recording::Time,
+        frames: &[&[u8]],
+        trailing_zero: bool,
+    ) {
+        let dir = tdb
+            .dirs_by_stream_id
+            .get(&testutil::TEST_STREAM_ID)
+            .unwrap();
+        let mut w = writer::Writer::new(
+            dir,
+            &tdb.db,
+            &tdb.syncer_channel,
+            testutil::TEST_STREAM_ID,
+            video_sample_entry_id,
+            None,
+        );
+        let mut shutdown_rx = tdb.shutdown_rx.clone();
+        for (i, f) in frames.iter().enumerate() {
+            let pts = TIME_UNITS_PER_SEC * i as i64;
+
+            // Each frame is received as it ends, so the recording starts at `start`.
+            let local_time = start + recording::Duration(pts + TIME_UNITS_PER_SEC);
+            w.write(&mut shutdown_rx, f, local_time, pts, true).unwrap();
+        }
+        let next_pts = TIME_UNITS_PER_SEC * frames.len() as i64;
+        w.close(if trailing_zero { None } else { Some(next_pts) }, None)
+            .unwrap();
+        tdb.syncer_channel.flush();
+    }
+
+    /// Returns the name and data of each entry in an uncompressed, non-ZIP64 archive.
+    fn zip_entries(zip: &[u8]) -> Vec<(String, &[u8])> {
+        let eocd = &zip[zip.len() - 22..];
+        assert_eq!(&eocd[0..4], b"PK\x05\x06");
+        let n = LittleEndian::read_u16(&eocd[10..12]);
+        let mut cd = &zip[LittleEndian::read_u32(&eocd[16..20]) as usize..];
+        let mut entries = Vec::new();
+        for _ in 0..n {
+            assert_eq!(&cd[0..4], b"PK\x01\x02");
+            let len = LittleEndian::read_u32(&cd[20..24]) as usize;
+            let name_len = usize::from(LittleEndian::read_u16(&cd[28..30]));
+            let extra_len = usize::from(LittleEndian::read_u16(&cd[30..32]));
+            let comment_len = usize::from(LittleEndian::read_u16(&cd[32..34]));
+            let offset = LittleEndian::read_u32(&cd[42..46]) as usize;
+            let name = std::str::from_utf8(&cd[46..46 + name_len])
+                .unwrap()
+                .to_owned();
+            let local = &zip[offset..];
+            assert_eq!(&local[0..4], b"PK\x03\x04");
+            let data_start = offset
+                + 30
+                + usize::from(LittleEndian::read_u16(&local[26..28]))
+                + usize::from(LittleEndian::read_u16(&local[28..30]));
+            entries.push((name, &zip[data_start..data_start + len]));
+            cd = &cd[46 + name_len + extra_len + comment_len..];
+        }
+        entries
+    }
+
+    #[tokio::test]
+    async fn export() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.view_video = true;
+        let s = Server::new(Some(permissions));
+        let video_sample_entry_id =
+            s.db.db
+                .lock()
+                .insert_video_sample_entry(db::VideoSampleEntryToInsert {
+                    width: 1920,
+                    height: 1080,
+                    pasp_h_spacing: 1,
+                    pasp_v_spacing: 1,
+                    data: testutil::TEST_VIDEO_SAMPLE_ENTRY_DATA.to_vec(),
+                    rfc6381_codec: "avc1.4d0029".to_owned(),
+                })
+                .unwrap();
+
+        // 2015-04-26 00:00:00 UTC. Recording 0 ends with a trailing zero, so recording 1 must
+        // start a new file even though it has the next id.
+        const START: recording::Time = recording::Time(1430006400i64 * TIME_UNITS_PER_SEC);
+        let second = recording::Duration(TIME_UNITS_PER_SEC);
+        write_run(
+            &s.db,
+            video_sample_entry_id,
+            START,
+            &[b"a1", b"a2", b"a3"],
+            true,
+        );
+        write_run(
+            &s.db,
+            video_sample_entry_id,
+            START + second * 10,
+            &[b"b1", b"b2"],
+            false,
+        );
+
+        // Trim the first second of recording 0, which covers [START, START + 2s).
+        let (start, end) = (START + second, START + second * 12);
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .get(&format!(
+                "{}/api/export.zip?startTime90k={}&endTime90k={}&stream={}/main",
+                &s.base_url, start.0, end.0, s.db.test_camera_uuid
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let zip = resp.bytes().await.unwrap();
+        let entries = zip_entries(&zip);
+        let names: Vec<_> = entries.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            &[
+                "20150425170001-test camera-main-0.mp4",
+                "20150425170010-test camera-main-1.mp4",
+                "manifest.json",
+            ]
+        );
+        let manifest: serde_json::Value = serde_json::from_slice(entries[2].1).unwrap();
+        assert_eq!(manifest["startTime90k"], start.0);
+        assert_eq!(manifest["endTime90k"], end.0);
+        let files = manifest["files"].as_array().unwrap();
+        assert_eq!(files.len(), 2);
+        let expected = [
+            (0, START + second, START + second * 2, &b"a1a2a3"[..]),
+            (1, START + second * 10, START + second * 12, &b"b1b2"[..]),
+        ];
+        for (i, (f, &(id, rec_start, rec_end, sample_file))) in
+            files.iter().zip(expected.iter()).enumerate()
+        {
+            let (name, data) = &entries[i];
+            assert_eq!(f["filename"], name.as_str());
+            assert_eq!(&data[4..8], b"ftyp");
+            assert_eq!(f["blake3"], blake3::hash(data).to_hex().as_str());
+            assert_eq!(f["startTime90k"], rec_start.0);
+            assert_eq!(f["endTime90k"], rec_end.0);
+            let recs = f["recordings"].as_array().unwrap();
+            assert_eq!(recs.len(), 1);
+            assert_eq!(recs[0]["id"], id);
+            assert_eq!(recs[0]["startTime90k"], rec_start.0);
+            assert_eq!(recs[0]["endTime90k"], rec_end.0);
+            assert_eq!(
+                recs[0]["sampleFileBlake3"],
+                blake3::hash(sample_file).to_hex().as_str()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn export_without_time_range() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.view_video = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .get(&format!(
+                "{}/api/export.zip?stream={}/main",
+                &s.base_url, s.db.test_camera_uuid
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn export_requires_view_video() {
+        testutil::init();
+        let s = Server::new(Some(db::Permissions::new()));
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .get(&format!(
+                "{}/api/export.zip?startTime90k=0&endTime90k=90000&stream={}/main",
+                &s.base_url, s.db.test_camera_uuid
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+    }
+}
@@ -13,6 +13,7 @@ use futures::{future::Either, SinkExt, StreamExt};
 use http::{header, Request, Response, StatusCode};
 use log::{info, warn};
 use tokio_tungstenite::tungstenite;
+use url::form_urlencoded;
 use uuid::Uuid;
 
 use crate::{mp4, web::plain_response};
@@ -30,6 +31,15 @@ impl Service {
         if !caller.permissions.view_video {
             bail_t!(PermissionDenied, "view_video required");
         }
+        let mut include_audio = false;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                match &*key {
+                    "audio" => include_audio = value == "true",
+                    _ => bail_t!(InvalidArgument, "parameter {} not understood", key),
+                }
+            }
+        }
 
         let stream_id;
         let open_id;
@@ -45,9 +55,12 @@ impl Service {
                 }
                 Some(o) => o.id,
             };
-            let camera = db.get_camera(uuid).ok_or_else(|| {
-                plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
-            })?;
+            let camera = db
+                .get_camera(uuid)
+                .filter(|_| caller.permissions.may_access_camera(uuid))
+                .ok_or_else(|| {
+                    plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
+                })?;
             stream_id = camera.streams[stream_type.index()].ok_or_else(|| {
                 format_err_t!(NotFound, "no such stream {}/{}", uuid, stream_type)
             })?;
@@ -63,7 +76,7 @@ impl Service {
                 .map_err(|e| bad_req(e.to_string()))?;
         let (parts, _) = response.into_parts();
 
-        tokio::spawn(self.stream_live_m4s_ws(stream_id, open_id, req, sub_rx));
+        tokio::spawn(self.stream_live_m4s_ws(stream_id, open_id, include_audio, req, sub_rx));
 
         Ok(Response::from_parts(parts, Body::from("")))
     }
@@ -72,6 +85,7 @@ impl Service {
         self: Arc<Self>,
         stream_id: i32,
         open_id: u32,
+        include_audio: bool,
         req: hyper::Request<hyper::Body>,
         sub_rx: futures::channel::mpsc::UnboundedReceiver<db::LiveSegment>,
     ) {
@@ -90,7 +104,7 @@ impl Service {
         .await;
 
         if let Err(e) = self
-            .stream_live_m4s_ws_loop(stream_id, open_id, sub_rx, ws)
+            .stream_live_m4s_ws_loop(stream_id, open_id, include_audio, sub_rx, ws)
             .await
         {
             info!("Dropping WebSocket after error: {}", e);
@@ -103,6 +117,7 @@ impl Service {
         self: Arc<Self>,
         stream_id: i32,
         open_id: u32,
+        include_audio: bool,
         sub_rx: futures::channel::mpsc::UnboundedReceiver<db::LiveSegment>,
         mut ws: tokio_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
     ) -> Result<(), Error> {
@@ -124,8 +139,15 @@ impl Service {
                 .unwrap_or_else(|| unreachable!("timer stream never ends"));
             match next {
                 Either::Left(live) => {
-                    self.stream_live_m4s_chunk(open_id, stream_id, &mut ws, live, start_at_key)
-                        .await?;
+                    self.stream_live_m4s_chunk(
+                        open_id,
+                        stream_id,
+                        include_audio,
+                        &mut ws,
+                        live,
+                        start_at_key,
+                    )
+                    .await?;
                     start_at_key = false;
                 }
                 Either::Right(_) => {
@@ -140,11 +162,13 @@ impl Service {
         &self,
         open_id: u32,
         stream_id: i32,
+        include_audio: bool,
         ws: &mut tokio_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
         live: db::LiveSegment,
         start_at_key: bool,
     ) -> Result<(), Error> {
         let mut builder = mp4::FileBuilder::new(mp4::Type::MediaSegment);
+        builder.include_audio(include_audio);
         let mut row = None;
         {
             let db = self.db.lock();
@@ -161,7 +185,7 @@ impl Service {
         }
         let row = row.unwrap();
         use http_serve::Entity;
-        let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id.clone())?;
+        let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id())?;
         let mut hdrs = header::HeaderMap::new();
         mp4.add_headers(&mut hdrs);
         let mime_type = hdrs.get(header::CONTENT_TYPE).unwrap();
@@ -173,7 +197,7 @@ impl Service {
             X-Media-Time-Range: {}-{}\r\n\
             X-Prev-Media-Duration: {}\r\n\
             X-Runs: {}\r\n\
-            X-Video-Sample-Entry-Id: {}\r\n\r\n",
+            X-Video-Sample-Entry-Id: {}\r\n{}\r\n",
             mime_type.to_str().unwrap(),
             row.start.0,
             open_id,
@@ -182,7 +206,11 @@ impl Service {
             live.media_off_90k.end,
             prev_media_duration.0,
             prev_runs + if row.run_offset == 0 { 1 } else { 0 },
-            &row.video_sample_entry_id
+            &row.video_sample_entry_id,
+            match row.audio_sample_entry_id.filter(|_| include_audio) {
+                Some(id) => format!("X-Audio-Sample-Entry-Id: {}\r\n", id),
+                None => String::new(),
+            }
         );
         let mut v = hdr.into_bytes();
         mp4.append_into_vec(&mut v).await?;
@@ -0,0 +1,266 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! `/metrics` handling, in the [Prometheus text exposition
+//! format](https://prometheus.io/docs/instrumenting/exposition_formats/).
+
+use base::bail_t;
+use db::recording::TIME_UNITS_PER_SEC;
+use http::header::{self, HeaderValue};
+use http::{Method, Request, StatusCode};
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::sync::atomic::Ordering;
+
+use super::{plain_response, Caller, ResponseResult, Service};
+
+impl Service {
+    pub(super) fn metrics(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            return Err(
+                plain_response(StatusCode::METHOD_NOT_ALLOWED, "GET or HEAD expected").into(),
+            );
+        }
+        if !caller.permissions.read_camera_configs {
+            bail_t!(PermissionDenied, "read_camera_configs required");
+        }
+        let mut out = String::new();
+        render(&self.db.lock(), &caller.permissions, &mut out);
+        let mut resp = plain_response(StatusCode::OK, out);
+        resp.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        );
+        Ok(resp)
+    }
+}
+
+/// Writes the `# HELP` and `# TYPE` lines introducing a metric family.
+fn write_header(out: &mut String, name: &str, type_: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, type_).unwrap();
+}
+
+/// Writes a label value with the escaping required by the text format.
+fn write_label_value(out: &mut String, v: &str) {
+    out.push('"');
+    for c in v.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// A stream along with its identifying labels.
+struct StreamSample<'a> {
+    camera: &'a str,
+    stream: &'static str,
+    s: &'a db::Stream,
+}
+
+impl<'a> StreamSample<'a> {
+    fn write_labels(&self, out: &mut String) {
+        out.push_str("{camera=");
+        write_label_value(out, self.camera);
+        out.push_str(",stream=");
+        write_label_value(out, self.stream);
+        out.push('}');
+    }
+}
+
+/// Renders the metrics visible with the given permissions: those of cameras it may access, and
+/// of the sample file directories they use. This holds the database lock but does no I/O.
+fn render(db: &db::LockedDatabase, permissions: &db::Permissions, out: &mut String) {
+    let mut streams = Vec::with_capacity(db.streams_by_id().len());
+    let mut dirs = BTreeSet::new();
+    for s in db.streams_by_id().values() {
+        let camera = &db.cameras_by_id()[&s.camera_id];
+        if !permissions.may_access_camera(camera.uuid) {
+            continue;
+        }
+        dirs.extend(s.sample_file_dir_id);
+        dirs.extend(s.cold_sample_file_dir_id);
+        streams.push(StreamSample {
+            camera: &camera.short_name,
+            stream: s.type_.as_str(),
+            s,
+        });
+    }
+
+    type StreamValue = fn(&db::Stream) -> i64;
+    let stream_families: [(&str, &str, &str, StreamValue); 6] = [
+        (
+            "moonfire_stream_frames_received_total",
+            "counter",
+            "Video frames received from the camera since startup.",
+            |s| s.metrics.frames_received.load(Ordering::Relaxed) as i64,
+        ),
+        (
+            "moonfire_stream_bytes_written_total",
+            "counter",
+            "Bytes of video and audio written to sample files since startup.",
+            |s| s.metrics.bytes_written.load(Ordering::Relaxed) as i64,
+        ),
+        (
+            "moonfire_stream_recordings_committed_total",
+            "counter",
+            "Recordings committed to the database since the stream was created.",
+            |s| i64::from(s.cum_recordings()),
+        ),
+        (
+            "moonfire_stream_reconnects_total",
+            "counter",
+            "Times the stream has been reopened after a failure since startup.",
+            |s| s.metrics.reconnects.load(Ordering::Relaxed) as i64,
+        ),
+        (
+            "moonfire_stream_fs_bytes",
+            "gauge",
+            "Bytes used on the filesystem by the stream's committed recordings.",
+            |s| s.fs_bytes,
+        ),
+        (
+            "moonfire_stream_retain_bytes",
+            "gauge",
+            "Configured maximum bytes to retain for the stream.",
+            |s| s.config.retain_bytes,
+        ),
+    ];
+    for (name, type_, help, value) in &stream_families {
+        write_header(out, name, type_, help);
+        for s in &streams {
+            out.push_str(name);
+            s.write_labels(out);
+            writeln!(out, " {}", value(s.s)).unwrap();
+        }
+    }
+
+    write_header(
+        out,
+        "moonfire_stream_last_end_reason_info",
+        "gauge",
+        "Why the stream's most recent recording ended other than by normal rotation.",
+    );
+    for s in &streams {
+        let reason = s.s.metrics.last_end_reason.lock();
+        if let Some(r) = reason.as_deref() {
+            out.push_str("moonfire_stream_last_end_reason_info");
+            s.write_labels(out);
+            out.pop(); // reopen the label set.
+            out.push_str(",reason=");
+            write_label_value(out, r);
+            out.push_str("} 1\n");
+        }
+    }
+
+    write_header(
+        out,
+        "moonfire_dir_garbage_recordings",
+        "gauge",
+        "Recordings in the garbage table awaiting unlink or removal.",
+    );
+    for d in dirs.iter().map(|id| &db.sample_file_dirs_by_id()[id]) {
+        out.push_str("moonfire_dir_garbage_recordings{path=");
+        write_label_value(out, &d.path.to_string_lossy());
+        writeln!(out, "}} {}", d.garbage_len()).unwrap();
+    }
+
+    write_header(
+        out,
+        "moonfire_db_flush_duration_seconds",
+        "summary",
+        "Time spent committing database flushes since startup.",
+    );
+    writeln!(
+        out,
+        "moonfire_db_flush_duration_seconds_sum {}",
+        db.flush_duration().0 as f64 / TIME_UNITS_PER_SEC as f64
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "moonfire_db_flush_duration_seconds_count {}",
+        db.flushes()
+    )
+    .unwrap();
+
+    write_header(
+        out,
+        "moonfire_slow_operations_total",
+        "counter",
+        "Operations which took at least a second since startup.",
+    );
+    writeln!(
+        out,
+        "moonfire_slow_operations_total {}",
+        base::clock::slow_operations()
+    )
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web::tests::Server;
+    use db::testutil::{self, TestDb};
+
+    #[tokio::test]
+    async fn requires_read_camera_configs() {
+        testutil::init();
+        let s = Server::new(Some(db::Permissions::new()));
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .get(&format!("{}/metrics", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+
+        let mut permissions = db::Permissions::new();
+        permissions.read_camera_configs = true;
+        let s = Server::new(Some(permissions));
+        let resp = cli
+            .get(&format!("{}/metrics", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert!(resp.text().await.unwrap().contains("test camera"));
+    }
+
+    #[test]
+    fn render() {
+        testutil::init();
+        let db = TestDb::new(base::clock::RealClocks {});
+        db.db.lock().streams_by_id()[&testutil::TEST_STREAM_ID]
+            .metrics
+            .set_end_reason("quote \" and\nnewline".to_owned());
+        let mut out = String::new();
+        super::render(&db.db.lock(), &db::Permissions::new(), &mut out);
+        assert!(out.contains(
+            "# TYPE moonfire_stream_frames_received_total counter\n\
+             moonfire_stream_frames_received_total{camera=\"test camera\",stream=\"main\"} 0\n"
+        ));
+        assert!(out.contains(
+            "moonfire_stream_last_end_reason_info{camera=\"test camera\",stream=\"main\",\
+             reason=\"quote \\\" and\\nnewline\"} 1\n"
+        ));
+        assert!(out.contains("moonfire_db_flush_duration_seconds_count "));
+        assert!(out.contains("moonfire_dir_garbage_recordings{path="));
+
+        // Permissions scoped to another camera omit this camera's stream and directory.
+        let mut permissions = db::Permissions::new();
+        permissions
+            .camera_uuids
+            .push("35144640-ff1e-4619-b0d5-4c74c185741c".to_owned());
+        let mut out = String::new();
+        super::render(&db.db.lock(), &permissions, &mut out);
+        assert!(!out.contains("test camera"));
+        assert!(!out.contains("moonfire_dir_garbage_recordings{"));
+        assert!(out.contains("moonfire_db_flush_duration_seconds_count "));
+    }
+}
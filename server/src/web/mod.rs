@@ -2,19 +2,26 @@
 // Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
+mod cameras;
+mod export;
 mod live;
+mod metrics;
 mod path;
 mod session;
 mod signals;
+mod snapshot;
 mod static_file;
+mod status;
+mod users;
 mod view;
+mod websocket;
 
 use self::path::Path;
 use crate::body::Body;
 use crate::json;
 use crate::mp4;
+use base::clock::Clocks;
 use base::{bail_t, ErrorKind};
-use base::{clock::Clocks, format_err_t};
 use core::borrow::Borrow;
 use core::str::FromStr;
 use db::dir::SampleFileDir;
@@ -27,7 +34,8 @@ use http::{status::StatusCode, Request, Response};
 use http_serve::dir::FsDir;
 use hyper::body::Bytes;
 use log::{debug, warn};
-use std::net::IpAddr;
+use parking_lot::Mutex;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use url::form_urlencoded;
 use uuid::Uuid;
@@ -82,6 +90,7 @@ fn from_base_error(err: base::Error) -> Response<Body> {
         PermissionDenied => StatusCode::FORBIDDEN,
         InvalidArgument | FailedPrecondition => StatusCode::BAD_REQUEST,
         NotFound => StatusCode::NOT_FOUND,
+        ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
         _ => StatusCode::INTERNAL_SERVER_ERROR,
     };
     plain_response(status_code, err.to_string())
@@ -90,6 +99,11 @@ fn from_base_error(err: base::Error) -> Response<Body> {
 struct Caller {
     permissions: db::Permissions,
     user: Option<json::ToplevelUser>,
+
+    /// The session's CSRF token, iff the caller was authenticated by the `s` cookie. Browsers
+    /// send the cookie even on requests initiated by other sites, so mutating requests from such
+    /// callers must also present this token; see `check_csrf`.
+    cookie_csrf: Option<auth::SessionHash>,
 }
 
 type ResponseResult = Result<Response<Body>, HttpError>;
@@ -112,6 +126,53 @@ fn csrf_matches(csrf: &str, session: auth::SessionHash) -> bool {
     ::ring::constant_time::verify_slices_are_equal(&b64[..], csrf.as_bytes()).is_ok()
 }
 
+/// Checks the CSRF token of a mutating request from a caller authenticated by the `s` cookie.
+/// The token is the `csrf` member of the JSON request body `body` or, for requests without a
+/// body, the `X-CSRF-Token` header. Other callers need no token.
+fn check_csrf(
+    req: &Request<hyper::Body>,
+    caller: &Caller,
+    body: Option<&[u8]>,
+) -> Result<(), HttpError> {
+    let expected = match caller.cookie_csrf {
+        None => return Ok(()),
+        Some(c) => c,
+    };
+    let csrf = match body {
+        Some(b) => {
+            serde_json::from_slice::<json::CsrfToken>(b)
+                .map_err(|e| bad_req(e.to_string()))?
+                .csrf
+        }
+        None => req
+            .headers()
+            .get("X-CSRF-Token")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned),
+    };
+    match csrf {
+        Some(c) if csrf_matches(&c, expected) => Ok(()),
+        _ => {
+            warn!("mutating request with missing/incorrect csrf");
+            Err(bad_req("missing or incorrect csrf token"))
+        }
+    }
+}
+
+/// Extracts a session id from an `Authorization: Bearer` header, as sent by API token users.
+/// Does not authenticate.
+fn extract_bearer(req: &Request<hyper::Body>) -> Option<auth::RawSessionId> {
+    let hdr = req.headers().get(header::AUTHORIZATION)?;
+    let token = hdr.as_bytes().strip_prefix(b"Bearer ")?;
+    auth::RawSessionId::decode_base64(token).ok()
+}
+
+/// Extracts the session id from a bearer token or, failing that, the `s` cookie. Does not
+/// authenticate.
+fn extract_session_id(req: &Request<hyper::Body>) -> Option<auth::RawSessionId> {
+    extract_bearer(req).or_else(|| extract_sid(req))
+}
+
 /// Extracts `s` cookie from the HTTP request. Does not authenticate.
 fn extract_sid(req: &Request<hyper::Body>) -> Option<auth::RawSessionId> {
     for hdr in req.headers().get_all(header::COOKIE) {
@@ -136,8 +197,8 @@ fn extract_sid(req: &Request<hyper::Body>) -> Option<auth::RawSessionId> {
 /// deserialization. Keeping the bytes allows the caller to use a `Deserialize`
 /// that borrows from the bytes.
 async fn extract_json_body(req: &mut Request<hyper::Body>) -> Result<Bytes, HttpError> {
-    if *req.method() != Method::POST {
-        return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected").into());
+    if *req.method() != Method::POST && *req.method() != Method::PUT {
+        return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST or PUT expected").into());
     }
     let correct_mime_type = match req.headers().get(header::CONTENT_TYPE) {
         Some(t) if t == "application/json" => true,
@@ -157,17 +218,35 @@ pub struct Config<'a> {
     pub db: Arc<db::Database>,
     pub ui_dir: Option<&'a std::path::Path>,
     pub trust_forward_hdrs: bool,
+
+    /// True iff this server terminates TLS itself, so every request arrived securely.
+    pub tls: bool,
+
     pub time_zone_name: String,
     pub allow_unauthenticated_permissions: Option<db::Permissions>,
 }
 
+/// Information about the connection on which a request arrived.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnData {
+    /// The peer's Unix UID, for connections over a Unix domain socket.
+    pub client_unix_uid: Option<libc::uid_t>,
+
+    /// The peer's address, for TCP connections.
+    pub client_addr: Option<SocketAddr>,
+}
+
 pub struct Service {
     db: Arc<db::Database>,
     ui_dir: Option<Arc<FsDir>>,
-    dirs_by_stream_id: Arc<FnvHashMap<i32, Arc<SampleFileDir>>>,
+
+    /// The sample file directory of each stream, replaced as streams change. Shared with the
+    /// services returned by `with_policy`.
+    dirs_by_stream_id: Arc<Mutex<Arc<FnvHashMap<i32, Arc<SampleFileDir>>>>>,
     time_zone_name: String,
     allow_unauthenticated_permissions: Option<db::Permissions>,
     trust_forward_hdrs: bool,
+    tls: bool,
 }
 
 /// Useful HTTP `Cache-Control` values to set on successful (HTTP 200) API responses.
@@ -199,19 +278,9 @@ impl Service {
                 Ok(d) => ui_dir = Some(d),
             };
         }
-        let dirs_by_stream_id = {
-            let l = config.db.lock();
-            let mut d =
-                FnvHashMap::with_capacity_and_hasher(l.streams_by_id().len(), Default::default());
-            for (&id, s) in l.streams_by_id().iter() {
-                let dir_id = match s.sample_file_dir_id {
-                    Some(d) => d,
-                    None => continue,
-                };
-                d.insert(id, l.sample_file_dirs_by_id().get(&dir_id).unwrap().get()?);
-            }
-            Arc::new(d)
-        };
+        let dirs_by_stream_id = Arc::new(Mutex::new(Arc::new(Self::load_dirs_by_stream_id(
+            &config.db.lock(),
+        )?)));
 
         Ok(Service {
             db: config.db,
@@ -219,10 +288,66 @@ impl Service {
             ui_dir,
             allow_unauthenticated_permissions: config.allow_unauthenticated_permissions,
             trust_forward_hdrs: config.trust_forward_hdrs,
+            tls: config.tls,
             time_zone_name: config.time_zone_name,
         })
     }
 
+    /// Returns a service for another listener, with its own authentication policy but sharing
+    /// this service's database and sample file directories.
+    pub fn with_policy(
+        &self,
+        allow_unauthenticated_permissions: Option<db::Permissions>,
+        trust_forward_hdrs: bool,
+        tls: bool,
+    ) -> Self {
+        Service {
+            db: self.db.clone(),
+            ui_dir: self.ui_dir.clone(),
+            dirs_by_stream_id: self.dirs_by_stream_id.clone(),
+            time_zone_name: self.time_zone_name.clone(),
+            allow_unauthenticated_permissions,
+            trust_forward_hdrs,
+            tls,
+        }
+    }
+
+    fn load_dirs_by_stream_id(
+        l: &db::LockedDatabase,
+    ) -> Result<FnvHashMap<i32, Arc<SampleFileDir>>, Error> {
+        let mut d =
+            FnvHashMap::with_capacity_and_hasher(l.streams_by_id().len(), Default::default());
+        for (&id, s) in l.streams_by_id().iter() {
+            let dir_id = match s.sample_file_dir_id {
+                Some(d) => d,
+                None => continue,
+            };
+            d.insert(id, l.sample_file_dirs_by_id().get(&dir_id).unwrap().get()?);
+        }
+        Ok(d)
+    }
+
+    fn dirs_by_stream_id(&self) -> Arc<FnvHashMap<i32, Arc<SampleFileDir>>> {
+        self.dirs_by_stream_id.lock().clone()
+    }
+
+    /// Reloads `dirs_by_stream_id` after a change to the streams, first opening any newly
+    /// referenced sample file directories.
+    fn reload_dirs_by_stream_id(&self, l: &mut db::LockedDatabase) -> Result<(), Error> {
+        let dirs_to_open: Vec<_> = l
+            .streams_by_id()
+            .values()
+            .flat_map(|s| {
+                s.sample_file_dir_id
+                    .into_iter()
+                    .chain(s.cold_sample_file_dir_id)
+            })
+            .collect();
+        l.open_sample_file_dirs(&dirs_to_open)?;
+        *self.dirs_by_stream_id.lock() = Arc::new(Self::load_dirs_by_stream_id(l)?);
+        Ok(())
+    }
+
     /// Serves an HTTP request.
     /// Note that the `serve` wrapper handles responses the same whether they
     /// are `Ok` or `Err`. But returning `Err` here with the `?` operator is
@@ -240,10 +365,18 @@ impl Service {
             ),
             Path::TopLevel => (CacheControl::PrivateDynamic, self.top_level(&req, caller)?),
             Path::Request => (CacheControl::PrivateDynamic, self.request(&req)?),
-            Path::Camera(uuid) => (CacheControl::PrivateDynamic, self.camera(&req, uuid)?),
+            Path::Cameras => (
+                CacheControl::PrivateDynamic,
+                self.cameras(req, caller).await?,
+            ),
+            Path::Camera(uuid) => (
+                CacheControl::PrivateDynamic,
+                self.camera(req, caller, uuid).await?,
+            ),
+            Path::Export => (CacheControl::PrivateDynamic, self.export(&req, caller)?),
             Path::StreamRecordings(uuid, type_) => (
                 CacheControl::PrivateDynamic,
-                self.stream_recordings(&req, uuid, type_)?,
+                self.stream_recordings(&req, caller, uuid, type_)?,
             ),
             Path::StreamViewMp4(uuid, type_, debug) => (
                 CacheControl::PrivateStatic,
@@ -257,18 +390,48 @@ impl Service {
                 CacheControl::PrivateDynamic,
                 self.stream_live_m4s(req, caller, uuid, type_)?,
             ),
+            Path::StreamRetention(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_retention(req, caller, uuid, type_).await?,
+            ),
+            Path::StreamSnapshotJpg(uuid, type_) => {
+                self.stream_snapshot_jpg(&req, caller, uuid, type_).await?
+            }
             Path::NotFound => return Err(not_found("path not understood")),
             Path::Login => (CacheControl::PrivateDynamic, self.login(req).await?),
             Path::Logout => (CacheControl::PrivateDynamic, self.logout(req).await?),
+            Path::Metrics => (CacheControl::PrivateDynamic, self.metrics(&req, caller)?),
             Path::Signals => (
                 CacheControl::PrivateDynamic,
                 self.signals(req, caller).await?,
             ),
+            Path::SignalsLive => (
+                CacheControl::PrivateDynamic,
+                self.signals_live(req, caller)?,
+            ),
             Path::Static => (CacheControl::None, self.static_file(req).await?),
+            Path::StatusLive => (CacheControl::PrivateDynamic, self.status_live(req, caller)?),
+            Path::Users => (CacheControl::PrivateDynamic, self.users(req, caller).await?),
             Path::User(id) => (
                 CacheControl::PrivateDynamic,
                 self.user(req, caller, id).await?,
             ),
+            Path::UserSessions(id) => (
+                CacheControl::PrivateDynamic,
+                self.user_sessions(&req, caller, id)?,
+            ),
+            Path::UserTokens(id) => (
+                CacheControl::PrivateDynamic,
+                self.user_tokens(req, caller, id).await?,
+            ),
+            Path::UserSessionsRevoke(id) => (
+                CacheControl::PrivateDynamic,
+                self.user_sessions_revoke(req, caller, id).await?,
+            ),
+            Path::UserUnlock(id) => (
+                CacheControl::PrivateDynamic,
+                self.user_unlock(&req, caller, id)?,
+            ),
         };
         match cache {
             CacheControl::PrivateStatic => {
@@ -296,15 +459,17 @@ impl Service {
     /// them to hyper as `Ok` results.
     pub async fn serve(
         self: Arc<Self>,
-        req: Request<::hyper::Body>,
+        mut req: Request<::hyper::Body>,
+        conn_data: ConnData,
     ) -> Result<Response<Body>, std::convert::Infallible> {
+        req.extensions_mut().insert(conn_data); // for authreq.
         let p = Path::decode(req.uri().path());
         let always_allow_unauthenticated = matches!(
             p,
             Path::NotFound | Path::Request | Path::Login | Path::Logout | Path::Static
         );
         debug!("request on: {}: {:?}", req.uri(), p);
-        let caller = match self.authenticate(&req, always_allow_unauthenticated) {
+        let caller = match self.authenticate(&req, conn_data, always_allow_unauthenticated) {
             Ok(c) => c,
             Err(e) => return Ok(from_base_error(e)),
         };
@@ -338,18 +503,24 @@ impl Service {
             &json::TopLevel {
                 time_zone_name: &self.time_zone_name,
                 server_version: env!("CARGO_PKG_VERSION"),
-                cameras: (&db, days, camera_configs),
+                cameras: (&db, days, camera_configs, &caller.permissions),
                 user: caller.user,
-                signals: (&db, days),
+                signals: (&db, days, &caller.permissions),
                 signal_types: &db,
             },
         )
     }
 
-    fn camera(&self, req: &Request<::hyper::Body>, uuid: Uuid) -> ResponseResult {
+    fn get_camera(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
         let db = self.db.lock();
         let camera = db
             .get_camera(uuid)
+            .filter(|_| caller.permissions.may_access_camera(uuid))
             .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
         serve_json(
             req,
@@ -360,6 +531,7 @@ impl Service {
     fn stream_recordings(
         &self,
         req: &Request<::hyper::Body>,
+        caller: Caller,
         uuid: Uuid,
         type_: db::StreamType,
     ) -> ResponseResult {
@@ -395,9 +567,12 @@ impl Service {
             recordings: Vec::new(),
             video_sample_entries: (&db, Vec::new()),
         };
-        let camera = db.get_camera(uuid).ok_or_else(|| {
-            plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
-        })?;
+        let camera = db
+            .get_camera(uuid)
+            .filter(|_| caller.permissions.may_access_camera(uuid))
+            .ok_or_else(|| {
+                plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
+            })?;
         let stream_id = camera.streams[type_.index()].ok_or_else(|| {
             plain_response(
                 StatusCode::NOT_FOUND,
@@ -444,8 +619,22 @@ impl Service {
             .get(&id)
             .ok_or_else(|| not_found("not such init segment"))?;
         builder.append_video_sample_entry(ent.clone());
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                if key == "audio" {
+                    let audio_id =
+                        i32::from_str(value).map_err(|_| bad_req("unparseable audio"))?;
+                    let ent = db
+                        .audio_sample_entries_by_id()
+                        .get(&audio_id)
+                        .ok_or_else(|| not_found("no such audio sample entry"))?;
+                    builder.append_audio_sample_entry(ent.clone());
+                }
+            }
+        }
         let mp4 = builder
-            .build(self.db.clone(), self.dirs_by_stream_id.clone())
+            .build(self.db.clone(), self.dirs_by_stream_id())
             .map_err(from_base_error)?;
         if debug {
             Ok(plain_response(StatusCode::OK, format!("{:#?}", mp4)))
@@ -454,39 +643,6 @@ impl Service {
         }
     }
 
-    async fn user(&self, req: Request<hyper::Body>, caller: Caller, id: i32) -> ResponseResult {
-        if caller.user.map(|u| u.id) != Some(id) {
-            bail_t!(Unauthenticated, "must be authenticated as supplied user");
-        }
-        match *req.method() {
-            Method::POST => self.post_user(req, id).await,
-            _ => Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected").into()),
-        }
-    }
-
-    async fn post_user(&self, mut req: Request<hyper::Body>, id: i32) -> ResponseResult {
-        let r = extract_json_body(&mut req).await?;
-        let r: json::PostUser = serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
-        let mut db = self.db.lock();
-        let user = db
-            .users_by_id()
-            .get(&id)
-            .ok_or_else(|| format_err_t!(Internal, "can't find currently authenticated user"))?;
-        if let Some(precondition) = r.precondition {
-            if matches!(precondition.preferences, Some(p) if p != user.config.preferences) {
-                bail_t!(FailedPrecondition, "preferences mismatch");
-            }
-        }
-        if let Some(update) = r.update {
-            let mut change = user.change();
-            if let Some(preferences) = update.preferences {
-                change.config.preferences = preferences;
-            }
-            db.apply_user_change(change).map_err(internal_server_err)?;
-        }
-        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
-    }
-
     fn authreq(&self, req: &Request<::hyper::Body>) -> auth::Request {
         auth::Request {
             when_sec: Some(self.db.clocks().realtime().sec),
@@ -496,7 +652,10 @@ impl Service {
                     .and_then(|v| v.to_str().ok())
                     .and_then(|v| IpAddr::from_str(v).ok())
             } else {
-                None
+                req.extensions()
+                    .get::<ConnData>()
+                    .and_then(|c| c.client_addr)
+                    .map(|a| a.ip())
             },
             user_agent: req
                 .headers()
@@ -538,27 +697,29 @@ impl Service {
         ))
     }
 
-    /// Returns true iff the client is connected over `https`.
-    /// Moonfire NVR currently doesn't directly serve `https`, but it supports
-    /// proxies which set the `X-Forwarded-Proto` header. See `guide/secure.md`
-    /// for more information.
+    /// Returns true iff the client is connected over `https`, either directly (with
+    /// `--tls-cert` and `--tls-key`) or through a proxy which sets the `X-Forwarded-Proto`
+    /// header. See `guide/secure.md` for more information.
     fn is_secure(&self, req: &Request<::hyper::Body>) -> bool {
-        self.trust_forward_hdrs
-            && req
-                .headers()
-                .get("X-Forwarded-Proto")
-                .map(|v| v.as_bytes() == b"https")
-                .unwrap_or(false)
+        self.tls
+            || self.trust_forward_hdrs
+                && req
+                    .headers()
+                    .get("X-Forwarded-Proto")
+                    .map(|v| v.as_bytes() == b"https")
+                    .unwrap_or(false)
     }
 
     /// Authenticates the session (if any) and returns a Caller.
     ///
     /// If there's no session,
-    /// 1.  if `allow_unauthenticated_permissions` is configured, returns okay
+    /// 1.  if the connection is over a Unix domain socket from a UID matching a
+    ///     user's `unix_uid`, returns okay with that user's permissions.
+    /// 2.  if `allow_unauthenticated_permissions` is configured, returns okay
     ///     with those permissions.
-    /// 2.  if the caller specifies `unauth_path`, returns okay with no
+    /// 3.  if the caller specifies `unauth_path`, returns okay with no
     ///     permissions.
-    /// 3.  returns `Unauthenticated` error otherwise.
+    /// 4.  returns `Unauthenticated` error otherwise.
     ///
     /// Does no authorization. That is, this doesn't check that the returned
     /// permissions are sufficient for whatever operation the caller is
@@ -566,9 +727,12 @@ impl Service {
     fn authenticate(
         &self,
         req: &Request<hyper::Body>,
+        conn_data: ConnData,
         unauth_path: bool,
     ) -> Result<Caller, base::Error> {
-        if let Some(sid) = extract_sid(req) {
+        let bearer = extract_bearer(req);
+        let from_cookie = bearer.is_none();
+        if let Some(sid) = bearer.or_else(|| extract_sid(req)) {
             let authreq = self.authreq(req);
 
             match self.db.lock().authenticate_session(authreq, &sid.hash()) {
@@ -581,6 +745,7 @@ impl Service {
                             preferences: u.config.preferences.clone(),
                             session: Some(json::Session { csrf: s.csrf() }),
                         }),
+                        cookie_csrf: if from_cookie { Some(s.csrf()) } else { None },
                     })
                 }
                 Err(e) if e.kind() == base::ErrorKind::Unauthenticated => {
@@ -593,10 +758,37 @@ impl Service {
             };
         }
 
+        if let Some(uid) = conn_data.client_unix_uid {
+            let l = self.db.lock();
+            let u = l
+                .users_by_id()
+                .values()
+                .find(|u| u.config.unix_uid == Some(u64::from(uid)));
+            match u {
+                Some(u) if u.config.disabled => {
+                    warn!("Unix uid {} maps to disabled user {:?}", uid, &u.username);
+                }
+                Some(u) => {
+                    return Ok(Caller {
+                        permissions: u.permissions.clone(),
+                        user: Some(json::ToplevelUser {
+                            id: u.id,
+                            name: u.username.clone(),
+                            preferences: u.config.preferences.clone(),
+                            session: None,
+                        }),
+                        cookie_csrf: None,
+                    });
+                }
+                None => {}
+            }
+        }
+
         if let Some(s) = self.allow_unauthenticated_permissions.as_ref() {
             return Ok(Caller {
                 permissions: s.clone(),
                 user: None,
+                cookie_csrf: None,
             });
         }
 
@@ -604,6 +796,7 @@ impl Service {
             return Ok(Caller {
                 permissions: db::Permissions::default(),
                 user: None,
+                cookie_csrf: None,
             });
         }
 
@@ -628,24 +821,61 @@ mod tests {
 
     impl Server {
         pub(super) fn new(allow_unauthenticated_permissions: Option<db::Permissions>) -> Server {
-            let db = TestDb::new(base::clock::RealClocks {});
+            Server::with_db(
+                TestDb::new(base::clock::RealClocks {}),
+                allow_unauthenticated_permissions,
+            )
+        }
+
+        /// Serves the given database, as opposed to a fresh one from [`TestDb::new`].
+        pub(super) fn with_db(
+            db: TestDb<base::clock::RealClocks>,
+            allow_unauthenticated_permissions: Option<db::Permissions>,
+        ) -> Server {
+            Server::start(db, allow_unauthenticated_permissions, true)
+        }
+
+        /// Ignores `X-Real-IP:` and such, identifying clients by their peer address instead.
+        pub(super) fn without_forward_hdrs(
+            allow_unauthenticated_permissions: Option<db::Permissions>,
+        ) -> Server {
+            Server::start(
+                TestDb::new(base::clock::RealClocks {}),
+                allow_unauthenticated_permissions,
+                false,
+            )
+        }
+
+        fn start(
+            db: TestDb<base::clock::RealClocks>,
+            allow_unauthenticated_permissions: Option<db::Permissions>,
+            trust_forward_hdrs: bool,
+        ) -> Server {
             let (shutdown_tx, shutdown_rx) = futures::channel::oneshot::channel::<()>();
             let service = Arc::new(
                 super::Service::new(super::Config {
                     db: db.db.clone(),
                     ui_dir: None,
                     allow_unauthenticated_permissions,
-                    trust_forward_hdrs: true,
+                    trust_forward_hdrs,
+                    tls: false,
                     time_zone_name: "".to_owned(),
                 })
                 .unwrap(),
             );
-            let make_svc = hyper::service::make_service_fn(move |_conn| {
-                futures::future::ok::<_, std::convert::Infallible>(hyper::service::service_fn({
-                    let s = Arc::clone(&service);
-                    move |req| Arc::clone(&s).serve(req)
-                }))
-            });
+            let make_svc =
+                hyper::service::make_service_fn(move |conn: &hyper::server::conn::AddrStream| {
+                    let conn_data = super::ConnData {
+                        client_addr: Some(conn.remote_addr()),
+                        ..Default::default()
+                    };
+                    futures::future::ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                        {
+                            let s = Arc::clone(&service);
+                            move |req| Arc::clone(&s).serve(req, conn_data)
+                        },
+                    ))
+                });
             let (tx, rx) = std::sync::mpsc::channel();
             let handle = ::std::thread::spawn(move || {
                 let addr = ([127, 0, 0, 1], 0).into();
@@ -666,7 +896,10 @@ mod tests {
             // Create a user.
             let mut c = db::UserChange::add_user("slamb".to_owned());
             c.set_password("hunter2".to_owned());
-            db.db.lock().apply_user_change(c).unwrap();
+            db.db
+                .lock()
+                .apply_user_change(Default::default(), c)
+                .unwrap();
 
             Server {
                 db,
@@ -697,6 +930,138 @@ mod tests {
         assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
     }
 
+    #[tokio::test]
+    async fn top_level_hides_other_cameras() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions
+            .camera_uuids
+            .push("35144640-ff1e-4619-b0d5-4c74c185741c".to_owned());
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let toplevel: serde_json::Value = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(toplevel["cameras"].as_array().unwrap().len(), 0);
+
+        let mut permissions = db::Permissions::new();
+        permissions
+            .camera_uuids
+            .push(s.db.test_camera_uuid.to_string());
+        let s = Server::new(Some(permissions));
+        let toplevel: serde_json::Value = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(toplevel["cameras"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn other_camera_not_found() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.view_video = true;
+        permissions
+            .camera_uuids
+            .push("35144640-ff1e-4619-b0d5-4c74c185741c".to_owned());
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        for path in &["view.mp4?s=1", "live.m4s", "snapshot.jpg?live=true"] {
+            let resp = cli
+                .get(&format!(
+                    "{}/api/cameras/{}/main/{}",
+                    &s.base_url, s.db.test_camera_uuid, path
+                ))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[test]
+    fn unix_uid_authentication() {
+        testutil::init();
+        let db = TestDb::new(base::clock::RealClocks {});
+        let mut c = db::UserChange::add_user("local".to_owned());
+        c.config.unix_uid = Some(1000);
+        c.permissions.view_video = true;
+        db.db
+            .lock()
+            .apply_user_change(Default::default(), c)
+            .unwrap();
+        let svc = super::Service::new(super::Config {
+            db: db.db.clone(),
+            ui_dir: None,
+            allow_unauthenticated_permissions: None,
+            trust_forward_hdrs: false,
+            tls: false,
+            time_zone_name: "".to_owned(),
+        })
+        .unwrap();
+        let req = Request::builder()
+            .uri("/api/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let conn_data = |uid| super::ConnData {
+            client_unix_uid: Some(uid),
+            client_addr: None,
+        };
+        let caller = svc.authenticate(&req, conn_data(1000), false).unwrap();
+        assert!(caller.permissions.view_video);
+        assert!(caller.cookie_csrf.is_none()); // a Unix socket peer isn't subject to CSRF.
+        assert_eq!(caller.user.unwrap().name, "local");
+        let e = svc
+            .authenticate(&req, conn_data(1001), false)
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::Unauthenticated);
+    }
+
+    #[test]
+    fn with_policy() {
+        testutil::init();
+        let db = TestDb::new(base::clock::RealClocks {});
+        let svc = super::Service::new(super::Config {
+            db: db.db.clone(),
+            ui_dir: None,
+            allow_unauthenticated_permissions: None,
+            trust_forward_hdrs: false,
+            tls: false,
+            time_zone_name: "".to_owned(),
+        })
+        .unwrap();
+        let mut perms = db::Permissions::new();
+        perms.view_video = true;
+        let local = svc.with_policy(Some(perms), false, false);
+        let req = Request::builder()
+            .uri("/api/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let e = svc
+            .authenticate(&req, super::ConnData::default(), false)
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::Unauthenticated);
+        let caller = local
+            .authenticate(&req, super::ConnData::default(), false)
+            .unwrap();
+        assert!(caller.permissions.view_video);
+        assert!(Arc::ptr_eq(
+            &svc.dirs_by_stream_id,
+            &local.dirs_by_stream_id
+        ));
+    }
+
     #[test]
     fn test_extract_sid() {
         let req = Request::builder()
@@ -710,6 +1075,28 @@ mod tests {
         let sid = super::extract_sid(&req).unwrap();
         assert_eq!(sid.as_ref(), &b":\xc2\xfa\n\x0e\"\x90\xbc:P\x85\xceOo-#\xeb\xcf{=\xeaX\x00\xa8\xbc\x8f\xa7,u\xb2\x8e\xc5\xb5\x11\x15\xfc\xde\xa4k9\x1d\xe0\xb8\xa7\x9ds\xc2\x0f"[..]);
     }
+
+    #[test]
+    fn test_extract_bearer() {
+        let sid = "OsL6Cg4ikLw6UIXOT28tI+vPez3qWACovI+nLHWyjsW1ERX83qRrOR3guKedc8IP";
+        let req = Request::builder()
+            .header(header::AUTHORIZATION, format!("Bearer {}", sid))
+            .body(hyper::Body::empty())
+            .unwrap();
+        let bearer = super::extract_bearer(&req).unwrap();
+        let req = Request::builder()
+            .header(header::COOKIE, format!("s={}", sid))
+            .body(hyper::Body::empty())
+            .unwrap();
+        let cookie = super::extract_sid(&req).unwrap();
+        assert_eq!(bearer.as_ref(), cookie.as_ref());
+
+        let req = Request::builder()
+            .header(header::AUTHORIZATION, "Basic Zm9vOmJhcg==")
+            .body(hyper::Body::empty())
+            .unwrap();
+        assert!(super::extract_bearer(&req).is_none());
+    }
 }
 
 #[cfg(all(test, feature = "nightly"))]
@@ -738,6 +1125,7 @@ mod bench {
                     ui_dir: None,
                     allow_unauthenticated_permissions: Some(db::Permissions::default()),
                     trust_forward_hdrs: false,
+                    tls: false,
                     time_zone_name: "".to_owned(),
                 })
                 .unwrap(),
@@ -745,7 +1133,7 @@ mod bench {
             let make_svc = hyper::service::make_service_fn(move |_conn| {
                 futures::future::ok::<_, std::convert::Infallible>(hyper::service::service_fn({
                     let s = Arc::clone(&service);
-                    move |req| Arc::clone(&s).serve(req)
+                    move |req| Arc::clone(&s).serve(req, super::ConnData::default())
                 }))
             });
             let rt = tokio::runtime::Runtime::new().unwrap();
@@ -13,32 +13,52 @@ pub(super) enum Path {
     TopLevel,                                         // "/api/"
     Request,                                          // "/api/request"
     InitSegment(i32, bool),                           // "/api/init/<id>.mp4{.txt}"
+    Cameras,                                          // "/api/cameras"
     Camera(Uuid),                                     // "/api/cameras/<uuid>/"
+    Export,                                           // "/api/export.zip"
     Signals,                                          // "/api/signals"
+    SignalsLive,                                      // "/api/signals/live"
+    StatusLive,                                       // "/api/status/live"
     StreamRecordings(Uuid, db::StreamType),           // "/api/cameras/<uuid>/<type>/recordings"
     StreamViewMp4(Uuid, db::StreamType, bool),        // "/api/cameras/<uuid>/<type>/view.mp4{.txt}"
     StreamViewMp4Segment(Uuid, db::StreamType, bool), // "/api/cameras/<uuid>/<type>/view.m4s{.txt}"
     StreamLiveMp4Segments(Uuid, db::StreamType),      // "/api/cameras/<uuid>/<type>/live.m4s"
+    StreamRetention(Uuid, db::StreamType),            // "/api/cameras/<uuid>/<type>/retention"
+    StreamSnapshotJpg(Uuid, db::StreamType),          // "/api/cameras/<uuid>/<type>/snapshot.jpg"
     Login,                                            // "/api/login"
     Logout,                                           // "/api/logout"
+    Metrics,                                          // "/metrics"
     Static,                                           // (anything that doesn't start with "/api/")
+    Users,                                            // "/api/users"
     User(i32),                                        // "/api/users/<id>"
+    UserSessions(i32),                                // "/api/users/<id>/sessions"
+    UserSessionsRevoke(i32),                          // "/api/users/<id>/sessions/revoke"
+    UserTokens(i32),                                  // "/api/users/<id>/tokens"
+    UserUnlock(i32),                                  // "/api/users/<id>/unlock"
     NotFound,
 }
 
 impl Path {
     /// Decodes a request path, notably not including any request parameters.
     pub(super) fn decode(path: &str) -> Self {
+        if path == "/metrics" {
+            return Path::Metrics;
+        }
         let path = match path.strip_prefix("/api/") {
             Some(p) => p,
             None => return Path::Static,
         };
         match path {
             "" => return Path::TopLevel,
+            "cameras" => return Path::Cameras,
+            "export.zip" => return Path::Export,
             "login" => return Path::Login,
             "logout" => return Path::Logout,
             "request" => return Path::Request,
             "signals" => return Path::Signals,
+            "signals/live" => return Path::SignalsLive,
+            "status/live" => return Path::StatusLive,
+            "users" => return Path::Users,
             _ => {}
         };
         if let Some(path) = path.strip_prefix("init/") {
@@ -87,13 +107,24 @@ impl Path {
                 "view.m4s" => Path::StreamViewMp4Segment(uuid, type_, false),
                 "view.m4s.txt" => Path::StreamViewMp4Segment(uuid, type_, true),
                 "live.m4s" => Path::StreamLiveMp4Segments(uuid, type_),
+                "retention" => Path::StreamRetention(uuid, type_),
+                "snapshot.jpg" => Path::StreamSnapshotJpg(uuid, type_),
                 _ => Path::NotFound,
             }
         } else if let Some(path) = path.strip_prefix("users/") {
-            if let Ok(id) = i32::from_str(path) {
-                return Path::User(id);
+            let (id, path) = path.split_once('/').unwrap_or((path, ""));
+            let id = match i32::from_str(id) {
+                Ok(id) => id,
+                Err(_) => return Path::NotFound,
+            };
+            match path {
+                "" => Path::User(id),
+                "sessions" => Path::UserSessions(id),
+                "sessions/revoke" => Path::UserSessionsRevoke(id),
+                "tokens" => Path::UserTokens(id),
+                "unlock" => Path::UserUnlock(id),
+                _ => Path::NotFound,
             }
-            Path::NotFound
         } else {
             Path::NotFound
         }
@@ -108,6 +139,9 @@ mod tests {
         use uuid::Uuid;
         let cam_uuid = Uuid::parse_str("35144640-ff1e-4619-b0d5-4c74c185741c").unwrap();
         assert_eq!(Path::decode("/foo"), Path::Static);
+        assert_eq!(Path::decode("/metrics"), Path::Metrics);
+        assert_eq!(Path::decode("/api/status/live"), Path::StatusLive);
+        assert_eq!(Path::decode("/api/signals/live"), Path::SignalsLive);
         assert_eq!(Path::decode("/api/"), Path::TopLevel);
         assert_eq!(
             Path::decode("/api/init/42.mp4"),
@@ -122,6 +156,7 @@ mod tests {
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/"),
             Path::Camera(cam_uuid)
         );
+        assert_eq!(Path::decode("/api/cameras"), Path::Cameras);
         assert_eq!(Path::decode("/api/cameras/asdf/"), Path::NotFound);
         assert_eq!(
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/recordings"),
@@ -155,6 +190,14 @@ mod tests {
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/live.m4s"),
             Path::StreamLiveMp4Segments(cam_uuid, db::StreamType::Main)
         );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/retention"),
+            Path::StreamRetention(cam_uuid, db::StreamType::Main)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/snapshot.jpg"),
+            Path::StreamSnapshotJpg(cam_uuid, db::StreamType::Sub)
+        );
         assert_eq!(
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/junk"),
             Path::NotFound
@@ -162,8 +205,21 @@ mod tests {
         assert_eq!(Path::decode("/api/login"), Path::Login);
         assert_eq!(Path::decode("/api/logout"), Path::Logout);
         assert_eq!(Path::decode("/api/signals"), Path::Signals);
+        assert_eq!(Path::decode("/api/export.zip"), Path::Export);
         assert_eq!(Path::decode("/api/junk"), Path::NotFound);
+        assert_eq!(Path::decode("/api/users"), Path::Users);
         assert_eq!(Path::decode("/api/users/42"), Path::User(42));
         assert_eq!(Path::decode("/api/users/asdf"), Path::NotFound);
+        assert_eq!(
+            Path::decode("/api/users/42/sessions"),
+            Path::UserSessions(42)
+        );
+        assert_eq!(
+            Path::decode("/api/users/42/sessions/revoke"),
+            Path::UserSessionsRevoke(42)
+        );
+        assert_eq!(Path::decode("/api/users/42/tokens"), Path::UserTokens(42));
+        assert_eq!(Path::decode("/api/users/42/unlock"), Path::UserUnlock(42));
+        assert_eq!(Path::decode("/api/users/42/junk"), Path::NotFound);
     }
 }
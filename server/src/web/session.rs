@@ -12,8 +12,8 @@ use memchr::memchr;
 use crate::json;
 
 use super::{
-    bad_req, csrf_matches, extract_json_body, extract_sid, internal_server_err, plain_response,
-    ResponseResult, Service,
+    bad_req, csrf_matches, extract_json_body, extract_sid, internal_server_err, ResponseResult,
+    Service,
 };
 use std::convert::TryFrom;
 
@@ -52,9 +52,8 @@ impl Service {
             } else {
                 0
             };
-        let (sid, _) = l
-            .login_by_password(authreq, &r.username, r.password, Some(domain), flags)
-            .map_err(|e| plain_response(StatusCode::UNAUTHORIZED, e.to_string()))?;
+        let (sid, _) =
+            l.login_by_password(authreq, &r.username, r.password, Some(domain), flags)?;
         let cookie = encode_sid(sid, flags);
         Ok(Response::builder()
             .header(
@@ -133,7 +132,7 @@ fn encode_sid(sid: db::RawSessionId, flags: i32) -> String {
 }
 
 #[cfg(test)]
-mod tests {
+pub(super) mod tests {
     use db::testutil;
     use fnv::FnvHashMap;
     use log::info;
@@ -255,6 +254,39 @@ mod tests {
         assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
     }
 
+    /// Without trusted forwarding headers, the client is identified by its peer address, which
+    /// is what login throttling tallies. (`db::db::tests::test_login_throttling` checks the
+    /// throttling itself.)
+    #[tokio::test]
+    async fn peer_addr() {
+        testutil::init();
+        let s = Server::without_forward_hdrs(None);
+        let cli = reqwest::Client::new();
+        let mut p = FnvHashMap::default();
+        p.insert("username", "slamb");
+        p.insert("password", "hunter2");
+        let resp = cli
+            .post(&format!("{}/api/login", &s.base_url))
+            .header("X-Real-IP", "192.0.2.1")
+            .json(&p)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let cookie = SessionCookie::new(resp.headers());
+        let id = s.db.db.lock().get_user("slamb").unwrap().id;
+        let resp: serde_json::Value = cli
+            .get(&format!("{}/api/users/{}/sessions", &s.base_url, id))
+            .header(reqwest::header::COOKIE, cookie.header())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(resp["sessions"][0]["creation"]["addr"], "127.0.0.1");
+    }
+
     #[test]
     fn encode_sid() {
         use super::encode_sid;
@@ -281,7 +313,7 @@ mod tests {
     }
 
     #[derive(Clone, Debug, Default)]
-    struct SessionCookie(Option<String>);
+    pub(crate) struct SessionCookie(Option<String>);
 
     impl SessionCookie {
         pub fn new(headers: &reqwest::header::HeaderMap) -> Self {
@@ -312,4 +344,22 @@ mod tests {
             self.0.clone().unwrap()
         }
     }
+
+    /// Returns the CSRF token of the session with the given `Cookie` header, retrieved as the UI
+    /// does from the top-level API request.
+    pub(crate) async fn csrf(cli: &reqwest::Client, base_url: &str, cookie: &str) -> String {
+        let toplevel: serde_json::Value = cli
+            .get(&format!("{}/api/", base_url))
+            .header(reqwest::header::COOKIE, cookie)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        toplevel["user"]["session"]["csrf"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
 }
@@ -10,14 +10,30 @@ use http::{Method, Request, StatusCode};
 use url::form_urlencoded;
 
 use crate::json;
+use fnv::FnvHashSet;
 
 use super::{
-    bad_req, extract_json_body, from_base_error, plain_response, serve_json, Caller,
-    ResponseResult, Service,
+    bad_req, check_csrf, extract_json_body, from_base_error, plain_response, serve_json, websocket,
+    Caller, ResponseResult, Service,
 };
 
 use std::borrow::Borrow;
 
+/// Returns the ids of the signals the given permissions allow, or `None` if they aren't
+/// restricted to particular cameras.
+fn accessible_signals(l: &db::LockedDatabase, p: &db::Permissions) -> Option<FnvHashSet<u32>> {
+    if p.camera_uuids.is_empty() {
+        return None;
+    }
+    Some(
+        l.signals_by_id()
+            .values()
+            .filter(|s| l.may_access_signal(p, s))
+            .map(|s| s.id)
+            .collect(),
+    )
+}
+
 impl Service {
     pub(super) async fn signals(
         &self,
@@ -26,7 +42,7 @@ impl Service {
     ) -> ResponseResult {
         match *req.method() {
             Method::POST => self.post_signals(req, caller).await,
-            Method::GET | Method::HEAD => self.get_signals(&req),
+            Method::GET | Method::HEAD => self.get_signals(&req, caller),
             _ => Err(plain_response(
                 StatusCode::METHOD_NOT_ALLOWED,
                 "POST, GET, or HEAD expected",
@@ -40,10 +56,18 @@ impl Service {
             bail_t!(PermissionDenied, "update_signals required");
         }
         let r = extract_json_body(&mut req).await?;
+        check_csrf(&req, &caller, Some(&r[..]))?;
         let r: json::PostSignalsRequest =
             serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
         let now = recording::Time::new(self.db.clocks().realtime());
         let mut l = self.db.lock();
+
+        // Signals the caller may not access are reported as if they didn't exist.
+        if let Some(accessible) = accessible_signals(&l, &caller.permissions) {
+            if let Some(id) = r.signal_ids.iter().find(|id| !accessible.contains(id)) {
+                bail_t!(InvalidArgument, "unknown signal {}", id);
+            }
+        }
         let start = match r.start {
             json::PostSignalsTimeBase::Epoch(t) => t,
             json::PostSignalsTimeBase::Now(d) => now + d,
@@ -57,7 +81,7 @@ impl Service {
         serve_json(&req, &json::PostSignalsResponse { time_90k: now })
     }
 
-    fn get_signals(&self, req: &Request<hyper::Body>) -> ResponseResult {
+    fn get_signals(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
         let mut time = recording::Time::min_value()..recording::Time::max_value();
         if let Some(q) = req.uri().query() {
             for (key, value) in form_urlencoded::parse(q.as_bytes()) {
@@ -77,13 +101,113 @@ impl Service {
         }
 
         let mut signals = json::Signals::default();
-        self.db
-            .lock()
-            .list_changes_by_time(time, &mut |c: &db::signal::ListStateChangesRow| {
-                signals.times_90k.push(c.when);
-                signals.signal_ids.push(c.signal);
-                signals.states.push(c.state);
-            });
+        let l = self.db.lock();
+        let accessible = accessible_signals(&l, &caller.permissions);
+        l.list_changes_by_time(time, &mut |c: &db::signal::ListStateChangesRow| {
+            if matches!(&accessible, Some(a) if !a.contains(&c.signal)) {
+                return;
+            }
+            signals.times_90k.push(c.when);
+            signals.signal_ids.push(c.signal);
+            signals.states.push(c.state);
+        });
+        drop(l);
         serve_json(req, &signals)
     }
+
+    /// Serves `/api/signals/live`, a WebSocket which sends each signal update as it's made.
+    pub(super) fn signals_live(&self, req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        let (sub_tx, sub_rx) = futures::channel::mpsc::unbounded();
+        let mut l = self.db.lock();
+        let accessible = accessible_signals(&l, &caller.permissions);
+        l.watch_signals(Box::new(move |u| {
+            let (signal_ids, states): (Vec<u32>, Vec<u16>) = u
+                .signals
+                .iter()
+                .zip(&u.states)
+                .filter(|(id, _)| accessible.as_ref().map(|a| a.contains(*id)).unwrap_or(true))
+                .unzip();
+            if signal_ids.is_empty() {
+                return !sub_tx.is_closed();
+            }
+            let msg = serde_json::to_string(&json::SignalsUpdate {
+                start_time_90k: u.when.start,
+                end_time_90k: u.when.end,
+                signal_ids: &signal_ids,
+                states: &states,
+            })
+            .expect("signals update should be serializable");
+            sub_tx.unbounded_send(msg).is_ok()
+        }));
+        drop(l);
+        websocket::serve_text_messages(req, sub_rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web::tests::Server;
+    use crate::web::websocket::testutil::{connect, next_json};
+    use db::recording;
+    use db::testutil::{self, TestDb, TEST_SIGNAL_ID};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn signals_live() {
+        testutil::init();
+        let s = Server::with_db(
+            TestDb::new_with_signal(base::clock::RealClocks {}),
+            Some(db::Permissions::new()),
+        );
+        let mut ws = connect(&s.base_url, "/api/signals/live").await;
+
+        let start = recording::Time(1430006400 * recording::TIME_UNITS_PER_SEC);
+        let end = start + recording::Duration(60 * recording::TIME_UNITS_PER_SEC);
+        s.db.db
+            .lock()
+            .update_signals(start..end, &[TEST_SIGNAL_ID], &[2])
+            .unwrap();
+        assert_eq!(
+            next_json(&mut ws).await,
+            json!({
+                "startTime90k": start.0,
+                "endTime90k": end.0,
+                "signalIds": [TEST_SIGNAL_ID],
+                "states": [2],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn post_signals_scoped() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.update_signals = true;
+        permissions
+            .camera_uuids
+            .push("35144640-ff1e-4619-b0d5-4c74c185741c".to_owned());
+        let s = Server::with_db(
+            TestDb::new_with_signal(base::clock::RealClocks {}),
+            Some(permissions),
+        );
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .post(&format!("{}/api/signals", &s.base_url))
+            .json(&json!({
+                "signalIds": [TEST_SIGNAL_ID],
+                "states": [2],
+                "start": {"base": "now", "rel90k": 0},
+                "end": {"base": "now", "rel90k": 60 * recording::TIME_UNITS_PER_SEC},
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        let mut changes = 0;
+        s.db.db.lock().list_changes_by_time(
+            recording::Time::min_value()..recording::Time::max_value(),
+            &mut |_| changes += 1,
+        );
+        assert_eq!(changes, 0);
+    }
 }
@@ -0,0 +1,347 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! `/snapshot.jpg` handling.
+
+use base::bail_t;
+use base::clock::Clocks;
+use db::recording::{self, rescale};
+use futures::TryStreamExt;
+use http::header::{self, HeaderValue};
+use http::{Request, Response, StatusCode};
+use std::borrow::Borrow;
+use std::cmp;
+use std::convert::TryFrom;
+use url::form_urlencoded;
+use uuid::Uuid;
+
+use crate::body::Body;
+
+use super::{
+    bad_req, from_base_error, internal_server_err, not_found, CacheControl, Caller, HttpError,
+    Service,
+};
+
+impl Service {
+    /// Serves a JPEG of the key frame at or before the `time` parameter or, given `live=true`,
+    /// of the most recent key frame.
+    ///
+    /// Returns the cache policy along with the response, as a fixed time's snapshot never
+    /// changes once its recording is committed but a live one does.
+    pub(super) async fn stream_snapshot_jpg(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> Result<(CacheControl, Response<Body>), HttpError> {
+        if !caller.permissions.view_video {
+            bail_t!(PermissionDenied, "view_video required");
+        }
+        let mut time = None;
+        let mut live = false;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "time" => {
+                        time = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable time"))?,
+                        )
+                    }
+                    "live" => live = value == "true",
+                    _ => return Err(bad_req(format!("parameter {} not understood", key))),
+                }
+            }
+        }
+        let (time, lookback) = match (time, live) {
+            (Some(t), false) => (t, recording::Duration(0)),
+
+            // Look back far enough to find the recording in progress, which may have been
+            // extended past the desired duration to end on a key frame.
+            (None, true) => (
+                recording::Time::new(self.db.clocks().realtime()),
+                recording::Duration(2 * recording::DESIRED_RECORDING_WALL_DURATION),
+            ),
+            _ => return Err(bad_req("exactly one of time and live=true is required")),
+        };
+
+        let (cache, entry, dir, cold, segment, key_frame_bytes) = {
+            let db = self.db.lock();
+            let camera = db
+                .get_camera(uuid)
+                .filter(|_| caller.permissions.may_access_camera(uuid))
+                .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+            let stream_id = camera.streams[stream_type.index()]
+                .ok_or_else(|| not_found(format!("no such stream {}/{}", uuid, stream_type)))?;
+
+            // Uncommitted recordings come after the others, so take the latest start.
+            let mut row: Option<db::ListRecordingsRow> = None;
+            db.list_recordings_by_time(
+                stream_id,
+                (time - lookback)..(time + recording::Duration(1)),
+                &mut |r| {
+                    if r.start <= time && row.as_ref().map(|p| p.start < r.start).unwrap_or(true) {
+                        row = Some(r);
+                    }
+                    Ok(())
+                },
+            )
+            .map_err(from_base_error)?;
+            let row = row.ok_or_else(|| not_found(format!("no recording at {}", time)))?;
+
+            // A fixed time's snapshot never changes once its recording is committed. Until then,
+            // the recording may grow past `time`, changing the key frame at or before it.
+            let cache = if live || (row.flags & db::RecordingFlags::Uncommitted as i32) != 0 {
+                CacheControl::PrivateDynamic
+            } else {
+                CacheControl::PrivateStatic
+            };
+
+            let wall_off_90k = cmp::min(
+                time - row.start,
+                recording::Duration(i64::from(row.wall_duration_90k)),
+            );
+            let wall_off_90k = i32::try_from(wall_off_90k.0).unwrap();
+            let media_off_90k =
+                rescale(wall_off_90k, row.wall_duration_90k, row.media_duration_90k);
+            let segment = recording::Segment::new(&db, &row, media_off_90k..media_off_90k, true)
+                .map_err(internal_server_err)?;
+            let mut key_frame_bytes = None;
+            db.with_recording_playback(segment.id, &mut |playback| {
+                segment.foreach(playback, |it| {
+                    key_frame_bytes.get_or_insert(it.bytes);
+                    Ok(())
+                })
+            })
+            .map_err(internal_server_err)?;
+            let key_frame_bytes = key_frame_bytes
+                .ok_or_else(|| not_found(format!("{}: no key frame at {}", segment.id, time)))?;
+
+            let stream = db
+                .streams_by_id()
+                .get(&stream_id)
+                .ok_or_else(|| not_found(format!("no such stream {}", stream_id)))?;
+            let dir = stream
+                .sample_file_dir_id_for(row.id.recording())
+                .ok_or_else(|| not_found(format!("{}: no sample file dir", row.id)))?;
+            let dir = db
+                .sample_file_dirs_by_id()
+                .get(&dir)
+                .unwrap()
+                .get()
+                .map_err(internal_server_err)?;
+
+            // The recording may be moved to the cold directory before it's read.
+            let cold = match stream.cold_sample_file_dir_id {
+                Some(c) if stream.sample_file_dir_id_for(row.id.recording()) != Some(c) => {
+                    db.sample_file_dirs_by_id().get(&c).unwrap().get().ok()
+                }
+                _ => None,
+            };
+            let entry = db
+                .video_sample_entries_by_id()
+                .get(&row.video_sample_entry_id)
+                .unwrap()
+                .clone();
+            (cache, entry, dir, cold, segment, key_frame_bytes)
+        };
+
+        let start = segment.sample_file_range().start;
+        let range = start..start + u64::try_from(key_frame_bytes).unwrap();
+        let key_frame: Vec<u8> = match cold {
+            Some(c) => dir.open_file_or_cold(segment.id, range, &c),
+            None => dir.open_file(segment.id, range),
+        }
+        .try_concat()
+        .await
+        .map_err(internal_server_err)?;
+        let jpeg = tokio::task::spawn_blocking(move || crate::snapshot::jpeg(&entry, &key_frame))
+            .await
+            .map_err(internal_server_err)?
+            .map_err(internal_server_err)?;
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"))
+            .body(jpeg.into())
+            .expect("hardcoded head should be valid");
+        Ok((cache, response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stream::{self, Opener};
+    use crate::web::tests::Server;
+    use base::clock::RealClocks;
+    use byteorder::{BigEndian, ByteOrder};
+    use db::recording::{self, TIME_UNITS_PER_SEC};
+    use db::testutil::{self, TestDb, TEST_STREAM_ID};
+    use db::writer;
+
+    /// Copies `src/testdata/clip.mp4` into a committed recording. Returns the video's width and
+    /// height.
+    fn copy_clip_to_db(db: &mut TestDb<RealClocks>) -> (u16, u16) {
+        let (extra_data, mut input) = stream::FFMPEG
+            .open(
+                "test".to_owned(),
+                stream::Source::File("src/testdata/clip.mp4"),
+            )
+            .unwrap();
+        let dims = (extra_data.entry.width, extra_data.entry.height);
+        let video_sample_entry_id = db
+            .db
+            .lock()
+            .insert_video_sample_entry(extra_data.entry)
+            .unwrap();
+        let dir = db.dirs_by_stream_id.get(&TEST_STREAM_ID).unwrap();
+        let mut output = writer::Writer::new(
+            dir,
+            &db.db,
+            &db.syncer_channel,
+            TEST_STREAM_ID,
+            video_sample_entry_id,
+            None,
+        );
+        let mut end_pts = None;
+        let mut frame_time = recording::Time(1430006400i64 * TIME_UNITS_PER_SEC);
+        loop {
+            let pkt = match input.next() {
+                Ok(stream::Frame::Video(p)) => p,
+                Ok(stream::Frame::Audio(_)) => unreachable!("ffmpeg streams are video-only"),
+                Err(e) if e.to_string().contains("End of file") => break,
+                Err(e) => panic!("unexpected input error: {}", e),
+            };
+            frame_time += recording::Duration(i64::from(pkt.duration));
+            output
+                .write(
+                    &mut db.shutdown_rx,
+                    pkt.data,
+                    frame_time,
+                    pkt.pts,
+                    pkt.is_key,
+                )
+                .unwrap();
+            end_pts = Some(pkt.pts + i64::from(pkt.duration));
+        }
+        output.close(end_pts, None).unwrap();
+        db.syncer_channel.flush();
+        dims
+    }
+
+    /// Returns the width and height from a JPEG's start-of-frame segment.
+    fn jpeg_dims(jpeg: &[u8]) -> (u16, u16) {
+        assert_eq!(&jpeg[0..2], &b"\xff\xd8"[..], "missing start of image");
+        let mut pos = 2;
+        loop {
+            assert_eq!(jpeg[pos], 0xff, "bad marker at {}", pos);
+            let marker = jpeg[pos + 1];
+            if (0xc0..=0xc2).contains(&marker) {
+                let height = BigEndian::read_u16(&jpeg[pos + 5..pos + 7]);
+                let width = BigEndian::read_u16(&jpeg[pos + 7..pos + 9]);
+                return (width, height);
+            }
+            pos += 2 + usize::from(BigEndian::read_u16(&jpeg[pos + 2..pos + 4]));
+        }
+    }
+
+    async fn get(s: &Server, query: &str) -> reqwest::StatusCode {
+        reqwest::get(&format!(
+            "{}/api/cameras/{}/main/snapshot.jpg{}",
+            &s.base_url, s.db.test_camera_uuid, query
+        ))
+        .await
+        .unwrap()
+        .status()
+    }
+
+    #[tokio::test]
+    async fn requires_view_video() {
+        testutil::init();
+        let s = Server::new(Some(db::Permissions::new()));
+        assert_eq!(get(&s, "?live=true").await, reqwest::StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn bad_params() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.view_video = true;
+        let s = Server::new(Some(permissions));
+        for query in &[
+            "",
+            "?live=false",
+            "?time=asdf",
+            "?time=135000000000&live=true",
+            "?foo=bar",
+        ] {
+            assert_eq!(
+                get(&s, query).await,
+                reqwest::StatusCode::BAD_REQUEST,
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn no_recording() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.view_video = true;
+        let s = Server::new(Some(permissions));
+        for query in &["?time=135000000000", "?live=true"] {
+            assert_eq!(
+                get(&s, query).await,
+                reqwest::StatusCode::NOT_FOUND,
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_key_frame() {
+        testutil::init();
+        let mut db = TestDb::new(RealClocks {});
+        let dims = copy_clip_to_db(&mut db);
+        let mut permissions = db::Permissions::new();
+        permissions.view_video = true;
+        let s = Server::with_db(db, Some(permissions));
+        let mut start = None;
+        s.db.db
+            .lock()
+            .list_recordings_by_time(
+                TEST_STREAM_ID,
+                recording::Time::min_value()..recording::Time::max_value(),
+                &mut |r| {
+                    start = Some(r.start);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        let resp = reqwest::get(&format!(
+            "{}/api/cameras/{}/main/snapshot.jpg?time={}",
+            &s.base_url,
+            s.db.test_camera_uuid,
+            start.unwrap().0
+        ))
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(reqwest::header::CONTENT_TYPE).unwrap(),
+            "image/jpeg"
+        );
+
+        // The recording is committed, so the snapshot won't change.
+        assert_eq!(
+            resp.headers().get(reqwest::header::CACHE_CONTROL).unwrap(),
+            "private, max-age=3600"
+        );
+        let jpeg = resp.bytes().await.unwrap();
+        assert_eq!(jpeg_dims(&jpeg), dims);
+    }
+}
@@ -0,0 +1,128 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! `/api/status/live` handling: a WebSocket feed of stream status changes.
+
+use crate::json;
+use fnv::FnvHashSet;
+use http::Request;
+
+use super::{websocket, Caller, ResponseResult, Service};
+
+/// Serializes a status update as a WebSocket message.
+fn update_msg(stream_id: i32, status: &db::StreamStatus) -> String {
+    serde_json::to_string(&json::StreamStatusUpdate {
+        stream_id,
+        status: json::StreamStatus::wrap(status),
+    })
+    .expect("status should be serializable")
+}
+
+impl Service {
+    pub(super) fn status_live(
+        &self,
+        req: Request<::hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        // Snapshot the current status of every stream and subscribe to changes atomically, so
+        // that the client sees every change after its initial state.
+        let (sub_tx, sub_rx) = futures::channel::mpsc::unbounded();
+        {
+            let mut db = self.db.lock();
+            let accessible: FnvHashSet<i32> = db
+                .streams_by_id()
+                .iter()
+                .filter(|(_, s)| {
+                    let c = &db.cameras_by_id()[&s.camera_id];
+                    caller.permissions.may_access_camera(c.uuid)
+                })
+                .map(|(&id, _)| id)
+                .collect();
+            for (&id, s) in db.streams_by_id() {
+                if accessible.contains(&id) {
+                    let _ = sub_tx.unbounded_send(update_msg(id, &s.status));
+                }
+            }
+            db.watch_stream_status(Box::new(move |id, status| {
+                if !accessible.contains(&id) {
+                    return !sub_tx.is_closed();
+                }
+                sub_tx.unbounded_send(update_msg(id, status)).is_ok()
+            }));
+        }
+        websocket::serve_text_messages(req, sub_rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web::tests::Server;
+    use crate::web::websocket::testutil::{connect, next_json};
+    use db::recording;
+    use db::testutil::{self, TEST_STREAM_ID};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn status_live() {
+        testutil::init();
+        let s = Server::new(Some(db::Permissions::new()));
+        let last_frame = recording::Time(1430006400 * recording::TIME_UNITS_PER_SEC);
+        s.db.db
+            .lock()
+            .update_stream_status(
+                TEST_STREAM_ID,
+                db::StreamStatus {
+                    state: db::StreamState::Streaming,
+                    last_error: None,
+                    last_frame: Some(last_frame),
+                    fps: 10.,
+                    bitrate_bps: 1_000_000,
+                },
+            )
+            .unwrap();
+        let mut ws = connect(&s.base_url, "/api/status/live").await;
+
+        // The initial snapshot has the current status.
+        assert_eq!(
+            next_json(&mut ws).await,
+            json!({
+                "streamId": TEST_STREAM_ID,
+                "status": {
+                    "state": "streaming",
+                    "lastFrameTime90k": last_frame.0,
+                    "fps": 10.,
+                    "bitrateBps": 1_000_000,
+                },
+            })
+        );
+
+        // A later change is pushed.
+        s.db.db
+            .lock()
+            .update_stream_status(
+                TEST_STREAM_ID,
+                db::StreamStatus {
+                    state: db::StreamState::BackingOff,
+                    last_error: Some("connection refused".to_owned()),
+                    last_frame: Some(last_frame),
+                    fps: 0.,
+                    bitrate_bps: 0,
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            next_json(&mut ws).await,
+            json!({
+                "streamId": TEST_STREAM_ID,
+                "status": {
+                    "state": "backingOff",
+                    "lastError": "connection refused",
+                    "lastFrameTime90k": last_frame.0,
+                    "fps": 0.,
+                    "bitrateBps": 0,
+                },
+            })
+        );
+    }
+}
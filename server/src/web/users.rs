@@ -0,0 +1,760 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! `/api/users` handling.
+
+use base::bail_t;
+use http::{Method, Request, StatusCode};
+
+use crate::json;
+
+use super::{
+    bad_req, check_csrf, extract_json_body, extract_session_id, internal_server_err, not_found,
+    plain_response, serve_json, Caller, HttpError, ResponseResult, Service,
+};
+
+fn require_admin_users(caller: &Caller) -> Result<(), HttpError> {
+    if !caller.permissions.admin_users {
+        bail_t!(PermissionDenied, "admin_users required");
+    }
+    Ok(())
+}
+
+/// Checks that the caller may view or edit the given user: either it's the caller's own user or
+/// the caller has the `admin_users` permission.
+fn require_self_or_admin(caller: &Caller, id: i32) -> Result<(), HttpError> {
+    if caller.permissions.admin_users {
+        return Ok(());
+    }
+    if caller.user.as_ref().map(|u| u.id) != Some(id) {
+        bail_t!(Unauthenticated, "must be authenticated as supplied user");
+    }
+    Ok(())
+}
+
+/// Applies the fields of `update` to `change`.
+fn apply_subset(
+    l: &db::LockedDatabase,
+    change: &mut db::UserChange,
+    id: Option<i32>,
+    update: json::UserSubset,
+) -> Result<(), HttpError> {
+    if let Some(username) = update.username {
+        if username.is_empty() {
+            return Err(bad_req("username must be non-empty"));
+        }
+        if matches!(l.get_user(&username), Some(u) if Some(u.id) != id) {
+            return Err(bad_req(format!("user {:?} already exists", &username)));
+        }
+        change.username = username;
+    }
+    if let Some(preferences) = update.preferences {
+        change.config.preferences = preferences;
+    }
+    match update.password {
+        None => {}
+        Some(Some(password)) => change.set_password(password),
+        Some(None) => change.clear_password(),
+    }
+    if let Some(permissions) = update.permissions {
+        change.permissions = permissions.into_db();
+    }
+    if let Some(disabled) = update.disabled {
+        change.config.disabled = disabled;
+    }
+    Ok(())
+}
+
+impl Service {
+    pub(super) async fn users(&self, req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        match *req.method() {
+            Method::GET | Method::HEAD => self.get_users(&req, caller),
+            Method::POST => self.post_users(req, caller).await,
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or POST expected",
+            )
+            .into()),
+        }
+    }
+
+    fn get_users(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        require_admin_users(&caller)?;
+        let l = self.db.lock();
+        let users = l.users_by_id().values().map(json::User::wrap).collect();
+        serve_json(req, &json::GetUsersResponse { users })
+    }
+
+    async fn post_users(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        require_admin_users(&caller)?;
+        let r = extract_json_body(&mut req).await?;
+        check_csrf(&req, &caller, Some(&r[..]))?;
+        let r: json::PostUsers = serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let username = match r.user.username.as_ref() {
+            Some(u) => u.clone(),
+            None => return Err(bad_req("username required")),
+        };
+        let mut l = self.db.lock();
+        let mut change = db::UserChange::add_user(username);
+        apply_subset(&l, &mut change, None, r.user)?;
+        let id = l
+            .apply_user_change(self.authreq(&req), change)
+            .map_err(internal_server_err)?
+            .id;
+        serve_json(&req, &json::PostUsersResponse { id })
+    }
+
+    pub(super) async fn user(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        match *req.method() {
+            Method::GET | Method::HEAD => self.get_user(&req, caller, id),
+            Method::POST => self.post_user(req, caller, id).await,
+            Method::DELETE => self.delete_user(&req, caller, id),
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, POST, or DELETE expected",
+            )
+            .into()),
+        }
+    }
+
+    fn get_user(&self, req: &Request<hyper::Body>, caller: Caller, id: i32) -> ResponseResult {
+        require_self_or_admin(&caller, id)?;
+        let l = self.db.lock();
+        let user = l
+            .users_by_id()
+            .get(&id)
+            .ok_or_else(|| not_found(format!("no such user {}", id)))?;
+        serve_json(req, &json::User::wrap(user))
+    }
+
+    async fn post_user(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        require_self_or_admin(&caller, id)?;
+        let r = extract_json_body(&mut req).await?;
+        check_csrf(&req, &caller, Some(&r[..]))?;
+        let r: json::PostUser = serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let mut l = self.db.lock();
+        let user = l
+            .users_by_id()
+            .get(&id)
+            .ok_or_else(|| not_found(format!("no such user {}", id)))?;
+        if let Some(precondition) = r.precondition {
+            if matches!(precondition.username, Some(n) if n != user.username) {
+                bail_t!(FailedPrecondition, "username mismatch");
+            }
+            if matches!(precondition.preferences, Some(p) if p != user.config.preferences) {
+                bail_t!(FailedPrecondition, "preferences mismatch");
+            }
+            if precondition.password.is_some() {
+                return Err(bad_req("password can't be a precondition"));
+            }
+            let permissions = json::Permissions::from(&user.permissions);
+            if matches!(precondition.permissions, Some(p) if p != permissions) {
+                bail_t!(FailedPrecondition, "permissions mismatch");
+            }
+            if matches!(precondition.disabled, Some(d) if d != user.config.disabled) {
+                bail_t!(FailedPrecondition, "disabled mismatch");
+            }
+        }
+        if let Some(update) = r.update {
+            // Users may change their own preferences; anything else requires admin_users.
+            if update.username.is_some()
+                || update.password.is_some()
+                || update.permissions.is_some()
+                || update.disabled.is_some()
+            {
+                require_admin_users(&caller)?;
+            }
+            let mut change = user.change();
+            apply_subset(&l, &mut change, Some(id), update)?;
+            l.apply_user_change(self.authreq(&req), change)
+                .map_err(internal_server_err)?;
+        }
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    fn delete_user(&self, req: &Request<hyper::Body>, caller: Caller, id: i32) -> ResponseResult {
+        require_admin_users(&caller)?;
+        check_csrf(req, &caller, None)?;
+        let mut l = self.db.lock();
+        if !l.users_by_id().contains_key(&id) {
+            return Err(not_found(format!("no such user {}", id)));
+        }
+        l.delete_user(id).map_err(internal_server_err)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    pub(super) fn user_sessions(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            return Err(
+                plain_response(StatusCode::METHOD_NOT_ALLOWED, "GET or HEAD expected").into(),
+            );
+        }
+        require_self_or_admin(&caller, id)?;
+        let current = extract_session_id(req).map(|s| s.hash());
+        let mut l = self.db.lock();
+        if !l.users_by_id().contains_key(&id) {
+            return Err(not_found(format!("no such user {}", id)));
+        }
+        let sessions = l
+            .user_sessions(id)
+            .map_err(internal_server_err)?
+            .into_iter()
+            .map(|(h, s)| json::UserSession::wrap(&h, s, Some(h) == current))
+            .collect();
+        serve_json(req, &json::GetUserSessionsResponse { sessions })
+    }
+
+    /// Creates an API token: a session with no cookie flags, meant to be sent as
+    /// `Authorization: Bearer <token>`.
+    pub(super) async fn user_tokens(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        if *req.method() != Method::POST {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected").into());
+        }
+        require_self_or_admin(&caller, id)?;
+        let r = extract_json_body(&mut req).await?;
+        check_csrf(&req, &caller, Some(&r[..]))?;
+        let r: json::PostUserTokens =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let authreq = self.authreq(&req);
+        if matches!((r.expiration_sec, authreq.when_sec), (Some(e), Some(now)) if e <= now) {
+            return Err(bad_req("expirationSec must be in the future"));
+        }
+        let mut l = self.db.lock();
+        let user = l
+            .users_by_id()
+            .get(&id)
+            .ok_or_else(|| not_found(format!("no such user {}", id)))?;
+        if user.config.disabled {
+            return Err(bad_req(format!("user {:?} is disabled", &user.username)));
+        }
+
+        // A user minting their own token gets the permissions of the session doing so, which may
+        // be narrower than the user's.
+        let permissions = match caller.user {
+            Some(ref u) if u.id == id => caller.permissions,
+            _ => user.permissions.clone(),
+        };
+        let (sid, _) = l
+            .make_session(
+                authreq,
+                id,
+                None,
+                0,
+                permissions,
+                r.description,
+                r.expiration_sec,
+            )
+            .map_err(internal_server_err)?;
+        serve_json(&req, &json::PostUserTokensResponse::wrap(&sid))
+    }
+
+    pub(super) async fn user_sessions_revoke(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        if *req.method() != Method::POST {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected").into());
+        }
+        require_self_or_admin(&caller, id)?;
+        let r = extract_json_body(&mut req).await?;
+        check_csrf(&req, &caller, Some(&r[..]))?;
+        let r: json::PostUserSessionsRevoke =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let hash = db::auth::SessionHash::decode_base64(r.id.as_bytes())
+            .map_err(|_| bad_req("invalid session id"))?;
+        let mut l = self.db.lock();
+        let found = l
+            .user_sessions(id)
+            .map_err(internal_server_err)?
+            .iter()
+            .any(|(h, _)| *h == hash);
+        if !found {
+            return Err(not_found("no such unrevoked session for this user"));
+        }
+        l.revoke_session(
+            db::auth::RevocationReason::UserRevoked,
+            None,
+            self.authreq(&req),
+            &hash,
+        )
+        .map_err(internal_server_err)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    /// Clears a user's failed password logins, so that their next login attempt isn't delayed.
+    pub(super) fn user_unlock(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        if *req.method() != Method::POST {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected").into());
+        }
+        require_admin_users(&caller)?;
+        check_csrf(req, &caller, None)?;
+        let mut l = self.db.lock();
+        if !l.users_by_id().contains_key(&id) {
+            return Err(not_found(format!("no such user {}", id)));
+        }
+        l.unlock_user(id).map_err(internal_server_err)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web::session::tests::{csrf, SessionCookie};
+    use crate::web::tests::Server;
+    use db::testutil;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn requires_admin() {
+        testutil::init();
+        let s = Server::new(Some(db::Permissions::new()));
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .post(&format!("{}/api/users", &s.base_url))
+            .json(&json!({"user": {"username": "guard"}}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+        assert!(s.db.db.lock().get_user("guard").is_none());
+    }
+
+    /// The unlock endpoint resets failures. The resulting delays depend on the time, so they're
+    /// checked in `db::db::tests::test_login_throttling` instead.
+    #[tokio::test]
+    async fn unlock() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.admin_users = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let login_url = format!("{}/api/login", &s.base_url);
+        for _ in 0..5 {
+            let resp = cli
+                .post(&login_url)
+                .json(&json!({"username": "slamb", "password": "asdf"}))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+        }
+
+        let id = s.db.db.lock().get_user("slamb").unwrap().id;
+        let resp = cli
+            .get(&format!("{}/api/users/{}", &s.base_url, id))
+            .send()
+            .await
+            .unwrap();
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(resp["passwordFailureCount"], 5);
+        let resp = cli
+            .post(&format!("{}/api/users/{}/unlock", &s.base_url, id))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let resp = cli
+            .post(&login_url)
+            .json(&json!({"username": "slamb", "password": "hunter2"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+    }
+
+    /// Clearing the password of a user with an unflushed login failure shouldn't break the flush.
+    #[tokio::test]
+    async fn clear_password_after_failure() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.admin_users = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .post(&format!("{}/api/login", &s.base_url))
+            .json(&json!({"username": "slamb", "password": "asdf"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let id = s.db.db.lock().get_user("slamb").unwrap().id;
+        let resp = cli
+            .post(&format!("{}/api/users/{}", &s.base_url, id))
+            .json(&json!({"update": {"password": null}}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let mut l = s.db.db.lock();
+        assert!(!l.get_user("slamb").unwrap().has_password());
+        l.flush("clear_password_after_failure").unwrap();
+    }
+
+    #[tokio::test]
+    async fn list_and_revoke_sessions() {
+        testutil::init();
+        let s = Server::new(None);
+        let cli = reqwest::Client::new();
+        let mut cookies = Vec::new();
+        for _ in 0..2 {
+            let resp = cli
+                .post(&format!("{}/api/login", &s.base_url))
+                .json(&json!({"username": "slamb", "password": "hunter2"}))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+            let cookie = SessionCookie::new(resp.headers());
+            cookies.push(cookie.header());
+        }
+        let id = s.db.db.lock().get_user("slamb").unwrap().id;
+        let sessions_url = format!("{}/api/users/{}/sessions", &s.base_url, id);
+
+        // Both sessions are listed; the one making the request is marked current.
+        let resp = cli
+            .get(&sessions_url)
+            .header(reqwest::header::COOKIE, &cookies[0])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let sessions = resp["sessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        let other = sessions.iter().find(|s| s["current"] == false).unwrap();
+
+        // Revoke the other session from this one.
+        let csrf = csrf(&cli, &s.base_url, &cookies[0]).await;
+        let resp = cli
+            .post(&format!("{}/revoke", &sessions_url))
+            .header(reqwest::header::COOKIE, &cookies[0])
+            .json(&json!({"id": other["id"], "csrf": csrf}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+
+        // The revoked session no longer works.
+        let resp = cli
+            .get(&sessions_url)
+            .header(reqwest::header::COOKIE, &cookies[1])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        // And only the current session remains.
+        let resp = cli
+            .get(&sessions_url)
+            .header(reqwest::header::COOKIE, &cookies[0])
+            .send()
+            .await
+            .unwrap();
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let sessions = resp["sessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0]["current"], true);
+    }
+
+    #[tokio::test]
+    async fn bearer_token() {
+        testutil::init();
+        let s = Server::new(None);
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .post(&format!("{}/api/login", &s.base_url))
+            .json(&json!({"username": "slamb", "password": "hunter2"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let cookie = SessionCookie::new(resp.headers());
+        let csrf = csrf(&cli, &s.base_url, &cookie.header()).await;
+        let id = s.db.db.lock().get_user("slamb").unwrap().id;
+
+        // Create a token.
+        let resp = cli
+            .post(&format!("{}/api/users/{}/tokens", &s.base_url, id))
+            .header(reqwest::header::COOKIE, cookie.header())
+            .json(&json!({"description": "motion detection worker", "csrf": csrf}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let token = resp["token"].as_str().unwrap().to_owned();
+        let token_id = resp["id"].as_str().unwrap().to_owned();
+
+        // The token authenticates requests without any cookie.
+        let bearer = format!("Bearer {}", &token);
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .header(reqwest::header::AUTHORIZATION, &bearer)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+
+        // It's listed among the user's sessions with its description.
+        let sessions_url = format!("{}/api/users/{}/sessions", &s.base_url, id);
+        let resp: serde_json::Value = cli
+            .get(&sessions_url)
+            .header(reqwest::header::AUTHORIZATION, &bearer)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let t = resp["sessions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|s| s["id"] == token_id.as_str())
+            .unwrap();
+        assert_eq!(t["description"], "motion detection worker");
+        assert_eq!(t["current"], true);
+
+        // And it can be revoked.
+        let resp = cli
+            .post(&format!("{}/revoke", &sessions_url))
+            .header(reqwest::header::COOKIE, cookie.header())
+            .json(&json!({ "id": token_id, "csrf": csrf }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .header(reqwest::header::AUTHORIZATION, &bearer)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    /// Mutating requests authenticated by the session cookie need the session's CSRF token;
+    /// those authenticated by a bearer token don't.
+    #[tokio::test]
+    async fn csrf_required_with_cookie() {
+        testutil::init();
+        let s = Server::new(None);
+        let id = {
+            let mut l = s.db.db.lock();
+            let u = l.get_user("slamb").unwrap();
+            let id = u.id;
+            let mut c = u.change();
+            c.permissions.admin_users = true;
+            l.apply_user_change(Default::default(), c).unwrap();
+            id
+        };
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .post(&format!("{}/api/login", &s.base_url))
+            .json(&json!({"username": "slamb", "password": "hunter2"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let cookie = SessionCookie::new(resp.headers()).header();
+        let csrf = csrf(&cli, &s.base_url, &cookie).await;
+        let user_url = format!("{}/api/users/{}", &s.base_url, id);
+        let unlock_url = format!("{}/unlock", &user_url);
+        let update = json!({"update": {"preferences": {"foo": "bar"}}});
+
+        // A JSON body needs a csrf member.
+        let resp = cli
+            .post(&user_url)
+            .header(reqwest::header::COOKIE, &cookie)
+            .json(&update)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        let resp = cli
+            .post(&user_url)
+            .header(reqwest::header::COOKIE, &cookie)
+            .json(&json!({"update": {"preferences": {"foo": "bar"}}, "csrf": "wrong"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        let resp = cli
+            .post(&user_url)
+            .header(reqwest::header::COOKIE, &cookie)
+            .json(&json!({"update": {"preferences": {"foo": "bar"}}, "csrf": &csrf}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+
+        // A request without a body needs the X-CSRF-Token header.
+        let resp = cli
+            .post(&unlock_url)
+            .header(reqwest::header::COOKIE, &cookie)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        let resp = cli
+            .post(&unlock_url)
+            .header(reqwest::header::COOKIE, &cookie)
+            .header("X-CSRF-Token", &csrf)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+
+        // A bearer token needs neither.
+        let resp: serde_json::Value = cli
+            .post(&format!("{}/tokens", &user_url))
+            .header(reqwest::header::COOKIE, &cookie)
+            .json(&json!({"description": "worker", "csrf": &csrf}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let bearer = format!("Bearer {}", resp["token"].as_str().unwrap());
+        let resp = cli
+            .post(&user_url)
+            .header(reqwest::header::AUTHORIZATION, &bearer)
+            .json(&update)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let resp = cli
+            .post(&unlock_url)
+            .header(reqwest::header::AUTHORIZATION, &bearer)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn create_update_delete() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.admin_users = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+
+        // Create a user.
+        let resp = cli
+            .post(&format!("{}/api/users", &s.base_url))
+            .json(&json!({
+                "user": {
+                    "username": "guard",
+                    "password": "hunter2",
+                    "permissions": {"viewVideo": true},
+                },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let id = resp["id"].as_i64().unwrap() as i32;
+        let user_url = format!("{}/api/users/{}", &s.base_url, id);
+        {
+            let l = s.db.db.lock();
+            let u = &l.users_by_id()[&id];
+            assert_eq!(u.username, "guard");
+            assert!(u.has_password());
+            assert!(u.permissions.view_video);
+            assert!(!u.permissions.admin_users);
+        }
+
+        // A duplicate username should fail.
+        let resp = cli
+            .post(&format!("{}/api/users", &s.base_url))
+            .json(&json!({"user": {"username": "guard"}}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        // An update with a stale precondition should fail.
+        let resp = cli
+            .post(&user_url)
+            .json(&json!({
+                "precondition": {"disabled": true},
+                "update": {"password": null},
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        assert!(s.db.db.lock().users_by_id()[&id].has_password());
+
+        // Clear the password and disable the user.
+        let resp = cli
+            .post(&user_url)
+            .json(&json!({
+                "precondition": {"disabled": false, "permissions": {"viewVideo": true}},
+                "update": {"password": null, "disabled": true},
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        {
+            let l = s.db.db.lock();
+            let u = &l.users_by_id()[&id];
+            assert!(!u.has_password());
+            assert!(u.config.disabled);
+        }
+
+        // The user should be listed.
+        let resp = cli
+            .get(&format!("{}/api/users", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let users = resp["users"].as_array().unwrap();
+        assert!(users
+            .iter()
+            .any(|u| u["username"] == "guard" && u["disabled"] == true));
+
+        // Delete the user.
+        let resp = cli.delete(&user_url).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        assert!(s.db.db.lock().get_user("guard").is_none());
+        let resp = cli.delete(&user_url).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+}
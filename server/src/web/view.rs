@@ -46,6 +46,7 @@ impl Service {
             let db = self.db.lock();
             let camera = db
                 .get_camera(uuid)
+                .filter(|_| caller.permissions.may_access_camera(uuid))
                 .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
             camera_name = camera.short_name.clone();
             stream_id = camera.streams[stream_type.index()]
@@ -54,6 +55,10 @@ impl Service {
         let mut start_time_for_filename = None;
         let mut builder = mp4::FileBuilder::new(mp4_type);
         if let Some(q) = req.uri().query() {
+            // This applies to every segment, so it must be set before any are appended.
+            builder.include_audio(
+                form_urlencoded::parse(q.as_bytes()).any(|(k, v)| k == "audio" && v == "true"),
+            );
             for (key, value) in form_urlencoded::parse(q.as_bytes()) {
                 let (key, value) = (key.borrow(), value.borrow());
                 match key {
@@ -177,6 +182,7 @@ impl Service {
                     "ts" => builder
                         .include_timestamp_subtitle_track(value == "true")
                         .map_err(from_base_error)?,
+                    "audio" => {}
                     _ => return Err(bad_req(format!("parameter {} not understood", key))),
                 }
             }
@@ -207,7 +213,7 @@ impl Service {
                 .map_err(from_base_error)?;
         }
         let mp4 = builder
-            .build(self.db.clone(), self.dirs_by_stream_id.clone())
+            .build(self.db.clone(), self.dirs_by_stream_id())
             .map_err(from_base_error)?;
         if debug {
             return Ok(plain_response(StatusCode::OK, format!("{:#?}", mp4)));
@@ -307,6 +313,27 @@ mod tests {
         assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
     }
 
+    #[tokio::test]
+    async fn view_other_camera() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.view_video = true;
+        permissions
+            .camera_uuids
+            .push("35144640-ff1e-4619-b0d5-4c74c185741c".to_owned());
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .get(&format!(
+                "{}/api/cameras/{}/main/view.mp4?s=1",
+                &s.base_url, s.db.test_camera_uuid
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn test_segments() {
@@ -0,0 +1,99 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! WebSocket handling shared by endpoints which push JSON text messages.
+
+use crate::body::Body;
+use failure::Error;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::{future::Either, SinkExt, StreamExt};
+use http::{Request, Response};
+use log::{info, warn};
+use tokio_tungstenite::tungstenite;
+
+use super::{bad_req, ResponseResult};
+
+/// Upgrades `req` to a WebSocket, then sends each message from `rx` as a text message until the
+/// connection is dropped.
+pub(super) fn serve_text_messages(
+    req: Request<::hyper::Body>,
+    rx: UnboundedReceiver<String>,
+) -> ResponseResult {
+    let response =
+        tungstenite::handshake::server::create_response_with_body(&req, hyper::Body::empty)
+            .map_err(|e| bad_req(e.to_string()))?;
+    let (parts, _) = response.into_parts();
+    tokio::spawn(text_messages_ws(req, rx));
+    Ok(Response::from_parts(parts, Body::from("")))
+}
+
+async fn text_messages_ws(req: hyper::Request<hyper::Body>, rx: UnboundedReceiver<String>) {
+    let upgraded = match hyper::upgrade::on(req).await {
+        Ok(u) => u,
+        Err(e) => {
+            warn!("Unable to upgrade stream to websocket: {}", e);
+            return;
+        }
+    };
+    let ws = tokio_tungstenite::WebSocketStream::from_raw_socket(
+        upgraded,
+        tungstenite::protocol::Role::Server,
+        None,
+    )
+    .await;
+
+    if let Err(e) = text_messages_ws_loop(rx, ws).await {
+        info!("Dropping WebSocket after error: {}", e);
+    }
+}
+
+/// Helper for `text_messages_ws` that returns error when the stream is dropped.
+/// The outer function logs the error.
+async fn text_messages_ws_loop(
+    rx: UnboundedReceiver<String>,
+    mut ws: tokio_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
+) -> Result<(), Error> {
+    let keepalive = tokio_stream::wrappers::IntervalStream::new(tokio::time::interval(
+        std::time::Duration::new(30, 0),
+    ));
+    let mut combo =
+        futures::stream::select(rx.map(Either::Left), keepalive.map(|_| Either::Right(())));
+    loop {
+        let next = combo
+            .next()
+            .await
+            .unwrap_or_else(|| unreachable!("timer stream never ends"));
+        match next {
+            Either::Left(msg) => ws.send(tungstenite::Message::Text(msg)).await?,
+            Either::Right(_) => ws.send(tungstenite::Message::Ping(Vec::new())).await?,
+        }
+    }
+}
+
+/// Helpers for testing endpoints which use `serve_text_messages`.
+#[cfg(test)]
+pub(super) mod testutil {
+    use futures::StreamExt;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+    pub(in crate::web) type Client = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    /// Connects to `path` on the test server at `base_url`.
+    pub(in crate::web) async fn connect(base_url: &str, path: &str) -> Client {
+        let url = format!("{}{}", base_url.replacen("http", "ws", 1), path);
+        tokio_tungstenite::connect_async(url).await.unwrap().0
+    }
+
+    /// Returns the next text message as JSON, skipping keepalives.
+    pub(in crate::web) async fn next_json(ws: &mut Client) -> serde_json::Value {
+        loop {
+            match ws.next().await.expect("WebSocket should be open").unwrap() {
+                Message::Text(t) => return serde_json::from_str(&t).unwrap(),
+                Message::Ping(_) | Message::Pong(_) => {}
+                m => panic!("unexpected message {:?}", m),
+            }
+        }
+    }
+}
@@ -0,0 +1,292 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Outbound webhook notifications of signal changes, as configured in
+//! `db::json::GlobalConfig::webhooks`.
+
+use crate::json;
+use base::clock::Clocks;
+use db::{json::WebhookConfig, recording};
+use failure::{bail, format_err, Error};
+use fnv::FnvHashMap;
+use futures::StreamExt;
+use log::warn;
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use tokio::time::Instant;
+use url::Url;
+use uuid::Uuid;
+
+/// The maximum number of attempts to deliver a single notification.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The delay before the first retry of a failed notification. It doubles on each later retry.
+const INITIAL_RETRY_DELAY: StdDuration = StdDuration::from_secs(1);
+
+const REQUEST_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+/// Starts sending notifications if any webhooks are configured.
+/// The returned task runs until shutdown.
+pub fn start<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    shutdown_rx: base::shutdown::Receiver,
+) -> Option<tokio::task::JoinHandle<()>> {
+    let (tx, rx) = futures::channel::mpsc::unbounded();
+    {
+        let mut l = db.lock();
+        let hooks = &l.config().webhooks;
+        if hooks.is_empty() {
+            return None;
+        }
+        for (i, h) in hooks.iter().enumerate() {
+            if h.url.is_none() {
+                warn!("Webhook {} has no url; ignoring it.", i);
+            }
+        }
+        l.watch_signals(Box::new(move |u| tx.unbounded_send(u.clone()).is_ok()));
+    }
+    Some(tokio::spawn(run(db, rx, shutdown_rx)))
+}
+
+async fn run<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    mut rx: futures::channel::mpsc::UnboundedReceiver<db::signal::Update>,
+    shutdown_rx: base::shutdown::Receiver,
+) {
+    let client = reqwest::Client::new();
+
+    // The time of the last notification sent for each (webhook index, signal id).
+    let mut last_sent: FnvHashMap<(usize, u32), Instant> = FnvHashMap::default();
+    loop {
+        let u = tokio::select! {
+            u = rx.next() => match u {
+                Some(u) => u,
+                None => return,
+            },
+            _ = shutdown_rx.as_future() => return,
+        };
+        let now = Instant::now();
+        let l = db.lock();
+        for (i, hook) in l.config().webhooks.iter().enumerate() {
+            let url = match hook.url.as_ref() {
+                Some(u) => u,
+                None => continue,
+            };
+            for (&signal, &state) in u.signals.iter().zip(&u.states) {
+                if !hook.matches(signal, state) {
+                    continue;
+                }
+                let min_interval = StdDuration::from_secs(u64::from(hook.min_interval_sec));
+                if let Some(&prev) = last_sent.get(&(i, signal)) {
+                    if now.saturating_duration_since(prev) < min_interval {
+                        continue;
+                    }
+                }
+                last_sent.insert((i, signal), now);
+                let body = match notification(&l, hook, signal, state, u.when.clone()) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        warn!("Unable to build notification for {}: {}", url, e);
+                        continue;
+                    }
+                };
+                tokio::spawn(deliver(client.clone(), url.clone(), body));
+            }
+        }
+    }
+}
+
+/// Serializes the body of a notification that `signal_id` has been set to `state` during `when`.
+fn notification(
+    db: &db::LockedDatabase,
+    hook: &WebhookConfig,
+    signal_id: u32,
+    state: u16,
+    when: Range<recording::Time>,
+) -> Result<Vec<u8>, Error> {
+    let signal = db
+        .signals_by_id()
+        .get(&signal_id)
+        .ok_or_else(|| format_err!("no such signal {}", signal_id))?;
+    let state_name = db
+        .signal_types_by_uuid()
+        .get(&signal.type_)
+        .and_then(|t| t.config.values.get(&u8::try_from(state).ok()?))
+        .map(|v| v.name.as_str());
+    let mut cameras = Vec::with_capacity(signal.config.camera_associations.len());
+    for (camera_id, association) in &signal.config.camera_associations {
+        let camera = match db.cameras_by_id().get(camera_id) {
+            Some(c) => c,
+            None => continue,
+        };
+        let view_url = camera.streams[db::StreamType::Main.index()]
+            .and_then(|id| view_path(db, camera.uuid, id, when.clone()))
+            .map(|p| match hook.nvr_url.as_ref() {
+                Some(base) => base
+                    .join(&p[1..])
+                    .map(String::from)
+                    .unwrap_or_else(|_| p.clone()),
+                None => p,
+            });
+        cameras.push(json::WebhookCamera {
+            uuid: camera.uuid,
+            short_name: &camera.short_name,
+            association,
+            view_url,
+        });
+    }
+    Ok(serde_json::to_vec(&json::WebhookNotification {
+        signal_id,
+        signal_short_name: &signal.config.short_name,
+        state,
+        state_name,
+        start_time_90k: when.start,
+        end_time_90k: when.end,
+        cameras,
+    })?)
+}
+
+/// Returns the path of a `view.mp4` covering as much of `when` as has been recorded so far on
+/// the given stream, or `None` if nothing has been.
+fn view_path(
+    db: &db::LockedDatabase,
+    camera_uuid: Uuid,
+    stream_id: i32,
+    when: Range<recording::Time>,
+) -> Option<String> {
+    let mut rows: Option<(db::ListRecordingsRow, db::ListRecordingsRow)> = None;
+    let mut same_open = true;
+    db.list_recordings_by_time(stream_id, when.clone(), &mut |r| {
+        rows = Some(match rows {
+            None => (r, r),
+            Some((first, last)) => {
+                same_open &= r.open_id == first.open_id;
+                if r.id.recording() < first.id.recording() {
+                    (r, last)
+                } else if r.id.recording() > last.id.recording() {
+                    (first, r)
+                } else {
+                    (first, last)
+                }
+            }
+        });
+        Ok(())
+    })
+    .ok()?;
+    let (first, last) = rows?;
+    let mut s = first.id.recording().to_string();
+    if last.id.recording() != first.id.recording() {
+        s.push_str(&format!("-{}", last.id.recording()));
+    }
+    if same_open {
+        s.push_str(&format!("@{}", first.open_id));
+    }
+    let rel_start = std::cmp::max(when.start - first.start, recording::Duration(0));
+    s.push_str(&format!(".{}-", rel_start.0));
+    let last_end = last.start + recording::Duration(i64::from(last.wall_duration_90k));
+    if when.end < last_end {
+        s.push_str(&(when.end - first.start).0.to_string());
+    }
+    Some(format!(
+        "/api/cameras/{}/{}/view.mp4?s={}",
+        camera_uuid,
+        db::StreamType::Main.as_str(),
+        s
+    ))
+}
+
+/// Delivers a notification, retrying with exponential backoff on failure.
+async fn deliver(client: reqwest::Client, url: Url, body: Vec<u8>) {
+    let mut delay = INITIAL_RETRY_DELAY;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let e = match post(&client, &url, body.clone()).await {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        if attempt == MAX_ATTEMPTS {
+            warn!(
+                "Giving up on notification to {} after {} attempts: {}",
+                url, attempt, e
+            );
+            return;
+        }
+        warn!(
+            "Notification to {} failed (attempt {}); retrying in {:?}: {}",
+            url, attempt, delay, e
+        );
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+async fn post(client: &reqwest::Client, url: &Url, body: Vec<u8>) -> Result<(), Error> {
+    let resp = client
+        .post(url.as_str())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .timeout(REQUEST_TIMEOUT)
+        .body(body)
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        bail!("HTTP status {}", resp.status());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use db::recording::{self, TIME_UNITS_PER_SEC};
+    use db::testutil::{self, TestDb};
+
+    #[test]
+    fn view_path() {
+        testutil::init();
+        let db = TestDb::new(base::clock::RealClocks {});
+        testutil::add_dummy_recordings_to_db(&db.db, 3);
+        let l = db.db.lock();
+        let open_id = l.open.unwrap().id;
+
+        // add_dummy_recordings_to_db starts at this time, with recordings of just under 60 seconds.
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let sec = recording::Duration(TIME_UNITS_PER_SEC);
+        let prefix = format!("/api/cameras/{}/main/view.mp4?s=", db.test_camera_uuid);
+
+        // A range within the recordings is clipped on both ends.
+        assert_eq!(
+            super::view_path(
+                &l,
+                db.test_camera_uuid,
+                testutil::TEST_STREAM_ID,
+                start + sec * 30..start + sec * 90
+            )
+            .unwrap(),
+            format!("{}0-1@{}.2700000-8100000", prefix, open_id)
+        );
+
+        // A range extending past the recordings is left open-ended.
+        assert_eq!(
+            super::view_path(
+                &l,
+                db.test_camera_uuid,
+                testutil::TEST_STREAM_ID,
+                start + sec * 150..start + sec * 600
+            )
+            .unwrap(),
+            format!("{}2@{}.2700030-", prefix, open_id)
+        );
+
+        // Nothing before the recordings.
+        assert_eq!(
+            super::view_path(
+                &l,
+                db.test_camera_uuid,
+                testutil::TEST_STREAM_ID,
+                start - sec * 60..start
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,325 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Minimal streaming `.zip` writer, as used by `/api/export.zip`.
+//!
+//! This only produces the framing; the caller streams each entry's data between the headers
+//! returned here. Entries are "stored" (uncompressed), which is the right choice for video. Their
+//! CRC-32s aren't known until the data has been streamed, so they're written in a data descriptor
+//! after each entry rather than in the local file header. ZIP64 extensions are used for entries
+//! (and the end of central directory record) only when the sizes or offsets require them.
+//!
+//! See PKWARE's [APPNOTE.TXT](https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT).
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+
+/// General purpose bit flags: bit 3 (sizes and CRC-32 are in the data descriptor) and bit 11
+/// (names are UTF-8).
+const FLAGS: u16 = 0x0808;
+
+/// "Version made by": UNIX, version 4.5 of the specification.
+const VERSION_MADE_BY: u16 = (3 << 8) | 45;
+
+/// "Version needed to extract" without and with ZIP64 extensions, respectively.
+const VERSION_NEEDED: u16 = 20;
+const VERSION_NEEDED_ZIP64: u16 = 45;
+
+/// The header ID of the ZIP64 extended information extra field.
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+
+/// External file attributes: a regular file with mode 0644.
+const EXTERNAL_ATTRIBUTES: u32 = 0o100644 << 16;
+
+struct Entry {
+    name: String,
+    len: u64,
+    crc32: u32,
+
+    /// The position of this entry's local file header within the archive.
+    offset: u64,
+}
+
+impl Entry {
+    fn zip64(&self) -> bool {
+        self.len >= 0xFFFF_FFFF || self.offset >= 0xFFFF_FFFF
+    }
+}
+
+pub struct Writer {
+    dos_time: u16,
+    dos_date: u16,
+    entries: Vec<Entry>,
+
+    /// The current position within the archive, including the data of the current entry.
+    pos: u64,
+    in_entry: bool,
+}
+
+impl Writer {
+    /// Creates a new writer which will mark all entries with the given modification time.
+    pub fn new(mtime: &time::Tm) -> Self {
+        // MS-DOS date and time formats, as used in the "last mod file date/time" fields.
+        // Years before 1980 can't be represented.
+        let year = std::cmp::max(mtime.tm_year + 1900, 1980);
+        Writer {
+            dos_time: ((mtime.tm_hour << 11) | (mtime.tm_min << 5) | (mtime.tm_sec / 2)) as u16,
+            dos_date: (((year - 1980) << 9) | ((mtime.tm_mon + 1) << 5) | mtime.tm_mday) as u16,
+            entries: Vec::new(),
+            pos: 0,
+            in_entry: false,
+        }
+    }
+
+    /// Starts an entry of the given name and length, returning its local file header.
+    /// The caller should follow this with exactly `len` bytes of data, then the data descriptor
+    /// returned by `finish_entry`.
+    pub fn start_entry(&mut self, name: &str, len: u64) -> Vec<u8> {
+        assert!(!self.in_entry, "start_entry called without finish_entry");
+        let e = Entry {
+            name: name.to_owned(),
+            len,
+            crc32: 0,
+            offset: self.pos,
+        };
+        let zip64 = e.zip64();
+        let mut buf = Vec::with_capacity(30 + name.len() + 20);
+        buf.write_u32::<LittleEndian>(LOCAL_FILE_HEADER_SIGNATURE)
+            .unwrap();
+        buf.write_u16::<LittleEndian>(if zip64 {
+            VERSION_NEEDED_ZIP64
+        } else {
+            VERSION_NEEDED
+        })
+        .unwrap();
+        buf.write_u16::<LittleEndian>(FLAGS).unwrap();
+        buf.write_u16::<LittleEndian>(0).unwrap(); // compression method: stored.
+        buf.write_u16::<LittleEndian>(self.dos_time).unwrap();
+        buf.write_u16::<LittleEndian>(self.dos_date).unwrap();
+
+        // CRC-32 and sizes are in the data descriptor instead. With ZIP64, the sizes must be
+        // 0xFFFFFFFF here, with zeroes in the extra field.
+        buf.write_u32::<LittleEndian>(0).unwrap(); // crc-32
+        let size = if zip64 { 0xFFFF_FFFF } else { 0 };
+        buf.write_u32::<LittleEndian>(size).unwrap(); // compressed size
+        buf.write_u32::<LittleEndian>(size).unwrap(); // uncompressed size
+        buf.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+        buf.write_u16::<LittleEndian>(if zip64 { 20 } else { 0 })
+            .unwrap(); // extra field length
+        buf.extend_from_slice(name.as_bytes());
+        if zip64 {
+            buf.write_u16::<LittleEndian>(ZIP64_EXTRA_FIELD_ID).unwrap();
+            buf.write_u16::<LittleEndian>(16).unwrap();
+            buf.write_u64::<LittleEndian>(0).unwrap(); // original size
+            buf.write_u64::<LittleEndian>(0).unwrap(); // compressed size
+        }
+        self.pos += buf.len() as u64 + len;
+        self.entries.push(e);
+        self.in_entry = true;
+        buf
+    }
+
+    /// Finishes the current entry, given the CRC-32 of its data. Returns its data descriptor.
+    pub fn finish_entry(&mut self, crc32: u32) -> Vec<u8> {
+        assert!(self.in_entry, "finish_entry called without start_entry");
+        let e = self.entries.last_mut().unwrap();
+        e.crc32 = crc32;
+        let mut buf = Vec::with_capacity(24);
+        buf.write_u32::<LittleEndian>(DATA_DESCRIPTOR_SIGNATURE)
+            .unwrap();
+        buf.write_u32::<LittleEndian>(crc32).unwrap();
+        if e.zip64() {
+            buf.write_u64::<LittleEndian>(e.len).unwrap(); // compressed size
+            buf.write_u64::<LittleEndian>(e.len).unwrap(); // uncompressed size
+        } else {
+            buf.write_u32::<LittleEndian>(e.len as u32).unwrap();
+            buf.write_u32::<LittleEndian>(e.len as u32).unwrap();
+        }
+        self.pos += buf.len() as u64;
+        self.in_entry = false;
+        buf
+    }
+
+    /// Returns the central directory and end of central directory record(s).
+    pub fn finish(self) -> Vec<u8> {
+        assert!(!self.in_entry, "finish called without finish_entry");
+        let mut buf = Vec::new();
+        let cd_offset = self.pos;
+        for e in &self.entries {
+            let zip64 = e.zip64();
+            buf.write_u32::<LittleEndian>(CENTRAL_DIRECTORY_HEADER_SIGNATURE)
+                .unwrap();
+            buf.write_u16::<LittleEndian>(VERSION_MADE_BY).unwrap();
+            buf.write_u16::<LittleEndian>(if zip64 {
+                VERSION_NEEDED_ZIP64
+            } else {
+                VERSION_NEEDED
+            })
+            .unwrap();
+            buf.write_u16::<LittleEndian>(FLAGS).unwrap();
+            buf.write_u16::<LittleEndian>(0).unwrap(); // compression method: stored.
+            buf.write_u16::<LittleEndian>(self.dos_time).unwrap();
+            buf.write_u16::<LittleEndian>(self.dos_date).unwrap();
+            buf.write_u32::<LittleEndian>(e.crc32).unwrap();
+
+            // With ZIP64, the sizes and offset are all in the extra field instead.
+            let (size, offset) = if zip64 {
+                (0xFFFF_FFFF, 0xFFFF_FFFF)
+            } else {
+                (e.len as u32, e.offset as u32)
+            };
+            buf.write_u32::<LittleEndian>(size).unwrap(); // compressed size
+            buf.write_u32::<LittleEndian>(size).unwrap(); // uncompressed size
+            buf.write_u16::<LittleEndian>(e.name.len() as u16).unwrap();
+            buf.write_u16::<LittleEndian>(if zip64 { 28 } else { 0 })
+                .unwrap(); // extra field length
+            buf.write_u16::<LittleEndian>(0).unwrap(); // file comment length
+            buf.write_u16::<LittleEndian>(0).unwrap(); // disk number start
+            buf.write_u16::<LittleEndian>(0).unwrap(); // internal file attributes
+            buf.write_u32::<LittleEndian>(EXTERNAL_ATTRIBUTES).unwrap();
+            buf.write_u32::<LittleEndian>(offset).unwrap(); // relative offset of local header
+            buf.extend_from_slice(e.name.as_bytes());
+            if zip64 {
+                buf.write_u16::<LittleEndian>(ZIP64_EXTRA_FIELD_ID).unwrap();
+                buf.write_u16::<LittleEndian>(24).unwrap();
+                buf.write_u64::<LittleEndian>(e.len).unwrap(); // original size
+                buf.write_u64::<LittleEndian>(e.len).unwrap(); // compressed size
+                buf.write_u64::<LittleEndian>(e.offset).unwrap();
+            }
+        }
+        let cd_size = buf.len() as u64;
+        let num_entries = self.entries.len() as u64;
+        let zip64 = self.entries.iter().any(Entry::zip64)
+            || cd_offset + cd_size >= 0xFFFF_FFFF
+            || num_entries >= 0xFFFF;
+        if zip64 {
+            // Zip64 end of central directory record.
+            let zip64_eocd_offset = cd_offset + cd_size;
+            buf.write_u32::<LittleEndian>(ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+                .unwrap();
+            buf.write_u64::<LittleEndian>(44).unwrap(); // size of the remaining record
+            buf.write_u16::<LittleEndian>(VERSION_MADE_BY).unwrap();
+            buf.write_u16::<LittleEndian>(VERSION_NEEDED_ZIP64).unwrap();
+            buf.write_u32::<LittleEndian>(0).unwrap(); // number of this disk
+            buf.write_u32::<LittleEndian>(0).unwrap(); // disk with the start of the cd
+            buf.write_u64::<LittleEndian>(num_entries).unwrap(); // entries on this disk
+            buf.write_u64::<LittleEndian>(num_entries).unwrap(); // total entries
+            buf.write_u64::<LittleEndian>(cd_size).unwrap();
+            buf.write_u64::<LittleEndian>(cd_offset).unwrap();
+
+            // Zip64 end of central directory locator.
+            buf.write_u32::<LittleEndian>(ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE)
+                .unwrap();
+            buf.write_u32::<LittleEndian>(0).unwrap(); // disk with the zip64 eocd record
+            buf.write_u64::<LittleEndian>(zip64_eocd_offset).unwrap();
+            buf.write_u32::<LittleEndian>(1).unwrap(); // total number of disks
+        }
+
+        // End of central directory record.
+        let (short_entries, short_cd_size, short_cd_offset) = if zip64 {
+            (0xFFFF, 0xFFFF_FFFF, 0xFFFF_FFFF)
+        } else {
+            (num_entries as u16, cd_size as u32, cd_offset as u32)
+        };
+        buf.write_u32::<LittleEndian>(END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+            .unwrap();
+        buf.write_u16::<LittleEndian>(0).unwrap(); // number of this disk
+        buf.write_u16::<LittleEndian>(0).unwrap(); // disk with the start of the cd
+        buf.write_u16::<LittleEndian>(short_entries).unwrap(); // entries on this disk
+        buf.write_u16::<LittleEndian>(short_entries).unwrap(); // total entries
+        buf.write_u32::<LittleEndian>(short_cd_size).unwrap();
+        buf.write_u32::<LittleEndian>(short_cd_offset).unwrap();
+        buf.write_u16::<LittleEndian>(0).unwrap(); // comment length
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Writer;
+    use db::testutil;
+
+    fn mtime() -> time::Tm {
+        time::at_utc(time::Timespec {
+            sec: 1609462861, // 2021-01-01T01:01:01Z
+            nsec: 0,
+        })
+    }
+
+    #[test]
+    fn small() {
+        testutil::init();
+        let mut w = Writer::new(&mtime());
+        let mut out = Vec::new();
+        out.extend_from_slice(&w.start_entry("a.txt", 5));
+        out.extend_from_slice(b"hello");
+        out.extend_from_slice(&w.finish_entry(crc32fast::hash(b"hello")));
+        out.extend_from_slice(&w.start_entry("b/c.txt", 0));
+        out.extend_from_slice(&w.finish_entry(crc32fast::hash(b"")));
+        out.extend_from_slice(&w.finish());
+        #[rustfmt::skip]
+        const EXPECTED: [u8; 235] = [
+            0x50, 0x4b, 0x03, 0x04, 0x14, 0x00, 0x08, 0x08,
+            0x00, 0x00, 0x20, 0x08, 0x21, 0x52, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x61, 0x2e,
+            0x74, 0x78, 0x74, 0x68, 0x65, 0x6c, 0x6c, 0x6f,
+            0x50, 0x4b, 0x07, 0x08, 0x86, 0xa6, 0x10, 0x36,
+            0x05, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00,
+            0x50, 0x4b, 0x03, 0x04, 0x14, 0x00, 0x08, 0x08,
+            0x00, 0x00, 0x20, 0x08, 0x21, 0x52, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x62, 0x2f,
+            0x63, 0x2e, 0x74, 0x78, 0x74, 0x50, 0x4b, 0x07,
+            0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x50, 0x4b, 0x01,
+            0x02, 0x2d, 0x03, 0x14, 0x00, 0x08, 0x08, 0x00,
+            0x00, 0x20, 0x08, 0x21, 0x52, 0x86, 0xa6, 0x10,
+            0x36, 0x05, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00,
+            0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0xa4, 0x81, 0x00,
+            0x00, 0x00, 0x00, 0x61, 0x2e, 0x74, 0x78, 0x74,
+            0x50, 0x4b, 0x01, 0x02, 0x2d, 0x03, 0x14, 0x00,
+            0x08, 0x08, 0x00, 0x00, 0x20, 0x08, 0x21, 0x52,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xa4, 0x81, 0x38, 0x00, 0x00, 0x00, 0x62, 0x2f,
+            0x63, 0x2e, 0x74, 0x78, 0x74, 0x50, 0x4b, 0x05,
+            0x06, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x02,
+            0x00, 0x68, 0x00, 0x00, 0x00, 0x6d, 0x00, 0x00,
+            0x00, 0x00, 0x00,
+        ];
+        assert_eq!(&out[..], &EXPECTED[..]);
+    }
+
+    #[test]
+    fn zip64() {
+        testutil::init();
+        let mut w = Writer::new(&mtime());
+        let len = 5 << 30;
+        let hdr = w.start_entry("a.mp4", len);
+        assert_eq!(&hdr[4..6], &[45, 0]); // version needed to extract
+        assert_eq!(&hdr[18..26], &[0xff; 8]); // sizes
+        assert_eq!(hdr.len(), 30 + 5 + 20);
+        let desc = w.finish_entry(0);
+        assert_eq!(desc.len(), 24);
+        let rest = w.finish();
+        let cd_offset = (hdr.len() + desc.len()) as u64 + len;
+
+        // The final end of central directory record should defer to the ZIP64 one.
+        let eocd = &rest[rest.len() - 22..];
+        assert_eq!(&eocd[0..4], b"PK\x05\x06");
+        assert_eq!(&eocd[16..20], &[0xff; 4]); // cd offset
+        let zip64_eocd = &rest[rest.len() - 22 - 20 - 56..];
+        assert_eq!(&zip64_eocd[0..4], b"PK\x06\x06");
+        assert_eq!(&zip64_eocd[48..56], &cd_offset.to_le_bytes());
+    }
+}